@@ -1,187 +1,308 @@
 use std::{
+    collections::VecDeque,
     fs::File,
     io::Read,
-    sync::{Arc, Condvar, Mutex},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
     thread::{self, JoinHandle},
 };
 
-struct ThreadWrapper {
+use thiserror::Error;
+
+const DEFAULT_QUEUE_BOUND: usize = 1024;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ExecutorError {
+    #[error("executor is shut down")]
+    ShutDown,
+    #[error("every worker queue is full")]
+    QueueFull,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkerStats {
+    pub thread_number: usize,
+    pub queue_depth: usize,
+    pub busy: bool,
+    pub tasks_run: u64,
+    pub panics: u64,
+    pub respawns: u64,
+}
+
+struct WorkerQueue {
+    tasks: VecDeque<Task>,
+    shutdown: bool,
+}
+
+struct WorkerState {
     thread_number: usize,
-    occupied: Arc<Mutex<bool>>,
-    task_added: Arc<(Mutex<bool>, Condvar)>,
-    task_queue: Arc<Mutex<Vec<Box<dyn FnOnce() + Send>>>>,
-    thread_join_handle: Option<JoinHandle<()>>,
+    queue: Mutex<WorkerQueue>,
+    task_added: Condvar,
+    busy: AtomicBool,
+    tasks_run: AtomicU64,
+    panics: AtomicU64,
+    respawns: AtomicU64,
+    thread_join_handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct ThreadWrapper {
+    state: Arc<WorkerState>,
 }
 
 impl ThreadWrapper {
     fn new(thread_number: usize) -> Self {
         Self {
-            thread_number,
-            occupied: Arc::new(Mutex::new(false)),
-            task_added: Arc::new((Mutex::new(false), Condvar::new())),
-            task_queue: Arc::new(Mutex::new(Vec::new())),
-            thread_join_handle: None,
+            state: Arc::new(WorkerState {
+                thread_number,
+                queue: Mutex::new(WorkerQueue {
+                    tasks: VecDeque::new(),
+                    shutdown: false,
+                }),
+                task_added: Condvar::new(),
+                busy: AtomicBool::new(false),
+                tasks_run: AtomicU64::new(0),
+                panics: AtomicU64::new(0),
+                respawns: AtomicU64::new(0),
+                thread_join_handle: Mutex::new(None),
+            }),
         }
     }
 
-    fn start(&mut self) {
-        let occupied = Arc::clone(&self.occupied);
-        let task_added = Arc::clone(&self.task_added);
-        let task_queue = Arc::clone(&self.task_queue);
+    fn start(&self) {
+        Self::spawn(Arc::clone(&self.state));
+    }
 
-        self.thread_join_handle = Some(
-            thread::Builder::new()
-                .name(format!("Redis thread {}", self.thread_number))
-                .spawn(move || loop {
-                    if (&*task_queue).lock().unwrap().is_empty() {
-                        let (mtx, cvar) = &*task_added;
-                        let added = mtx.lock().unwrap();
+    fn spawn(state: Arc<WorkerState>) {
+        let mut thread_join_handle = state.thread_join_handle.lock().unwrap();
 
-                        cvar.wait(added).unwrap();
-                    }
+        Self::spawn_locked(&state, &mut thread_join_handle);
+    }
 
-                    if let Some(task) = (&*task_queue).lock().unwrap().pop() {
-                        *(&*occupied).lock().unwrap() = true;
+    /// Runs queued tasks in submission order. A panicking task is caught so
+    /// it cannot take the queue down with it, and the worker hands its queue
+    /// over to a freshly spawned thread before exiting.
+    ///
+    /// The handle is published while the caller still holds the handle lock,
+    /// so a replacement spawned by a task that panics straight away waits for
+    /// it and is never overwritten by its own parent's handle.
+    fn spawn_locked(state: &Arc<WorkerState>, thread_join_handle: &mut Option<JoinHandle<()>>) {
+        let worker_state = Arc::clone(state);
+        let spawned = thread::Builder::new()
+            .name(format!("Redis thread {}", state.thread_number))
+            .spawn(move || loop {
+                let task = {
+                    let mut queue = worker_state.queue.lock().unwrap();
 
-                        task();
-                    }
+                    loop {
+                        if let Some(task) = queue.tasks.pop_front() {
+                            break task;
+                        }
+
+                        if queue.shutdown {
+                            return;
+                        }
 
-                    if (&*task_queue).lock().unwrap().is_empty() {
-                        *(&*occupied).lock().unwrap() = false;
+                        queue = worker_state.task_added.wait(queue).unwrap();
                     }
-                })
-                .unwrap(),
-        );
-    }
+                };
+
+                worker_state.busy.store(true, Ordering::SeqCst);
+
+                let outcome = panic::catch_unwind(AssertUnwindSafe(task));
 
-    fn is_occupied(&self) -> bool {
-        *(self.occupied.lock().unwrap())
+                worker_state.busy.store(false, Ordering::SeqCst);
+                worker_state.tasks_run.fetch_add(1, Ordering::SeqCst);
+
+                if outcome.is_err() {
+                    worker_state.panics.fetch_add(1, Ordering::SeqCst);
+                    worker_state.respawns.fetch_add(1, Ordering::SeqCst);
+
+                    Self::spawn(Arc::clone(&worker_state));
+
+                    return;
+                }
+            })
+            .unwrap();
+
+        *thread_join_handle = Some(spawned);
     }
 
-    fn is_live(&self) -> bool {
-        if let Some(ref thread_join_handle) = self.thread_join_handle {
-            if !thread_join_handle.is_finished() {
-                return true;
-            }
-        }
+    /// Starts a replacement if the worker thread has died. The check and the
+    /// spawn happen under one lock, so concurrent submitters cannot both
+    /// start a worker on the same queue.
+    fn respawn_if_dead(&self) {
+        let mut thread_join_handle = self.state.thread_join_handle.lock().unwrap();
+        let is_live = thread_join_handle
+            .as_ref()
+            .is_some_and(|thread_join_handle| !thread_join_handle.is_finished());
 
-        false
+        if !is_live {
+            self.state.respawns.fetch_add(1, Ordering::SeqCst);
+            Self::spawn_locked(&self.state, &mut thread_join_handle);
+        }
     }
 
     fn task_queue_size(&self) -> usize {
-        self.task_queue.lock().unwrap().len()
+        self.state.queue.lock().unwrap().tasks.len()
     }
 
-    fn set_task<T>(&mut self, task: T)
-    where
-        T: FnOnce() + Send + 'static,
-    {
-        if let Ok(mut task_queue) = self.task_queue.lock() {
-            (*task_queue).push(Box::new(task));
+    fn try_set_task(&self, task: Task, queue_bound: usize) -> Result<(), Task> {
+        let mut queue = self.state.queue.lock().unwrap();
+
+        if queue.shutdown || queue.tasks.len() >= queue_bound {
+            return Err(task);
+        }
 
-            let (mtx, cvar) = &*self.task_added;
-            let mut added = mtx.lock().unwrap();
+        queue.tasks.push_back(task);
+        self.state.task_added.notify_one();
 
-            *added = true;
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        self.state.queue.lock().unwrap().shutdown = true;
+        self.state.task_added.notify_all();
+    }
+
+    fn join(&self) {
+        // A worker that panicked installs its replacement's handle before it
+        // exits, so keep joining until no handle is left.
+        loop {
+            let thread_join_handle = self.state.thread_join_handle.lock().unwrap().take();
+
+            if let Some(thread_join_handle) = thread_join_handle {
+                let _ = thread_join_handle.join();
+            } else {
+                break;
+            }
+        }
+    }
 
-            cvar.notify_one();
+    fn stats(&self) -> WorkerStats {
+        WorkerStats {
+            thread_number: self.state.thread_number,
+            queue_depth: self.task_queue_size(),
+            busy: self.state.busy.load(Ordering::SeqCst),
+            tasks_run: self.state.tasks_run.load(Ordering::SeqCst),
+            panics: self.state.panics.load(Ordering::SeqCst),
+            respawns: self.state.respawns.load(Ordering::SeqCst),
         }
     }
 }
 
 pub struct ThreadPoolExecutor {
-    thread_count: usize,
+    queue_bound: usize,
     threads: Vec<ThreadWrapper>,
-    dead_thread_numbers: Vec<usize>,
+    shutdown: AtomicBool,
+}
+
+impl Default for ThreadPoolExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ThreadPoolExecutor {
     pub fn new() -> Self {
+        Self::with_config(Self::cpu_count(), DEFAULT_QUEUE_BOUND)
+    }
+
+    pub fn with_config(thread_count: usize, queue_bound: usize) -> Self {
+        let threads = (0..thread_count.max(1))
+            .map(ThreadWrapper::new)
+            .collect::<Vec<_>>();
+
+        for thread_wrapper in threads.iter() {
+            thread_wrapper.start();
+        }
+
         Self {
-            thread_count: Self::cpu_count(),
-            threads: Vec::new(),
-            dead_thread_numbers: Vec::new(),
+            queue_bound: queue_bound.max(1),
+            threads,
+            shutdown: AtomicBool::new(false),
         }
     }
 
-    pub fn submit<T>(&mut self, task: T)
+    /// Queues `task` on the least loaded worker without blocking, failing
+    /// when every queue is full.
+    pub fn try_submit<T>(&self, task: T) -> Result<(), ExecutorError>
     where
         T: FnOnce() + Send + 'static,
     {
-        self.evict_dead_threads();
+        if self.shutdown.load(Ordering::SeqCst) {
+            return Err(ExecutorError::ShutDown);
+        }
 
-        let free_thread = self
-            .threads
-            .iter_mut()
-            .find(|thread_wrapper| thread_wrapper.is_live() && !thread_wrapper.is_occupied());
+        self.respawn_dead_threads();
 
-        if let Some(free_thread) = free_thread {
-            free_thread.set_task(task);
-        } else {
-            if self.threads.len() < self.thread_count {
-                let thread_number;
+        let mut candidates = self.threads.iter().collect::<Vec<_>>();
 
-                if self.dead_thread_numbers.is_empty() {
-                    thread_number = self.threads.len()
-                } else {
-                    thread_number = self.dead_thread_numbers.first().unwrap().to_owned();
-                }
+        candidates.sort_by_key(|thread_wrapper| {
+            (
+                thread_wrapper.task_queue_size(),
+                thread_wrapper.state.busy.load(Ordering::SeqCst),
+            )
+        });
 
-                let mut thread_wrapper = ThreadWrapper::new(thread_number);
+        let mut task: Task = Box::new(task);
 
-                thread_wrapper.start();
-                thread_wrapper.set_task(task);
+        for thread_wrapper in candidates {
+            task = match thread_wrapper.try_set_task(task, self.queue_bound) {
+                Ok(()) => return Ok(()),
+                Err(task) => task,
+            };
+        }
 
-                self.threads.push(thread_wrapper);
-            } else {
-                if let Some(least_occupied_thread) = self
-                    .threads
-                    .iter_mut()
-                    .min_by(|a, b| a.task_queue_size().cmp(&b.task_queue_size()))
-                {
-                    least_occupied_thread.set_task(task);
-                } else {
-                    self.threads.first_mut().unwrap().set_task(task);
-                }
-            }
+        if self.shutdown.load(Ordering::SeqCst) {
+            Err(ExecutorError::ShutDown)
+        } else {
+            Err(ExecutorError::QueueFull)
         }
     }
 
-    fn evict_dead_threads(&mut self) {
-        self.dead_thread_numbers
-            .extend(self.threads.iter().filter_map(|thread_wrapper| {
-                if !thread_wrapper.is_live() {
-                    Some(thread_wrapper.thread_number)
-                } else {
-                    None
-                }
-            }));
-
-        for thread_number in self.dead_thread_numbers.iter() {
-            if let Some(index) =
-                self.threads
-                    .iter()
-                    .enumerate()
-                    .find_map(|(index, thread_wrapper)| {
-                        if thread_wrapper.thread_number == *thread_number {
-                            Some(index)
-                        } else {
-                            None
-                        }
-                    })
-            {
-                self.threads.remove(index);
-            }
+    /// Stops accepting tasks. Workers finish what is already queued and exit.
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+
+        for thread_wrapper in self.threads.iter() {
+            thread_wrapper.shutdown();
+        }
+    }
+
+    /// Waits for every worker to drain its queue and exit after `shutdown`.
+    pub fn join(&self) {
+        for thread_wrapper in self.threads.iter() {
+            thread_wrapper.join();
+        }
+    }
+
+    pub fn stats(&self) -> Vec<WorkerStats> {
+        self.threads
+            .iter()
+            .map(|thread_wrapper| thread_wrapper.stats())
+            .collect()
+    }
+
+    fn respawn_dead_threads(&self) {
+        for thread_wrapper in self.threads.iter() {
+            thread_wrapper.respawn_if_dead();
         }
     }
 
     fn cpu_count() -> usize {
         let mut cpuinfo = String::new();
 
-        File::open("/proc/cpuinfo")
-            .unwrap()
-            .read_to_string(&mut cpuinfo)
-            .unwrap();
+        if File::open("/proc/cpuinfo")
+            .and_then(|mut file| file.read_to_string(&mut cpuinfo))
+            .is_err()
+        {
+            return thread::available_parallelism().map_or(1, |count| count.get());
+        }
 
         cpuinfo
             .lines()
@@ -189,3 +310,122 @@ impl ThreadPoolExecutor {
             .count()
     }
 }
+
+impl Drop for ThreadPoolExecutor {
+    fn drop(&mut self) {
+        self.shutdown();
+        self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc, Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{ExecutorError, ThreadPoolExecutor};
+
+    #[test]
+    fn runs_tasks_in_submission_order() {
+        let executor = ThreadPoolExecutor::with_config(1, 16);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for index in 0..10 {
+            let order = Arc::clone(&order);
+
+            executor
+                .try_submit(move || order.lock().unwrap().push(index))
+                .unwrap();
+        }
+
+        executor.shutdown();
+        executor.join();
+
+        assert_eq!(*order.lock().unwrap(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn isolates_panics() {
+        let executor = ThreadPoolExecutor::with_config(1, 16);
+        let (sender, receiver) = mpsc::channel();
+
+        executor.try_submit(|| panic!("task failure")).unwrap();
+        executor
+            .try_submit(move || sender.send(()).unwrap())
+            .unwrap();
+
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let stats = executor.stats();
+
+        assert_eq!(stats[0].panics, 1);
+        assert_eq!(stats[0].respawns, 1);
+        assert_eq!(stats[0].tasks_run, 2);
+    }
+
+    #[test]
+    fn replaces_a_panicked_worker_exactly_once() {
+        let executor = ThreadPoolExecutor::with_config(1, 64);
+        let (sender, receiver) = mpsc::channel();
+
+        for _ in 0..32 {
+            executor.try_submit(|| panic!("task failure")).unwrap();
+        }
+
+        executor
+            .try_submit(move || sender.send(()).unwrap())
+            .unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let stats = executor.stats();
+
+        assert_eq!(stats[0].panics, 32);
+        assert_eq!(stats[0].respawns, 32);
+
+        executor.shutdown();
+        executor.join();
+    }
+
+    #[test]
+    fn bounded_queues_reject_tasks_when_full() {
+        let executor = ThreadPoolExecutor::with_config(1, 1);
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+        let (started_sender, started_receiver) = mpsc::channel();
+
+        executor
+            .try_submit(move || {
+                started_sender.send(()).unwrap();
+                release_receiver.recv().unwrap();
+            })
+            .unwrap();
+        started_receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        executor.try_submit(|| {}).unwrap();
+
+        assert_eq!(executor.try_submit(|| {}), Err(ExecutorError::QueueFull));
+
+        release_sender.send(()).unwrap();
+
+        // Space frees up as the worker catches up.
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while executor.try_submit(|| {}).is_err() {
+            assert!(Instant::now() < deadline);
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn rejects_tasks_after_shutdown() {
+        let executor = ThreadPoolExecutor::with_config(2, 4);
+
+        executor.shutdown();
+        executor.join();
+
+        assert_eq!(executor.try_submit(|| {}), Err(ExecutorError::ShutDown));
+    }
+}
@@ -1,10 +1,22 @@
-use redis::Redis;
+use std::{env, process};
+
+use redis::{Config, Redis};
 
 mod executor;
 mod redis;
 
 fn main() {
-    let mut redis_server = Redis::default();
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("*** FATAL CONFIG ERROR *** {error}");
+            process::exit(1);
+        }
+    };
+    let mut redis_server = Redis::new(config);
 
-    redis_server.listen().unwrap();
+    if let Err(error) = redis_server.listen() {
+        eprintln!("*** FATAL ERROR *** {error}");
+        process::exit(1);
+    }
 }
@@ -0,0 +1,450 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+
+use crate::redis::{
+    client::Client,
+    commands::RedisCommand,
+    config::AppendFsync,
+    error::AofError,
+    resp::{RESPDataTypes, RESPDecoder},
+    server::ServerContext,
+    store::{SnapshotEntry, Value},
+};
+
+/// Elements added per command when a rewrite rebuilds a collection.
+const REWRITE_BATCH_SIZE: usize = 64;
+
+struct AofState {
+    file: Option<File>,
+    /// Writes made while a rewrite is running, replayed onto the new file
+    /// before it replaces the old one.
+    rewrite_buffer: Option<Vec<u8>>,
+    unsynced: bool,
+    /// Replication offset reached by the last appended command.
+    written_offset: u64,
+    /// Replication offset up to which the log is known to be on disk.
+    synced_offset: u64,
+}
+
+/// The append-only log every write command is recorded in.
+pub struct AppendOnlyFile {
+    state: Mutex<AofState>,
+}
+
+impl Default for AppendOnlyFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AppendOnlyFile {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(AofState {
+                file: None,
+                rewrite_buffer: None,
+                unsynced: false,
+                written_offset: 0,
+                synced_offset: 0,
+            }),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state.lock().unwrap().file.is_some()
+    }
+
+    pub fn is_rewriting(&self) -> bool {
+        self.state.lock().unwrap().rewrite_buffer.is_some()
+    }
+
+    pub fn open(&self, path: &Path) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut state = self.state.lock().unwrap();
+
+        state.file = Some(file);
+        state.synced_offset = state.written_offset;
+
+        Ok(())
+    }
+
+    /// The replication offset the log is fsynced up to, or `None` while it
+    /// is closed.
+    pub fn synced_offset(&self) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+
+        state.file.as_ref().map(|_| state.synced_offset)
+    }
+
+    pub fn close(&self) -> io::Result<()> {
+        let file = self.state.lock().unwrap().file.take();
+
+        if let Some(file) = file {
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends a command that brought the replication stream to `offset`.
+    /// Under `appendfsync no` the kernel decides when data reaches the
+    /// disk, so written commands count as synced.
+    pub fn append(&self, command: &[u8], fsync: AppendFsync, offset: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        state.written_offset = offset;
+
+        if let Some(rewrite_buffer) = state.rewrite_buffer.as_mut() {
+            rewrite_buffer.extend_from_slice(command);
+        }
+
+        if let Some(file) = state.file.as_mut() {
+            file.write_all(command)?;
+
+            match fsync {
+                AppendFsync::Always => {
+                    file.sync_data()?;
+                    state.synced_offset = offset;
+                }
+                AppendFsync::EverySec => state.unsynced = true,
+                AppendFsync::No => state.synced_offset = offset,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Flushes appended commands to disk. The lock is only held to clone the
+    /// handle, so writers are not blocked behind the sync itself.
+    pub fn fsync(&self) -> io::Result<()> {
+        let (file, offset) = {
+            let mut state = self.state.lock().unwrap();
+
+            if !state.unsynced {
+                return Ok(());
+            }
+
+            state.unsynced = false;
+
+            match state.file.as_ref() {
+                Some(file) => (file.try_clone()?, state.written_offset),
+                None => return Ok(()),
+            }
+        };
+
+        file.sync_data()?;
+
+        let mut state = self.state.lock().unwrap();
+
+        state.synced_offset = state.synced_offset.max(offset);
+
+        Ok(())
+    }
+
+    pub fn begin_rewrite(&self) -> Result<(), AofError> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.rewrite_buffer.is_some() {
+            return Err(AofError::RewriteInProgress);
+        }
+
+        state.rewrite_buffer = Some(Vec::new());
+
+        Ok(())
+    }
+
+    /// Appends the writes buffered during the rewrite to `rewritten`, moves it
+    /// over `path` and, when `reopen` is set, continues appending to it.
+    pub fn finish_rewrite(&self, rewritten: &Path, path: &Path, reopen: bool) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let rewrite_buffer = state.rewrite_buffer.take().unwrap_or_default();
+        let mut file = OpenOptions::new().append(true).open(rewritten)?;
+
+        file.write_all(&rewrite_buffer)?;
+        file.sync_data()?;
+        fs::rename(rewritten, path)?;
+
+        state.file = reopen.then_some(file);
+        state.unsynced = false;
+        state.synced_offset = state.written_offset;
+
+        Ok(())
+    }
+
+    pub fn abort_rewrite(&self) {
+        self.state.lock().unwrap().rewrite_buffer = None;
+    }
+}
+
+/// Writes the commands that rebuild `entries` from an empty keyspace.
+/// Collections are rebuilt a batch of elements per command.
+pub fn write_base(path: &Path, entries: &[SnapshotEntry]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    for (key, value, expires_at) in entries {
+        let mut commands = match value.as_ref() {
+            Value::String(string) => {
+                vec![vec![
+                    Bytes::from_static(b"SET"),
+                    key.clone(),
+                    string.clone(),
+                ]]
+            }
+            Value::List(list) => list
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .chunks(REWRITE_BATCH_SIZE)
+                .map(|batch| [&[Bytes::from_static(b"RPUSH"), key.clone()], batch].concat())
+                .collect(),
+            Value::Hash(hash) => hash
+                .iter()
+                .flat_map(|(field, value)| [field.clone(), value.clone()])
+                .collect::<Vec<_>>()
+                .chunks(2 * REWRITE_BATCH_SIZE)
+                .map(|batch| [&[Bytes::from_static(b"HSET"), key.clone()], batch].concat())
+                .collect(),
+            Value::Set(set) => set
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .chunks(REWRITE_BATCH_SIZE)
+                .map(|batch| [&[Bytes::from_static(b"SADD"), key.clone()], batch].concat())
+                .collect(),
+            Value::SortedSet(zset) => zset
+                .iter()
+                .flat_map(|(member, score)| [Bytes::from(score.to_string()), member.clone()])
+                .collect::<Vec<_>>()
+                .chunks(2 * REWRITE_BATCH_SIZE)
+                .map(|batch| [&[Bytes::from_static(b"ZADD"), key.clone()], batch].concat())
+                .collect(),
+        };
+
+        if let Some(expires_at) = expires_at {
+            commands.push(vec![
+                Bytes::from_static(b"PEXPIREAT"),
+                key.clone(),
+                Bytes::from(expires_at.to_string()),
+            ]);
+        }
+
+        for command in commands {
+            writer.write_all(&serialize_command(&command))?;
+        }
+    }
+
+    writer
+        .into_inner()
+        .map_err(|error| error.into_error())?
+        .sync_all()
+}
+
+pub fn serialize_command(command: &[Bytes]) -> Bytes {
+    RESPDataTypes::Array(Some(
+        command
+            .iter()
+            .map(|arg| RESPDataTypes::BulkString(Some(arg.clone())))
+            .collect(),
+    ))
+    .serialize()
+}
+
+/// Replays the log at `path`, returning how many commands were applied. A
+/// command cut short by a crash is dropped and the file truncated to the
+/// last complete one, as Redis does with `aof-load-truncated yes`.
+pub fn load(path: &Path, context: &Arc<ServerContext>) -> Result<usize, AofError> {
+    let contents = fs::read(path)?;
+    let mut decoder = RESPDecoder::new();
+    let mut client = Client::new();
+    let mut applied = 0;
+
+    decoder.buffer_mut().extend_from_slice(&contents);
+
+    while let Some(request) = decoder.decode()? {
+        let mut command = RedisCommand::try_from(request).map_err(|error| match error {
+            RESPDataTypes::BulkError(message) | RESPDataTypes::SimpleError(message) => {
+                AofError::InvalidCommand(message)
+            }
+            error => AofError::InvalidCommand(format!("{error:?}")),
+        })?;
+
+        command.respond(&mut io::sink(), context, &mut client);
+        applied += 1;
+    }
+
+    let remaining = decoder.buffer_mut().len();
+
+    if remaining > 0 {
+        let valid_length = (contents.len() - remaining) as u64;
+
+        eprintln!(
+            "!!! Warning: short read while loading the AOF file {}, truncating to {valid_length} bytes",
+            path.display()
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(valid_length)?;
+    }
+
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process, sync::Arc};
+
+    use bytes::Bytes;
+
+    use super::{load, serialize_command, write_base, AppendOnlyFile};
+    use crate::redis::{
+        config::{AppendFsync, Config},
+        server::ServerContext,
+        sorted_set::SortedSet,
+        store::{now_millis, Value},
+    };
+
+    #[test]
+    fn buffers_writes_during_rewrite() {
+        let directory = env::temp_dir();
+        let path = directory.join(format!("redis-aof-{}.aof", process::id()));
+        let rewritten = directory.join(format!("redis-aof-rewrite-{}.aof", process::id()));
+        let aof = AppendOnlyFile::new();
+        let first = serialize_command(&[Bytes::from("SET"), Bytes::from("a"), Bytes::from("1")]);
+        let second = serialize_command(&[Bytes::from("SET"), Bytes::from("b"), Bytes::from("2")]);
+
+        assert_eq!(aof.synced_offset(), None);
+
+        aof.open(&path).unwrap();
+        aof.append(&first, AppendFsync::Always, 10).unwrap();
+        aof.begin_rewrite().unwrap();
+
+        assert!(aof.begin_rewrite().is_err());
+        assert_eq!(aof.synced_offset(), Some(10));
+
+        write_base(
+            &rewritten,
+            &[(
+                Bytes::from("a"),
+                Arc::new(Value::String(Bytes::from("1"))),
+                None,
+            )],
+        )
+        .unwrap();
+        aof.append(&second, AppendFsync::EverySec, 20).unwrap();
+
+        assert_eq!(aof.synced_offset(), Some(10));
+
+        aof.finish_rewrite(&rewritten, &path, true).unwrap();
+
+        assert_eq!(aof.synced_offset(), Some(20));
+
+        aof.append(&second, AppendFsync::EverySec, 30).unwrap();
+        aof.fsync().unwrap();
+
+        assert_eq!(aof.synced_offset(), Some(30));
+
+        let contents = fs::read(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(!aof.is_rewriting());
+        assert_eq!(contents, [&first[..], &second[..], &second[..]].concat());
+    }
+
+    #[test]
+    fn replays_and_truncates_partial_commands() {
+        let path = env::temp_dir().join(format!("redis-aof-load-{}.aof", process::id()));
+        let complete = [
+            serialize_command(&[Bytes::from("SET"), Bytes::from("a"), Bytes::from("1")]),
+            serialize_command(&[
+                Bytes::from("PEXPIREAT"),
+                Bytes::from("a"),
+                Bytes::from("99999999999999"),
+            ]),
+        ]
+        .concat();
+        let partial = serialize_command(&[Bytes::from("SET"), Bytes::from("b"), Bytes::from("2")]);
+
+        fs::write(&path, [&complete[..], &partial[..10]].concat()).unwrap();
+
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let applied = load(&path, &context).unwrap();
+        let contents = fs::read(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(applied, 2);
+        assert_eq!(context.store.get(b"a").unwrap(), Some(Bytes::from("1")));
+        assert_eq!(context.store.get(b"b").unwrap(), None);
+        assert_eq!(contents, complete);
+    }
+
+    #[test]
+    fn base_rebuilds_every_type() {
+        let path = env::temp_dir().join(format!("redis-aof-base-{}.aof", process::id()));
+        let deadline = now_millis() + 60_000;
+        let mut zset = SortedSet::new();
+
+        zset.insert(Bytes::from("a"), 1.5);
+        zset.insert(Bytes::from("b"), f64::INFINITY);
+
+        let entries = vec![
+            (Bytes::from("string"), Value::String(Bytes::from("1")), None),
+            (
+                Bytes::from("list"),
+                Value::List(
+                    (0..100)
+                        .map(|index| Bytes::from(index.to_string()))
+                        .collect(),
+                ),
+                Some(deadline),
+            ),
+            (
+                Bytes::from("hash"),
+                Value::Hash(
+                    (0..100)
+                        .map(|index| (Bytes::from(index.to_string()), Bytes::from("value")))
+                        .collect(),
+                ),
+                None,
+            ),
+            (
+                Bytes::from("set"),
+                Value::Set(
+                    (0..10)
+                        .map(|index| Bytes::from(index.to_string()))
+                        .collect(),
+                ),
+                None,
+            ),
+            (Bytes::from("zset"), Value::SortedSet(zset), None),
+        ];
+
+        let mut entries = entries
+            .into_iter()
+            .map(|(key, value, expires_at)| (key, Arc::new(value), expires_at))
+            .collect::<Vec<_>>();
+
+        write_base(&path, &entries).unwrap();
+
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let applied = load(&path, &context).unwrap();
+        let mut restored = context.store.snapshot();
+
+        fs::remove_file(&path).unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        restored.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // The list takes two RPUSH batches and a PEXPIREAT, the hash two
+        // HSET batches, the set one SADD and the sorted set one ZADD.
+        assert_eq!(applied, 8);
+        assert_eq!(restored, entries);
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
+use crate::redis::{resp::RESPDataTypes, server::ServerContext, store::ListEnd};
+
+/// What a blocked client does with the first of its keys that holds a
+/// list again.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockedOperation {
+    /// BLPOP and BRPOP.
+    Pop { end: ListEnd },
+    /// BLMOVE and BRPOPLPUSH.
+    Move {
+        destination: Bytes,
+        from: ListEnd,
+        to: ListEnd,
+    },
+    /// BLMPOP.
+    MultiPop { end: ListEnd, count: i64 },
+}
+
+struct Waiter {
+    keys: Vec<Bytes>,
+    operation: BlockedOperation,
+    reply: oneshot::Sender<RESPDataTypes>,
+}
+
+#[derive(Default)]
+struct WaitersState {
+    waiters: HashMap<u64, Waiter>,
+    /// Clients blocked on each key, longest waiting first.
+    keys: HashMap<Bytes, VecDeque<u64>>,
+    /// Keys pushed onto since blocked clients were last served.
+    ready: VecDeque<Bytes>,
+}
+
+/// The clients blocked on keys, shared by every connection. Pushes mark
+/// keys as ready and the write that made them serves their waiters before
+/// it replies, so a client that sees the push acknowledged can no longer
+/// take the elements first.
+#[derive(Default)]
+pub struct KeyWaiters {
+    state: Mutex<WaitersState>,
+    /// Held while ready keys are served and while a client unregisters, so
+    /// a client is never unregistered between being picked and receiving
+    /// what was popped for it.
+    serving: Mutex<()>,
+}
+
+impl KeyWaiters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Blocks `client_id` on `keys`. The reply is sent once the client is
+    /// served.
+    pub fn register(
+        &self,
+        client_id: u64,
+        keys: Vec<Bytes>,
+        operation: BlockedOperation,
+    ) -> oneshot::Receiver<RESPDataTypes> {
+        let (reply, receiver) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+
+        for key in keys.iter() {
+            let queue = state.keys.entry(key.clone()).or_default();
+
+            if !queue.contains(&client_id) {
+                queue.push_back(client_id);
+            }
+        }
+
+        state.waiters.insert(
+            client_id,
+            Waiter {
+                keys,
+                operation,
+                reply,
+            },
+        );
+
+        receiver
+    }
+
+    pub fn unregister(&self, client_id: u64) {
+        let _serving = self.serving.lock().unwrap();
+
+        Self::remove(&mut self.state.lock().unwrap(), client_id);
+    }
+
+    /// Marks `key` as ready when clients are blocked on it.
+    pub fn signal(&self, key: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.keys.contains_key(key) && !state.ready.iter().any(|ready| ready == key) {
+            state.ready.push_back(Bytes::copy_from_slice(key));
+        }
+    }
+
+    /// Serves the clients blocked on ready keys, longest waiting first.
+    /// `serve` runs a client's operation on a key and returns its reply, or
+    /// `None` when the key holds nothing for it, which leaves the rest of
+    /// that key's clients waiting.
+    pub fn serve<F>(&self, mut serve: F)
+    where
+        F: FnMut(&Bytes, &BlockedOperation) -> Option<RESPDataTypes>,
+    {
+        if self.state.lock().unwrap().ready.is_empty() {
+            return;
+        }
+
+        let _serving = self.serving.lock().unwrap();
+
+        while let Some(key) = self.take_ready() {
+            while let Some((client_id, operation)) = self.next_waiter(&key) {
+                match serve(&key, &operation) {
+                    Some(reply) => self.unblock(client_id, reply),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    fn take_ready(&self) -> Option<Bytes> {
+        self.state.lock().unwrap().ready.pop_front()
+    }
+
+    /// The client that has waited longest on `key`, skipping those whose
+    /// connection went away.
+    fn next_waiter(&self, key: &[u8]) -> Option<(u64, BlockedOperation)> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            let client_id = *state.keys.get(key)?.front()?;
+            let waiter = &state.waiters[&client_id];
+
+            if !waiter.reply.is_closed() {
+                return Some((client_id, waiter.operation.clone()));
+            }
+
+            Self::remove(&mut state, client_id);
+        }
+    }
+
+    /// Unblocks `client_id` with `reply`.
+    fn unblock(&self, client_id: u64, reply: RESPDataTypes) {
+        if let Some(waiter) = Self::remove(&mut self.state.lock().unwrap(), client_id) {
+            let _ = waiter.reply.send(reply);
+        }
+    }
+
+    fn remove(state: &mut WaitersState, client_id: u64) -> Option<Waiter> {
+        let waiter = state.waiters.remove(&client_id)?;
+
+        for key in waiter.keys.iter() {
+            if let Some(queue) = state.keys.get_mut(key) {
+                queue.retain(|waiting| *waiting != client_id);
+
+                if queue.is_empty() {
+                    state.keys.remove(key);
+                }
+            }
+        }
+
+        Some(waiter)
+    }
+}
+
+/// Unregisters a blocked client when its wait ends, whether it was served,
+/// timed out or its connection dropped.
+pub struct Registration {
+    context: Arc<ServerContext>,
+    client_id: u64,
+}
+
+impl Registration {
+    pub fn new(context: Arc<ServerContext>, client_id: u64) -> Self {
+        Self { context, client_id }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.context.waiters.unregister(self.client_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{BlockedOperation, KeyWaiters};
+    use crate::redis::{resp::RESPDataTypes, store::ListEnd};
+
+    #[test]
+    fn serves_the_longest_waiting_client() {
+        let waiters = KeyWaiters::new();
+        let pop = BlockedOperation::Pop { end: ListEnd::Left };
+        let first = waiters.register(1, vec![Bytes::from("a"), Bytes::from("b")], pop.clone());
+        let second = waiters.register(2, vec![Bytes::from("b")], pop.clone());
+        let gone = waiters.register(3, vec![Bytes::from("c")], pop.clone());
+
+        drop(gone);
+        waiters.signal(b"missing");
+        waiters.signal(b"b");
+        waiters.signal(b"b");
+        waiters.signal(b"c");
+
+        assert_eq!(waiters.take_ready(), Some(Bytes::from("b")));
+        assert_eq!(waiters.next_waiter(b"b"), Some((1, pop.clone())));
+
+        waiters.unblock(1, RESPDataTypes::Integer(1));
+
+        assert_eq!(waiters.next_waiter(b"b"), Some((2, pop)));
+        assert_eq!(waiters.next_waiter(b"a"), None);
+        assert_eq!(waiters.take_ready(), Some(Bytes::from("c")));
+        assert_eq!(waiters.next_waiter(b"c"), None);
+        assert_eq!(waiters.take_ready(), None);
+        assert_eq!(first.blocking_recv(), Ok(RESPDataTypes::Integer(1)));
+
+        waiters.unregister(2);
+        waiters.signal(b"b");
+
+        assert_eq!(waiters.take_ready(), None);
+        assert!(second.blocking_recv().is_err());
+    }
+
+    #[test]
+    fn serve_leaves_clients_waiting_on_empty_keys() {
+        let waiters = KeyWaiters::new();
+        let pop = BlockedOperation::Pop { end: ListEnd::Left };
+        let mut first = waiters.register(1, vec![Bytes::from("a")], pop.clone());
+        let second = waiters.register(2, vec![Bytes::from("a")], pop);
+        let mut elements = vec![RESPDataTypes::Integer(1)];
+
+        waiters.signal(b"a");
+        waiters.serve(|_, _| elements.pop());
+
+        assert_eq!(first.try_recv(), Ok(RESPDataTypes::Integer(1)));
+        assert_eq!(
+            waiters.next_waiter(b"a").map(|(client_id, _)| client_id),
+            Some(2)
+        );
+
+        waiters.unregister(2);
+
+        assert!(second.blocking_recv().is_err());
+    }
+}
@@ -0,0 +1,55 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::redis::{
+    replication::ReplicaLink,
+    resp::{ProtocolVersion, RESPDataTypes},
+};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The reply to a command that blocks its connection until it resolves.
+pub type BlockedReply = Pin<Box<dyn Future<Output = RESPDataTypes> + Send>>;
+
+pub struct Client {
+    pub id: u64,
+    pub name: Option<String>,
+    pub protocol: ProtocolVersion,
+    pub address: Option<SocketAddr>,
+    /// Port a replica announced with `REPLCONF listening-port`.
+    pub listening_port: Option<u16>,
+    /// Set once PSYNC succeeds; the connection then becomes a replication
+    /// link.
+    pub replica_link: Option<ReplicaLink>,
+    /// Replication offset reached by this client's last write, which WAIT
+    /// and WAITAOF wait for.
+    pub write_offset: u64,
+    /// Set by a blocking command; the connection awaits it before reading
+    /// further commands.
+    pub blocked: Option<BlockedReply>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            name: None,
+            protocol: ProtocolVersion::RESP2,
+            address: None,
+            listening_port: None,
+            replica_link: None,
+            write_offset: 0,
+            blocked: None,
+        }
+    }
+}
@@ -1,6 +1,29 @@
-use std::{cell::RefCell, collections::HashMap, io::Write};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future,
+    io::Write,
+    ops::Range,
+    slice,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 
-use crate::redis::resp::RESPDataTypes;
+use bytes::Bytes;
+use tokio::time::{self, Instant};
+
+use crate::redis::{
+    blocking::{BlockedOperation, Registration},
+    client::Client,
+    error::WrongType,
+    replication::{random_index, ReplicaLink},
+    resp::{ProtocolVersion, RESPDataTypes},
+    server::ServerContext,
+    sorted_set::{LexBound, ScoreBound, SortedSet},
+    store::{
+        now_millis, ExpireCondition, Expiry, Hash, KvStore, List, ListEnd, LockedKeys, Set,
+        SetCondition, Ttl, Value,
+    },
+};
 
 macro_rules! redis_err {
     ($message:expr) => {
@@ -8,22 +31,446 @@ macro_rules! redis_err {
     };
 }
 
-thread_local! {
-    pub static KV_STORE: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
-}
+pub const REDIS_VERSION: &str = "7.2.0";
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum RedisCommand {
-    PING { message: Option<String> },
-    ECHO { message: String },
-    SET { key: String, value: String },
-    GET { key: String },
+    PING {
+        message: Option<Bytes>,
+    },
+    HELLO {
+        protocol_version: Option<String>,
+        auth: Option<(String, String)>,
+        client_name: Option<String>,
+    },
+    ECHO {
+        message: Bytes,
+    },
+    SET {
+        key: Bytes,
+        value: Bytes,
+        condition: Option<SetCondition>,
+        expiry: Option<SetExpiry>,
+        get: bool,
+    },
+    GET {
+        key: Bytes,
+    },
+    EXPIRE {
+        key: Bytes,
+        seconds: i64,
+        conditions: Vec<ExpireCondition>,
+    },
+    PEXPIRE {
+        key: Bytes,
+        milliseconds: i64,
+        conditions: Vec<ExpireCondition>,
+    },
+    EXPIREAT {
+        key: Bytes,
+        unix_time_seconds: i64,
+        conditions: Vec<ExpireCondition>,
+    },
+    PEXPIREAT {
+        key: Bytes,
+        unix_time_milliseconds: i64,
+        conditions: Vec<ExpireCondition>,
+    },
+    TTL {
+        key: Bytes,
+    },
+    PTTL {
+        key: Bytes,
+    },
+    EXPIRETIME {
+        key: Bytes,
+    },
+    PEXPIRETIME {
+        key: Bytes,
+    },
+    PERSIST {
+        key: Bytes,
+    },
+    DEL {
+        keys: Vec<Bytes>,
+    },
+    CONFIG {
+        subcommand: ConfigSubcommand,
+    },
+    SAVE,
+    BGSAVE,
+    LASTSAVE,
+    BGREWRITEAOF,
+    REPLCONF {
+        options: Vec<(String, Bytes)>,
+    },
+    PSYNC {
+        replid: String,
+        offset: i64,
+    },
+    ROLE,
+    REPLICAOF {
+        master: Option<(String, u16)>,
+    },
+    INFO {
+        sections: Vec<String>,
+    },
+    WAIT {
+        numreplicas: i64,
+        timeout: i64,
+    },
+    WAITAOF {
+        numlocal: i64,
+        numreplicas: i64,
+        timeout: i64,
+    },
+    TYPE {
+        key: Bytes,
+    },
+    LPUSH {
+        key: Bytes,
+        elements: Vec<Bytes>,
+    },
+    RPUSH {
+        key: Bytes,
+        elements: Vec<Bytes>,
+    },
+    LPUSHX {
+        key: Bytes,
+        elements: Vec<Bytes>,
+    },
+    RPUSHX {
+        key: Bytes,
+        elements: Vec<Bytes>,
+    },
+    LPOP {
+        key: Bytes,
+        count: Option<i64>,
+    },
+    RPOP {
+        key: Bytes,
+        count: Option<i64>,
+    },
+    LLEN {
+        key: Bytes,
+    },
+    LRANGE {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    LINDEX {
+        key: Bytes,
+        index: i64,
+    },
+    LSET {
+        key: Bytes,
+        index: i64,
+        element: Bytes,
+    },
+    LINSERT {
+        key: Bytes,
+        before: bool,
+        pivot: Bytes,
+        element: Bytes,
+    },
+    LREM {
+        key: Bytes,
+        count: i64,
+        element: Bytes,
+    },
+    LTRIM {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    LPOS {
+        key: Bytes,
+        element: Bytes,
+        rank: i64,
+        count: Option<i64>,
+        maxlen: i64,
+    },
+    LMOVE {
+        source: Bytes,
+        destination: Bytes,
+        from: ListEnd,
+        to: ListEnd,
+    },
+    RPOPLPUSH {
+        source: Bytes,
+        destination: Bytes,
+    },
+    LMPOP {
+        keys: Vec<Bytes>,
+        end: ListEnd,
+        count: i64,
+    },
+    BLPOP {
+        keys: Vec<Bytes>,
+        timeout: i64,
+    },
+    BRPOP {
+        keys: Vec<Bytes>,
+        timeout: i64,
+    },
+    BLMOVE {
+        source: Bytes,
+        destination: Bytes,
+        from: ListEnd,
+        to: ListEnd,
+        timeout: i64,
+    },
+    BRPOPLPUSH {
+        source: Bytes,
+        destination: Bytes,
+        timeout: i64,
+    },
+    BLMPOP {
+        keys: Vec<Bytes>,
+        end: ListEnd,
+        count: i64,
+        timeout: i64,
+    },
+    HSET {
+        key: Bytes,
+        pairs: Vec<(Bytes, Bytes)>,
+    },
+    HSETNX {
+        key: Bytes,
+        field: Bytes,
+        value: Bytes,
+    },
+    HGET {
+        key: Bytes,
+        field: Bytes,
+    },
+    HMGET {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+    HGETALL {
+        key: Bytes,
+    },
+    HDEL {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+    HEXISTS {
+        key: Bytes,
+        field: Bytes,
+    },
+    HLEN {
+        key: Bytes,
+    },
+    HKEYS {
+        key: Bytes,
+    },
+    HVALS {
+        key: Bytes,
+    },
+    HINCRBY {
+        key: Bytes,
+        field: Bytes,
+        increment: i64,
+    },
+    HINCRBYFLOAT {
+        key: Bytes,
+        field: Bytes,
+        increment: f64,
+    },
+    HSTRLEN {
+        key: Bytes,
+        field: Bytes,
+    },
+    HRANDFIELD {
+        key: Bytes,
+        count: Option<i64>,
+        with_values: bool,
+    },
+    SADD {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    SREM {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    SMEMBERS {
+        key: Bytes,
+    },
+    SISMEMBER {
+        key: Bytes,
+        member: Bytes,
+    },
+    SMISMEMBER {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    SCARD {
+        key: Bytes,
+    },
+    SINTER {
+        keys: Vec<Bytes>,
+    },
+    SUNION {
+        keys: Vec<Bytes>,
+    },
+    SDIFF {
+        keys: Vec<Bytes>,
+    },
+    SINTERSTORE {
+        destination: Bytes,
+        keys: Vec<Bytes>,
+    },
+    SUNIONSTORE {
+        destination: Bytes,
+        keys: Vec<Bytes>,
+    },
+    SDIFFSTORE {
+        destination: Bytes,
+        keys: Vec<Bytes>,
+    },
+    SINTERCARD {
+        keys: Vec<Bytes>,
+        limit: usize,
+    },
+    SMOVE {
+        source: Bytes,
+        destination: Bytes,
+        member: Bytes,
+    },
+    SPOP {
+        key: Bytes,
+        count: Option<i64>,
+    },
+    SRANDMEMBER {
+        key: Bytes,
+        count: Option<i64>,
+    },
+    ZADD {
+        key: Bytes,
+        condition: Option<SetCondition>,
+        comparison: Option<ScoreComparison>,
+        changed: bool,
+        increment: bool,
+        pairs: Vec<(f64, Bytes)>,
+    },
+    ZINCRBY {
+        key: Bytes,
+        increment: f64,
+        member: Bytes,
+    },
+    ZREM {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    ZCARD {
+        key: Bytes,
+    },
+    ZSCORE {
+        key: Bytes,
+        member: Bytes,
+    },
+    ZMSCORE {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    ZRANK {
+        key: Bytes,
+        member: Bytes,
+        with_score: bool,
+    },
+    ZREVRANK {
+        key: Bytes,
+        member: Bytes,
+        with_score: bool,
+    },
+    ZCOUNT {
+        key: Bytes,
+        min: ScoreBound,
+        max: ScoreBound,
+    },
+    ZLEXCOUNT {
+        key: Bytes,
+        min: LexBound,
+        max: LexBound,
+    },
+    ZRANGE {
+        key: Bytes,
+        by: ZRangeBy,
+        reverse: bool,
+        limit: Option<(i64, i64)>,
+        with_scores: bool,
+    },
+    ZREMRANGEBYRANK {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    ZREMRANGEBYSCORE {
+        key: Bytes,
+        min: ScoreBound,
+        max: ScoreBound,
+    },
+    ZREMRANGEBYLEX {
+        key: Bytes,
+        min: LexBound,
+        max: LexBound,
+    },
+    ZRANDMEMBER {
+        key: Bytes,
+        count: Option<i64>,
+        with_scores: bool,
+    },
+}
+
+/// How SINTER, SUNION and SDIFF combine their sets.
+#[derive(Clone, Copy)]
+enum SetAlgebra {
+    Intersection,
+    Union,
+    Difference,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub enum SetExpiry {
+    EX(i64),
+    PX(i64),
+    EXAT(i64),
+    PXAT(i64),
+    KEEPTTL,
+}
+
+/// ZADD's GT and LT, which only let an update raise or lower a score.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum ScoreComparison {
+    GT,
+    LT,
+}
+
+/// What ZRANGE selects. Bounds are held lowest first even when REV took
+/// them highest first.
+pub enum ZRangeBy {
+    Rank { start: i64, stop: i64 },
+    Score { min: ScoreBound, max: ScoreBound },
+    Lex { min: LexBound, max: LexBound },
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub enum ConfigSubcommand {
+    GET { patterns: Vec<String> },
+    SET { parameters: Vec<(String, String)> },
+    REWRITE,
 }
 
 impl TryFrom<RESPDataTypes> for RedisCommand {
     type Error = RESPDataTypes;
 
+    #[allow(clippy::match_like_matches_macro)]
     fn try_from(request: RESPDataTypes) -> Result<Self, Self::Error> {
-        let command_and_args = if let RESPDataTypes::Array(elements) = request {
+        let command_and_args = if let RESPDataTypes::Array(Some(elements)) = request {
             elements
         } else {
             return redis_err!("resp datatype other than array of bulk string not supported");
@@ -65,7 +512,7 @@ impl TryFrom<RESPDataTypes> for RedisCommand {
             }));
         }
 
-        match command.to_lowercase().as_str() {
+        match lowercase(command).as_str() {
             "ping" => {
                 let mut message = None;
 
@@ -84,16 +531,8 @@ impl TryFrom<RESPDataTypes> for RedisCommand {
                     redis_err!("message not provided for echo command")
                 }
             }
-            "set" => {
-                if args.len() < 2 {
-                    redis_err!("both key and value should be provided")
-                } else {
-                    Ok(RedisCommand::SET {
-                        key: args[0].to_owned(),
-                        value: args[1].to_owned(),
-                    })
-                }
-            }
+            "hello" => parse_hello_args(&args),
+            "set" => parse_set_args(&args),
             "get" => {
                 if let Some(key) = args.first() {
                     Ok(RedisCommand::GET {
@@ -103,103 +542,5170 @@ impl TryFrom<RESPDataTypes> for RedisCommand {
                     redis_err!("key not provided")
                 }
             }
-            _ => redis_err!("unknown command"),
-        }
-    }
-}
+            "expire" => {
+                let (key, seconds, conditions) = parse_expire_args(&args, "expire")?;
 
-impl RedisCommand {
-    pub fn respond<T>(&mut self, stream: &mut T)
-    where
-        T: Write,
-    {
-        use RedisCommand::*;
+                Ok(RedisCommand::EXPIRE {
+                    key,
+                    seconds,
+                    conditions,
+                })
+            }
+            "pexpire" => {
+                let (key, milliseconds, conditions) = parse_expire_args(&args, "pexpire")?;
 
-        let response = match self {
-            PING { message } => {
-                if let Some(message) = message {
-                    RESPDataTypes::BulkString(Some(message.to_owned()))
-                } else {
-                    RESPDataTypes::SimpleString("PONG".to_string())
+                Ok(RedisCommand::PEXPIRE {
+                    key,
+                    milliseconds,
+                    conditions,
+                })
+            }
+            "expireat" => {
+                let (key, unix_time_seconds, conditions) = parse_expire_args(&args, "expireat")?;
+
+                Ok(RedisCommand::EXPIREAT {
+                    key,
+                    unix_time_seconds,
+                    conditions,
+                })
+            }
+            "pexpireat" => {
+                let (key, unix_time_milliseconds, conditions) =
+                    parse_expire_args(&args, "pexpireat")?;
+
+                Ok(RedisCommand::PEXPIREAT {
+                    key,
+                    unix_time_milliseconds,
+                    conditions,
+                })
+            }
+            "ttl" => Ok(RedisCommand::TTL {
+                key: parse_single_key(&args, "ttl")?,
+            }),
+            "pttl" => Ok(RedisCommand::PTTL {
+                key: parse_single_key(&args, "pttl")?,
+            }),
+            "expiretime" => Ok(RedisCommand::EXPIRETIME {
+                key: parse_single_key(&args, "expiretime")?,
+            }),
+            "pexpiretime" => Ok(RedisCommand::PEXPIRETIME {
+                key: parse_single_key(&args, "pexpiretime")?,
+            }),
+            "persist" => Ok(RedisCommand::PERSIST {
+                key: parse_single_key(&args, "persist")?,
+            }),
+            "del" => Ok(RedisCommand::DEL {
+                keys: parse_keys(&args, "del")?,
+            }),
+            "config" => parse_config_args(&args),
+            "save" => parse_no_args(&args, "save", RedisCommand::SAVE),
+            "bgsave" => parse_no_args(&args, "bgsave", RedisCommand::BGSAVE),
+            "lastsave" => parse_no_args(&args, "lastsave", RedisCommand::LASTSAVE),
+            "bgrewriteaof" => parse_no_args(&args, "bgrewriteaof", RedisCommand::BGREWRITEAOF),
+            "replconf" => {
+                if args.is_empty() {
+                    return Err(wrong_arity("replconf"));
+                }
+
+                if args.len() % 2 != 0 {
+                    return redis_err!("ERR syntax error");
                 }
+
+                Ok(RedisCommand::REPLCONF {
+                    options: args
+                        .chunks(2)
+                        .map(|option| (lowercase(&option[0]), option[1].to_owned()))
+                        .collect(),
+                })
             }
-            ECHO { message } => RESPDataTypes::BulkString(Some(message.to_owned())),
-            SET { key, value } => {
-                let mut previous_value = None;
+            "psync" => {
+                if args.len() != 2 {
+                    return Err(wrong_arity("psync"));
+                }
 
-                KV_STORE.with_borrow_mut(|kv| {
-                    if let Some(v) = kv.get(key) {
-                        previous_value = Some(v.to_owned());
-                    }
+                Ok(RedisCommand::PSYNC {
+                    replid: String::from_utf8_lossy(&args[0]).into_owned(),
+                    offset: parse_integer(&args[1])?,
+                })
+            }
+            "role" => parse_no_args(&args, "role", RedisCommand::ROLE),
+            "replicaof" | "slaveof" => {
+                if args.len() != 2 {
+                    return Err(wrong_arity(&lowercase(command)));
+                }
 
-                    kv.insert(key.to_owned(), value.to_owned());
-                });
+                let host = String::from_utf8_lossy(&args[0]).into_owned();
 
-                if let Some(previous_value) = previous_value {
-                    RESPDataTypes::BulkString(Some(previous_value))
-                } else {
-                    RESPDataTypes::SimpleString("OK".to_string())
+                if host.eq_ignore_ascii_case("no") && lowercase(&args[1]) == "one" {
+                    return Ok(RedisCommand::REPLICAOF { master: None });
+                }
+
+                let port = u16::try_from(parse_integer(&args[1])?)
+                    .map_or(redis_err!("ERR Invalid master port"), Ok)?;
+
+                Ok(RedisCommand::REPLICAOF {
+                    master: Some((host, port)),
+                })
+            }
+            "info" => Ok(RedisCommand::INFO {
+                sections: args.iter().map(|section| lowercase(section)).collect(),
+            }),
+            "wait" => {
+                if args.len() != 2 {
+                    return Err(wrong_arity("wait"));
                 }
+
+                Ok(RedisCommand::WAIT {
+                    numreplicas: parse_integer(&args[0])?,
+                    timeout: parse_timeout(&args[1])?,
+                })
             }
-            GET { key } => {
-                let mut value = None;
+            "waitaof" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("waitaof"));
+                }
 
-                KV_STORE.with_borrow(|kv| {
-                    let found_value = kv.get(key);
+                Ok(RedisCommand::WAITAOF {
+                    numlocal: parse_integer(&args[0])?,
+                    numreplicas: parse_integer(&args[1])?,
+                    timeout: parse_timeout(&args[2])?,
+                })
+            }
+            "type" => Ok(RedisCommand::TYPE {
+                key: parse_single_key(&args, "type")?,
+            }),
+            "lpush" => {
+                let (key, elements) = parse_key_and_values(&args, "lpush")?;
 
-                    if let Some(found_value) = found_value {
-                        value = Some(found_value.to_owned())
-                    }
-                });
+                Ok(RedisCommand::LPUSH { key, elements })
+            }
+            "rpush" => {
+                let (key, elements) = parse_key_and_values(&args, "rpush")?;
 
-                if let Some(value) = value {
-                    RESPDataTypes::BulkString(Some(value.to_owned()))
-                } else {
-                    RESPDataTypes::Null
+                Ok(RedisCommand::RPUSH { key, elements })
+            }
+            "lpushx" => {
+                let (key, elements) = parse_key_and_values(&args, "lpushx")?;
+
+                Ok(RedisCommand::LPUSHX { key, elements })
+            }
+            "rpushx" => {
+                let (key, elements) = parse_key_and_values(&args, "rpushx")?;
+
+                Ok(RedisCommand::RPUSHX { key, elements })
+            }
+            "lpop" => {
+                let (key, count) = parse_pop_args(&args, "lpop")?;
+
+                Ok(RedisCommand::LPOP { key, count })
+            }
+            "rpop" => {
+                let (key, count) = parse_pop_args(&args, "rpop")?;
+
+                Ok(RedisCommand::RPOP { key, count })
+            }
+            "llen" => Ok(RedisCommand::LLEN {
+                key: parse_single_key(&args, "llen")?,
+            }),
+            "lrange" => {
+                let (key, start, stop) = parse_range_args(&args, "lrange")?;
+
+                Ok(RedisCommand::LRANGE { key, start, stop })
+            }
+            "ltrim" => {
+                let (key, start, stop) = parse_range_args(&args, "ltrim")?;
+
+                Ok(RedisCommand::LTRIM { key, start, stop })
+            }
+            "lindex" => {
+                if args.len() != 2 {
+                    return Err(wrong_arity("lindex"));
+                }
+
+                Ok(RedisCommand::LINDEX {
+                    key: args[0].to_owned(),
+                    index: parse_integer(&args[1])?,
+                })
+            }
+            "lset" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("lset"));
                 }
+
+                Ok(RedisCommand::LSET {
+                    key: args[0].to_owned(),
+                    index: parse_integer(&args[1])?,
+                    element: args[2].to_owned(),
+                })
             }
-        };
+            "linsert" => {
+                if args.len() != 4 {
+                    return Err(wrong_arity("linsert"));
+                }
+
+                let before = match lowercase(&args[1]).as_str() {
+                    "before" => true,
+                    "after" => false,
+                    _ => return redis_err!("ERR syntax error"),
+                };
+
+                Ok(RedisCommand::LINSERT {
+                    key: args[0].to_owned(),
+                    before,
+                    pivot: args[2].to_owned(),
+                    element: args[3].to_owned(),
+                })
+            }
+            "lrem" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("lrem"));
+                }
+
+                Ok(RedisCommand::LREM {
+                    key: args[0].to_owned(),
+                    count: parse_integer(&args[1])?,
+                    element: args[2].to_owned(),
+                })
+            }
+            "lpos" => parse_lpos_args(&args),
+            "lmove" => {
+                if args.len() != 4 {
+                    return Err(wrong_arity("lmove"));
+                }
+
+                Ok(RedisCommand::LMOVE {
+                    source: args[0].to_owned(),
+                    destination: args[1].to_owned(),
+                    from: parse_list_end(&args[2])?,
+                    to: parse_list_end(&args[3])?,
+                })
+            }
+            "rpoplpush" => {
+                if args.len() != 2 {
+                    return Err(wrong_arity("rpoplpush"));
+                }
+
+                Ok(RedisCommand::RPOPLPUSH {
+                    source: args[0].to_owned(),
+                    destination: args[1].to_owned(),
+                })
+            }
+            "lmpop" => {
+                let (keys, end, count) = parse_mpop_args(&args, "lmpop")?;
+
+                Ok(RedisCommand::LMPOP { keys, end, count })
+            }
+            "blpop" => {
+                let (keys, timeout) = parse_bpop_args(&args, "blpop")?;
+
+                Ok(RedisCommand::BLPOP { keys, timeout })
+            }
+            "brpop" => {
+                let (keys, timeout) = parse_bpop_args(&args, "brpop")?;
+
+                Ok(RedisCommand::BRPOP { keys, timeout })
+            }
+            "blmove" => {
+                if args.len() != 5 {
+                    return Err(wrong_arity("blmove"));
+                }
+
+                Ok(RedisCommand::BLMOVE {
+                    source: args[0].to_owned(),
+                    destination: args[1].to_owned(),
+                    from: parse_list_end(&args[2])?,
+                    to: parse_list_end(&args[3])?,
+                    timeout: parse_blocking_timeout(&args[4])?,
+                })
+            }
+            "brpoplpush" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("brpoplpush"));
+                }
+
+                Ok(RedisCommand::BRPOPLPUSH {
+                    source: args[0].to_owned(),
+                    destination: args[1].to_owned(),
+                    timeout: parse_blocking_timeout(&args[2])?,
+                })
+            }
+            "blmpop" => {
+                if args.is_empty() {
+                    return Err(wrong_arity("blmpop"));
+                }
+
+                let timeout = parse_blocking_timeout(&args[0])?;
+                let (keys, end, count) = parse_mpop_args(&args[1..], "blmpop")?;
+
+                Ok(RedisCommand::BLMPOP {
+                    keys,
+                    end,
+                    count,
+                    timeout,
+                })
+            }
+            "hset" => {
+                if args.len() < 3 || args.len() % 2 == 0 {
+                    return Err(wrong_arity("hset"));
+                }
+
+                Ok(RedisCommand::HSET {
+                    key: args[0].to_owned(),
+                    pairs: args[1..]
+                        .chunks(2)
+                        .map(|pair| (pair[0].to_owned(), pair[1].to_owned()))
+                        .collect(),
+                })
+            }
+            "hsetnx" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("hsetnx"));
+                }
+
+                Ok(RedisCommand::HSETNX {
+                    key: args[0].to_owned(),
+                    field: args[1].to_owned(),
+                    value: args[2].to_owned(),
+                })
+            }
+            "hget" => {
+                let (key, field) = parse_field_args(&args, "hget")?;
+
+                Ok(RedisCommand::HGET { key, field })
+            }
+            "hmget" => {
+                let (key, fields) = parse_key_and_values(&args, "hmget")?;
+
+                Ok(RedisCommand::HMGET { key, fields })
+            }
+            "hgetall" => Ok(RedisCommand::HGETALL {
+                key: parse_single_key(&args, "hgetall")?,
+            }),
+            "hdel" => {
+                let (key, fields) = parse_key_and_values(&args, "hdel")?;
+
+                Ok(RedisCommand::HDEL { key, fields })
+            }
+            "hexists" => {
+                let (key, field) = parse_field_args(&args, "hexists")?;
+
+                Ok(RedisCommand::HEXISTS { key, field })
+            }
+            "hlen" => Ok(RedisCommand::HLEN {
+                key: parse_single_key(&args, "hlen")?,
+            }),
+            "hkeys" => Ok(RedisCommand::HKEYS {
+                key: parse_single_key(&args, "hkeys")?,
+            }),
+            "hvals" => Ok(RedisCommand::HVALS {
+                key: parse_single_key(&args, "hvals")?,
+            }),
+            "hincrby" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("hincrby"));
+                }
+
+                Ok(RedisCommand::HINCRBY {
+                    key: args[0].to_owned(),
+                    field: args[1].to_owned(),
+                    increment: parse_integer(&args[2])?,
+                })
+            }
+            "hincrbyfloat" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("hincrbyfloat"));
+                }
+
+                Ok(RedisCommand::HINCRBYFLOAT {
+                    key: args[0].to_owned(),
+                    field: args[1].to_owned(),
+                    increment: parse_float(&args[2])?,
+                })
+            }
+            "hstrlen" => {
+                let (key, field) = parse_field_args(&args, "hstrlen")?;
+
+                Ok(RedisCommand::HSTRLEN { key, field })
+            }
+            "hrandfield" => match &args[..] {
+                [key] => Ok(RedisCommand::HRANDFIELD {
+                    key: key.to_owned(),
+                    count: None,
+                    with_values: false,
+                }),
+                [key, count, options @ ..] if options.len() <= 1 => {
+                    let with_values = match options.first() {
+                        Some(option) if lowercase(option) == "withvalues" => true,
+                        Some(_) => return redis_err!("ERR syntax error"),
+                        None => false,
+                    };
+
+                    Ok(RedisCommand::HRANDFIELD {
+                        key: key.to_owned(),
+                        count: Some(parse_random_count(count, with_values)?),
+                        with_values,
+                    })
+                }
+                _ => Err(wrong_arity("hrandfield")),
+            },
+            "sadd" => {
+                let (key, members) = parse_key_and_values(&args, "sadd")?;
+
+                Ok(RedisCommand::SADD { key, members })
+            }
+            "srem" => {
+                let (key, members) = parse_key_and_values(&args, "srem")?;
+
+                Ok(RedisCommand::SREM { key, members })
+            }
+            "smembers" => Ok(RedisCommand::SMEMBERS {
+                key: parse_single_key(&args, "smembers")?,
+            }),
+            "sismember" => {
+                let (key, member) = parse_field_args(&args, "sismember")?;
+
+                Ok(RedisCommand::SISMEMBER { key, member })
+            }
+            "smismember" => {
+                let (key, members) = parse_key_and_values(&args, "smismember")?;
+
+                Ok(RedisCommand::SMISMEMBER { key, members })
+            }
+            "scard" => Ok(RedisCommand::SCARD {
+                key: parse_single_key(&args, "scard")?,
+            }),
+            "sinter" => Ok(RedisCommand::SINTER {
+                keys: parse_keys(&args, "sinter")?,
+            }),
+            "sunion" => Ok(RedisCommand::SUNION {
+                keys: parse_keys(&args, "sunion")?,
+            }),
+            "sdiff" => Ok(RedisCommand::SDIFF {
+                keys: parse_keys(&args, "sdiff")?,
+            }),
+            "sinterstore" => {
+                let (destination, keys) = parse_key_and_values(&args, "sinterstore")?;
+
+                Ok(RedisCommand::SINTERSTORE { destination, keys })
+            }
+            "sunionstore" => {
+                let (destination, keys) = parse_key_and_values(&args, "sunionstore")?;
+
+                Ok(RedisCommand::SUNIONSTORE { destination, keys })
+            }
+            "sdiffstore" => {
+                let (destination, keys) = parse_key_and_values(&args, "sdiffstore")?;
+
+                Ok(RedisCommand::SDIFFSTORE { destination, keys })
+            }
+            "sintercard" => parse_sintercard_args(&args),
+            "smove" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("smove"));
+                }
+
+                Ok(RedisCommand::SMOVE {
+                    source: args[0].to_owned(),
+                    destination: args[1].to_owned(),
+                    member: args[2].to_owned(),
+                })
+            }
+            "spop" => {
+                let (key, count) = parse_pop_args(&args, "spop")?;
+
+                Ok(RedisCommand::SPOP { key, count })
+            }
+            "srandmember" => match &args[..] {
+                [key] => Ok(RedisCommand::SRANDMEMBER {
+                    key: key.to_owned(),
+                    count: None,
+                }),
+                [key, count] => Ok(RedisCommand::SRANDMEMBER {
+                    key: key.to_owned(),
+                    count: Some(parse_random_count(count, false)?),
+                }),
+                _ => Err(wrong_arity("srandmember")),
+            },
+            "zadd" => parse_zadd_args(&args),
+            "zincrby" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("zincrby"));
+                }
+
+                Ok(RedisCommand::ZINCRBY {
+                    key: args[0].to_owned(),
+                    increment: parse_score(&args[1])?,
+                    member: args[2].to_owned(),
+                })
+            }
+            "zrem" => {
+                let (key, members) = parse_key_and_values(&args, "zrem")?;
+
+                Ok(RedisCommand::ZREM { key, members })
+            }
+            "zcard" => Ok(RedisCommand::ZCARD {
+                key: parse_single_key(&args, "zcard")?,
+            }),
+            "zscore" => {
+                let (key, member) = parse_field_args(&args, "zscore")?;
+
+                Ok(RedisCommand::ZSCORE { key, member })
+            }
+            "zmscore" => {
+                let (key, members) = parse_key_and_values(&args, "zmscore")?;
+
+                Ok(RedisCommand::ZMSCORE { key, members })
+            }
+            "zrank" => {
+                let (key, member, with_score) = parse_zrank_args(&args, "zrank")?;
+
+                Ok(RedisCommand::ZRANK {
+                    key,
+                    member,
+                    with_score,
+                })
+            }
+            "zrevrank" => {
+                let (key, member, with_score) = parse_zrank_args(&args, "zrevrank")?;
+
+                Ok(RedisCommand::ZREVRANK {
+                    key,
+                    member,
+                    with_score,
+                })
+            }
+            "zcount" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("zcount"));
+                }
+
+                Ok(RedisCommand::ZCOUNT {
+                    key: args[0].to_owned(),
+                    min: parse_score_bound(&args[1])?,
+                    max: parse_score_bound(&args[2])?,
+                })
+            }
+            "zlexcount" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("zlexcount"));
+                }
+
+                Ok(RedisCommand::ZLEXCOUNT {
+                    key: args[0].to_owned(),
+                    min: parse_lex_bound(&args[1])?,
+                    max: parse_lex_bound(&args[2])?,
+                })
+            }
+            "zrange" => parse_zrange_args(&args),
+            "zremrangebyrank" => {
+                let (key, start, stop) = parse_range_args(&args, "zremrangebyrank")?;
+
+                Ok(RedisCommand::ZREMRANGEBYRANK { key, start, stop })
+            }
+            "zremrangebyscore" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("zremrangebyscore"));
+                }
+
+                Ok(RedisCommand::ZREMRANGEBYSCORE {
+                    key: args[0].to_owned(),
+                    min: parse_score_bound(&args[1])?,
+                    max: parse_score_bound(&args[2])?,
+                })
+            }
+            "zremrangebylex" => {
+                if args.len() != 3 {
+                    return Err(wrong_arity("zremrangebylex"));
+                }
+
+                Ok(RedisCommand::ZREMRANGEBYLEX {
+                    key: args[0].to_owned(),
+                    min: parse_lex_bound(&args[1])?,
+                    max: parse_lex_bound(&args[2])?,
+                })
+            }
+            "zrandmember" => match &args[..] {
+                [key] => Ok(RedisCommand::ZRANDMEMBER {
+                    key: key.to_owned(),
+                    count: None,
+                    with_scores: false,
+                }),
+                [key, count, options @ ..] if options.len() <= 1 => {
+                    let with_scores = match options.first() {
+                        Some(option) if lowercase(option) == "withscores" => true,
+                        Some(_) => return redis_err!("ERR syntax error"),
+                        None => false,
+                    };
 
-        stream.write(response.serialize().as_bytes()).unwrap();
-        stream.flush().unwrap();
+                    Ok(RedisCommand::ZRANDMEMBER {
+                        key: key.to_owned(),
+                        count: Some(parse_random_count(count, with_scores)?),
+                        with_scores,
+                    })
+                }
+                _ => Err(wrong_arity("zrandmember")),
+            },
+            _ => redis_err!("unknown command"),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{RESPDataTypes, RedisCommand};
+fn wrong_arity(command: &str) -> RESPDataTypes {
+    RESPDataTypes::BulkError(format!(
+        "ERR wrong number of arguments for '{command}' command"
+    ))
+}
 
-    macro_rules! raw_request {
-        ($command:literal) => {
-            RESPDataTypes::Array(
-                vec![
-                    RESPDataTypes::BulkString($command.to_string())
-                ]
-            )
-        };
-        ($command:literal, [$($arg:literal),*]) => {
-            RESPDataTypes::Array(
-                vec![
-                    RESPDataTypes::BulkString(Some($command.to_string())),
-                    $(
-                        RESPDataTypes::BulkString(Some($arg.to_string())),
-                    )*
-                ]
-            )
-        };
+fn lowercase(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).to_lowercase()
+}
+
+fn parse_integer(arg: &[u8]) -> Result<i64, RESPDataTypes> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse::<i64>().ok())
+        .map_or(
+            redis_err!("ERR value is not an integer or out of range"),
+            Ok,
+        )
+}
+
+fn parse_float(arg: &[u8]) -> Result<f64, RESPDataTypes> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse::<f64>().ok())
+        .filter(|value| value.is_finite())
+        .map_or(redis_err!("ERR value is not a valid float"), Ok)
+}
+
+/// Parses a sorted set score, where the infinities are allowed.
+fn parse_score(arg: &[u8]) -> Result<f64, RESPDataTypes> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .map_or(redis_err!("ERR value is not a valid float"), Ok)
+}
+
+/// Parses a score range end, which `(` makes exclusive.
+fn parse_score_bound(arg: &[u8]) -> Result<ScoreBound, RESPDataTypes> {
+    let (score, exclusive) = match arg.strip_prefix(b"(") {
+        Some(score) => (score, true),
+        None => (arg, false),
+    };
+
+    parse_score(score)
+        .map(|score| ScoreBound { score, exclusive })
+        .map_err(|_| RESPDataTypes::BulkError("ERR min or max is not a float".to_string()))
+}
+
+/// Parses a lexicographical range end: `-`, `+`, or a member after `[` or
+/// `(`.
+fn parse_lex_bound(arg: &[u8]) -> Result<LexBound, RESPDataTypes> {
+    match arg.split_first() {
+        Some((b'-', [])) => Ok(LexBound::Min),
+        Some((b'+', [])) => Ok(LexBound::Max),
+        Some((b'[', member)) => Ok(LexBound::Inclusive(Bytes::copy_from_slice(member))),
+        Some((b'(', member)) => Ok(LexBound::Exclusive(Bytes::copy_from_slice(member))),
+        _ => redis_err!("ERR min or max not valid string range item"),
     }
+}
 
-    #[test]
-    fn echo() {
-        let redis_command = RedisCommand::try_from(raw_request!("echo", ["Hello World!"]));
+/// Parses a timeout in milliseconds, where 0 blocks forever.
+fn parse_timeout(arg: &[u8]) -> Result<i64, RESPDataTypes> {
+    let timeout = parse_integer(arg).map_err(|_| {
+        RESPDataTypes::BulkError("ERR timeout is not an integer or out of range".to_string())
+    })?;
 
-        assert!(match redis_command {
-            Ok(RedisCommand::ECHO { message: _ }) => true,
-            _ => false,
-        });
+    if timeout < 0 {
+        return redis_err!("ERR timeout is negative");
+    }
 
-        if let Ok(RedisCommand::ECHO { message }) = redis_command {
-            assert_eq!(message, String::from("Hello World!"));
+    Ok(timeout)
+}
+
+/// Parses a timeout in seconds, which may be fractional, to milliseconds.
+/// 0 blocks forever.
+fn parse_blocking_timeout(arg: &[u8]) -> Result<i64, RESPDataTypes> {
+    let seconds = std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite())
+        .map_or(redis_err!("ERR timeout is not a float or out of range"), Ok)?;
+
+    if seconds < 0.0 {
+        return redis_err!("ERR timeout is negative");
+    }
+
+    Ok((seconds * 1000.0).ceil() as i64)
+}
+
+fn parse_single_key(args: &[Bytes], command: &str) -> Result<Bytes, RESPDataTypes> {
+    if args.len() != 1 {
+        return Err(wrong_arity(command));
+    }
+
+    Ok(args[0].to_owned())
+}
+
+fn parse_no_args(
+    args: &[Bytes],
+    command: &str,
+    parsed: RedisCommand,
+) -> Result<RedisCommand, RESPDataTypes> {
+    if !args.is_empty() {
+        return Err(wrong_arity(command));
+    }
+
+    Ok(parsed)
+}
+
+fn parse_key_and_values(
+    args: &[Bytes],
+    command: &str,
+) -> Result<(Bytes, Vec<Bytes>), RESPDataTypes> {
+    if args.len() < 2 {
+        return Err(wrong_arity(command));
+    }
+
+    Ok((args[0].to_owned(), args[1..].to_vec()))
+}
+
+fn parse_keys(args: &[Bytes], command: &str) -> Result<Vec<Bytes>, RESPDataTypes> {
+    if args.is_empty() {
+        return Err(wrong_arity(command));
+    }
+
+    Ok(args.to_vec())
+}
+
+fn parse_field_args(args: &[Bytes], command: &str) -> Result<(Bytes, Bytes), RESPDataTypes> {
+    if args.len() != 2 {
+        return Err(wrong_arity(command));
+    }
+
+    Ok((args[0].to_owned(), args[1].to_owned()))
+}
+
+fn parse_pop_args(args: &[Bytes], command: &str) -> Result<(Bytes, Option<i64>), RESPDataTypes> {
+    match args {
+        [key] => Ok((key.to_owned(), None)),
+        [key, count] => {
+            let count = parse_integer(count)?;
+
+            if count < 0 {
+                return redis_err!("ERR value is out of range, must be positive");
+            }
+
+            Ok((key.to_owned(), Some(count)))
         }
+        _ => Err(wrong_arity(command)),
+    }
+}
+
+/// Parses `key [key ...] timeout`.
+fn parse_bpop_args(args: &[Bytes], command: &str) -> Result<(Vec<Bytes>, i64), RESPDataTypes> {
+    if args.len() < 2 {
+        return Err(wrong_arity(command));
+    }
+
+    let timeout = parse_blocking_timeout(&args[args.len() - 1])?;
+
+    Ok((args[..args.len() - 1].to_vec(), timeout))
+}
+
+fn parse_range_args(args: &[Bytes], command: &str) -> Result<(Bytes, i64, i64), RESPDataTypes> {
+    if args.len() != 3 {
+        return Err(wrong_arity(command));
+    }
+
+    Ok((
+        args[0].to_owned(),
+        parse_integer(&args[1])?,
+        parse_integer(&args[2])?,
+    ))
+}
+
+fn parse_list_end(arg: &[u8]) -> Result<ListEnd, RESPDataTypes> {
+    match lowercase(arg).as_str() {
+        "left" => Ok(ListEnd::Left),
+        "right" => Ok(ListEnd::Right),
+        _ => redis_err!("ERR syntax error"),
+    }
+}
+
+fn parse_lpos_args(args: &[Bytes]) -> Result<RedisCommand, RESPDataTypes> {
+    if args.len() < 2 {
+        return Err(wrong_arity("lpos"));
+    }
+
+    let mut rank = 1;
+    let mut count = None;
+    let mut maxlen = 0;
+    let mut options = args[2..].iter();
+
+    while let Some(option) = options.next() {
+        let value = if let Some(value) = options.next() {
+            parse_integer(value)?
+        } else {
+            return redis_err!("ERR syntax error");
+        };
+
+        match lowercase(option).as_str() {
+            "rank" if value == 0 => {
+                return redis_err!("ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list")
+            }
+            "rank" => rank = value,
+            "count" if value < 0 => return redis_err!("ERR COUNT can't be negative"),
+            "count" => count = Some(value),
+            "maxlen" if value < 0 => return redis_err!("ERR MAXLEN can't be negative"),
+            "maxlen" => maxlen = value,
+            _ => return redis_err!("ERR syntax error"),
+        }
+    }
+
+    Ok(RedisCommand::LPOS {
+        key: args[0].to_owned(),
+        element: args[1].to_owned(),
+        rank,
+        count,
+        maxlen,
+    })
+}
+
+/// Parses `numkeys key [key ...] LEFT|RIGHT [COUNT count]`.
+fn parse_mpop_args(
+    args: &[Bytes],
+    command: &str,
+) -> Result<(Vec<Bytes>, ListEnd, i64), RESPDataTypes> {
+    if args.len() < 3 {
+        return Err(wrong_arity(command));
+    }
+
+    let numkeys = parse_integer(&args[0])?;
+
+    if numkeys <= 0 {
+        return redis_err!("ERR numkeys should be greater than 0");
+    }
+
+    let keys = if let Some(keys) = args.get(1..).and_then(|args| args.get(..numkeys as usize)) {
+        keys.to_vec()
+    } else {
+        return redis_err!("ERR syntax error");
+    };
+    let end = if let Some(end) = args.get(1 + keys.len()) {
+        parse_list_end(end)?
+    } else {
+        return redis_err!("ERR syntax error");
+    };
+
+    match &args[2 + keys.len()..] {
+        [] => Ok((keys, end, 1)),
+        [option, count] if lowercase(option) == "count" => {
+            let count = parse_integer(count)?;
+
+            if count <= 0 {
+                return redis_err!("ERR count should be greater than 0");
+            }
+
+            Ok((keys, end, count))
+        }
+        _ => redis_err!("ERR syntax error"),
+    }
+}
+
+fn parse_zadd_args(args: &[Bytes]) -> Result<RedisCommand, RESPDataTypes> {
+    if args.len() < 3 {
+        return Err(wrong_arity("zadd"));
+    }
+
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    let mut changed = false;
+    let mut increment = false;
+    let mut index = 1;
+
+    while let Some(option) = args.get(index) {
+        match lowercase(option).as_str() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "gt" => gt = true,
+            "lt" => lt = true,
+            "ch" => changed = true,
+            "incr" => increment = true,
+            _ => break,
+        }
+
+        index += 1;
+    }
+
+    let pairs = &args[index..];
+
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return redis_err!("ERR syntax error");
+    }
+
+    if nx && xx {
+        return redis_err!("ERR XX and NX options at the same time are not compatible");
+    }
+
+    if (gt && lt) || (nx && (gt || lt)) {
+        return redis_err!("ERR GT, LT, and/or NX options at the same time are not compatible");
+    }
+
+    if increment && pairs.len() > 2 {
+        return redis_err!("ERR INCR option supports a single increment-element pair");
+    }
+
+    Ok(RedisCommand::ZADD {
+        key: args[0].to_owned(),
+        condition: match (nx, xx) {
+            (true, _) => Some(SetCondition::NX),
+            (_, true) => Some(SetCondition::XX),
+            _ => None,
+        },
+        comparison: match (gt, lt) {
+            (true, _) => Some(ScoreComparison::GT),
+            (_, true) => Some(ScoreComparison::LT),
+            _ => None,
+        },
+        changed,
+        increment,
+        pairs: pairs
+            .chunks(2)
+            .map(|pair| Ok((parse_score(&pair[0])?, pair[1].to_owned())))
+            .collect::<Result<_, RESPDataTypes>>()?,
+    })
+}
+
+fn parse_zrank_args(args: &[Bytes], command: &str) -> Result<(Bytes, Bytes, bool), RESPDataTypes> {
+    match args {
+        [key, member] => Ok((key.to_owned(), member.to_owned(), false)),
+        [key, member, option] if lowercase(option) == "withscore" => {
+            Ok((key.to_owned(), member.to_owned(), true))
+        }
+        [_, _, _] => redis_err!("ERR syntax error"),
+        _ => Err(wrong_arity(command)),
+    }
+}
+
+/// Parses `key start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count]
+/// [WITHSCORES]`.
+fn parse_zrange_args(args: &[Bytes]) -> Result<RedisCommand, RESPDataTypes> {
+    if args.len() < 3 {
+        return Err(wrong_arity("zrange"));
+    }
+
+    let (mut by_score, mut by_lex, mut reverse, mut with_scores) = (false, false, false, false);
+    let mut limit = None;
+    let mut options = args[3..].iter();
+
+    while let Some(option) = options.next() {
+        match lowercase(option).as_str() {
+            "byscore" => by_score = true,
+            "bylex" => by_lex = true,
+            "rev" => reverse = true,
+            "withscores" => with_scores = true,
+            "limit" => match (options.next(), options.next()) {
+                (Some(offset), Some(count)) => {
+                    limit = Some((parse_integer(offset)?, parse_integer(count)?));
+                }
+                _ => return redis_err!("ERR syntax error"),
+            },
+            _ => return redis_err!("ERR syntax error"),
+        }
+    }
+
+    if by_score && by_lex {
+        return redis_err!("ERR syntax error");
+    }
+
+    if limit.is_some() && !by_score && !by_lex {
+        return redis_err!(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+        );
+    }
+
+    if with_scores && by_lex {
+        return redis_err!("ERR syntax error, WITHSCORES not supported in combination with BYLEX");
+    }
+
+    // REV takes score and lexicographical bounds highest first.
+    let (min, max) = if reverse {
+        (&args[2], &args[1])
+    } else {
+        (&args[1], &args[2])
+    };
+    let by = if by_score {
+        ZRangeBy::Score {
+            min: parse_score_bound(min)?,
+            max: parse_score_bound(max)?,
+        }
+    } else if by_lex {
+        ZRangeBy::Lex {
+            min: parse_lex_bound(min)?,
+            max: parse_lex_bound(max)?,
+        }
+    } else {
+        ZRangeBy::Rank {
+            start: parse_integer(&args[1])?,
+            stop: parse_integer(&args[2])?,
+        }
+    };
+
+    Ok(RedisCommand::ZRANGE {
+        key: args[0].to_owned(),
+        by,
+        reverse,
+        limit,
+        with_scores,
+    })
+}
+
+/// Parses `numkeys key [key ...] [LIMIT limit]`.
+fn parse_sintercard_args(args: &[Bytes]) -> Result<RedisCommand, RESPDataTypes> {
+    if args.len() < 2 {
+        return Err(wrong_arity("sintercard"));
+    }
+
+    let numkeys = parse_integer(&args[0])?;
+
+    if numkeys <= 0 {
+        return redis_err!("ERR numkeys should be greater than 0");
+    }
+
+    let keys = if let Some(keys) = args[1..].get(..numkeys as usize) {
+        keys.to_vec()
+    } else {
+        return redis_err!("ERR Number of keys can't be greater than number of args");
+    };
+
+    match &args[1 + keys.len()..] {
+        [] => Ok(RedisCommand::SINTERCARD { keys, limit: 0 }),
+        [option, limit] if lowercase(option) == "limit" => {
+            let limit = parse_integer(limit)?;
+
+            if limit < 0 {
+                return redis_err!("ERR LIMIT can't be negative");
+            }
+
+            Ok(RedisCommand::SINTERCARD {
+                keys,
+                limit: limit as usize,
+            })
+        }
+        _ => redis_err!("ERR syntax error"),
+    }
+}
+
+/// Nil in place of an array reply, which RESP2 spells as a nil array.
+fn null_array(protocol: ProtocolVersion) -> RESPDataTypes {
+    match protocol {
+        ProtocolVersion::RESP2 => RESPDataTypes::Array(None),
+        ProtocolVersion::RESP3 => RESPDataTypes::Null,
+    }
+}
+
+/// Picks `count` random items out of the `length` that `items` yields:
+/// distinct ones for a positive count, possibly repeated ones for a negative
+/// count. Only the chosen indexes are drawn, and `items` is walked once, up
+/// to the last of them.
+fn random_picks<T: Clone>(items: impl Iterator<Item = T>, length: usize, count: i64) -> Vec<T> {
+    if length == 0 || count == 0 {
+        return Vec::new();
+    }
+
+    let indexes = if count < 0 {
+        // Grown as the picks are drawn, never reserved up front from a
+        // count the client chose.
+        let mut indexes = Vec::new();
+
+        for _ in 0..count.unsigned_abs() {
+            indexes.push(random_index(length));
+        }
+
+        indexes
+    } else if count as u64 >= length as u64 {
+        return items.collect();
+    } else {
+        // Floyd's sampling draws `count` distinct indexes in as many steps.
+        let count = count as usize;
+        let mut chosen = BTreeSet::new();
+
+        for bound in length - count..length {
+            let index = random_index(bound + 1);
+
+            if !chosen.insert(index) {
+                chosen.insert(bound);
+            }
+        }
+
+        chosen.into_iter().collect::<Vec<_>>()
+    };
+
+    let wanted = indexes.iter().copied().collect::<BTreeSet<_>>();
+    let last = wanted.last().copied().unwrap_or_default();
+    let found = items
+        .take(last + 1)
+        .enumerate()
+        .filter(|(index, _)| wanted.contains(index))
+        .collect::<BTreeMap<_, _>>();
+
+    indexes.iter().map(|index| found[index].clone()).collect()
+}
+
+/// Checks the count of SRANDMEMBER, HRANDFIELD and ZRANDMEMBER against the
+/// range Redis accepts. Counts returning pairs are limited to half of it.
+fn parse_random_count(arg: &[u8], paired: bool) -> Result<i64, RESPDataTypes> {
+    let count = parse_integer(arg)?;
+    let limit = if paired { i64::MAX / 2 } else { i64::MAX };
+
+    if count < -limit {
+        return redis_err!("ERR value is out of range");
+    }
+
+    Ok(count)
+}
+
+fn set_reply(members: impl IntoIterator<Item = Bytes>) -> RESPDataTypes {
+    RESPDataTypes::Set(
+        members
+            .into_iter()
+            .map(|member| RESPDataTypes::BulkString(Some(member)))
+            .collect(),
+    )
+}
+
+/// Sorted set members, with their scores as a flat array under RESP2 and as
+/// pairs under RESP3.
+fn scored_reply(
+    members: Vec<(Bytes, f64)>,
+    with_scores: bool,
+    protocol: ProtocolVersion,
+) -> RESPDataTypes {
+    let bulk = |member| RESPDataTypes::BulkString(Some(member));
+
+    RESPDataTypes::Array(Some(match (with_scores, protocol) {
+        (false, _) => members
+            .into_iter()
+            .map(|(member, _)| bulk(member))
+            .collect(),
+        (true, ProtocolVersion::RESP2) => members
+            .into_iter()
+            .flat_map(|(member, score)| [bulk(member), RESPDataTypes::Double(score)])
+            .collect(),
+        (true, ProtocolVersion::RESP3) => members
+            .into_iter()
+            .map(|(member, score)| {
+                RESPDataTypes::Array(Some(vec![bulk(member), RESPDataTypes::Double(score)]))
+            })
+            .collect(),
+    }))
+}
+
+/// The ascending ranks of sorted set members given by ranks that count
+/// from the highest score when `reverse` is set.
+fn rank_range(length: usize, start: i64, stop: i64, reverse: bool) -> Range<usize> {
+    match list_range(length, start, stop) {
+        Some((start, stop)) if reverse => length - 1 - stop..length - start,
+        Some((start, stop)) => start..stop + 1,
+        None => 0..0,
+    }
+}
+
+/// Narrows `ranks` to LIMIT's window, counted from the end the range is
+/// walked from. A negative count takes everything after the offset.
+fn limit_range(ranks: Range<usize>, reverse: bool, limit: Option<(i64, i64)>) -> Range<usize> {
+    let (offset, count) = match limit {
+        Some((offset, _)) if offset < 0 => return 0..0,
+        Some((offset, count)) => (offset as usize, usize::try_from(count).ok()),
+        None => return ranks,
+    };
+    let available = ranks.len().saturating_sub(offset);
+    let taken = count.map_or(available, |count| count.min(available));
+
+    if reverse {
+        let end = ranks.end - offset.min(ranks.len());
+
+        end - taken..end
+    } else {
+        let start = ranks.start + offset.min(ranks.len());
+
+        start..start + taken
+    }
+}
+
+fn list_end_arg(end: ListEnd) -> Bytes {
+    match end {
+        ListEnd::Left => Bytes::from_static(b"LEFT"),
+        ListEnd::Right => Bytes::from_static(b"RIGHT"),
+    }
+}
+
+/// Resolves `start` and `stop`, which count from the end when negative, to
+/// an inclusive range of indexes into a list of `length` elements.
+fn list_range(length: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let length = length as i64;
+    let start = if start < 0 {
+        (length + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        length + stop
+    } else {
+        stop.min(length - 1)
+    };
+
+    (start <= stop && start < length).then_some((start as usize, stop as usize))
+}
+
+/// Removes up to `count` occurrences of `element`, counting from the tail
+/// when `count` is negative and removing all of them when it is 0.
+fn list_remove(list: &mut List, count: i64, element: &[u8]) -> usize {
+    let limit = if count == 0 {
+        usize::MAX
+    } else {
+        count.unsigned_abs() as usize
+    };
+    let mut removed = 0;
+
+    if count >= 0 {
+        let mut index = 0;
+
+        while index < list.len() && removed < limit {
+            if list[index] == element {
+                list.remove(index);
+                removed += 1;
+            } else {
+                index += 1;
+            }
+        }
+    } else {
+        let mut index = list.len();
+
+        while index > 0 && removed < limit {
+            index -= 1;
+
+            if list[index] == element {
+                list.remove(index);
+                removed += 1;
+            }
+        }
+    }
+
+    removed
+}
+
+/// Indexes of `element` for LPOS: matching starts at the `rank`th match,
+/// from the tail when it is negative, returns up to `count` of them, all
+/// with 0, and only compares the first `maxlen` elements, all with 0.
+fn list_positions(list: &List, element: &[u8], rank: i64, count: i64, maxlen: i64) -> Vec<usize> {
+    let indexes: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+        Box::new(0..list.len())
+    } else {
+        Box::new((0..list.len()).rev())
+    };
+    let limit = |value: i64| {
+        if value == 0 {
+            usize::MAX
+        } else {
+            value as usize
+        }
+    };
+
+    indexes
+        .take(limit(maxlen))
+        .filter(|index| list[*index] == element)
+        .skip(rank.unsigned_abs() as usize - 1)
+        .take(limit(count))
+        .collect()
+}
+
+/// Resolves an index that counts from the end when negative.
+fn list_index(length: usize, index: i64) -> Option<usize> {
+    let index = if index < 0 {
+        index + length as i64
+    } else {
+        index
+    };
+
+    (0..length as i64)
+        .contains(&index)
+        .then_some(index as usize)
+}
+
+fn parse_hello_args(args: &[Bytes]) -> Result<RedisCommand, RESPDataTypes> {
+    let mut auth = None;
+    let mut client_name = None;
+    let mut options = args.iter().skip(1);
+
+    while let Some(option) = options.next() {
+        match lowercase(option).as_str() {
+            "auth" => {
+                if let (Some(username), Some(password)) = (options.next(), options.next()) {
+                    auth = Some((
+                        String::from_utf8_lossy(username).into_owned(),
+                        String::from_utf8_lossy(password).into_owned(),
+                    ));
+                } else {
+                    return redis_err!("ERR Syntax error in HELLO option 'auth'");
+                }
+            }
+            "setname" => {
+                if let Some(name) = options.next() {
+                    client_name = Some(String::from_utf8_lossy(name).into_owned());
+                } else {
+                    return redis_err!("ERR Syntax error in HELLO option 'setname'");
+                }
+            }
+            _ => {
+                return redis_err!(format!(
+                    "ERR Syntax error in HELLO option '{}'",
+                    String::from_utf8_lossy(option)
+                ))
+            }
+        }
+    }
+
+    Ok(RedisCommand::HELLO {
+        protocol_version: args
+            .first()
+            .map(|version| String::from_utf8_lossy(version).into_owned()),
+        auth,
+        client_name,
+    })
+}
+
+fn parse_set_args(args: &[Bytes]) -> Result<RedisCommand, RESPDataTypes> {
+    if args.len() < 2 {
+        return Err(wrong_arity("set"));
+    }
+
+    let mut condition = None;
+    let mut expiry = None;
+    let mut get = false;
+    let mut options = args[2..].iter();
+
+    while let Some(option) = options.next() {
+        let option = lowercase(option);
+
+        match option.as_str() {
+            "nx" | "xx" if condition.is_none() => {
+                condition = Some(if option == "nx" {
+                    SetCondition::NX
+                } else {
+                    SetCondition::XX
+                });
+            }
+            "get" => get = true,
+            "keepttl" if expiry.is_none() => expiry = Some(SetExpiry::KEEPTTL),
+            "ex" | "px" | "exat" | "pxat" if expiry.is_none() => {
+                let time = if let Some(time) = options.next() {
+                    parse_integer(time)?
+                } else {
+                    return redis_err!("ERR syntax error");
+                };
+
+                if time <= 0 {
+                    return redis_err!("ERR invalid expire time in 'set' command");
+                }
+
+                expiry = Some(match option.as_str() {
+                    "ex" => SetExpiry::EX(time),
+                    "px" => SetExpiry::PX(time),
+                    "exat" => SetExpiry::EXAT(time),
+                    _ => SetExpiry::PXAT(time),
+                });
+            }
+            _ => return redis_err!("ERR syntax error"),
+        }
+    }
+
+    Ok(RedisCommand::SET {
+        key: args[0].to_owned(),
+        value: args[1].to_owned(),
+        condition,
+        expiry,
+        get,
+    })
+}
+
+fn parse_config_args(args: &[Bytes]) -> Result<RedisCommand, RESPDataTypes> {
+    let subcommand = if let Some(subcommand) = args.first() {
+        lowercase(subcommand)
+    } else {
+        return Err(wrong_arity("config"));
+    };
+    let args = args[1..]
+        .iter()
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect::<Vec<_>>();
+    let subcommand = match subcommand.as_str() {
+        "get" if !args.is_empty() => ConfigSubcommand::GET { patterns: args },
+        "set" if !args.is_empty() && args.len() % 2 == 0 => ConfigSubcommand::SET {
+            parameters: args
+                .chunks(2)
+                .map(|pair| (pair[0].to_owned(), pair[1].to_owned()))
+                .collect(),
+        },
+        "rewrite" if args.is_empty() => ConfigSubcommand::REWRITE,
+        "get" | "set" | "rewrite" => return Err(wrong_arity(&format!("config|{subcommand}"))),
+        _ => {
+            return redis_err!(format!(
+                "ERR unknown subcommand '{subcommand}'. Try CONFIG HELP."
+            ))
+        }
+    };
+
+    Ok(RedisCommand::CONFIG { subcommand })
+}
+
+fn parse_expire_args(
+    args: &[Bytes],
+    command: &str,
+) -> Result<(Bytes, i64, Vec<ExpireCondition>), RESPDataTypes> {
+    if args.len() < 2 {
+        return Err(wrong_arity(command));
+    }
+
+    let time = parse_integer(&args[1])?;
+    let mut conditions = Vec::new();
+
+    for option in args[2..].iter() {
+        let condition = match lowercase(option).as_str() {
+            "nx" => ExpireCondition::NX,
+            "xx" => ExpireCondition::XX,
+            "gt" => ExpireCondition::GT,
+            "lt" => ExpireCondition::LT,
+            _ => {
+                return redis_err!(format!(
+                    "ERR Unsupported option {}",
+                    String::from_utf8_lossy(option)
+                ))
+            }
+        };
+
+        if !conditions.contains(&condition) {
+            conditions.push(condition);
+        }
+    }
+
+    if conditions.contains(&ExpireCondition::NX) && conditions.len() > 1 {
+        return redis_err!("ERR NX and XX, GT or LT options at the same time are not compatible");
+    }
+
+    if conditions.contains(&ExpireCondition::GT) && conditions.contains(&ExpireCondition::LT) {
+        return redis_err!("ERR GT and LT options at the same time are not compatible");
+    }
+
+    Ok((args[0].to_owned(), time, conditions))
+}
+
+impl RedisCommand {
+    pub fn respond<T>(&mut self, stream: &mut T, context: &Arc<ServerContext>, client: &mut Client)
+    where
+        T: Write,
+    {
+        let response = if self.is_write() && context.is_read_only() {
+            let response = RESPDataTypes::SimpleError(
+                "READONLY You can't write against a read only replica.".to_string(),
+            );
+
+            context.stats.record_rejected(self.name(), &response);
+
+            Some(response)
+        } else {
+            let write = self.is_write().then(|| context.lock_write());
+            let started = Instant::now();
+            let response = self.execute(context, client);
+            // Keys the command found expired are recorded like a write.
+            let write = write.or_else(|| {
+                context
+                    .store
+                    .has_lazily_expired()
+                    .then(|| context.lock_write())
+            });
+
+            if write.is_some() {
+                context.propagate_expired();
+                drop(write);
+                context.sync_aof();
+            }
+
+            context
+                .stats
+                .record_call(self.name(), started.elapsed(), response.as_ref());
+
+            response
+        };
+
+        if let Some(response) = response {
+            stream
+                .write_all(&response.serialize_for(client.protocol))
+                .unwrap();
+            stream.flush().unwrap();
+        }
+    }
+
+    /// Runs the command and propagates the write it made, if any. Callers
+    /// hold the write lock for write commands. Returns `None` for commands
+    /// that are never answered.
+    pub fn execute(
+        &mut self,
+        context: &Arc<ServerContext>,
+        client: &mut Client,
+    ) -> Option<RESPDataTypes> {
+        use RedisCommand::*;
+
+        let store = &context.store;
+        let mut propagated = None;
+
+        let response = match self {
+            PING { message } => {
+                if let Some(message) = message {
+                    RESPDataTypes::BulkString(Some(message.to_owned()))
+                } else {
+                    RESPDataTypes::SimpleString("PONG".to_string())
+                }
+            }
+            HELLO {
+                protocol_version,
+                auth,
+                client_name,
+            } => Self::hello(context, client, protocol_version, auth, client_name),
+            ECHO { message } => RESPDataTypes::BulkString(Some(message.to_owned())),
+            SET {
+                key,
+                value,
+                condition,
+                expiry,
+                get,
+            } => {
+                let expiry = match expiry {
+                    None => Some(Expiry::Persist),
+                    Some(SetExpiry::KEEPTTL) => Some(Expiry::Keep),
+                    Some(SetExpiry::EX(seconds)) => seconds
+                        .checked_mul(1000)
+                        .and_then(|milliseconds| milliseconds.checked_add(now_millis()))
+                        .map(Expiry::At),
+                    Some(SetExpiry::PX(milliseconds)) => {
+                        milliseconds.checked_add(now_millis()).map(Expiry::At)
+                    }
+                    Some(SetExpiry::EXAT(seconds)) => seconds.checked_mul(1000).map(Expiry::At),
+                    Some(SetExpiry::PXAT(milliseconds)) => Some(Expiry::At(*milliseconds)),
+                };
+
+                // GET refuses to replace a value it cannot return.
+                let outcome = expiry.map(|expiry| {
+                    let outcome = if *get {
+                        store.set_get(key, value, *condition, expiry)
+                    } else {
+                        Ok(store.set(key, value, *condition, expiry))
+                    };
+
+                    outcome.map(|outcome| (expiry, outcome))
+                });
+
+                match outcome {
+                    Some(Ok((expiry, outcome))) => {
+                        if outcome.applied {
+                            let mut command =
+                                vec![Bytes::from_static(b"SET"), key.clone(), value.clone()];
+
+                            match expiry {
+                                Expiry::Persist => {}
+                                Expiry::Keep => command.push(Bytes::from_static(b"KEEPTTL")),
+                                Expiry::At(expires_at) => {
+                                    command.push(Bytes::from_static(b"PXAT"));
+                                    command.push(Bytes::from(expires_at.to_string()));
+                                }
+                            }
+
+                            propagated = Some(command);
+                        }
+
+                        if *get {
+                            RESPDataTypes::BulkString(outcome.previous)
+                        } else if outcome.applied {
+                            RESPDataTypes::SimpleString("OK".to_string())
+                        } else {
+                            RESPDataTypes::Null
+                        }
+                    }
+                    Some(Err(error)) => error.into(),
+                    None => RESPDataTypes::BulkError(
+                        "ERR invalid expire time in 'set' command".to_string(),
+                    ),
+                }
+            }
+            GET { key } => match store.get(key) {
+                Ok(Some(value)) => RESPDataTypes::BulkString(Some(value)),
+                Ok(None) => RESPDataTypes::Null,
+                Err(error) => error.into(),
+            },
+            EXPIRE {
+                key,
+                seconds,
+                conditions,
+            } => Self::expire_at(
+                store,
+                key,
+                seconds
+                    .checked_mul(1000)
+                    .and_then(|milliseconds| milliseconds.checked_add(now_millis())),
+                conditions,
+                "expire",
+                &mut propagated,
+            ),
+            PEXPIRE {
+                key,
+                milliseconds,
+                conditions,
+            } => Self::expire_at(
+                store,
+                key,
+                milliseconds.checked_add(now_millis()),
+                conditions,
+                "pexpire",
+                &mut propagated,
+            ),
+            EXPIREAT {
+                key,
+                unix_time_seconds,
+                conditions,
+            } => Self::expire_at(
+                store,
+                key,
+                unix_time_seconds.checked_mul(1000),
+                conditions,
+                "expireat",
+                &mut propagated,
+            ),
+            PEXPIREAT {
+                key,
+                unix_time_milliseconds,
+                conditions,
+            } => Self::expire_at(
+                store,
+                key,
+                Some(*unix_time_milliseconds),
+                conditions,
+                "pexpireat",
+                &mut propagated,
+            ),
+            TTL { key } => Self::ttl_reply(store.ttl(key), |expires_at| {
+                (expires_at - now_millis() + 500) / 1000
+            }),
+            PTTL { key } => Self::ttl_reply(store.ttl(key), |expires_at| expires_at - now_millis()),
+            EXPIRETIME { key } => Self::ttl_reply(store.ttl(key), |expires_at| expires_at / 1000),
+            PEXPIRETIME { key } => Self::ttl_reply(store.ttl(key), |expires_at| expires_at),
+            PERSIST { key } => {
+                let persisted = store.persist(key);
+
+                if persisted {
+                    propagated = Some(vec![Bytes::from_static(b"PERSIST"), key.clone()]);
+                }
+
+                RESPDataTypes::Integer(persisted as i64)
+            }
+            DEL { keys } => {
+                let removed = keys.iter().filter(|key| store.remove(key)).count();
+
+                if removed > 0 {
+                    propagated = Some([&[Bytes::from_static(b"DEL")], &keys[..]].concat());
+                }
+
+                RESPDataTypes::Integer(removed as i64)
+            }
+            CONFIG { subcommand } => Self::config(context, subcommand),
+            SAVE => match context.save() {
+                Ok(()) => RESPDataTypes::SimpleString("OK".to_string()),
+                Err(error) => RESPDataTypes::BulkError(format!("ERR {error}")),
+            },
+            BGSAVE => match context.background_save() {
+                Ok(()) => RESPDataTypes::SimpleString("Background saving started".to_string()),
+                Err(error) => RESPDataTypes::BulkError(format!("ERR {error}")),
+            },
+            LASTSAVE => RESPDataTypes::Integer(context.snapshots.last_save()),
+            BGREWRITEAOF => match context.background_rewrite_aof() {
+                Ok(()) => RESPDataTypes::SimpleString(
+                    "Background append only file rewriting started".to_string(),
+                ),
+                Err(error) => RESPDataTypes::BulkError(format!("ERR {error}")),
+            },
+            REPLCONF { options } => Self::replconf(client, options)?,
+            PSYNC { replid, offset } => Self::psync(context, client, replid, *offset),
+            ROLE => context.replication.role(),
+            REPLICAOF { master } => Self::replicaof(context, master),
+            INFO { sections } => RESPDataTypes::VerbatimString {
+                encoding: "txt".to_string(),
+                string: context.info(sections),
+            },
+            WAIT {
+                numreplicas,
+                timeout,
+            } => Self::wait(context, client, None, *numreplicas, *timeout)?,
+            WAITAOF {
+                numlocal,
+                numreplicas,
+                timeout,
+            } => Self::wait(context, client, Some(*numlocal), *numreplicas, *timeout)?,
+            TYPE { key } => {
+                RESPDataTypes::SimpleString(store.value_type(key).unwrap_or("none").to_string())
+            }
+            LPUSH { key, elements } => {
+                Self::push(context, key, elements, ListEnd::Left, true, &mut propagated)
+            }
+            RPUSH { key, elements } => Self::push(
+                context,
+                key,
+                elements,
+                ListEnd::Right,
+                true,
+                &mut propagated,
+            ),
+            LPUSHX { key, elements } => Self::push(
+                context,
+                key,
+                elements,
+                ListEnd::Left,
+                false,
+                &mut propagated,
+            ),
+            RPUSHX { key, elements } => Self::push(
+                context,
+                key,
+                elements,
+                ListEnd::Right,
+                false,
+                &mut propagated,
+            ),
+            LPOP { key, count } => Self::pop(
+                store,
+                client.protocol,
+                key,
+                *count,
+                ListEnd::Left,
+                &mut propagated,
+            ),
+            RPOP { key, count } => Self::pop(
+                store,
+                client.protocol,
+                key,
+                *count,
+                ListEnd::Right,
+                &mut propagated,
+            ),
+            LLEN { key } => match store.read(key, |list: &List| list.len()) {
+                Ok(length) => RESPDataTypes::Integer(length.unwrap_or(0) as i64),
+                Err(error) => error.into(),
+            },
+            LRANGE { key, start, stop } => {
+                let elements = store.read(key, |list: &List| {
+                    list_range(list.len(), *start, *stop).map_or(Vec::new(), |(start, stop)| {
+                        list.range(start..=stop)
+                            .map(|element| RESPDataTypes::BulkString(Some(element.to_owned())))
+                            .collect()
+                    })
+                });
+
+                match elements {
+                    Ok(elements) => RESPDataTypes::Array(Some(elements.unwrap_or_default())),
+                    Err(error) => error.into(),
+                }
+            }
+            LINDEX { key, index } => {
+                let element = store.read(key, |list: &List| {
+                    list_index(list.len(), *index).map(|index| list[index].to_owned())
+                });
+
+                match element {
+                    Ok(Some(Some(element))) => RESPDataTypes::BulkString(Some(element)),
+                    Ok(_) => RESPDataTypes::Null,
+                    Err(error) => error.into(),
+                }
+            }
+            LSET {
+                key,
+                index,
+                element,
+            } => {
+                let set = store.write(key, false, |list: &mut List| {
+                    list_index(list.len(), *index)
+                        .map(|index| list[index] = element.to_owned())
+                        .is_some()
+                });
+
+                match set {
+                    Ok(Some(true)) => {
+                        propagated = Some(vec![
+                            Bytes::from_static(b"LSET"),
+                            key.clone(),
+                            Bytes::from(index.to_string()),
+                            element.clone(),
+                        ]);
+
+                        RESPDataTypes::SimpleString("OK".to_string())
+                    }
+                    Ok(Some(false)) => {
+                        RESPDataTypes::BulkError("ERR index out of range".to_string())
+                    }
+                    Ok(None) => RESPDataTypes::BulkError("ERR no such key".to_string()),
+                    Err(error) => error.into(),
+                }
+            }
+            LINSERT {
+                key,
+                before,
+                pivot,
+                element,
+            } => {
+                let inserted = store.write(key, false, |list: &mut List| {
+                    let index = list.iter().position(|candidate| candidate == pivot)?;
+
+                    list.insert(if *before { index } else { index + 1 }, element.to_owned());
+
+                    Some(list.len())
+                });
+
+                match inserted {
+                    Ok(Some(Some(length))) => {
+                        propagated = Some(vec![
+                            Bytes::from_static(b"LINSERT"),
+                            key.clone(),
+                            Bytes::from_static(if *before { b"BEFORE" } else { b"AFTER" }),
+                            pivot.clone(),
+                            element.clone(),
+                        ]);
+                        context.waiters.signal(key);
+
+                        RESPDataTypes::Integer(length as i64)
+                    }
+                    Ok(Some(None)) => RESPDataTypes::Integer(-1),
+                    Ok(None) => RESPDataTypes::Integer(0),
+                    Err(error) => error.into(),
+                }
+            }
+            LREM {
+                key,
+                count,
+                element,
+            } => match store.write(key, false, |list: &mut List| {
+                list_remove(list, *count, element)
+            }) {
+                Ok(removed) => {
+                    let removed = removed.unwrap_or(0);
+
+                    if removed > 0 {
+                        propagated = Some(vec![
+                            Bytes::from_static(b"LREM"),
+                            key.clone(),
+                            Bytes::from(count.to_string()),
+                            element.clone(),
+                        ]);
+                    }
+
+                    RESPDataTypes::Integer(removed as i64)
+                }
+                Err(error) => error.into(),
+            },
+            LTRIM { key, start, stop } => {
+                let trimmed = store.write(key, false, |list: &mut List| {
+                    match list_range(list.len(), *start, *stop) {
+                        Some((start, stop)) => {
+                            list.truncate(stop + 1);
+                            list.drain(..start);
+                        }
+                        None => list.clear(),
+                    }
+                });
+
+                match trimmed {
+                    Ok(trimmed) => {
+                        if trimmed.is_some() {
+                            propagated = Some(vec![
+                                Bytes::from_static(b"LTRIM"),
+                                key.clone(),
+                                Bytes::from(start.to_string()),
+                                Bytes::from(stop.to_string()),
+                            ]);
+                        }
+
+                        RESPDataTypes::SimpleString("OK".to_string())
+                    }
+                    Err(error) => error.into(),
+                }
+            }
+            LPOS {
+                key,
+                element,
+                rank,
+                count,
+                maxlen,
+            } => {
+                let positions = store.read(key, |list: &List| {
+                    list_positions(list, element, *rank, count.unwrap_or(1), *maxlen)
+                });
+
+                match (positions, count) {
+                    (Err(error), _) => error.into(),
+                    (Ok(positions), Some(_)) => RESPDataTypes::Array(Some(
+                        positions
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|position| RESPDataTypes::Integer(position as i64))
+                            .collect(),
+                    )),
+                    (Ok(positions), None) => positions
+                        .and_then(|positions| positions.first().copied())
+                        .map_or(RESPDataTypes::Null, |position| {
+                            RESPDataTypes::Integer(position as i64)
+                        }),
+                }
+            }
+            LMOVE {
+                source,
+                destination,
+                from,
+                to,
+            } => Self::lmove(context, source, destination, *from, *to, &mut propagated),
+            RPOPLPUSH {
+                source,
+                destination,
+            } => Self::lmove(
+                context,
+                source,
+                destination,
+                ListEnd::Right,
+                ListEnd::Left,
+                &mut propagated,
+            ),
+            LMPOP { keys, end, count } => {
+                Self::mpop(store, client.protocol, keys, *end, *count, &mut propagated)
+            }
+            BLPOP { keys, timeout } => Self::blocking_pop(
+                context,
+                client,
+                keys,
+                BlockedOperation::Pop { end: ListEnd::Left },
+                *timeout,
+                &mut propagated,
+            )?,
+            BRPOP { keys, timeout } => Self::blocking_pop(
+                context,
+                client,
+                keys,
+                BlockedOperation::Pop {
+                    end: ListEnd::Right,
+                },
+                *timeout,
+                &mut propagated,
+            )?,
+            BLMOVE {
+                source,
+                destination,
+                from,
+                to,
+                timeout,
+            } => Self::blocking_pop(
+                context,
+                client,
+                slice::from_ref(source),
+                BlockedOperation::Move {
+                    destination: destination.clone(),
+                    from: *from,
+                    to: *to,
+                },
+                *timeout,
+                &mut propagated,
+            )?,
+            BRPOPLPUSH {
+                source,
+                destination,
+                timeout,
+            } => Self::blocking_pop(
+                context,
+                client,
+                slice::from_ref(source),
+                BlockedOperation::Move {
+                    destination: destination.clone(),
+                    from: ListEnd::Right,
+                    to: ListEnd::Left,
+                },
+                *timeout,
+                &mut propagated,
+            )?,
+            BLMPOP {
+                keys,
+                end,
+                count,
+                timeout,
+            } => Self::blocking_pop(
+                context,
+                client,
+                keys,
+                BlockedOperation::MultiPop {
+                    end: *end,
+                    count: *count,
+                },
+                *timeout,
+                &mut propagated,
+            )?,
+            HSET { key, pairs } => match store.write(key, true, |hash: &mut Hash| {
+                let mut added = 0;
+
+                for (field, value) in pairs.iter() {
+                    if hash.insert(field.to_owned(), value.to_owned()).is_none() {
+                        added += 1;
+                    }
+                }
+
+                added
+            }) {
+                Ok(added) => {
+                    let mut command = vec![Bytes::from_static(b"HSET"), key.clone()];
+
+                    command.extend(
+                        pairs
+                            .iter()
+                            .flat_map(|(field, value)| [field.clone(), value.clone()]),
+                    );
+                    propagated = Some(command);
+
+                    RESPDataTypes::Integer(added.unwrap_or(0))
+                }
+                Err(error) => error.into(),
+            },
+            HSETNX { key, field, value } => match store.write(key, true, |hash: &mut Hash| {
+                if hash.contains_key(field) {
+                    return false;
+                }
+
+                hash.insert(field.to_owned(), value.to_owned());
+
+                true
+            }) {
+                Ok(set) => {
+                    let set = set.unwrap_or(false);
+
+                    if set {
+                        propagated = Some(vec![
+                            Bytes::from_static(b"HSETNX"),
+                            key.clone(),
+                            field.clone(),
+                            value.clone(),
+                        ]);
+                    }
+
+                    RESPDataTypes::Integer(set as i64)
+                }
+                Err(error) => error.into(),
+            },
+            HGET { key, field } => match store.read(key, |hash: &Hash| hash.get(field).cloned()) {
+                Ok(Some(Some(value))) => RESPDataTypes::BulkString(Some(value)),
+                Ok(_) => RESPDataTypes::Null,
+                Err(error) => error.into(),
+            },
+            HMGET { key, fields } => {
+                let values = store.read(key, |hash: &Hash| {
+                    fields
+                        .iter()
+                        .map(|field| hash.get(field).cloned())
+                        .collect::<Vec<_>>()
+                });
+
+                match values {
+                    Ok(values) => RESPDataTypes::Array(Some(
+                        values
+                            .unwrap_or_else(|| vec![None; fields.len()])
+                            .into_iter()
+                            .map(|value| {
+                                value.map_or(RESPDataTypes::Null, |value| {
+                                    RESPDataTypes::BulkString(Some(value))
+                                })
+                            })
+                            .collect(),
+                    )),
+                    Err(error) => error.into(),
+                }
+            }
+            HGETALL { key } => {
+                let entries = store.read(key, |hash: &Hash| {
+                    hash.iter()
+                        .map(|(field, value)| {
+                            (
+                                RESPDataTypes::BulkString(Some(field.to_owned())),
+                                RESPDataTypes::BulkString(Some(value.to_owned())),
+                            )
+                        })
+                        .collect()
+                });
+
+                match entries {
+                    Ok(entries) => RESPDataTypes::Map(entries.unwrap_or_default()),
+                    Err(error) => error.into(),
+                }
+            }
+            HDEL { key, fields } => match store.write(key, false, |hash: &mut Hash| {
+                fields
+                    .iter()
+                    .filter(|field| hash.remove(*field).is_some())
+                    .count()
+            }) {
+                Ok(removed) => {
+                    let removed = removed.unwrap_or(0);
+
+                    if removed > 0 {
+                        propagated = Some(
+                            [&[Bytes::from_static(b"HDEL"), key.clone()], &fields[..]].concat(),
+                        );
+                    }
+
+                    RESPDataTypes::Integer(removed as i64)
+                }
+                Err(error) => error.into(),
+            },
+            HEXISTS { key, field } => match store.read(key, |hash: &Hash| hash.contains_key(field))
+            {
+                Ok(exists) => RESPDataTypes::Integer(exists.unwrap_or(false) as i64),
+                Err(error) => error.into(),
+            },
+            HLEN { key } => match store.read(key, |hash: &Hash| hash.len()) {
+                Ok(length) => RESPDataTypes::Integer(length.unwrap_or(0) as i64),
+                Err(error) => error.into(),
+            },
+            HKEYS { key } => Self::hash_items(store, key, |field, _| field.clone()),
+            HVALS { key } => Self::hash_items(store, key, |_, value| value.clone()),
+            HINCRBY {
+                key,
+                field,
+                increment,
+            } => match Self::hash_increment(store, key, field, |value| {
+                let value = match value {
+                    Some(value) => parse_integer(value).map_err(|_| {
+                        RESPDataTypes::BulkError("ERR hash value is not an integer".to_string())
+                    })?,
+                    None => 0,
+                };
+
+                value.checked_add(*increment).map_or(
+                    redis_err!("ERR increment or decrement would overflow"),
+                    |value| Ok(Bytes::from(value.to_string())),
+                )
+            }) {
+                Ok(value) => {
+                    propagated = Some(vec![
+                        Bytes::from_static(b"HINCRBY"),
+                        key.clone(),
+                        field.clone(),
+                        Bytes::from(increment.to_string()),
+                    ]);
+
+                    RESPDataTypes::Integer(parse_integer(&value).unwrap_or_default())
+                }
+                Err(error) => error,
+            },
+            HINCRBYFLOAT {
+                key,
+                field,
+                increment,
+            } => match Self::hash_increment(store, key, field, |value| {
+                let value = match value {
+                    Some(value) => parse_float(value).map_err(|_| {
+                        RESPDataTypes::BulkError("ERR hash value is not a float".to_string())
+                    })?,
+                    None => 0.0,
+                };
+                let value = value + *increment;
+
+                if !value.is_finite() {
+                    return redis_err!("ERR increment would produce NaN or Infinity");
+                }
+
+                Ok(Bytes::from(value.to_string()))
+            }) {
+                // The result is propagated as is so replicas do not repeat
+                // the floating point arithmetic.
+                Ok(value) => {
+                    propagated = Some(vec![
+                        Bytes::from_static(b"HSET"),
+                        key.clone(),
+                        field.clone(),
+                        value.clone(),
+                    ]);
+
+                    RESPDataTypes::BulkString(Some(value))
+                }
+                Err(error) => error,
+            },
+            HSTRLEN { key, field } => {
+                match store.read(key, |hash: &Hash| {
+                    hash.get(field).map_or(0, |value| value.len())
+                }) {
+                    Ok(length) => RESPDataTypes::Integer(length.unwrap_or(0) as i64),
+                    Err(error) => error.into(),
+                }
+            }
+            HRANDFIELD {
+                key,
+                count,
+                with_values,
+            } => Self::hrandfield(store, client.protocol, key, *count, *with_values),
+            SADD { key, members } => match store.write(key, true, |set: &mut Set| {
+                members
+                    .iter()
+                    .filter(|member| set.insert((*member).to_owned()))
+                    .count()
+            }) {
+                Ok(added) => {
+                    let added = added.unwrap_or(0);
+
+                    if added > 0 {
+                        propagated = Some(
+                            [&[Bytes::from_static(b"SADD"), key.clone()], &members[..]].concat(),
+                        );
+                    }
+
+                    RESPDataTypes::Integer(added as i64)
+                }
+                Err(error) => error.into(),
+            },
+            SREM { key, members } => match store.write(key, false, |set: &mut Set| {
+                members.iter().filter(|member| set.remove(*member)).count()
+            }) {
+                Ok(removed) => {
+                    let removed = removed.unwrap_or(0);
+
+                    if removed > 0 {
+                        propagated = Some(
+                            [&[Bytes::from_static(b"SREM"), key.clone()], &members[..]].concat(),
+                        );
+                    }
+
+                    RESPDataTypes::Integer(removed as i64)
+                }
+                Err(error) => error.into(),
+            },
+            SMEMBERS { key } => match store.read(key, |set: &Set| set.clone()) {
+                Ok(set) => set_reply(set.unwrap_or_default()),
+                Err(error) => error.into(),
+            },
+            SISMEMBER { key, member } => match store.read(key, |set: &Set| set.contains(member)) {
+                Ok(contains) => RESPDataTypes::Integer(contains.unwrap_or(false) as i64),
+                Err(error) => error.into(),
+            },
+            SMISMEMBER { key, members } => {
+                let contained = store.read(key, |set: &Set| {
+                    members
+                        .iter()
+                        .map(|member| set.contains(member))
+                        .collect::<Vec<_>>()
+                });
+
+                match contained {
+                    Ok(contained) => RESPDataTypes::Array(Some(
+                        contained
+                            .unwrap_or_else(|| vec![false; members.len()])
+                            .into_iter()
+                            .map(|contains| RESPDataTypes::Integer(contains as i64))
+                            .collect(),
+                    )),
+                    Err(error) => error.into(),
+                }
+            }
+            SCARD { key } => match store.read(key, |set: &Set| set.len()) {
+                Ok(length) => RESPDataTypes::Integer(length.unwrap_or(0) as i64),
+                Err(error) => error.into(),
+            },
+            SINTER { keys } => {
+                match Self::combine_sets(&store.lock(keys.iter()), keys, SetAlgebra::Intersection) {
+                    Ok(set) => set_reply(set),
+                    Err(error) => error.into(),
+                }
+            }
+            SUNION { keys } => {
+                match Self::combine_sets(&store.lock(keys.iter()), keys, SetAlgebra::Union) {
+                    Ok(set) => set_reply(set),
+                    Err(error) => error.into(),
+                }
+            }
+            SDIFF { keys } => {
+                match Self::combine_sets(&store.lock(keys.iter()), keys, SetAlgebra::Difference) {
+                    Ok(set) => set_reply(set),
+                    Err(error) => error.into(),
+                }
+            }
+            SINTERSTORE { destination, keys } => Self::store_combined_sets(
+                store,
+                destination,
+                keys,
+                SetAlgebra::Intersection,
+                &mut propagated,
+            ),
+            SUNIONSTORE { destination, keys } => Self::store_combined_sets(
+                store,
+                destination,
+                keys,
+                SetAlgebra::Union,
+                &mut propagated,
+            ),
+            SDIFFSTORE { destination, keys } => Self::store_combined_sets(
+                store,
+                destination,
+                keys,
+                SetAlgebra::Difference,
+                &mut propagated,
+            ),
+            SINTERCARD { keys, limit } => {
+                match Self::combine_sets(&store.lock(keys.iter()), keys, SetAlgebra::Intersection) {
+                    Ok(set) if *limit > 0 => RESPDataTypes::Integer(set.len().min(*limit) as i64),
+                    Ok(set) => RESPDataTypes::Integer(set.len() as i64),
+                    Err(error) => error.into(),
+                }
+            }
+            SMOVE {
+                source,
+                destination,
+                member,
+            } => Self::smove(store, source, destination, member, &mut propagated),
+            SPOP { key, count } => {
+                let popped = store.write(key, false, |set: &mut Set| {
+                    let popped = random_picks(set.iter().cloned(), set.len(), count.unwrap_or(1));
+
+                    for member in popped.iter() {
+                        set.remove(member);
+                    }
+
+                    popped
+                });
+
+                match popped {
+                    Ok(popped) => {
+                        let popped = popped.unwrap_or_default();
+
+                        // The members are chosen at random, so replicas and
+                        // the AOF are told which ones went.
+                        if !popped.is_empty() {
+                            propagated = Some(
+                                [&[Bytes::from_static(b"SREM"), key.clone()], &popped[..]].concat(),
+                            );
+                        }
+
+                        match count {
+                            Some(_) => set_reply(popped),
+                            None => popped
+                                .into_iter()
+                                .next()
+                                .map_or(RESPDataTypes::Null, |member| {
+                                    RESPDataTypes::BulkString(Some(member))
+                                }),
+                        }
+                    }
+                    Err(error) => error.into(),
+                }
+            }
+            SRANDMEMBER { key, count } => {
+                let picked = store.read(key, |set: &Set| {
+                    random_picks(set.iter(), set.len(), count.unwrap_or(1))
+                        .into_iter()
+                        .cloned()
+                        .collect::<Vec<_>>()
+                });
+
+                match (picked, count) {
+                    (Ok(picked), Some(_)) => RESPDataTypes::Array(Some(
+                        picked
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|member| RESPDataTypes::BulkString(Some(member)))
+                            .collect(),
+                    )),
+                    (Ok(picked), None) => picked
+                        .and_then(|picked| picked.into_iter().next())
+                        .map_or(RESPDataTypes::Null, |member| {
+                            RESPDataTypes::BulkString(Some(member))
+                        }),
+                    (Err(error), _) => error.into(),
+                }
+            }
+            ZADD {
+                key,
+                condition,
+                comparison,
+                changed,
+                increment,
+                pairs,
+            } => Self::zadd(
+                store,
+                key,
+                *condition,
+                *comparison,
+                *changed,
+                *increment,
+                pairs,
+                &mut propagated,
+            ),
+            ZINCRBY {
+                key,
+                increment,
+                member,
+            } => Self::zadd(
+                store,
+                key,
+                None,
+                None,
+                false,
+                true,
+                &[(*increment, member.clone())],
+                &mut propagated,
+            ),
+            ZREM { key, members } => match store.write(key, false, |zset: &mut SortedSet| {
+                members.iter().filter(|member| zset.remove(member)).count()
+            }) {
+                Ok(removed) => {
+                    let removed = removed.unwrap_or(0);
+
+                    if removed > 0 {
+                        propagated = Some(
+                            [&[Bytes::from_static(b"ZREM"), key.clone()], &members[..]].concat(),
+                        );
+                    }
+
+                    RESPDataTypes::Integer(removed as i64)
+                }
+                Err(error) => error.into(),
+            },
+            ZCARD { key } => match store.read(key, |zset: &SortedSet| zset.len()) {
+                Ok(length) => RESPDataTypes::Integer(length.unwrap_or(0) as i64),
+                Err(error) => error.into(),
+            },
+            ZSCORE { key, member } => {
+                match store.read(key, |zset: &SortedSet| zset.score(member)) {
+                    Ok(Some(Some(score))) => RESPDataTypes::Double(score),
+                    Ok(_) => RESPDataTypes::Null,
+                    Err(error) => error.into(),
+                }
+            }
+            ZMSCORE { key, members } => {
+                let scores = store.read(key, |zset: &SortedSet| {
+                    members
+                        .iter()
+                        .map(|member| zset.score(member))
+                        .collect::<Vec<_>>()
+                });
+
+                match scores {
+                    Ok(scores) => RESPDataTypes::Array(Some(
+                        scores
+                            .unwrap_or_else(|| vec![None; members.len()])
+                            .into_iter()
+                            .map(|score| score.map_or(RESPDataTypes::Null, RESPDataTypes::Double))
+                            .collect(),
+                    )),
+                    Err(error) => error.into(),
+                }
+            }
+            ZRANK {
+                key,
+                member,
+                with_score,
+            } => Self::zrank(store, client.protocol, key, member, false, *with_score),
+            ZREVRANK {
+                key,
+                member,
+                with_score,
+            } => Self::zrank(store, client.protocol, key, member, true, *with_score),
+            ZCOUNT { key, min, max } => {
+                match store.read(key, |zset: &SortedSet| zset.score_range(min, max).len()) {
+                    Ok(count) => RESPDataTypes::Integer(count.unwrap_or(0) as i64),
+                    Err(error) => error.into(),
+                }
+            }
+            ZLEXCOUNT { key, min, max } => {
+                match store.read(key, |zset: &SortedSet| zset.lex_range(min, max).len()) {
+                    Ok(count) => RESPDataTypes::Integer(count.unwrap_or(0) as i64),
+                    Err(error) => error.into(),
+                }
+            }
+            ZRANGE {
+                key,
+                by,
+                reverse,
+                limit,
+                with_scores,
+            } => {
+                let members = store.read(key, |zset: &SortedSet| {
+                    let ranks = match by {
+                        ZRangeBy::Rank { start, stop } => {
+                            rank_range(zset.len(), *start, *stop, *reverse)
+                        }
+                        ZRangeBy::Score { min, max } => zset.score_range(min, max),
+                        ZRangeBy::Lex { min, max } => zset.lex_range(min, max),
+                    };
+
+                    zset.range(limit_range(ranks, *reverse, *limit), *reverse)
+                        .map(|(member, score)| (member.to_owned(), score))
+                        .collect()
+                });
+
+                match members {
+                    Ok(members) => {
+                        scored_reply(members.unwrap_or_default(), *with_scores, client.protocol)
+                    }
+                    Err(error) => error.into(),
+                }
+            }
+            ZREMRANGEBYRANK { key, start, stop } => {
+                Self::zremrange(store, key, &mut propagated, |zset| {
+                    rank_range(zset.len(), *start, *stop, false)
+                })
+            }
+            ZREMRANGEBYSCORE { key, min, max } => {
+                Self::zremrange(store, key, &mut propagated, |zset| {
+                    zset.score_range(min, max)
+                })
+            }
+            ZREMRANGEBYLEX { key, min, max } => {
+                Self::zremrange(store, key, &mut propagated, |zset| zset.lex_range(min, max))
+            }
+            ZRANDMEMBER {
+                key,
+                count,
+                with_scores,
+            } => {
+                let picked = store.read(key, |zset: &SortedSet| {
+                    random_picks(zset.iter(), zset.len(), count.unwrap_or(1))
+                        .into_iter()
+                        .map(|(member, score)| (member.to_owned(), score))
+                        .collect::<Vec<_>>()
+                });
+
+                match (picked, count) {
+                    (Ok(picked), Some(_)) => {
+                        scored_reply(picked.unwrap_or_default(), *with_scores, client.protocol)
+                    }
+                    (Ok(picked), None) => picked
+                        .and_then(|picked| picked.into_iter().next())
+                        .map_or(RESPDataTypes::Null, |(member, _)| {
+                            RESPDataTypes::BulkString(Some(member))
+                        }),
+                    (Err(error), _) => error.into(),
+                }
+            }
+        };
+
+        if let Some(command) = propagated {
+            context.propagate(&command);
+            client.write_offset = context.replication.offset();
+        }
+
+        // Only writes hold the write lock that propagating the pops needs.
+        if self.is_write() {
+            Self::serve_blocked(context);
+        }
+
+        Some(response)
+    }
+
+    fn hello(
+        context: &ServerContext,
+        client: &mut Client,
+        protocol_version: &Option<String>,
+        auth: &Option<(String, String)>,
+        client_name: &Option<String>,
+    ) -> RESPDataTypes {
+        let protocol = match protocol_version.as_deref().map(str::parse::<i64>) {
+            None => client.protocol,
+            Some(Ok(2)) => ProtocolVersion::RESP2,
+            Some(Ok(3)) => ProtocolVersion::RESP3,
+            Some(Ok(_)) => {
+                return RESPDataTypes::SimpleError(
+                    "NOPROTO unsupported protocol version".to_string(),
+                )
+            }
+            Some(Err(_)) => {
+                return RESPDataTypes::BulkError(
+                    "ERR Protocol version is not an integer or out of range".to_string(),
+                )
+            }
+        };
+
+        if let Some((username, _)) = auth {
+            // No ACL users are configured, so only the passwordless default user exists.
+            if username != "default" {
+                return RESPDataTypes::SimpleError(
+                    "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+                );
+            }
+        }
+
+        if let Some(client_name) = client_name {
+            if client_name.contains(|character: char| character <= ' ' || character > '~') {
+                return RESPDataTypes::BulkError(
+                    "ERR Client names cannot contain spaces, newlines or special characters."
+                        .to_string(),
+                );
+            }
+
+            client.name = Some(client_name.to_owned()).filter(|name| !name.is_empty());
+        }
+
+        client.protocol = protocol;
+
+        let bulk = |value: &str| RESPDataTypes::BulkString(Some(Bytes::from(value.to_string())));
+
+        RESPDataTypes::Map(vec![
+            (bulk("server"), bulk("redis")),
+            (bulk("version"), bulk(REDIS_VERSION)),
+            (
+                bulk("proto"),
+                RESPDataTypes::Integer(match protocol {
+                    ProtocolVersion::RESP2 => 2,
+                    ProtocolVersion::RESP3 => 3,
+                }),
+            ),
+            (bulk("id"), RESPDataTypes::Integer(client.id as i64)),
+            (bulk("mode"), bulk("standalone")),
+            (
+                bulk("role"),
+                bulk(if context.replication.is_replica() {
+                    "replica"
+                } else {
+                    "master"
+                }),
+            ),
+            (bulk("modules"), RESPDataTypes::Array(Some(Vec::new()))),
+        ])
+    }
+
+    /// Returns `None` for `REPLCONF ACK`, which is never answered.
+    fn replconf(client: &mut Client, options: &[(String, Bytes)]) -> Option<RESPDataTypes> {
+        for (option, value) in options {
+            match option.as_str() {
+                "listening-port" => {
+                    match std::str::from_utf8(value)
+                        .ok()
+                        .and_then(|port| port.parse::<u16>().ok())
+                    {
+                        Some(port) => client.listening_port = Some(port),
+                        None => {
+                            return Some(RESPDataTypes::BulkError(
+                                "ERR value is not an integer or out of range".to_string(),
+                            ))
+                        }
+                    }
+                }
+                "capa" | "ip-address" => {}
+                "ack" => return None,
+                _ => {
+                    return Some(RESPDataTypes::BulkError(format!(
+                        "ERR Unrecognized REPLCONF option: {option}"
+                    )))
+                }
+            }
+        }
+
+        Some(RESPDataTypes::SimpleString("OK".to_string()))
+    }
+
+    /// Continues the replica's stream from the backlog when possible and
+    /// answers with a full resync otherwise. Either way the replica is
+    /// registered under the write lock, so the stream that follows picks up
+    /// exactly where the backlog or the snapshot ends.
+    fn psync(
+        context: &ServerContext,
+        client: &mut Client,
+        replid: &str,
+        psync_offset: i64,
+    ) -> RESPDataTypes {
+        let _write = context.write_lock.write().unwrap();
+        let address = client.address.map(|address| address.ip());
+        let listening_port = client.listening_port.unwrap_or(0);
+
+        if let Some((replid, writes, acks)) = context.replication.continue_replica(
+            client.id,
+            address,
+            listening_port,
+            replid,
+            psync_offset,
+        ) {
+            client.replica_link = Some(ReplicaLink {
+                entries: None,
+                writes,
+                acks,
+            });
+            context
+                .stats
+                .sync_partial_ok
+                .fetch_add(1, Ordering::Relaxed);
+
+            return RESPDataTypes::SimpleString(format!("CONTINUE {replid}"));
+        }
+
+        let (replid, offset, writes, acks) =
+            context
+                .replication
+                .add_replica(client.id, address, listening_port);
+
+        client.replica_link = Some(ReplicaLink {
+            entries: Some(context.store.snapshot()),
+            writes,
+            acks,
+        });
+        context.stats.sync_full.fetch_add(1, Ordering::Relaxed);
+
+        // Replicas asking for `?` want a full resync from the start.
+        if replid != "?" {
+            context
+                .stats
+                .sync_partial_err
+                .fetch_add(1, Ordering::Relaxed);
+        }
+
+        RESPDataTypes::SimpleString(format!("FULLRESYNC {replid} {offset}"))
+    }
+
+    fn replicaof(context: &Arc<ServerContext>, master: &Option<(String, u16)>) -> RESPDataTypes {
+        let (host, port) = if let Some(master) = master {
+            master
+        } else {
+            context.config.update(|values| values.replicaof = None);
+
+            // Promotion may create the backlog, after which writes have to be
+            // recorded in order.
+            let _write = context.write_lock.write().unwrap();
+
+            context.replication.promote();
+
+            return RESPDataTypes::SimpleString("OK".to_string());
+        };
+
+        if context.replication.master().as_ref() == Some(&(host.to_owned(), *port)) {
+            return RESPDataTypes::SimpleString(
+                "OK Already connected to specified master".to_string(),
+            );
+        }
+
+        context
+            .config
+            .update(|values| values.replicaof = Some((host.to_owned(), *port)));
+        context.replicate(host.to_owned(), *port);
+
+        RESPDataTypes::SimpleString("OK".to_string())
+    }
+
+    /// Replies once `numreplicas` replicas acknowledged the client's last
+    /// write, and for WAITAOF once they, and with `numlocal` the local AOF,
+    /// fsynced it; or once the timeout passes, with the counts reached by
+    /// then. Returns `None` when the connection blocks for the reply.
+    fn wait(
+        context: &Arc<ServerContext>,
+        client: &mut Client,
+        numlocal: Option<i64>,
+        numreplicas: i64,
+        timeout: i64,
+    ) -> Option<RESPDataTypes> {
+        let command = if numlocal.is_some() {
+            "WAITAOF"
+        } else {
+            "WAIT"
+        };
+
+        if context.replication.is_replica() {
+            return Some(RESPDataTypes::BulkError(format!(
+                "ERR {command} cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated."
+            )));
+        }
+
+        if numlocal.unwrap_or(0) > 0 && !context.config.values().appendonly {
+            return Some(RESPDataTypes::BulkError(
+                "ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled."
+                    .to_string(),
+            ));
+        }
+
+        let offset = client.write_offset;
+        let check = move |context: &ServerContext| {
+            let replicas = context.replication.count_acks(offset, numlocal.is_some());
+            let local = context
+                .aof
+                .synced_offset()
+                .is_some_and(|synced| synced >= offset);
+            let reached = replicas as i64 >= numreplicas && (numlocal.unwrap_or(0) <= 0 || local);
+            let reply = match numlocal {
+                None => RESPDataTypes::Integer(replicas as i64),
+                Some(_) => RESPDataTypes::Array(Some(vec![
+                    RESPDataTypes::Integer(local as i64),
+                    RESPDataTypes::Integer(replicas as i64),
+                ])),
+            };
+
+            (reply, reached)
+        };
+        // Subscribed before the first check so no acknowledgement is missed.
+        let mut acks = context.replication.subscribe_acks();
+        let (reply, reached) = check(context);
+
+        if reached {
+            return Some(reply);
+        }
+
+        context.replication.request_acks();
+
+        let context = Arc::clone(context);
+        let deadline =
+            (timeout > 0).then(|| Instant::now() + Duration::from_millis(timeout as u64));
+
+        client.blocked = Some(Box::pin(async move {
+            loop {
+                let expired = async {
+                    match deadline {
+                        Some(deadline) => time::sleep_until(deadline).await,
+                        None => future::pending().await,
+                    }
+                };
+
+                tokio::select! {
+                    _ = acks.changed() => {}
+                    _ = expired => return check(&context).0,
+                }
+
+                let (reply, reached) = check(&context);
+
+                if reached {
+                    return reply;
+                }
+            }
+        }));
+
+        None
+    }
+
+    /// The name the command is reported under in INFO commandstats.
+    pub fn name(&self) -> &'static str {
+        use RedisCommand::*;
+
+        match self {
+            PING { .. } => "ping",
+            HELLO { .. } => "hello",
+            ECHO { .. } => "echo",
+            SET { .. } => "set",
+            GET { .. } => "get",
+            EXPIRE { .. } => "expire",
+            PEXPIRE { .. } => "pexpire",
+            EXPIREAT { .. } => "expireat",
+            PEXPIREAT { .. } => "pexpireat",
+            TTL { .. } => "ttl",
+            PTTL { .. } => "pttl",
+            EXPIRETIME { .. } => "expiretime",
+            PEXPIRETIME { .. } => "pexpiretime",
+            PERSIST { .. } => "persist",
+            DEL { .. } => "del",
+            CONFIG { subcommand } => match subcommand {
+                ConfigSubcommand::GET { .. } => "config|get",
+                ConfigSubcommand::SET { .. } => "config|set",
+                ConfigSubcommand::REWRITE => "config|rewrite",
+            },
+            SAVE => "save",
+            BGSAVE => "bgsave",
+            LASTSAVE => "lastsave",
+            BGREWRITEAOF => "bgrewriteaof",
+            REPLCONF { .. } => "replconf",
+            PSYNC { .. } => "psync",
+            ROLE => "role",
+            REPLICAOF { .. } => "replicaof",
+            INFO { .. } => "info",
+            WAIT { .. } => "wait",
+            WAITAOF { .. } => "waitaof",
+            TYPE { .. } => "type",
+            LPUSH { .. } => "lpush",
+            RPUSH { .. } => "rpush",
+            LPUSHX { .. } => "lpushx",
+            RPUSHX { .. } => "rpushx",
+            LPOP { .. } => "lpop",
+            RPOP { .. } => "rpop",
+            LLEN { .. } => "llen",
+            LRANGE { .. } => "lrange",
+            LINDEX { .. } => "lindex",
+            LSET { .. } => "lset",
+            LINSERT { .. } => "linsert",
+            LREM { .. } => "lrem",
+            LTRIM { .. } => "ltrim",
+            LPOS { .. } => "lpos",
+            LMOVE { .. } => "lmove",
+            RPOPLPUSH { .. } => "rpoplpush",
+            LMPOP { .. } => "lmpop",
+            BLPOP { .. } => "blpop",
+            BRPOP { .. } => "brpop",
+            BLMOVE { .. } => "blmove",
+            BRPOPLPUSH { .. } => "brpoplpush",
+            BLMPOP { .. } => "blmpop",
+            HSET { .. } => "hset",
+            HSETNX { .. } => "hsetnx",
+            HGET { .. } => "hget",
+            HMGET { .. } => "hmget",
+            HGETALL { .. } => "hgetall",
+            HDEL { .. } => "hdel",
+            HEXISTS { .. } => "hexists",
+            HLEN { .. } => "hlen",
+            HKEYS { .. } => "hkeys",
+            HVALS { .. } => "hvals",
+            HINCRBY { .. } => "hincrby",
+            HINCRBYFLOAT { .. } => "hincrbyfloat",
+            HSTRLEN { .. } => "hstrlen",
+            HRANDFIELD { .. } => "hrandfield",
+            SADD { .. } => "sadd",
+            SREM { .. } => "srem",
+            SMEMBERS { .. } => "smembers",
+            SISMEMBER { .. } => "sismember",
+            SMISMEMBER { .. } => "smismember",
+            SCARD { .. } => "scard",
+            SINTER { .. } => "sinter",
+            SUNION { .. } => "sunion",
+            SDIFF { .. } => "sdiff",
+            SINTERSTORE { .. } => "sinterstore",
+            SUNIONSTORE { .. } => "sunionstore",
+            SDIFFSTORE { .. } => "sdiffstore",
+            SINTERCARD { .. } => "sintercard",
+            SMOVE { .. } => "smove",
+            SPOP { .. } => "spop",
+            SRANDMEMBER { .. } => "srandmember",
+            ZADD { .. } => "zadd",
+            ZINCRBY { .. } => "zincrby",
+            ZREM { .. } => "zrem",
+            ZCARD { .. } => "zcard",
+            ZSCORE { .. } => "zscore",
+            ZMSCORE { .. } => "zmscore",
+            ZRANK { .. } => "zrank",
+            ZREVRANK { .. } => "zrevrank",
+            ZCOUNT { .. } => "zcount",
+            ZLEXCOUNT { .. } => "zlexcount",
+            ZRANGE { .. } => "zrange",
+            ZREMRANGEBYRANK { .. } => "zremrangebyrank",
+            ZREMRANGEBYSCORE { .. } => "zremrangebyscore",
+            ZREMRANGEBYLEX { .. } => "zremrangebylex",
+            ZRANDMEMBER { .. } => "zrandmember",
+        }
+    }
+
+    fn is_write(&self) -> bool {
+        use RedisCommand::*;
+
+        matches!(
+            self,
+            SET { .. }
+                | EXPIRE { .. }
+                | PEXPIRE { .. }
+                | EXPIREAT { .. }
+                | PEXPIREAT { .. }
+                | PERSIST { .. }
+                | DEL { .. }
+                | LPUSH { .. }
+                | RPUSH { .. }
+                | LPUSHX { .. }
+                | RPUSHX { .. }
+                | LPOP { .. }
+                | RPOP { .. }
+                | LSET { .. }
+                | LINSERT { .. }
+                | LREM { .. }
+                | LTRIM { .. }
+                | LMOVE { .. }
+                | RPOPLPUSH { .. }
+                | LMPOP { .. }
+                | BLPOP { .. }
+                | BRPOP { .. }
+                | BLMOVE { .. }
+                | BRPOPLPUSH { .. }
+                | BLMPOP { .. }
+                | HSET { .. }
+                | HSETNX { .. }
+                | HDEL { .. }
+                | HINCRBY { .. }
+                | HINCRBYFLOAT { .. }
+                | SADD { .. }
+                | SREM { .. }
+                | SINTERSTORE { .. }
+                | SUNIONSTORE { .. }
+                | SDIFFSTORE { .. }
+                | SMOVE { .. }
+                | SPOP { .. }
+                | ZADD { .. }
+                | ZINCRBY { .. }
+                | ZREM { .. }
+                | ZREMRANGEBYRANK { .. }
+                | ZREMRANGEBYSCORE { .. }
+                | ZREMRANGEBYLEX { .. }
+        )
+    }
+
+    fn config(context: &Arc<ServerContext>, subcommand: &ConfigSubcommand) -> RESPDataTypes {
+        let config = &context.config;
+        let bulk = |value: &str| RESPDataTypes::BulkString(Some(Bytes::from(value.to_string())));
+        let outcome = match subcommand {
+            ConfigSubcommand::GET { patterns } => {
+                return RESPDataTypes::Map(
+                    config
+                        .get(patterns)
+                        .into_iter()
+                        .map(|(name, value)| (bulk(name), bulk(&value)))
+                        .collect(),
+                )
+            }
+            ConfigSubcommand::SET { parameters } => config
+                .set(parameters)
+                .map_err(|error| error.to_string())
+                .and_then(|_| {
+                    context
+                        .replication
+                        .set_backlog_size(config.values().repl_backlog_size);
+                    context
+                        .apply_append_only()
+                        .map_err(|error| error.to_string())
+                }),
+            ConfigSubcommand::REWRITE => config.rewrite().map_err(|error| error.to_string()),
+        };
+
+        match outcome {
+            Ok(()) => RESPDataTypes::SimpleString("OK".to_string()),
+            Err(error) => RESPDataTypes::BulkError(format!("ERR {error}")),
+        }
+    }
+
+    fn expire_at(
+        store: &KvStore,
+        key: &Bytes,
+        unix_time_milliseconds: Option<i64>,
+        conditions: &[ExpireCondition],
+        command: &str,
+        propagated: &mut Option<Vec<Bytes>>,
+    ) -> RESPDataTypes {
+        if let Some(unix_time_milliseconds) = unix_time_milliseconds {
+            let applied = store.expire(key, unix_time_milliseconds, conditions);
+
+            // Relative times are logged as absolute ones so replaying the
+            // log later does not extend the deadline.
+            if applied {
+                *propagated = Some(vec![
+                    Bytes::from_static(b"PEXPIREAT"),
+                    key.clone(),
+                    Bytes::from(unix_time_milliseconds.to_string()),
+                ]);
+            }
+
+            RESPDataTypes::Integer(applied as i64)
+        } else {
+            RESPDataTypes::BulkError(format!("ERR invalid expire time in '{command}' command"))
+        }
+    }
+
+    /// Pushes `elements` one after the other, so LPUSH leaves them in
+    /// reverse order. The X variants only push onto an existing list.
+    fn push(
+        context: &ServerContext,
+        key: &Bytes,
+        elements: &[Bytes],
+        end: ListEnd,
+        create: bool,
+        propagated: &mut Option<Vec<Bytes>>,
+    ) -> RESPDataTypes {
+        let pushed = context.store.write(key, create, |list: &mut List| {
+            for element in elements {
+                end.push(list, element.to_owned());
+            }
+
+            list.len()
+        });
+
+        match pushed {
+            Ok(length) => {
+                if length.is_some() {
+                    let command: &'static [u8] = match (end, create) {
+                        (ListEnd::Left, true) => b"LPUSH",
+                        (ListEnd::Right, true) => b"RPUSH",
+                        (ListEnd::Left, false) => b"LPUSHX",
+                        (ListEnd::Right, false) => b"RPUSHX",
+                    };
+
+                    *propagated =
+                        Some([&[Bytes::from_static(command), key.clone()], elements].concat());
+                    context.waiters.signal(key);
+                }
+
+                RESPDataTypes::Integer(length.unwrap_or(0) as i64)
+            }
+            Err(error) => error.into(),
+        }
+    }
+
+    /// Pops one element, or up to `count` as an array.
+    fn pop(
+        store: &KvStore,
+        protocol: ProtocolVersion,
+        key: &Bytes,
+        count: Option<i64>,
+        end: ListEnd,
+        propagated: &mut Option<Vec<Bytes>>,
+    ) -> RESPDataTypes {
+        let popped = store.write(key, false, |list: &mut List| {
+            (0..count.unwrap_or(1))
+                .map_while(|_| end.pop(list))
+                .collect::<Vec<_>>()
+        });
+
+        match popped {
+            Ok(Some(elements)) => {
+                if !elements.is_empty() {
+                    let mut command = vec![
+                        Bytes::from_static(match end {
+                            ListEnd::Left => b"LPOP",
+                            ListEnd::Right => b"RPOP",
+                        }),
+                        key.clone(),
+                    ];
+
+                    command.extend(count.map(|count| Bytes::from(count.to_string())));
+                    *propagated = Some(command);
+                }
+
+                match count {
+                    Some(_) => RESPDataTypes::Array(Some(
+                        elements
+                            .into_iter()
+                            .map(|element| RESPDataTypes::BulkString(Some(element)))
+                            .collect(),
+                    )),
+                    None => RESPDataTypes::BulkString(elements.into_iter().next()),
+                }
+            }
+            Ok(None) if count.is_some() => null_array(protocol),
+            Ok(None) => RESPDataTypes::Null,
+            Err(error) => error.into(),
+        }
+    }
+
+    /// Pops from `source` and pushes onto `destination`, which may be the
+    /// same list to rotate it.
+    fn lmove(
+        context: &ServerContext,
+        source: &Bytes,
+        destination: &Bytes,
+        from: ListEnd,
+        to: ListEnd,
+        propagated: &mut Option<Vec<Bytes>>,
+    ) -> RESPDataTypes {
+        let store = &context.store;
+        let moved = if source == destination {
+            store
+                .write(source, false, |list: &mut List| {
+                    let element = from.pop(list)?;
+
+                    to.push(list, element.to_owned());
+
+                    Some(element)
+                })
+                .map(Option::flatten)
+        } else {
+            // Both keys stay locked for the whole move, and the destination
+            // is checked first so a wrong type leaves the source untouched.
+            let mut locked = store.lock([source, destination]);
+
+            locked
+                .read(destination, |_: &List| ())
+                .and_then(|_| locked.write(source, false, |list: &mut List| from.pop(list)))
+                .map(Option::flatten)
+                .and_then(|element| {
+                    if let Some(element) = &element {
+                        locked.write(destination, true, |list: &mut List| {
+                            to.push(list, element.to_owned())
+                        })?;
+                    }
+
+                    Ok(element)
+                })
+        };
+
+        match moved {
+            Ok(Some(element)) => {
+                *propagated = Some(vec![
+                    Bytes::from_static(b"LMOVE"),
+                    source.clone(),
+                    destination.clone(),
+                    list_end_arg(from),
+                    list_end_arg(to),
+                ]);
+                context.waiters.signal(destination);
+
+                RESPDataTypes::BulkString(Some(element))
+            }
+            Ok(None) => RESPDataTypes::Null,
+            Err(error) => error.into(),
+        }
+    }
+
+    /// Replies with what `item` picks from each field and value of a hash.
+    fn hash_items<F>(store: &KvStore, key: &Bytes, item: F) -> RESPDataTypes
+    where
+        F: Fn(&Bytes, &Bytes) -> Bytes,
+    {
+        let items = store.read(key, |hash: &Hash| {
+            hash.iter()
+                .map(|(field, value)| RESPDataTypes::BulkString(Some(item(field, value))))
+                .collect()
+        });
+
+        match items {
+            Ok(items) => RESPDataTypes::Array(Some(items.unwrap_or_default())),
+            Err(error) => error.into(),
+        }
+    }
+
+    /// Replaces a hash field with what `increment` computes from its
+    /// current value, creating the hash and field when missing.
+    fn hash_increment<F>(
+        store: &KvStore,
+        key: &Bytes,
+        field: &Bytes,
+        increment: F,
+    ) -> Result<Bytes, RESPDataTypes>
+    where
+        F: FnOnce(Option<&Bytes>) -> Result<Bytes, RESPDataTypes>,
+    {
+        store
+            .write(key, true, |hash: &mut Hash| {
+                let value = increment(hash.get(field))?;
+
+                hash.insert(field.to_owned(), value.clone());
+
+                Ok(value)
+            })?
+            .unwrap_or_else(|| redis_err!("ERR no such key"))
+    }
+
+    /// Picks one random field, or `count` of them as an array, optionally
+    /// with their values. RESP3 clients get each field and value as a pair.
+    fn hrandfield(
+        store: &KvStore,
+        protocol: ProtocolVersion,
+        key: &Bytes,
+        count: Option<i64>,
+        with_values: bool,
+    ) -> RESPDataTypes {
+        let picked = store.read(key, |hash: &Hash| {
+            random_picks(hash.iter(), hash.len(), count.unwrap_or(1))
+                .into_iter()
+                .map(|(field, value)| (field.to_owned(), value.to_owned()))
+                .collect::<Vec<_>>()
+        });
+        let picked = match picked {
+            Ok(picked) => picked.unwrap_or_default(),
+            Err(error) => return error.into(),
+        };
+
+        if count.is_none() {
+            return picked
+                .into_iter()
+                .next()
+                .map_or(RESPDataTypes::Null, |(field, _)| {
+                    RESPDataTypes::BulkString(Some(field))
+                });
+        }
+
+        let bulk = |value| RESPDataTypes::BulkString(Some(value));
+
+        RESPDataTypes::Array(Some(match (with_values, protocol) {
+            (false, _) => picked.into_iter().map(|(field, _)| bulk(field)).collect(),
+            (true, ProtocolVersion::RESP2) => picked
+                .into_iter()
+                .flat_map(|(field, value)| [bulk(field), bulk(value)])
+                .collect(),
+            (true, ProtocolVersion::RESP3) => picked
+                .into_iter()
+                .map(|(field, value)| RESPDataTypes::Array(Some(vec![bulk(field), bulk(value)])))
+                .collect(),
+        }))
+    }
+
+    /// Combines the sets at `keys` in order, treating missing keys as empty
+    /// sets. The keys are read under one lock, so the sets are combined as
+    /// they stood at one point.
+    fn combine_sets(
+        locked: &LockedKeys,
+        keys: &[Bytes],
+        algebra: SetAlgebra,
+    ) -> Result<Set, WrongType> {
+        let mut combined = locked
+            .read(&keys[0], |set: &Set| set.clone())?
+            .unwrap_or_default();
+
+        for key in keys[1..].iter() {
+            let found = match algebra {
+                SetAlgebra::Intersection => locked.read(key, |set: &Set| {
+                    combined.retain(|member| set.contains(member))
+                })?,
+                SetAlgebra::Union => {
+                    locked.read(key, |set: &Set| combined.extend(set.iter().cloned()))?
+                }
+                SetAlgebra::Difference => locked.read(key, |set: &Set| {
+                    combined.retain(|member| !set.contains(member))
+                })?,
+            };
+
+            if found.is_none() && matches!(algebra, SetAlgebra::Intersection) {
+                combined.clear();
+            }
+        }
+
+        Ok(combined)
+    }
+
+    /// Stores the combined sets at `destination`, replacing whatever it held
+    /// and deleting it when the result is empty. The sources stay locked
+    /// until the destination is written.
+    fn store_combined_sets(
+        store: &KvStore,
+        destination: &Bytes,
+        keys: &[Bytes],
+        algebra: SetAlgebra,
+        propagated: &mut Option<Vec<Bytes>>,
+    ) -> RESPDataTypes {
+        let mut locked = store.lock(keys.iter().chain([destination]));
+        let combined = match Self::combine_sets(&locked, keys, algebra) {
+            Ok(combined) => combined,
+            Err(error) => return error.into(),
+        };
+        let length = combined.len();
+
+        if combined.is_empty() {
+            locked.remove(destination);
+        } else {
+            locked.insert(destination.clone(), Value::Set(combined), None);
+        }
+
+        let command: &'static [u8] = match algebra {
+            SetAlgebra::Intersection => b"SINTERSTORE",
+            SetAlgebra::Union => b"SUNIONSTORE",
+            SetAlgebra::Difference => b"SDIFFSTORE",
+        };
+
+        *propagated = Some([&[Bytes::from_static(command), destination.clone()], keys].concat());
+
+        RESPDataTypes::Integer(length as i64)
+    }
+
+    /// Moves `member` between sets. Both keys stay locked for the whole
+    /// move, and the destination's type is checked before anything is
+    /// removed from the source.
+    fn smove(
+        store: &KvStore,
+        source: &Bytes,
+        destination: &Bytes,
+        member: &Bytes,
+        propagated: &mut Option<Vec<Bytes>>,
+    ) -> RESPDataTypes {
+        let mut locked = store.lock([source, destination]);
+
+        if let Err(error) = locked.read(destination, |_: &Set| ()) {
+            return error.into();
+        }
+
+        if source == destination {
+            return match locked.read(source, |set: &Set| set.contains(member)) {
+                Ok(contains) => RESPDataTypes::Integer(contains.unwrap_or(false) as i64),
+                Err(error) => error.into(),
+            };
+        }
+
+        match locked.write(source, false, |set: &mut Set| set.remove(member)) {
+            Ok(Some(true)) => {}
+            Ok(_) => return RESPDataTypes::Integer(0),
+            Err(error) => return error.into(),
+        }
+
+        if let Err(error) = locked.write(destination, true, |set: &mut Set| {
+            set.insert(member.to_owned())
+        }) {
+            return error.into();
+        }
+
+        *propagated = Some(vec![
+            Bytes::from_static(b"SMOVE"),
+            source.clone(),
+            destination.clone(),
+            member.clone(),
+        ]);
+
+        RESPDataTypes::Integer(1)
+    }
+
+    /// Adds or updates members under ZADD's conditions, replying with how
+    /// many were added (or changed, with CH), or with the new score for
+    /// INCR. Applied scores are propagated as a plain ZADD so replicas need
+    /// neither the conditions nor the arithmetic.
+    #[allow(clippy::too_many_arguments)]
+    fn zadd(
+        store: &KvStore,
+        key: &Bytes,
+        condition: Option<SetCondition>,
+        comparison: Option<ScoreComparison>,
+        changed: bool,
+        increment: bool,
+        pairs: &[(f64, Bytes)],
+        propagated: &mut Option<Vec<Bytes>>,
+    ) -> RESPDataTypes {
+        let outcome = store.write(key, true, |zset: &mut SortedSet| {
+            let (mut added, mut updated, mut last_score) = (0, 0, None);
+            let mut applied = Vec::new();
+
+            for (score, member) in pairs.iter() {
+                let current = zset.score(member);
+                let score = match current {
+                    Some(current) if increment => current + score,
+                    _ => *score,
+                };
+
+                if score.is_nan() {
+                    return redis_err!("ERR resulting score is not a number (NaN)");
+                }
+
+                let skipped = match current {
+                    None => condition == Some(SetCondition::XX),
+                    Some(current) => {
+                        condition == Some(SetCondition::NX)
+                            || match comparison {
+                                Some(ScoreComparison::GT) => score <= current,
+                                Some(ScoreComparison::LT) => score >= current,
+                                None => false,
+                            }
+                    }
+                };
+
+                if skipped {
+                    continue;
+                }
+
+                if zset.insert(member.to_owned(), score) {
+                    added += 1;
+                } else if current != Some(score) {
+                    updated += 1;
+                }
+
+                if current != Some(score) {
+                    applied.push(Bytes::from(score.to_string()));
+                    applied.push(member.to_owned());
+                }
+
+                last_score = Some(score);
+            }
+
+            Ok((added, updated, last_score, applied))
+        });
+        let (added, updated, last_score, applied) = match outcome {
+            Ok(outcome) => match outcome.unwrap_or(Ok(Default::default())) {
+                Ok(outcome) => outcome,
+                Err(error) => return error,
+            },
+            Err(error) => return error.into(),
+        };
+
+        if !applied.is_empty() {
+            *propagated =
+                Some([&[Bytes::from_static(b"ZADD"), key.clone()], &applied[..]].concat());
+        }
+
+        if increment {
+            last_score.map_or(RESPDataTypes::Null, RESPDataTypes::Double)
+        } else {
+            RESPDataTypes::Integer(added + if changed { updated } else { 0 })
+        }
+    }
+
+    /// Replies with a member's rank, counted from the highest score when
+    /// `reverse` is set, and optionally its score.
+    fn zrank(
+        store: &KvStore,
+        protocol: ProtocolVersion,
+        key: &Bytes,
+        member: &Bytes,
+        reverse: bool,
+        with_score: bool,
+    ) -> RESPDataTypes {
+        let found = store.read(key, |zset: &SortedSet| {
+            let rank = zset.rank(member)?;
+            let rank = if reverse { zset.len() - 1 - rank } else { rank };
+
+            Some((rank, zset.score(member)?))
+        });
+
+        match found {
+            Ok(Some(Some((rank, score)))) if with_score => RESPDataTypes::Array(Some(vec![
+                RESPDataTypes::Integer(rank as i64),
+                RESPDataTypes::Double(score),
+            ])),
+            Ok(Some(Some((rank, _)))) => RESPDataTypes::Integer(rank as i64),
+            Ok(_) if with_score => null_array(protocol),
+            Ok(_) => RESPDataTypes::Null,
+            Err(error) => error.into(),
+        }
+    }
+
+    /// Removes the members at the ranks `ranks` picks, propagating them as a
+    /// ZREM.
+    fn zremrange<F>(
+        store: &KvStore,
+        key: &Bytes,
+        propagated: &mut Option<Vec<Bytes>>,
+        ranks: F,
+    ) -> RESPDataTypes
+    where
+        F: FnOnce(&SortedSet) -> Range<usize>,
+    {
+        let removed = store.write(key, false, |zset: &mut SortedSet| {
+            let ranks = ranks(zset);
+
+            zset.remove_range(ranks)
+        });
+
+        match removed {
+            Ok(removed) => {
+                let removed = removed.unwrap_or_default();
+
+                if !removed.is_empty() {
+                    *propagated =
+                        Some([&[Bytes::from_static(b"ZREM"), key.clone()], &removed[..]].concat());
+                }
+
+                RESPDataTypes::Integer(removed.len() as i64)
+            }
+            Err(error) => error.into(),
+        }
+    }
+
+    /// Pops up to `count` elements from the first non-empty list of `keys`,
+    /// replying with its key and the elements.
+    fn mpop(
+        store: &KvStore,
+        protocol: ProtocolVersion,
+        keys: &[Bytes],
+        end: ListEnd,
+        count: i64,
+        propagated: &mut Option<Vec<Bytes>>,
+    ) -> RESPDataTypes {
+        for key in keys {
+            match Self::pop(store, protocol, key, Some(count), end, propagated) {
+                RESPDataTypes::Array(Some(elements)) => {
+                    return RESPDataTypes::Array(Some(vec![
+                        RESPDataTypes::BulkString(Some(key.clone())),
+                        RESPDataTypes::Array(Some(elements)),
+                    ]))
+                }
+                RESPDataTypes::Array(None) | RESPDataTypes::Null => {}
+                error => return error,
+            }
+        }
+
+        null_array(protocol)
+    }
+
+    /// Runs `operation` on the first of `keys` that holds a list, or blocks
+    /// the client on all of them until a push serves it or `timeout` passes.
+    /// Returns `None` when the connection blocks for the reply.
+    fn blocking_pop(
+        context: &Arc<ServerContext>,
+        client: &mut Client,
+        keys: &[Bytes],
+        operation: BlockedOperation,
+        timeout: i64,
+        propagated: &mut Option<Vec<Bytes>>,
+    ) -> Option<RESPDataTypes> {
+        for key in keys {
+            match Self::serve_operation(context, key, &operation, propagated) {
+                Ok(Some(reply)) => return Some(reply),
+                Ok(None) => {}
+                Err(error) => return Some(error),
+            }
+        }
+
+        let timed_out = match operation {
+            BlockedOperation::Move { .. } => RESPDataTypes::Null,
+            _ => null_array(client.protocol),
+        };
+        let mut reply = context
+            .waiters
+            .register(client.id, keys.to_vec(), operation);
+        let registration = Registration::new(Arc::clone(context), client.id);
+
+        // A push may have landed since the keys were checked; serving them
+        // again once the client is registered means it cannot be missed.
+        for key in keys {
+            context.waiters.signal(key);
+        }
+        let deadline =
+            (timeout > 0).then(|| Instant::now() + Duration::from_millis(timeout as u64));
+
+        client.blocked = Some(Box::pin(async move {
+            let expired = async {
+                match deadline {
+                    Some(deadline) => time::sleep_until(deadline).await,
+                    None => future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                Ok(reply) = &mut reply => return reply,
+                _ = expired => {}
+            }
+
+            // The client may have been served just before it was
+            // unregistered.
+            drop(registration);
+
+            reply.try_recv().unwrap_or(timed_out)
+        }));
+
+        None
+    }
+
+    /// Runs a blocked client's `operation` on `key`. Returns `None` when the
+    /// key does not exist, so the client keeps waiting, and an error reply
+    /// when it, or the destination of a move, holds another type.
+    fn serve_operation(
+        context: &ServerContext,
+        key: &Bytes,
+        operation: &BlockedOperation,
+        propagated: &mut Option<Vec<Bytes>>,
+    ) -> Result<Option<RESPDataTypes>, RESPDataTypes> {
+        let store = &context.store;
+
+        match store.read(key, |_: &List| ()) {
+            Ok(Some(())) => {}
+            Ok(None) => return Ok(None),
+            Err(error) => return Err(error.into()),
+        }
+
+        let reply = match operation {
+            BlockedOperation::Pop { end } => {
+                match Self::pop(store, ProtocolVersion::RESP3, key, None, *end, propagated) {
+                    RESPDataTypes::BulkString(Some(element)) => RESPDataTypes::Array(Some(vec![
+                        RESPDataTypes::BulkString(Some(key.clone())),
+                        RESPDataTypes::BulkString(Some(element)),
+                    ])),
+                    reply => reply,
+                }
+            }
+            BlockedOperation::Move {
+                destination,
+                from,
+                to,
+            } => Self::lmove(context, key, destination, *from, *to, propagated),
+            BlockedOperation::MultiPop { end, count } => Self::mpop(
+                store,
+                ProtocolVersion::RESP3,
+                slice::from_ref(key),
+                *end,
+                *count,
+                propagated,
+            ),
+        };
+
+        match reply {
+            RESPDataTypes::SimpleError(_) => Err(reply),
+            // Another client emptied the key since it was checked.
+            RESPDataTypes::Null | RESPDataTypes::BulkString(None) | RESPDataTypes::Array(None) => {
+                Ok(None)
+            }
+            reply => Ok(Some(reply)),
+        }
+    }
+
+    /// Hands what writes pushed onto keys to the clients blocked on them,
+    /// longest waiting first. Each pop is propagated as its non-blocking
+    /// form.
+    fn serve_blocked(context: &ServerContext) {
+        context.waiters.serve(|key, operation| {
+            let mut propagated = None;
+            let reply = match Self::serve_operation(context, key, operation, &mut propagated) {
+                Ok(Some(reply)) => reply,
+                Ok(None) => return None,
+                Err(error) => error,
+            };
+
+            if let Some(command) = propagated {
+                context.propagate(&command);
+            }
+
+            Some(reply)
+        });
+    }
+
+    fn ttl_reply<F>(ttl: Ttl, remaining: F) -> RESPDataTypes
+    where
+        F: FnOnce(i64) -> i64,
+    {
+        match ttl {
+            Ttl::Missing => RESPDataTypes::Integer(-2),
+            Ttl::Persistent => RESPDataTypes::Integer(-1),
+            Ttl::ExpiresAt(expires_at) => RESPDataTypes::Integer(remaining(expires_at).max(0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use tokio::time;
+
+    use super::{
+        random_picks, ConfigSubcommand, LexBound, ListEnd, ProtocolVersion, RESPDataTypes,
+        RedisCommand, ScoreBound, SetCondition, SetExpiry, ZRangeBy,
+    };
+    use crate::redis::{
+        aof,
+        client::Client,
+        config::Config,
+        server::ServerContext,
+        store::{now_millis, Value},
+    };
+
+    fn run(context: &Arc<ServerContext>, client: &mut Client, request: RESPDataTypes) -> String {
+        let mut output = Vec::new();
+
+        RedisCommand::try_from(request)
+            .unwrap()
+            .respond(&mut output, context, client);
+
+        String::from_utf8(output).unwrap()
+    }
+
+    macro_rules! raw_request {
+        ($command:literal) => {
+            RESPDataTypes::Array(Some(
+                vec![
+                    RESPDataTypes::BulkString(Some(Bytes::from($command)))
+                ]
+            ))
+        };
+        ($command:literal, [$($arg:literal),*]) => {
+            RESPDataTypes::Array(Some(
+                vec![
+                    RESPDataTypes::BulkString(Some(Bytes::from($command))),
+                    $(
+                        RESPDataTypes::BulkString(Some(Bytes::from($arg))),
+                    )*
+                ]
+            ))
+        };
+    }
+
+    #[test]
+    #[allow(clippy::match_like_matches_macro)]
+    fn echo() {
+        let redis_command = RedisCommand::try_from(raw_request!("echo", ["Hello World!"]));
+
+        assert!(match redis_command {
+            Ok(RedisCommand::ECHO { message: _ }) => true,
+            _ => false,
+        });
+
+        if let Ok(RedisCommand::ECHO { message }) = redis_command {
+            assert_eq!(message, String::from("Hello World!"));
+        }
+    }
+
+    #[test]
+    fn expire_options() {
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("expire", ["key", "10", "xx", "gt"])),
+            Ok(RedisCommand::EXPIRE { seconds: 10, ref conditions, .. }) if conditions.len() == 2
+        ));
+        assert!(RedisCommand::try_from(raw_request!("expire", ["key", "10", "nx", "xx"])).is_err());
+        assert!(
+            RedisCommand::try_from(raw_request!("pexpire", ["key", "10", "gt", "lt"])).is_err()
+        );
+        assert!(RedisCommand::try_from(raw_request!("expireat", ["key", "soon"])).is_err());
+    }
+
+    #[test]
+    fn del() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let mut client = Client::new();
+        let client = &mut client;
+
+        run(&context, client, raw_request!("set", ["string", "a"]));
+        run(&context, client, raw_request!("rpush", ["list", "a"]));
+
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("del", ["string", "list", "missing"])
+            ),
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("type", ["list"])),
+            "+none\r\n"
+        );
+        assert!(RedisCommand::try_from(raw_request!("del", [])).is_err());
+    }
+
+    #[test]
+    fn lazily_expired_keys_propagate_deletes() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let mut client = Client::new();
+        let client = &mut client;
+        let (_, _, mut receiver, _) = context.replication.add_replica(0, None, 6380);
+        let expired = |key: &'static str| {
+            context.store.insert(
+                Bytes::from(key),
+                Value::String(Bytes::from("old")),
+                Some(now_millis() - 1),
+            )
+        };
+        let del = |key: &'static str| {
+            aof::serialize_command(&[Bytes::from_static(b"DEL"), Bytes::from(key)])
+        };
+
+        expired("read");
+
+        assert_eq!(
+            run(&context, client, raw_request!("get", ["read"])),
+            "$-1\r\n"
+        );
+        assert_eq!(receiver.try_recv().unwrap(), del("read"));
+
+        expired("written");
+        run(&context, client, raw_request!("rpush", ["written", "new"]));
+
+        assert_eq!(receiver.try_recv().unwrap(), del("written"));
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            aof::serialize_command(&[
+                Bytes::from_static(b"RPUSH"),
+                Bytes::from("written"),
+                Bytes::from("new")
+            ])
+        );
+    }
+
+    #[test]
+    fn set_options() {
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("set", ["lock", "token", "NX", "PX", "30000"])),
+            Ok(RedisCommand::SET {
+                condition: Some(SetCondition::NX),
+                expiry: Some(SetExpiry::PX(30000)),
+                get: false,
+                ..
+            })
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("set", ["key", "value", "keepttl", "get"])),
+            Ok(RedisCommand::SET {
+                condition: None,
+                expiry: Some(SetExpiry::KEEPTTL),
+                get: true,
+                ..
+            })
+        ));
+        assert!(RedisCommand::try_from(raw_request!("set", ["key", "value", "nx", "xx"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!(
+            "set",
+            ["key", "value", "ex", "1", "px", "1"]
+        ))
+        .is_err());
+        assert!(RedisCommand::try_from(raw_request!("set", ["key", "value", "ex"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("set", ["key", "value", "ex", "0"])).is_err());
+    }
+
+    #[test]
+    fn hello_options() {
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("hello", ["3", "AUTH", "default", "secret", "SETNAME", "app"])),
+            Ok(RedisCommand::HELLO {
+                protocol_version: Some(ref version),
+                auth: Some(_),
+                client_name: Some(ref name),
+            }) if version == "3" && name == "app"
+        ));
+        assert!(RedisCommand::try_from(raw_request!("hello", ["3", "AUTH", "default"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("hello", ["3", "SETNAME"])).is_err());
+    }
+
+    #[test]
+    fn config_subcommands() {
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("config", ["SET", "dir", "/tmp", "dbfilename", "a.rdb"])),
+            Ok(RedisCommand::CONFIG {
+                subcommand: ConfigSubcommand::SET { ref parameters }
+            }) if parameters.len() == 2
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("config", ["get", "*"])),
+            Ok(RedisCommand::CONFIG {
+                subcommand: ConfigSubcommand::GET { .. }
+            })
+        ));
+        assert!(RedisCommand::try_from(raw_request!("config", ["set", "dir"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("config", ["rewrite", "now"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("config", ["reset"])).is_err());
+    }
+
+    #[test]
+    fn replicaof_args() {
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("replicaof", ["localhost", "6380"])),
+            Ok(RedisCommand::REPLICAOF {
+                master: Some((ref host, 6380))
+            }) if host == "localhost"
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("slaveof", ["NO", "one"])),
+            Ok(RedisCommand::REPLICAOF { master: None })
+        ));
+        assert!(RedisCommand::try_from(raw_request!("replicaof", ["localhost", "70000"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("replicaof", ["localhost"])).is_err());
+    }
+
+    #[test]
+    fn wait_args() {
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("waitaof", ["1", "2", "100"])),
+            Ok(RedisCommand::WAITAOF {
+                numlocal: 1,
+                numreplicas: 2,
+                timeout: 100
+            })
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("wait", ["1", "-1"])),
+            Err(RESPDataTypes::BulkError(message)) if message == "ERR timeout is negative"
+        ));
+        assert!(RedisCommand::try_from(raw_request!("wait", ["1"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn wait_blocks_until_replicas_acknowledge() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let mut client = Client::new();
+        let mut output = Vec::new();
+        let (_, _, mut receiver, acks) = context.replication.add_replica(0, None, 6380);
+
+        RedisCommand::try_from(raw_request!("set", ["a", "1"]))
+            .unwrap()
+            .respond(&mut io::sink(), &context, &mut client);
+        RedisCommand::try_from(raw_request!("wait", ["1", "0"]))
+            .unwrap()
+            .respond(&mut output, &context, &mut client);
+
+        assert!(output.is_empty());
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver
+            .try_recv()
+            .unwrap()
+            .starts_with(b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK"));
+
+        let blocked = client.blocked.take().unwrap();
+
+        acks.offset.store(client.write_offset, Ordering::SeqCst);
+        context.replication.acknowledge();
+
+        assert_eq!(blocked.await, RESPDataTypes::Integer(1));
+
+        RedisCommand::try_from(raw_request!("waitaof", ["0", "1", "10"]))
+            .unwrap()
+            .respond(&mut output, &context, &mut client);
+
+        assert_eq!(
+            client.blocked.take().unwrap().await,
+            RESPDataTypes::Array(Some(vec![
+                RESPDataTypes::Integer(0),
+                RESPDataTypes::Integer(0)
+            ]))
+        );
+
+        RedisCommand::try_from(raw_request!("waitaof", ["1", "0", "0"]))
+            .unwrap()
+            .respond(&mut output, &context, &mut client);
+
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .starts_with("-ERR WAITAOF cannot be used when numlocal is set"));
+    }
+
+    #[test]
+    fn list_args() {
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!(
+                "lpos",
+                ["list", "a", "RANK", "-2", "COUNT", "0", "MAXLEN", "10"]
+            )),
+            Ok(RedisCommand::LPOS {
+                rank: -2,
+                count: Some(0),
+                maxlen: 10,
+                ..
+            })
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("lmpop", ["2", "a", "b", "right", "count", "3"])),
+            Ok(RedisCommand::LMPOP { ref keys, end: ListEnd::Right, count: 3 }) if keys.len() == 2
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("lmove", ["a", "b", "LEFT", "RIGHT"])),
+            Ok(RedisCommand::LMOVE {
+                from: ListEnd::Left,
+                to: ListEnd::Right,
+                ..
+            })
+        ));
+        assert!(RedisCommand::try_from(raw_request!("lpos", ["list", "a", "RANK", "0"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("lpop", ["list", "-1"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("lmpop", ["0", "a", "left"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("lmpop", ["3", "a", "left"])).is_err());
+        assert!(
+            RedisCommand::try_from(raw_request!("linsert", ["list", "middle", "a", "b"])).is_err()
+        );
+        assert!(RedisCommand::try_from(raw_request!("lpush", ["list"])).is_err());
+    }
+
+    #[test]
+    fn list_commands() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let mut client = Client::new();
+        let client = &mut client;
+
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("rpush", ["list", "a", "b", "c"])
+            ),
+            ":3\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("lpush", ["list", "y", "z"])),
+            ":5\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("lrange", ["list", "0", "-1"])
+            ),
+            "*5\r\n$1\r\nz\r\n$1\r\ny\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("lrange", ["list", "-2", "100"])
+            ),
+            "*2\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("lindex", ["list", "-1"])),
+            "$1\r\nc\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("linsert", ["list", "before", "a", "b"])
+            ),
+            ":6\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("lpos", ["list", "b", "rank", "-1", "count", "0"])
+            ),
+            "*2\r\n:4\r\n:2\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("lrem", ["list", "-1", "b"])),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("ltrim", ["list", "1", "-2"])),
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("lpop", ["list", "2"])),
+            "*2\r\n$1\r\ny\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("type", ["list"])),
+            "+list\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("lpop", ["list"])),
+            "$1\r\na\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("type", ["list"])),
+            "+none\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("lpop", ["list", "1"])),
+            "*-1\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("lset", ["list", "0", "a"])),
+            "-ERR no such key\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("rpushx", ["list", "a"])),
+            ":0\r\n"
+        );
+    }
+
+    #[test]
+    fn list_moves_and_wrong_types() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let mut client = Client::new();
+        let client = &mut client;
+        let wrongtype = "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+
+        run(
+            &context,
+            client,
+            raw_request!("rpush", ["source", "a", "b"]),
+        );
+        run(&context, client, raw_request!("set", ["string", "value"]));
+
+        assert_eq!(
+            run(&context, client, raw_request!("lpush", ["string", "a"])),
+            wrongtype
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("get", ["source"])),
+            wrongtype
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("set", ["source", "a", "get"])
+            ),
+            wrongtype
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("lmove", ["source", "string", "left", "left"])
+            ),
+            wrongtype
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("llen", ["source"])),
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("rpoplpush", ["source", "source"])
+            ),
+            "$1\r\nb\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("lmove", ["source", "destination", "right", "left"])
+            ),
+            "$1\r\na\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("lmpop", ["3", "missing", "source", "destination", "left"])
+            ),
+            "*2\r\n$6\r\nsource\r\n*1\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("lmpop", ["1", "string", "left"])
+            ),
+            wrongtype
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("set", ["destination", "value"])
+            ),
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("type", ["destination"])),
+            "+string\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn blocking_pops_serve_the_longest_waiting_client() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let (mut first, mut second, mut pusher) = (Client::new(), Client::new(), Client::new());
+        let (_, _, mut replica, _) = context.replication.add_replica(0, None, 6380);
+        let bulk = |value: &'static str| RESPDataTypes::BulkString(Some(Bytes::from(value)));
+
+        assert_eq!(
+            run(&context, &mut first, raw_request!("blpop", ["queue", "0"])),
+            ""
+        );
+        assert_eq!(
+            run(
+                &context,
+                &mut second,
+                raw_request!("blpop", ["other", "queue", "0"])
+            ),
+            ""
+        );
+        assert_eq!(
+            run(
+                &context,
+                &mut pusher,
+                raw_request!("rpush", ["queue", "a", "b", "c"])
+            ),
+            ":3\r\n"
+        );
+
+        let served = |client: &mut Client| {
+            time::timeout(Duration::from_secs(1), client.blocked.take().unwrap())
+        };
+
+        assert_eq!(
+            served(&mut first).await.unwrap(),
+            RESPDataTypes::Array(Some(vec![bulk("queue"), bulk("a")]))
+        );
+        assert_eq!(
+            served(&mut second).await.unwrap(),
+            RESPDataTypes::Array(Some(vec![bulk("queue"), bulk("b")]))
+        );
+
+        // Replicas see the push followed by a plain pop per served client.
+        let propagated = [
+            replica.try_recv().unwrap(),
+            replica.try_recv().unwrap(),
+            replica.try_recv().unwrap(),
+        ]
+        .concat();
+
+        assert!(propagated.starts_with(b"*5\r\n$5\r\nRPUSH"));
+        assert!(propagated.ends_with(b"*2\r\n$4\r\nLPOP\r\n$5\r\nqueue\r\n"));
+
+        assert_eq!(
+            run(&context, &mut first, raw_request!("brpop", ["queue", "0"])),
+            "*2\r\n$5\r\nqueue\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                &mut first,
+                raw_request!("blmove", ["queue", "moved", "left", "right", "0"])
+            ),
+            ""
+        );
+        assert_eq!(
+            run(
+                &context,
+                &mut second,
+                raw_request!("blmpop", ["0", "1", "moved", "left", "count", "5"])
+            ),
+            ""
+        );
+
+        run(&context, &mut pusher, raw_request!("lpush", ["queue", "d"]));
+
+        // The move feeds the client blocked on its destination.
+        assert_eq!(served(&mut first).await.unwrap(), bulk("d"));
+        assert_eq!(
+            served(&mut second).await.unwrap(),
+            RESPDataTypes::Array(Some(vec![
+                bulk("moved"),
+                RESPDataTypes::Array(Some(vec![bulk("d")]))
+            ]))
+        );
+        assert_eq!(
+            run(&context, &mut pusher, raw_request!("type", ["moved"])),
+            "+none\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn blocking_pops_time_out() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let mut client = Client::new();
+
+        run(
+            &context,
+            &mut client,
+            raw_request!("set", ["string", "value"]),
+        );
+
+        assert!(run(
+            &context,
+            &mut client,
+            raw_request!("blpop", ["string", "0"])
+        )
+        .starts_with("-WRONGTYPE"));
+        assert_eq!(
+            run(
+                &context,
+                &mut client,
+                raw_request!("blpop", ["queue", "0.01"])
+            ),
+            ""
+        );
+        assert_eq!(
+            client.blocked.take().unwrap().await,
+            RESPDataTypes::Array(None)
+        );
+
+        run(
+            &context,
+            &mut client,
+            raw_request!("blmove", ["queue", "b", "left", "left", "0.01"]),
+        );
+
+        assert_eq!(client.blocked.take().unwrap().await, RESPDataTypes::Null);
+
+        // Nothing is left waiting, so a later push stays in the list.
+        run(&context, &mut client, raw_request!("rpush", ["queue", "a"]));
+
+        assert_eq!(
+            run(&context, &mut client, raw_request!("llen", ["queue"])),
+            ":1\r\n"
+        );
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("blpop", ["queue", "-1"])),
+            Err(RESPDataTypes::BulkError(message)) if message == "ERR timeout is negative"
+        ));
+        assert!(RedisCommand::try_from(raw_request!("blpop", ["queue", "soon"])).is_err());
+    }
+
+    #[test]
+    fn hash_args() {
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("hset", ["hash", "a", "1", "b", "2"])),
+            Ok(RedisCommand::HSET { pairs, .. }) if pairs.len() == 2
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("hrandfield", ["hash", "-3", "WithValues"])),
+            Ok(RedisCommand::HRANDFIELD {
+                count: Some(-3),
+                with_values: true,
+                ..
+            })
+        ));
+        assert!(RedisCommand::try_from(raw_request!("hset", ["hash", "a"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("hset", ["hash", "a", "1", "b"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("hdel", ["hash"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("hincrby", ["hash", "a", "1.5"])).is_err());
+        assert!(
+            RedisCommand::try_from(raw_request!("hincrbyfloat", ["hash", "a", "nan"])).is_err()
+        );
+        assert!(
+            RedisCommand::try_from(raw_request!("hrandfield", ["hash", "1", "values"])).is_err()
+        );
+    }
+
+    #[test]
+    fn hash_commands() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let mut client = Client::new();
+        let client = &mut client;
+
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("hset", ["hash", "a", "1", "b", "2"])
+            ),
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("hset", ["hash", "a", "3", "c", "text"])
+            ),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("hsetnx", ["hash", "a", "4"])),
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("hget", ["hash", "a"])),
+            "$1\r\n3\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("hmget", ["hash", "b", "missing"])
+            ),
+            "*2\r\n$1\r\n2\r\n$-1\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("hincrby", ["hash", "a", "-5"])
+            ),
+            ":-2\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("hincrbyfloat", ["hash", "b", "0.5"])
+            ),
+            "$3\r\n2.5\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("hincrby", ["hash", "b", "1"])
+            ),
+            "-ERR hash value is not an integer\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("hincrbyfloat", ["hash", "c", "1"])
+            ),
+            "-ERR hash value is not a float\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("hincrby", ["hash", "d", "9223372036854775807"])
+            ),
+            ":9223372036854775807\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("hincrby", ["hash", "d", "1"])
+            ),
+            "-ERR increment or decrement would overflow\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("hstrlen", ["hash", "c"])),
+            ":4\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("hdel", ["hash", "b", "d", "missing"])
+            ),
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("hexists", ["hash", "b"])),
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("hlen", ["hash"])),
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("type", ["hash"])),
+            "+hash\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("hdel", ["hash", "a", "c"])),
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("type", ["hash"])),
+            "+none\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("hgetall", ["hash"])),
+            "*0\r\n"
+        );
+
+        run(&context, client, raw_request!("hset", ["hash", "f", "v"]));
+        run(&context, client, raw_request!("rpush", ["list", "a"]));
+
+        assert_eq!(
+            run(&context, client, raw_request!("hgetall", ["hash"])),
+            "*2\r\n$1\r\nf\r\n$1\r\nv\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("hkeys", ["list"])),
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+
+        client.protocol = ProtocolVersion::RESP3;
+
+        assert_eq!(
+            run(&context, client, raw_request!("hgetall", ["hash"])),
+            "%1\r\n$1\r\nf\r\n$1\r\nv\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("hget", ["hash", "missing"])),
+            "_\r\n"
+        );
+    }
+
+    #[test]
+    fn hrandfield() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let mut client = Client::new();
+        let client = &mut client;
+
+        run(
+            &context,
+            client,
+            raw_request!("hset", ["hash", "a", "1", "b", "2", "c", "3"]),
+        );
+
+        let mut fields = run(&context, client, raw_request!("hrandfield", ["hash", "5"]))
+            .split("\r\n")
+            .skip(1)
+            .filter(|line| !line.starts_with('$') && !line.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+
+        fields.sort();
+
+        assert_eq!(fields, ["a", "b", "c"]);
+        assert!(
+            run(&context, client, raw_request!("hrandfield", ["hash", "-5"])).starts_with("*5\r\n")
+        );
+        assert!(run(
+            &context,
+            client,
+            raw_request!("hrandfield", ["hash", "2", "withvalues"])
+        )
+        .starts_with("*4\r\n"));
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("hrandfield", ["missing", "2"])
+            ),
+            "*0\r\n"
+        );
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("hrandfield", ["hash", "-9223372036854775808"])),
+            Err(RESPDataTypes::BulkError(message)) if message == "ERR value is out of range"
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!(
+                "hrandfield",
+                ["hash", "-4611686018427387904", "withvalues"]
+            )),
+            Err(RESPDataTypes::BulkError(message)) if message == "ERR value is out of range"
+        ));
+        assert_eq!(
+            run(&context, client, raw_request!("hrandfield", ["missing"])),
+            "$-1\r\n"
+        );
+
+        client.protocol = ProtocolVersion::RESP3;
+
+        let reply = run(
+            &context,
+            client,
+            raw_request!("hrandfield", ["hash", "-1", "withvalues"]),
+        );
+
+        assert!(reply.starts_with("*1\r\n*2\r\n$1\r\n"));
+    }
+
+    /// The bulk strings of a reply, sorted, for replies whose order is
+    /// unspecified.
+    fn sorted_bulks(reply: &str) -> Vec<String> {
+        let mut lines = reply.split("\r\n").skip(1).collect::<Vec<_>>();
+
+        lines.retain(|line| !line.is_empty() && !line.starts_with(['$', '*', '~']));
+        lines.sort();
+
+        lines.into_iter().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn set_args() {
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("sintercard", ["2", "a", "b", "LIMIT", "5"])),
+            Ok(RedisCommand::SINTERCARD { keys, limit: 5 }) if keys.len() == 2
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("srandmember", ["set", "-5"])),
+            Ok(RedisCommand::SRANDMEMBER {
+                count: Some(-5),
+                ..
+            })
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("sintercard", ["3", "a", "b"])),
+            Err(RESPDataTypes::BulkError(message))
+                if message == "ERR Number of keys can't be greater than number of args"
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("sintercard", ["1", "a", "limit", "-1"])),
+            Err(RESPDataTypes::BulkError(message)) if message == "ERR LIMIT can't be negative"
+        ));
+        assert!(RedisCommand::try_from(raw_request!("sintercard", ["0", "a"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("spop", ["set", "-1"])).is_err());
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("srandmember", ["set", "-9223372036854775808"])),
+            Err(RESPDataTypes::BulkError(message)) if message == "ERR value is out of range"
+        ));
+        assert!(RedisCommand::try_from(raw_request!("sinterstore", ["destination"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("sunion")).is_err());
+    }
+
+    #[test]
+    fn random_picks_draw_only_what_they_return() {
+        let mut distinct = random_picks(0..1_000_000, 1_000_000, 3);
+
+        distinct.sort();
+        distinct.dedup();
+
+        assert_eq!(distinct.len(), 3);
+        assert_eq!(random_picks(0..4, 4, 10), [0, 1, 2, 3]);
+
+        let repeated = random_picks(0..2, 2, -50);
+
+        assert_eq!(repeated.len(), 50);
+        assert!(repeated.iter().all(|item| *item < 2));
+        assert!(random_picks(0..0, 0, -50).is_empty());
+    }
+
+    #[test]
+    fn set_commands() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let mut client = Client::new();
+        let client = &mut client;
+
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("sadd", ["a", "1", "2", "3", "2"])
+            ),
+            ":3\r\n"
+        );
+        run(&context, client, raw_request!("sadd", ["b", "2", "3", "4"]));
+        run(&context, client, raw_request!("rpush", ["list", "x"]));
+
+        assert_eq!(
+            sorted_bulks(&run(&context, client, raw_request!("sinter", ["a", "b"]))),
+            ["2", "3"]
+        );
+        assert_eq!(
+            sorted_bulks(&run(&context, client, raw_request!("sunion", ["a", "b"]))),
+            ["1", "2", "3", "4"]
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("sdiff", ["a", "b", "missing"])
+            ),
+            "*1\r\n$1\r\n1\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("sinter", ["a", "missing"])),
+            "*0\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("sunion", ["a", "list"])),
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("sintercard", ["2", "a", "b", "limit", "1"])
+            ),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("sunionstore", ["list", "a", "b"])
+            ),
+            ":4\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("type", ["list"])),
+            "+set\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("sinterstore", ["list", "a", "missing"])
+            ),
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("type", ["list"])),
+            "+none\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("smismember", ["a", "1", "4"])
+            ),
+            "*2\r\n:1\r\n:0\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("smove", ["a", "b", "1"])),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("smove", ["a", "b", "1"])),
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("sismember", ["b", "1"])),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("srem", ["b", "1", "2", "9"])),
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("scard", ["b"])),
+            ":2\r\n"
+        );
+        assert_eq!(
+            sorted_bulks(&run(&context, client, raw_request!("spop", ["a", "5"]))),
+            ["2", "3"]
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("type", ["a"])),
+            "+none\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("spop", ["a"])),
+            "$-1\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("srandmember", ["b", "-3"]))
+                .matches('$')
+                .count(),
+            3
+        );
+        assert_eq!(
+            sorted_bulks(&run(
+                &context,
+                client,
+                raw_request!("srandmember", ["b", "3"])
+            )),
+            ["3", "4"]
+        );
+
+        client.protocol = ProtocolVersion::RESP3;
+
+        assert_eq!(
+            sorted_bulks(&run(&context, client, raw_request!("smembers", ["b"]))),
+            ["3", "4"]
+        );
+        assert!(run(&context, client, raw_request!("smembers", ["b"])).starts_with("~2\r\n"));
+        assert_eq!(
+            run(&context, client, raw_request!("smembers", ["missing"])),
+            "~0\r\n"
+        );
+    }
+
+    #[test]
+    fn sorted_set_args() {
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!(
+                "zrandmember",
+                ["board", "-4611686018427387904", "withscores"]
+            )),
+            Err(RESPDataTypes::BulkError(message)) if message == "ERR value is out of range"
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!(
+                "zrandmember",
+                ["board", "-4611686018427387904"]
+            )),
+            Ok(RedisCommand::ZRANDMEMBER {
+                count: Some(-4611686018427387904),
+                ..
+            })
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("zadd", ["board", "XX", "ch", "1", "a", "+inf", "b"])),
+            Ok(RedisCommand::ZADD {
+                condition: Some(SetCondition::XX),
+                comparison: None,
+                changed: true,
+                increment: false,
+                pairs,
+                ..
+            }) if pairs == [(1.0, Bytes::from("a")), (f64::INFINITY, Bytes::from("b"))]
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("zrange", ["board", "5", "(1", "byscore", "rev"])),
+            Ok(RedisCommand::ZRANGE {
+                by: ZRangeBy::Score { min, max },
+                reverse: true,
+                ..
+            }) if min == ScoreBound { score: 1.0, exclusive: true }
+                && max == ScoreBound { score: 5.0, exclusive: false }
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("zlexcount", ["board", "-", "(b"])),
+            Ok(RedisCommand::ZLEXCOUNT {
+                min: LexBound::Min,
+                max: LexBound::Exclusive(member),
+                ..
+            }) if member == "b"
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("zadd", ["board", "nx", "xx", "1", "a"])),
+            Err(RESPDataTypes::BulkError(message))
+                if message == "ERR XX and NX options at the same time are not compatible"
+        ));
+        assert!(matches!(
+            RedisCommand::try_from(raw_request!("zrange", ["board", "0", "1", "limit", "0", "1"])),
+            Err(RESPDataTypes::BulkError(message)) if message.contains("LIMIT is only supported")
+        ));
+        assert!(
+            RedisCommand::try_from(raw_request!("zadd", ["board", "gt", "lt", "1", "a"])).is_err()
+        );
+        assert!(
+            RedisCommand::try_from(raw_request!("zadd", ["board", "nx", "gt", "1", "a"])).is_err()
+        );
+        assert!(RedisCommand::try_from(raw_request!(
+            "zadd",
+            ["board", "incr", "1", "a", "2", "b"]
+        ))
+        .is_err());
+        assert!(RedisCommand::try_from(raw_request!("zadd", ["board", "1", "a", "2"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!("zadd", ["board", "nan", "a"])).is_err());
+        assert!(RedisCommand::try_from(raw_request!(
+            "zrange",
+            ["board", "[a", "[b", "bylex", "withscores"]
+        ))
+        .is_err());
+        assert!(
+            RedisCommand::try_from(raw_request!("zrange", ["board", "a", "b", "byscore"])).is_err()
+        );
+        assert!(
+            RedisCommand::try_from(raw_request!("zrange", ["board", "a", "b", "bylex"])).is_err()
+        );
+        assert!(RedisCommand::try_from(raw_request!("zrank", ["board", "a", "scores"])).is_err());
+    }
+
+    #[test]
+    fn sorted_set_commands() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let mut client = Client::new();
+        let client = &mut client;
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zadd", ["board", "1", "a", "2", "b", "3", "c", "4", "d"])
+            ),
+            ":4\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zadd", ["board", "xx", "ch", "10", "a", "5", "e"])
+            ),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zadd", ["board", "nx", "20", "a", "5", "e"])
+            ),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zadd", ["board", "gt", "ch", "1", "b", "6", "c"])
+            ),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zadd", ["board", "incr", "5", "b"])
+            ),
+            "$1\r\n7\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zadd", ["board", "nx", "incr", "1", "b"])
+            ),
+            "$-1\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zincrby", ["board", "-0.5", "a"])
+            ),
+            "$3\r\n9.5\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zrange", ["board", "0", "-1"])
+            ),
+            "*5\r\n$1\r\nd\r\n$1\r\ne\r\n$1\r\nc\r\n$1\r\nb\r\n$1\r\na\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zrange", ["board", "0", "1", "rev", "withscores"])
+            ),
+            "*4\r\n$1\r\na\r\n$3\r\n9.5\r\n$1\r\nb\r\n$1\r\n7\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!(
+                    "zrange",
+                    ["board", "(5", "+inf", "byscore", "limit", "1", "2"]
+                )
+            ),
+            "*2\r\n$1\r\nb\r\n$1\r\na\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zrange", ["board", "7", "-inf", "byscore", "rev"])
+            ),
+            "*4\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\ne\r\n$1\r\nd\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!(
+                    "zrange",
+                    ["board", "+inf", "(7", "byscore", "rev", "limit", "0", "1"]
+                )
+            ),
+            "*1\r\n$1\r\na\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("zrank", ["board", "c"])),
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zrevrank", ["board", "c", "withscore"])
+            ),
+            "*2\r\n:2\r\n$1\r\n6\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zrank", ["board", "missing", "withscore"])
+            ),
+            "*-1\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("zscore", ["board", "e"])),
+            "$1\r\n5\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zmscore", ["board", "d", "missing"])
+            ),
+            "*2\r\n$1\r\n4\r\n$-1\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zcount", ["board", "(4", "7"])
+            ),
+            ":3\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zremrangebyscore", ["board", "-inf", "(5"])
+            ),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zremrangebyrank", ["board", "-1", "-1"])
+            ),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zrem", ["board", "e", "missing"])
+            ),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("zcard", ["board"])),
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("type", ["board"])),
+            "+zset\r\n"
+        );
+
+        run(
+            &context,
+            client,
+            raw_request!("zadd", ["lex", "0", "a", "0", "b", "0", "c", "0", "d"]),
+        );
+        run(&context, client, raw_request!("rpush", ["list", "a"]));
+
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zrange", ["lex", "[b", "(d", "bylex"])
+            ),
+            "*2\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zrange", ["lex", "+", "[c", "bylex", "rev"])
+            ),
+            "*2\r\n$1\r\nd\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zlexcount", ["lex", "-", "+"])
+            ),
+            ":4\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zremrangebylex", ["lex", "-", "(b"])
+            ),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("zadd", ["list", "1", "a"])),
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("zrandmember", ["lex", "-5"]))
+                .matches('$')
+                .count(),
+            5
+        );
+        assert_eq!(
+            run(&context, client, raw_request!("zrandmember", ["missing"])),
+            "$-1\r\n"
+        );
+    }
+
+    #[test]
+    fn sorted_set_scores_are_doubles_under_resp3() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let mut client = Client::new();
+        let client = &mut client;
+
+        client.protocol = ProtocolVersion::RESP3;
+
+        run(
+            &context,
+            client,
+            raw_request!("zadd", ["board", "6", "c", "-inf", "z"]),
+        );
+
+        assert_eq!(
+            run(&context, client, raw_request!("zscore", ["board", "c"])),
+            ",6\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zrange", ["board", "0", "-1", "withscores"])
+            ),
+            "*2\r\n*2\r\n$1\r\nz\r\n,-inf\r\n*2\r\n$1\r\nc\r\n,6\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zrank", ["board", "c", "withscore"])
+            ),
+            "*2\r\n:1\r\n,6\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zrandmember", ["board", "1", "withscores"])
+            )
+            .matches("*2\r\n")
+            .count(),
+            1
+        );
+        assert_eq!(
+            run(
+                &context,
+                client,
+                raw_request!("zscore", ["board", "missing"])
+            ),
+            "_\r\n"
+        );
     }
 }
@@ -0,0 +1,761 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{RwLock, RwLockReadGuard},
+};
+
+use crate::redis::{error::ConfigError, glob::glob_match};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigValues {
+    pub bind: Vec<String>,
+    pub port: u16,
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    pub proto_max_bulk_len: usize,
+    /// `(seconds, changes)` pairs: snapshot once `changes` writes happened
+    /// and at least `seconds` passed since the last snapshot.
+    pub save: Vec<(u64, u64)>,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: AppendFsync,
+    /// Host and port of the master this instance replicates from.
+    pub replicaof: Option<(String, u16)>,
+    pub replica_read_only: bool,
+    pub repl_backlog_size: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No,
+}
+
+impl Default for ConfigValues {
+    fn default() -> Self {
+        Self {
+            bind: vec!["127.0.0.1".to_string()],
+            port: 6379,
+            dir: PathBuf::from("."),
+            dbfilename: "dump.rdb".to_string(),
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: AppendFsync::EverySec,
+            replicaof: None,
+            replica_read_only: true,
+            repl_backlog_size: 1024 * 1024,
+        }
+    }
+}
+
+struct ConfigParameter {
+    name: &'static str,
+    mutable: bool,
+    get: fn(&ConfigValues) -> String,
+    set: fn(&mut ConfigValues, &str) -> Result<(), String>,
+}
+
+const PARAMETERS: &[ConfigParameter] = &[
+    ConfigParameter {
+        name: "bind",
+        mutable: false,
+        get: |values| values.bind.join(" "),
+        set: |values, value| {
+            let bind = value
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect::<Vec<_>>();
+
+            if bind.is_empty() {
+                return Err("at least one address is required".to_string());
+            }
+
+            values.bind = bind;
+
+            Ok(())
+        },
+    },
+    ConfigParameter {
+        name: "port",
+        mutable: false,
+        get: |values| values.port.to_string(),
+        set: |values, value| {
+            values.port = value
+                .parse::<u16>()
+                .map_err(|_| "argument must be between 0 and 65535".to_string())?;
+
+            Ok(())
+        },
+    },
+    ConfigParameter {
+        name: "dir",
+        mutable: true,
+        get: |values| values.dir.display().to_string(),
+        set: |values, value| {
+            if !Path::new(value).is_dir() {
+                return Err("No such file or directory".to_string());
+            }
+
+            values.dir = PathBuf::from(value);
+
+            Ok(())
+        },
+    },
+    ConfigParameter {
+        name: "dbfilename",
+        mutable: true,
+        get: |values| values.dbfilename.to_owned(),
+        set: |values, value| {
+            if value.is_empty() || value.contains('/') {
+                return Err("dbfilename can't be a path, just a filename".to_string());
+            }
+
+            values.dbfilename = value.to_owned();
+
+            Ok(())
+        },
+    },
+    ConfigParameter {
+        name: "proto-max-bulk-len",
+        mutable: true,
+        get: |values| values.proto_max_bulk_len.to_string(),
+        set: |values, value| {
+            let length = parse_memory(value)?;
+
+            if length < 1024 * 1024 {
+                return Err("argument must be a memory value of at least 1mb".to_string());
+            }
+
+            values.proto_max_bulk_len = length;
+
+            Ok(())
+        },
+    },
+    ConfigParameter {
+        name: "save",
+        mutable: true,
+        get: |values| {
+            values
+                .save
+                .iter()
+                .map(|(seconds, changes)| format!("{seconds} {changes}"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        },
+        set: |values, value| {
+            let numbers = value
+                .split_whitespace()
+                .map(str::parse::<u64>)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| "Invalid save parameters".to_string())?;
+
+            if numbers.len() % 2 != 0 {
+                return Err("Invalid save parameters".to_string());
+            }
+
+            values.save = numbers.chunks(2).map(|rule| (rule[0], rule[1])).collect();
+
+            Ok(())
+        },
+    },
+    ConfigParameter {
+        name: "appendonly",
+        mutable: true,
+        get: |values| format_bool(values.appendonly),
+        set: |values, value| {
+            values.appendonly = parse_bool(value)?;
+
+            Ok(())
+        },
+    },
+    ConfigParameter {
+        name: "appendfilename",
+        mutable: false,
+        get: |values| values.appendfilename.to_owned(),
+        set: |values, value| {
+            if value.is_empty() || value.contains('/') {
+                return Err("appendfilename can't be a path, just a filename".to_string());
+            }
+
+            values.appendfilename = value.to_owned();
+
+            Ok(())
+        },
+    },
+    ConfigParameter {
+        name: "appendfsync",
+        mutable: true,
+        get: |values| {
+            match values.appendfsync {
+                AppendFsync::Always => "always",
+                AppendFsync::EverySec => "everysec",
+                AppendFsync::No => "no",
+            }
+            .to_string()
+        },
+        set: |values, value| {
+            values.appendfsync = match value.to_lowercase().as_str() {
+                "always" => AppendFsync::Always,
+                "everysec" => AppendFsync::EverySec,
+                "no" => AppendFsync::No,
+                _ => {
+                    return Err(
+                        "argument(s) must be one of the following: always, everysec, no"
+                            .to_string(),
+                    )
+                }
+            };
+
+            Ok(())
+        },
+    },
+    ConfigParameter {
+        name: "replicaof",
+        mutable: false,
+        get: |values| {
+            values
+                .replicaof
+                .as_ref()
+                .map_or_else(String::new, |(host, port)| format!("{host} {port}"))
+        },
+        set: |values, value| {
+            let arguments = value.split_whitespace().collect::<Vec<_>>();
+
+            values.replicaof = match arguments[..] {
+                [] => None,
+                [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => {
+                    None
+                }
+                [host, port] => Some((
+                    host.to_owned(),
+                    port.parse::<u16>()
+                        .map_err(|_| "Invalid master port".to_string())?,
+                )),
+                _ => return Err("argument must be 'host port' or 'no one'".to_string()),
+            };
+
+            Ok(())
+        },
+    },
+    ConfigParameter {
+        name: "replica-read-only",
+        mutable: true,
+        get: |values| format_bool(values.replica_read_only),
+        set: |values, value| {
+            values.replica_read_only = parse_bool(value)?;
+
+            Ok(())
+        },
+    },
+    ConfigParameter {
+        name: "repl-backlog-size",
+        mutable: true,
+        get: |values| values.repl_backlog_size.to_string(),
+        set: |values, value| {
+            values.repl_backlog_size = parse_memory(value)?;
+
+            Ok(())
+        },
+    },
+];
+
+/// Typed registry of server parameters, seeded from an optional redis.conf
+/// file and command-line flags, and adjustable at runtime via CONFIG SET.
+#[derive(Default)]
+pub struct Config {
+    path: Option<PathBuf>,
+    values: RwLock<ConfigValues>,
+}
+
+impl Config {
+    /// Parses `redis-server [/path/to/redis.conf] [--name value ...]`.
+    /// Flags override directives read from the file.
+    pub fn from_args<I>(args: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter().peekable();
+        let mut config = Config::default();
+
+        if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+            let contents = fs::read_to_string(&path)?;
+
+            config.load(&contents)?;
+            config.path = Some(fs::canonicalize(path)?);
+        }
+
+        while let Some(flag) = args.next() {
+            let name = if let Some(name) = flag.strip_prefix("--") {
+                name.to_owned()
+            } else {
+                return Err(ConfigError::BadDirective(flag));
+            };
+            let mut value = Vec::new();
+
+            while let Some(arg) = args.next_if(|arg| !arg.starts_with("--")) {
+                value.push(arg);
+            }
+
+            config.set_directive(&name, &value.join(" "))?;
+        }
+
+        Ok(config)
+    }
+
+    /// The config file the server was started with, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn values(&self) -> RwLockReadGuard<'_, ConfigValues> {
+        self.values.read().unwrap()
+    }
+
+    /// Changes values on behalf of the server itself, such as REPLICAOF,
+    /// bypassing the mutability checks CONFIG SET applies.
+    pub fn update<F>(&self, update: F)
+    where
+        F: FnOnce(&mut ConfigValues),
+    {
+        update(&mut self.values.write().unwrap());
+    }
+
+    /// Returns every parameter whose name matches one of the glob `patterns`.
+    pub fn get(&self, patterns: &[String]) -> Vec<(&'static str, String)> {
+        let values = self.values();
+
+        PARAMETERS
+            .iter()
+            .filter(|parameter| {
+                patterns
+                    .iter()
+                    .any(|pattern| glob_match(pattern.as_bytes(), parameter.name.as_bytes(), true))
+            })
+            .map(|parameter| (parameter.name, (parameter.get)(&values)))
+            .collect()
+    }
+
+    /// Applies every `(name, value)` pair or none of them.
+    pub fn set(&self, parameters: &[(String, String)]) -> Result<(), ConfigError> {
+        let mut values = self.values.write().unwrap();
+        let mut updated = values.clone();
+
+        for (name, value) in parameters {
+            let parameter = Self::parameter(name)
+                .ok_or_else(|| ConfigError::UnknownParameter(name.to_owned()))?;
+
+            if !parameter.mutable {
+                return Err(ConfigError::Immutable(parameter.name.to_string()));
+            }
+
+            (parameter.set)(&mut updated, value).map_err(|reason| {
+                ConfigError::InvalidArgument {
+                    name: parameter.name.to_string(),
+                    reason,
+                }
+            })?;
+        }
+
+        *values = updated;
+
+        Ok(())
+    }
+
+    /// Writes the current values back to the file the server was started
+    /// with. Comments and unrelated lines are kept, known directives are
+    /// updated in place and the rest are appended when they differ from
+    /// their defaults.
+    pub fn rewrite(&self) -> Result<(), ConfigError> {
+        let path = self.path.as_ref().ok_or(ConfigError::NoConfigFile)?;
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        let values = self.values();
+        let defaults = ConfigValues::default();
+        let mut written = Vec::new();
+        let mut lines = Vec::new();
+
+        for line in contents.lines() {
+            let name = tokenize(line)
+                .ok()
+                .and_then(|tokens| tokens.first().map(|name| name.to_lowercase()));
+
+            match name.as_deref().and_then(Self::parameter) {
+                Some(parameter) if written.contains(&parameter.name) => {}
+                Some(parameter) => {
+                    lines.push(format_directive(parameter, &values));
+                    written.push(parameter.name);
+                }
+                None => lines.push(line.to_owned()),
+            }
+        }
+
+        for parameter in PARAMETERS {
+            if !written.contains(&parameter.name)
+                && (parameter.get)(&values) != (parameter.get)(&defaults)
+            {
+                lines.push(format_directive(parameter, &values));
+            }
+        }
+
+        let mut rewritten = lines.join("\n");
+
+        rewritten.push('\n');
+
+        let temporary_path = path.with_extension("rewrite.tmp");
+
+        fs::write(&temporary_path, rewritten)?;
+        fs::rename(&temporary_path, path)?;
+
+        Ok(())
+    }
+
+    fn load(&self, contents: &str) -> Result<(), ConfigError> {
+        let mut save_rules = None;
+
+        for (index, line) in contents.lines().enumerate() {
+            let tokens = tokenize(line).map_err(|reason| ConfigError::File {
+                line: index + 1,
+                reason,
+            })?;
+
+            if let Some((name, value)) = tokens.split_first() {
+                let mut value = value.join(" ");
+
+                // As in Redis, every `save` line in a file adds a rule
+                // instead of replacing the previous ones; `save ""` clears.
+                if name.eq_ignore_ascii_case("save") {
+                    let rules = save_rules.get_or_insert_with(String::new);
+
+                    if value.is_empty() {
+                        rules.clear();
+                    } else {
+                        rules.push(' ');
+                        rules.push_str(&value);
+                    }
+
+                    value = rules.to_owned();
+                }
+
+                self.set_directive(name, &value)
+                    .map_err(|error| ConfigError::File {
+                        line: index + 1,
+                        reason: error.to_string(),
+                    })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets a parameter at startup, where immutable parameters are allowed.
+    fn set_directive(&self, name: &str, value: &str) -> Result<(), ConfigError> {
+        let parameter =
+            Self::parameter(name).ok_or_else(|| ConfigError::BadDirective(name.to_owned()))?;
+
+        (parameter.set)(&mut self.values.write().unwrap(), value).map_err(|reason| {
+            ConfigError::InvalidArgument {
+                name: parameter.name.to_string(),
+                reason,
+            }
+        })
+    }
+
+    fn parameter(name: &str) -> Option<&'static ConfigParameter> {
+        PARAMETERS
+            .iter()
+            .find(|parameter| parameter.name.eq_ignore_ascii_case(name))
+    }
+}
+
+fn format_directive(parameter: &ConfigParameter, values: &ConfigValues) -> String {
+    let value = (parameter.get)(values);
+
+    if value.is_empty()
+        || (value.contains(' ') && !["bind", "save", "replicaof"].contains(&parameter.name))
+    {
+        format!("{} \"{}\"", parameter.name, value.replace('"', "\\\""))
+    } else {
+        format!("{} {}", parameter.name, value)
+    }
+}
+
+/// Splits a redis.conf line into arguments, honouring double and single
+/// quotes and skipping comments.
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let line = line.trim();
+
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(Vec::new());
+    }
+
+    let mut tokens = Vec::new();
+    let mut characters = line.chars().peekable();
+
+    while let Some(character) = characters.next() {
+        if character.is_whitespace() {
+            continue;
+        }
+
+        let mut token = String::new();
+
+        if character == '"' || character == '\'' {
+            loop {
+                match characters.next() {
+                    Some('\\') if character == '"' => match characters.next() {
+                        Some('n') => token.push('\n'),
+                        Some('t') => token.push('\t'),
+                        Some(escaped) => token.push(escaped),
+                        None => return Err("unbalanced quotes in configuration line".to_string()),
+                    },
+                    Some(closing) if closing == character => break,
+                    Some(quoted) => token.push(quoted),
+                    None => return Err("unbalanced quotes in configuration line".to_string()),
+                }
+            }
+        } else {
+            token.push(character);
+
+            while let Some(next) = characters.next_if(|next| !next.is_whitespace()) {
+                token.push(next);
+            }
+        }
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn format_bool(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+/// Parses sizes such as `1048576`, `512mb` or `1gb`.
+fn parse_memory(value: &str) -> Result<usize, String> {
+    let value = value.to_lowercase();
+    let digits_end = value
+        .find(|character: char| !character.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (digits, unit) = value.split_at(digits_end);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err("argument must be a memory value".to_string()),
+    };
+
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|digits| digits.checked_mul(multiplier))
+        .ok_or_else(|| "argument must be a memory value".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::{parse_memory, tokenize, AppendFsync, Config};
+    use crate::redis::error::ConfigError;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn command_line_flags() {
+        let config = Config::from_args(args(&[
+            "--port",
+            "7000",
+            "--bind",
+            "127.0.0.1",
+            "::1",
+            "--dbfilename",
+            "snapshot.rdb",
+        ]))
+        .unwrap();
+        let values = config.values();
+
+        assert_eq!(values.port, 7000);
+        assert_eq!(values.bind, vec!["127.0.0.1", "::1"]);
+        assert_eq!(values.dbfilename, "snapshot.rdb");
+    }
+
+    #[test]
+    fn save_rules() {
+        let path = env::temp_dir().join(format!("redis-save-{}.conf", process::id()));
+
+        fs::write(&path, "save 900 1\nsave 300 10\n").unwrap();
+
+        let config = Config::from_args(args(&[path.to_str().unwrap()])).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.values().save, vec![(900, 1), (300, 10)]);
+
+        config.set(&[("save".to_string(), "".to_string())]).unwrap();
+
+        assert!(config.values().save.is_empty());
+        assert!(config
+            .set(&[("save".to_string(), "60".to_string())])
+            .is_err());
+        assert!(Config::from_args(args(&["--save", ""]))
+            .unwrap()
+            .values()
+            .save
+            .is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_flags() {
+        assert!(matches!(
+            Config::from_args(args(&["--no-such-option", "1"])),
+            Err(ConfigError::BadDirective(_))
+        ));
+        assert!(Config::from_args(args(&["--port", "seventy"])).is_err());
+    }
+
+    #[test]
+    fn get_with_patterns() {
+        let config = Config::default();
+
+        assert_eq!(
+            config.get(&["db*".to_string(), "PORT".to_string()]),
+            vec![
+                ("port", "6379".to_string()),
+                ("dbfilename", "dump.rdb".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn set_is_atomic_and_respects_mutability() {
+        let config = Config::default();
+
+        assert!(matches!(
+            config.set(&[("port".to_string(), "7000".to_string())]),
+            Err(ConfigError::Immutable(_))
+        ));
+        assert!(config
+            .set(&[
+                ("dbfilename".to_string(), "other.rdb".to_string()),
+                ("proto-max-bulk-len".to_string(), "1kb".to_string()),
+            ])
+            .is_err());
+        assert_eq!(config.values().dbfilename, "dump.rdb");
+
+        config
+            .set(&[("proto-max-bulk-len".to_string(), "2mb".to_string())])
+            .unwrap();
+
+        assert_eq!(config.values().proto_max_bulk_len, 2 * 1024 * 1024);
+    }
+
+    #[test]
+    fn rewrite_preserves_comments() {
+        let path = env::temp_dir().join(format!("redis-config-{}.conf", process::id()));
+
+        fs::write(
+            &path,
+            "# main instance\nport 7000\ndbfilename \"old.rdb\"\n",
+        )
+        .unwrap();
+
+        let config = Config::from_args(args(&[path.to_str().unwrap()])).unwrap();
+
+        config
+            .set(&[
+                ("dbfilename".to_string(), "new.rdb".to_string()),
+                ("dir".to_string(), env::temp_dir().display().to_string()),
+            ])
+            .unwrap();
+        config.rewrite().unwrap();
+
+        let rewritten = fs::read_to_string(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            rewritten,
+            format!(
+                "# main instance\nport 7000\ndbfilename new.rdb\ndir {}\n",
+                env::temp_dir().display()
+            )
+        );
+    }
+
+    #[test]
+    fn tokenizer() {
+        assert_eq!(
+            tokenize("save \"\" 'a b'  c").unwrap(),
+            vec!["save", "", "a b", "c"]
+        );
+        assert!(tokenize("# comment").unwrap().is_empty());
+        assert!(tokenize("dir \"unterminated").is_err());
+    }
+
+    #[test]
+    fn memory_units() {
+        assert_eq!(parse_memory("512mb"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_memory("1k"), Ok(1000));
+        assert!(parse_memory("lots").is_err());
+    }
+
+    #[test]
+    fn append_only_parameters() {
+        let config =
+            Config::from_args(args(&["--appendonly", "yes", "--appendfsync", "always"])).unwrap();
+
+        assert!(config.values().appendonly);
+        assert_eq!(config.values().appendfsync, AppendFsync::Always);
+        assert!(config
+            .set(&[("appendfsync".to_string(), "sometimes".to_string())])
+            .is_err());
+        assert!(config
+            .set(&[("appendonly".to_string(), "maybe".to_string())])
+            .is_err());
+    }
+
+    #[test]
+    fn replication_parameters() {
+        let config = Config::from_args(args(&["--replicaof", "127.0.0.1", "6380"])).unwrap();
+
+        assert_eq!(
+            config.values().replicaof,
+            Some(("127.0.0.1".to_string(), 6380))
+        );
+        assert!(config.values().replica_read_only);
+        assert!(matches!(
+            config.set(&[("replicaof".to_string(), "no one".to_string())]),
+            Err(ConfigError::Immutable(_))
+        ));
+        assert!(Config::from_args(args(&["--replicaof", "127.0.0.1", "port"])).is_err());
+
+        config.update(|values| values.replicaof = None);
+        config
+            .set(&[("replica-read-only".to_string(), "no".to_string())])
+            .unwrap();
+
+        config
+            .set(&[("repl-backlog-size".to_string(), "2mb".to_string())])
+            .unwrap();
+
+        assert_eq!(config.values().replicaof, None);
+        assert!(!config.values().replica_read_only);
+        assert_eq!(config.values().repl_backlog_size, 2 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,130 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::{executor::ExecutorError, redis::resp::RESPDataTypes};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ProtocolError {
+    #[error("unknown type marker '{0}'")]
+    UnknownMarker(String),
+    #[error("expected '{expected}', got '{found}'")]
+    UnexpectedMarker { expected: String, found: String },
+    #[error("invalid bulk length")]
+    InvalidBulkLength,
+    #[error("invalid multibulk length")]
+    InvalidMultibulkLength,
+    #[error("invalid {0}")]
+    InvalidValue(&'static str),
+    #[error("bulk payload not terminated by CRLF")]
+    MissingCrlf,
+    #[error("too big header line")]
+    HeaderTooLong,
+    #[error("too deeply nested aggregate")]
+    TooDeeplyNested,
+    #[error("unexpected end of stream")]
+    UnexpectedEof,
+}
+
+impl From<ProtocolError> for RESPDataTypes {
+    fn from(error: ProtocolError) -> Self {
+        RESPDataTypes::SimpleError(format!("ERR Protocol error: {error}"))
+    }
+}
+
+/// A command met a key holding another type of value.
+#[derive(Debug, Error, PartialEq)]
+#[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+pub struct WrongType;
+
+impl From<WrongType> for RESPDataTypes {
+    fn from(error: WrongType) -> Self {
+        RESPDataTypes::SimpleError(error.to_string())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Bad directive or wrong number of arguments: '{0}'")]
+    BadDirective(String),
+    #[error("Unknown option or number of arguments for CONFIG SET - '{0}'")]
+    UnknownParameter(String),
+    #[error("CONFIG SET failed (possibly related to argument '{0}') - can't set immutable config")]
+    Immutable(String),
+    #[error("CONFIG SET failed (possibly related to argument '{name}') - {reason}")]
+    InvalidArgument { name: String, reason: String },
+    #[error("configuration file error at line {line}: {reason}")]
+    File { line: usize, reason: String },
+    #[error("The server is running without a config file")]
+    NoConfigFile,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum RdbError {
+    #[error("wrong signature trying to load DB from file")]
+    InvalidHeader,
+    #[error("can't handle RDB format version {0}")]
+    UnsupportedVersion(u32),
+    #[error("unsupported RDB opcode {0:#04x}")]
+    UnsupportedOpcode(u8),
+    #[error("unsupported RDB value type {0}")]
+    UnsupportedType(u8),
+    #[error("unknown RDB string encoding type {0}")]
+    UnsupportedEncoding(u8),
+    #[error("invalid length in RDB file")]
+    InvalidLength,
+    #[error("invalid LZF compressed string")]
+    InvalidLzf,
+    #[error("Listpack integrity check failed.")]
+    InvalidListpack,
+    #[error("Intset integrity check failed.")]
+    InvalidIntset,
+    #[error("Zset with NAN score detected")]
+    InvalidScore,
+    #[error("wrong RDB checksum expected: ({expected:#018x}) got: ({computed:#018x})")]
+    ChecksumMismatch { expected: u64, computed: u64 },
+    #[error("short read or OOM loading DB, unrecoverable error")]
+    UnexpectedEof,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum SaveError {
+    #[error("Background save already in progress")]
+    InProgress,
+    #[error(transparent)]
+    Rdb(#[from] RdbError),
+    #[error("Background saving failed to start: {0}")]
+    Executor(#[from] ExecutorError),
+}
+
+#[derive(Debug, Error)]
+pub enum ReplicationError {
+    #[error("Connection closed by the master")]
+    Closed,
+    #[error("Unexpected reply from the master: {0}")]
+    UnexpectedReply(String),
+    #[error("Protocol error from the master: {0}")]
+    Protocol(#[from] ProtocolError),
+    #[error("Failed to load the RDB payload from the master: {0}")]
+    Rdb(#[from] RdbError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum AofError {
+    #[error("Bad file format reading the append only file: {0}")]
+    Protocol(#[from] ProtocolError),
+    #[error("Unknown command in the append only file: {0}")]
+    InvalidCommand(String),
+    #[error("Background append only file rewriting already in progress")]
+    RewriteInProgress,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Background append only file rewriting failed to start: {0}")]
+    Executor(#[from] ExecutorError),
+}
@@ -0,0 +1,140 @@
+/// Redis-style glob matching: `*`, `?`, `[...]` classes with ranges and `^`
+/// negation, and `\` escapes.
+pub fn glob_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let fold = |byte: u8| {
+        if nocase {
+            byte.to_ascii_lowercase()
+        } else {
+            byte
+        }
+    };
+    let (mut pattern_index, mut string_index) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while string_index < string.len() {
+        if pattern_index < pattern.len() {
+            match pattern[pattern_index] {
+                b'*' => {
+                    backtrack = Some((pattern_index, string_index));
+                    pattern_index += 1;
+
+                    continue;
+                }
+                b'?' => {
+                    pattern_index += 1;
+                    string_index += 1;
+
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, class_end)) =
+                        match_class(pattern, pattern_index, fold(string[string_index]), &fold)
+                    {
+                        if matched {
+                            pattern_index = class_end;
+                            string_index += 1;
+
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if pattern_index + 1 < pattern.len() => {
+                    if fold(pattern[pattern_index + 1]) == fold(string[string_index]) {
+                        pattern_index += 2;
+                        string_index += 1;
+
+                        continue;
+                    }
+                }
+                literal => {
+                    if fold(literal) == fold(string[string_index]) {
+                        pattern_index += 1;
+                        string_index += 1;
+
+                        continue;
+                    }
+                }
+            }
+        }
+
+        if let Some((star_index, star_string_index)) = backtrack {
+            pattern_index = star_index + 1;
+            string_index = star_string_index + 1;
+            backtrack = Some((star_index, string_index));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[pattern_index..].iter().all(|byte| *byte == b'*')
+}
+
+/// Matches `byte` against the class opening at `start`, returning whether it
+/// matched and the index just past the closing bracket.
+fn match_class<F>(pattern: &[u8], start: usize, byte: u8, fold: &F) -> Option<(bool, usize)>
+where
+    F: Fn(u8) -> u8,
+{
+    let mut index = start + 1;
+    let negated = pattern.get(index) == Some(&b'^');
+    let mut matched = false;
+
+    if negated {
+        index += 1;
+    }
+
+    while index < pattern.len() && pattern[index] != b']' {
+        if pattern[index] == b'\\' && index + 1 < pattern.len() {
+            matched |= fold(pattern[index + 1]) == byte;
+            index += 2;
+        } else if index + 2 < pattern.len()
+            && pattern[index + 1] == b'-'
+            && pattern[index + 2] != b']'
+        {
+            let (low, high) = (fold(pattern[index]), fold(pattern[index + 2]));
+            let (low, high) = (low.min(high), low.max(high));
+
+            matched |= (low..=high).contains(&byte);
+            index += 3;
+        } else {
+            matched |= fold(pattern[index]) == byte;
+            index += 1;
+        }
+    }
+
+    if index >= pattern.len() {
+        return None;
+    }
+
+    Some((matched != negated, index + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn wildcards() {
+        assert!(glob_match(b"*", b"anything", false));
+        assert!(glob_match(b"h?llo", b"hello", false));
+        assert!(glob_match(b"h*llo", b"heeeello", false));
+        assert!(glob_match(b"*max-*-len", b"proto-max-bulk-len", false));
+        assert!(!glob_match(b"h?llo", b"hllo", false));
+        assert!(!glob_match(b"port", b"ports", false));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(glob_match(b"h[ae]llo", b"hallo", false));
+        assert!(!glob_match(b"h[^e]llo", b"hello", false));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo", false));
+        assert!(glob_match(b"h\\*llo", b"h*llo", false));
+        assert!(!glob_match(b"h\\*llo", b"hello", false));
+    }
+
+    #[test]
+    fn case_folding() {
+        assert!(glob_match(b"DB*", b"dbfilename", true));
+        assert!(!glob_match(b"DB*", b"dbfilename", false));
+    }
+}
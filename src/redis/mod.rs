@@ -1,5 +1,20 @@
+pub use config::Config;
 pub use server::Redis;
 
+mod aof;
+mod blocking;
+mod client;
 mod commands;
+mod config;
+mod crc64;
+mod error;
+mod glob;
+mod persistence;
+mod rdb;
+mod replica;
+mod replication;
 mod resp;
 mod server;
+mod sorted_set;
+mod stats;
+mod store;
@@ -0,0 +1,417 @@
+use std::{
+    fmt::Write,
+    fs,
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc, RwLockReadGuard, RwLockWriteGuard,
+    },
+};
+
+use bytes::Bytes;
+
+use crate::redis::{
+    aof,
+    config::AppendFsync,
+    error::{AofError, SaveError},
+    rdb,
+    server::ServerContext,
+    store::{now_millis, SnapshotEntry},
+};
+
+/// After a failed background save, automatic snapshots wait this many
+/// seconds before trying again.
+const BGSAVE_RETRY_DELAY: i64 = 5;
+
+/// A write's hold on `write_lock`. Writes share it while nothing records
+/// them, and take it exclusively while the AOF or the replication backlog
+/// does, so they are recorded in the order they reached the keyspace. The
+/// guards are only held, never read.
+#[allow(dead_code)]
+pub enum WriteGuard<'a> {
+    Shared(RwLockReadGuard<'a, ()>),
+    Exclusive(RwLockWriteGuard<'a, ()>),
+}
+
+fn now_seconds() -> i64 {
+    now_millis() / 1000
+}
+
+/// Bookkeeping for RDB snapshots: only one snapshot is written at a time,
+/// and the `save` rules compare the store's write counter against the
+/// value it had when the last successful snapshot started.
+pub struct SnapshotState {
+    saving: AtomicBool,
+    last_save: AtomicI64,
+    last_attempt: AtomicI64,
+    last_save_ok: AtomicBool,
+    dirty_at_last_save: AtomicU64,
+}
+
+impl Default for SnapshotState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SnapshotState {
+    pub fn new() -> Self {
+        Self {
+            saving: AtomicBool::new(false),
+            last_save: AtomicI64::new(now_seconds()),
+            last_attempt: AtomicI64::new(0),
+            last_save_ok: AtomicBool::new(true),
+            dirty_at_last_save: AtomicU64::new(0),
+        }
+    }
+
+    /// Unix time in seconds of the last successful snapshot, or of the
+    /// server start when none was written yet.
+    pub fn last_save(&self) -> i64 {
+        self.last_save.load(Ordering::SeqCst)
+    }
+
+    fn begin(&self) -> Result<(), SaveError> {
+        self.saving
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .map(|_| self.last_attempt.store(now_seconds(), Ordering::SeqCst))
+            .map_err(|_| SaveError::InProgress)
+    }
+
+    fn finish(&self, dirty_at_start: u64, saved: bool) {
+        if saved {
+            self.last_save.store(now_seconds(), Ordering::SeqCst);
+            self.dirty_at_last_save
+                .store(dirty_at_start, Ordering::SeqCst);
+        }
+
+        self.last_save_ok.store(saved, Ordering::SeqCst);
+        self.saving.store(false, Ordering::SeqCst);
+    }
+
+    fn is_due(&self, rules: &[(u64, u64)], dirty: u64) -> bool {
+        let now = now_seconds();
+
+        if self.saving.load(Ordering::SeqCst)
+            || (!self.last_save_ok.load(Ordering::SeqCst)
+                && now - self.last_attempt.load(Ordering::SeqCst) < BGSAVE_RETRY_DELAY)
+        {
+            return false;
+        }
+
+        let changes = dirty.saturating_sub(self.dirty_at_last_save.load(Ordering::SeqCst));
+        let elapsed = now - self.last_save();
+
+        rules
+            .iter()
+            .any(|(seconds, min_changes)| changes >= *min_changes && elapsed > *seconds as i64)
+    }
+}
+
+impl ServerContext {
+    /// Writes a snapshot on the calling thread.
+    pub fn save(&self) -> Result<(), SaveError> {
+        self.snapshots.begin()?;
+        self.write_snapshot()
+    }
+
+    /// Hands the snapshot to the background pool so clients keep being
+    /// served while it is copied and written.
+    pub fn background_save(self: &Arc<Self>) -> Result<(), SaveError> {
+        self.snapshots.begin()?;
+
+        let context = Arc::clone(self);
+
+        self.executor
+            .try_submit(move || {
+                let _ = context.write_snapshot();
+            })
+            .map_err(|error| {
+                self.snapshots.finish(0, false);
+
+                SaveError::from(error)
+            })
+    }
+
+    /// Starts a background save when one of the `save` rules is met.
+    pub fn run_save_rules(self: &Arc<Self>) {
+        let due = self
+            .snapshots
+            .is_due(&self.config.values().save, self.store.dirty());
+
+        if due {
+            let _ = self.background_save();
+        }
+    }
+
+    /// Copies the keyspace while writes are paused, the same cut an AOF
+    /// rewrite takes, so writes spanning keys are saved whole, and writes
+    /// it out.
+    fn write_snapshot(&self) -> Result<(), SaveError> {
+        let (dirty, entries) = {
+            let _write = self.write_lock.write().unwrap();
+
+            (self.store.dirty(), self.store.snapshot())
+        };
+        let saved = rdb::save(&self.snapshot_path(), &entries);
+
+        self.snapshots.finish(dirty, saved.is_ok());
+
+        Ok(saved?)
+    }
+
+    /// The `# Persistence` section of INFO.
+    pub fn persistence_info(&self) -> String {
+        let snapshots = &self.snapshots;
+        let status = |ok: bool| if ok { "ok" } else { "err" };
+        let mut info = String::from("# Persistence\r\nloading:0\r\n");
+
+        writeln!(
+            info,
+            "rdb_changes_since_last_save:{}\r",
+            self.store
+                .dirty()
+                .saturating_sub(snapshots.dirty_at_last_save.load(Ordering::SeqCst))
+        )
+        .unwrap();
+        writeln!(
+            info,
+            "rdb_bgsave_in_progress:{}\r",
+            snapshots.saving.load(Ordering::SeqCst) as u8
+        )
+        .unwrap();
+        writeln!(info, "rdb_last_save_time:{}\r", snapshots.last_save()).unwrap();
+        writeln!(
+            info,
+            "rdb_last_bgsave_status:{}\r",
+            status(snapshots.last_save_ok.load(Ordering::SeqCst))
+        )
+        .unwrap();
+        writeln!(
+            info,
+            "aof_enabled:{}\r",
+            self.config.values().appendonly as u8
+        )
+        .unwrap();
+        writeln!(
+            info,
+            "aof_rewrite_in_progress:{}\r",
+            self.aof.is_rewriting() as u8
+        )
+        .unwrap();
+
+        info
+    }
+
+    pub fn snapshot_path(&self) -> PathBuf {
+        let values = self.config.values();
+
+        values.dir.join(&values.dbfilename)
+    }
+
+    pub fn aof_path(&self) -> PathBuf {
+        let values = self.config.values();
+
+        values.dir.join(&values.appendfilename)
+    }
+
+    /// Locks out operations that need a consistent cut for the duration of
+    /// a write, and other writes as well when the write is propagated.
+    pub fn lock_write(&self) -> WriteGuard<'_> {
+        let shared = self.write_lock.read().unwrap();
+
+        // Whether writes are recorded only changes under the exclusive lock,
+        // so the answer holds for as long as the shared one is kept.
+        if !self.is_propagating() {
+            return WriteGuard::Shared(shared);
+        }
+
+        drop(shared);
+
+        WriteGuard::Exclusive(self.write_lock.write().unwrap())
+    }
+
+    fn is_propagating(&self) -> bool {
+        self.aof.is_open() || self.aof.is_rewriting() || self.replication.has_backlog()
+    }
+
+    /// Records a write that was applied to the keyspace in the replication
+    /// stream and the AOF. Callers hold the write lock, exclusively whenever
+    /// either is recording, so both see writes in the order they were
+    /// applied. A replica relays its master's stream as received instead,
+    /// so only the AOF gets the write here. Keys that commands found expired
+    /// are recorded first.
+    pub fn propagate(&self, command: &[Bytes]) {
+        self.propagate_expired();
+        self.record(command);
+    }
+
+    /// Records the keys commands removed lazily, because their deadline
+    /// passed, as DELs. Callers hold the write lock. A replica drops them:
+    /// its master sends DELs of its own.
+    pub fn propagate_expired(&self) {
+        let expired = self.store.take_lazily_expired();
+
+        if self.replication.is_replica() {
+            return;
+        }
+
+        for key in expired {
+            self.record(&[Bytes::from_static(b"DEL"), key]);
+        }
+    }
+
+    fn record(&self, command: &[Bytes]) {
+        let fsync = match self.config.values().appendfsync {
+            // Synced by `sync_aof` once the write lock is released.
+            AppendFsync::Always => AppendFsync::EverySec,
+            fsync => fsync,
+        };
+
+        let command = aof::serialize_command(command);
+
+        if !self.replication.is_replica() {
+            self.replication.feed(&command);
+        }
+
+        let offset = self.replication.offset();
+
+        if let Err(error) = self.aof.append(&command, fsync, offset) {
+            eprintln!("Error writing to the AOF file: {error}");
+        }
+    }
+
+    /// Syncs what writes appended to the log under `appendfsync always`.
+    /// Called after the write lock is released and before the writer
+    /// replies, so other writes do not queue behind the disk.
+    pub fn sync_aof(&self) {
+        if self.config.values().appendfsync != AppendFsync::Always {
+            return;
+        }
+
+        if let Err(error) = self.aof.fsync() {
+            eprintln!("Error trying to fsync the AOF file: {error}");
+        }
+    }
+
+    /// Compacts the log in the background: the keyspace is copied while
+    /// writes are paused, written out as plain SET commands, and writes made
+    /// meanwhile are appended before the new file replaces the old one.
+    pub fn background_rewrite_aof(self: &Arc<Self>) -> Result<(), AofError> {
+        let entries = {
+            let _write = self.write_lock.write().unwrap();
+
+            self.aof.begin_rewrite()?;
+            self.store.snapshot()
+        };
+        let context = Arc::clone(self);
+
+        self.executor
+            .try_submit(move || {
+                if let Err(error) = context.rewrite_aof(&entries) {
+                    eprintln!("Background AOF rewrite failed: {error}");
+                }
+            })
+            .map_err(|error| {
+                self.aof.abort_rewrite();
+
+                AofError::from(error)
+            })
+    }
+
+    /// Rewrites the log on the calling thread, used at startup to create it
+    /// from the snapshot that was just loaded.
+    pub fn rewrite_aof_now(&self) -> Result<(), AofError> {
+        self.aof.begin_rewrite()?;
+        self.rewrite_aof(&self.store.snapshot())
+    }
+
+    /// Opens or closes the log after `appendonly` changed at runtime. Turning
+    /// it on starts a rewrite, which opens the new log once it is complete.
+    pub fn apply_append_only(self: &Arc<Self>) -> Result<(), AofError> {
+        let enabled = self.config.values().appendonly;
+
+        if enabled && !self.aof.is_open() && !self.aof.is_rewriting() {
+            self.background_rewrite_aof()
+        } else if !enabled && self.aof.is_open() {
+            Ok(self.aof.close()?)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn rewrite_aof(&self, entries: &[SnapshotEntry]) -> Result<(), AofError> {
+        let path = self.aof_path();
+        let rewritten = path.with_file_name(format!("temp-rewriteaof-bg-{}.aof", process::id()));
+        let written = aof::write_base(&rewritten, entries).and_then(|_| {
+            self.aof
+                .finish_rewrite(&rewritten, &path, self.config.values().appendonly)
+        });
+
+        if written.is_err() {
+            self.aof.abort_rewrite();
+            let _ = fs::remove_file(&rewritten);
+        } else {
+            self.replication.acknowledge();
+        }
+
+        Ok(written?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::{now_seconds, SnapshotState, WriteGuard};
+    use crate::redis::{config::Config, error::SaveError, server::ServerContext};
+
+    #[test]
+    fn one_snapshot_at_a_time() {
+        let snapshots = SnapshotState::new();
+
+        snapshots.begin().unwrap();
+
+        assert!(matches!(snapshots.begin(), Err(SaveError::InProgress)));
+
+        snapshots.finish(0, true);
+        snapshots.begin().unwrap();
+    }
+
+    #[test]
+    fn save_rules() {
+        let snapshots = SnapshotState::new();
+        let rules = [(60, 10)];
+
+        assert!(!snapshots.is_due(&rules, 100));
+
+        snapshots
+            .last_save
+            .store(now_seconds() - 61, Ordering::SeqCst);
+
+        assert!(!snapshots.is_due(&rules, 9));
+        assert!(snapshots.is_due(&rules, 10));
+        assert!(!snapshots.is_due(&[], 10));
+
+        snapshots.begin().unwrap();
+        snapshots.finish(10, false);
+
+        assert!(!snapshots.is_due(&rules, 20));
+    }
+
+    #[test]
+    fn writes_run_in_parallel_until_they_are_recorded() {
+        let context = ServerContext::new(Config::default());
+        let first = context.lock_write();
+
+        assert!(matches!(first, WriteGuard::Shared(_)));
+        assert!(matches!(context.lock_write(), WriteGuard::Shared(_)));
+        assert!(context.write_lock.try_write().is_err());
+
+        drop(first);
+        context.replication.add_replica(1, None, 6380);
+
+        assert!(matches!(context.lock_write(), WriteGuard::Exclusive(_)));
+    }
+}
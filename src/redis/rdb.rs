@@ -0,0 +1,1089 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+    process,
+};
+
+use bytes::Bytes;
+
+use crate::redis::{
+    commands::REDIS_VERSION,
+    crc64::crc64,
+    error::RdbError,
+    sorted_set::SortedSet,
+    store::{now_millis, Hash, KvStore, List, Set, SnapshotEntry, Value},
+};
+
+const MAGIC: &[u8] = b"REDIS";
+/// The format written by Redis 7.2.
+const RDB_VERSION: u32 = 11;
+const MAX_VERSION: u32 = 12;
+/// Files older than this carry no checksum after the EOF opcode.
+const CHECKSUM_VERSION: u32 = 5;
+
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+const LISTPACK_HEADER_SIZE: usize = 6;
+const LISTPACK_END: u8 = 0xff;
+
+const ENCODING_INT8: u8 = 0;
+const ENCODING_INT16: u8 = 1;
+const ENCODING_INT32: u8 = 2;
+const ENCODING_LZF: u8 = 3;
+
+/// The most output a byte of LZF input can stand for: a three byte back
+/// reference copies up to 264 bytes.
+const LZF_MAX_EXPANSION: usize = 88;
+
+enum Length {
+    Plain(u64),
+    Encoded(u8),
+}
+
+/// Loads the snapshot at `path` into `store`, returning how many keys were
+/// restored. A missing file is not an error: the server simply starts empty.
+pub fn load(path: &Path, store: &KvStore) -> Result<usize, RdbError> {
+    let buffer = match fs::read(path) {
+        Ok(buffer) => buffer,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(error) => return Err(error.into()),
+    };
+
+    load_bytes(&buffer, store)
+}
+
+/// Loads a snapshot held in memory, such as the payload of a full resync.
+pub fn load_bytes(buffer: &[u8], store: &KvStore) -> Result<usize, RdbError> {
+    RdbParser::new(buffer).load(store)
+}
+
+/// Writes a snapshot of `entries` to `path`. The data goes to a temporary
+/// file that is synced and then renamed over `path`, so readers only ever
+/// see a complete snapshot.
+pub fn save(path: &Path, entries: &[SnapshotEntry]) -> Result<(), RdbError> {
+    let temporary_path = path.with_file_name(format!("temp-{}.rdb", process::id()));
+    let written =
+        write_file(&temporary_path, entries).and_then(|_| fs::rename(&temporary_path, path));
+
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary_path);
+    }
+
+    Ok(written?)
+}
+
+fn write_file(path: &Path, entries: &[SnapshotEntry]) -> io::Result<()> {
+    let mut writer = RdbWriter::new(BufWriter::new(File::create(path)?));
+
+    writer.write_snapshot(entries)?;
+    writer
+        .writer
+        .into_inner()
+        .map_err(|error| error.into_error())?
+        .sync_all()
+}
+
+/// Serializes `entries` in memory, as sent to replicas on a full resync.
+pub fn to_bytes(entries: &[SnapshotEntry]) -> Vec<u8> {
+    let mut writer = RdbWriter::new(Vec::new());
+
+    writer
+        .write_snapshot(entries)
+        .expect("writing to a Vec cannot fail");
+
+    writer.writer
+}
+
+struct RdbWriter<W> {
+    writer: W,
+    checksum: u64,
+}
+
+impl<W: Write> RdbWriter<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            checksum: 0,
+        }
+    }
+
+    fn write_snapshot(&mut self, entries: &[SnapshotEntry]) -> io::Result<()> {
+        self.write_bytes(MAGIC)?;
+        self.write_bytes(format!("{RDB_VERSION:04}").as_bytes())?;
+        self.write_aux("redis-ver", REDIS_VERSION)?;
+        self.write_aux("redis-bits", &(usize::BITS).to_string())?;
+        self.write_aux("ctime", &(now_millis() / 1000).to_string())?;
+
+        self.write_bytes(&[OPCODE_SELECTDB])?;
+        self.write_length(0)?;
+        self.write_bytes(&[OPCODE_RESIZEDB])?;
+        self.write_length(entries.len() as u64)?;
+        self.write_length(
+            entries
+                .iter()
+                .filter(|(_, _, expires_at)| expires_at.is_some())
+                .count() as u64,
+        )?;
+
+        for (key, value, expires_at) in entries {
+            if let Some(expires_at) = expires_at {
+                self.write_bytes(&[OPCODE_EXPIRETIME_MS])?;
+                self.write_bytes(&expires_at.to_le_bytes())?;
+            }
+
+            self.write_value(key, value)?;
+        }
+
+        self.write_bytes(&[OPCODE_EOF])?;
+
+        let checksum = self.checksum;
+
+        self.write_bytes(&checksum.to_le_bytes())
+    }
+
+    /// Collections use the plain encodings, which every RDB version loads.
+    fn write_value(&mut self, key: &[u8], value: &Value) -> io::Result<()> {
+        match value {
+            Value::String(string) => {
+                self.write_bytes(&[TYPE_STRING])?;
+                self.write_string(key)?;
+                self.write_string(string)
+            }
+            Value::List(list) => {
+                self.write_bytes(&[TYPE_LIST])?;
+                self.write_string(key)?;
+                self.write_length(list.len() as u64)?;
+
+                for element in list {
+                    self.write_string(element)?;
+                }
+
+                Ok(())
+            }
+            Value::Hash(hash) => {
+                self.write_bytes(&[TYPE_HASH])?;
+                self.write_string(key)?;
+                self.write_length(hash.len() as u64)?;
+
+                for (field, value) in hash {
+                    self.write_string(field)?;
+                    self.write_string(value)?;
+                }
+
+                Ok(())
+            }
+            Value::Set(set) => {
+                self.write_bytes(&[TYPE_SET])?;
+                self.write_string(key)?;
+                self.write_length(set.len() as u64)?;
+
+                for member in set {
+                    self.write_string(member)?;
+                }
+
+                Ok(())
+            }
+            Value::SortedSet(zset) => {
+                self.write_bytes(&[TYPE_ZSET_2])?;
+                self.write_string(key)?;
+                self.write_length(zset.len() as u64)?;
+
+                for (member, score) in zset.iter() {
+                    self.write_string(member)?;
+                    self.write_bytes(&score.to_le_bytes())?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    fn write_aux(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.write_bytes(&[OPCODE_AUX])?;
+        self.write_string(key.as_bytes())?;
+        self.write_string(value.as_bytes())
+    }
+
+    fn write_string(&mut self, string: &[u8]) -> io::Result<()> {
+        self.write_length(string.len() as u64)?;
+        self.write_bytes(string)
+    }
+
+    fn write_length(&mut self, length: u64) -> io::Result<()> {
+        if length < 1 << 6 {
+            self.write_bytes(&[length as u8])
+        } else if length < 1 << 14 {
+            self.write_bytes(&[0x40 | (length >> 8) as u8, length as u8])
+        } else if let Ok(length) = u32::try_from(length) {
+            self.write_bytes(&[0x80])?;
+            self.write_bytes(&length.to_be_bytes())
+        } else {
+            self.write_bytes(&[0x81])?;
+            self.write_bytes(&length.to_be_bytes())
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.checksum = crc64(self.checksum, bytes);
+        self.writer.write_all(bytes)
+    }
+}
+
+struct RdbParser<'a> {
+    buffer: &'a [u8],
+    position: usize,
+}
+
+impl<'a> RdbParser<'a> {
+    fn new(buffer: &'a [u8]) -> Self {
+        Self {
+            buffer,
+            position: 0,
+        }
+    }
+
+    fn load(&mut self, store: &KvStore) -> Result<usize, RdbError> {
+        let version = self.read_header()?;
+        let now = now_millis();
+        let mut database = 0;
+        let mut expires_at = None;
+        let mut loaded = 0;
+
+        loop {
+            let opcode = self.read_u8()?;
+
+            match opcode {
+                OPCODE_EOF => break,
+                OPCODE_SELECTDB => database = self.read_length()?,
+                OPCODE_RESIZEDB => {
+                    self.read_length()?;
+                    self.read_length()?;
+                }
+                OPCODE_AUX => {
+                    self.read_string()?;
+                    self.read_string()?;
+                }
+                OPCODE_EXPIRETIME => {
+                    let seconds = u32::from_le_bytes(self.read_array()?);
+
+                    expires_at = Some(seconds as i64 * 1000);
+                }
+                OPCODE_EXPIRETIME_MS => {
+                    expires_at = Some(i64::from_le_bytes(self.read_array()?));
+                }
+                OPCODE_IDLE => {
+                    self.read_length()?;
+                }
+                OPCODE_FREQ => {
+                    self.read_u8()?;
+                }
+                // Functions, module data and newer metadata opcodes.
+                0xf0..=0xf7 => return Err(RdbError::UnsupportedOpcode(opcode)),
+                value_type => {
+                    let key = self.read_string()?;
+                    let value = self.read_value(value_type)?;
+                    let expires_at = expires_at.take();
+
+                    // Only a single keyspace exists, so keys from other
+                    // databases are parsed and dropped. Keys whose deadline
+                    // passed while the server was down are dropped as well.
+                    if database != 0 || matches!(expires_at, Some(at) if at <= now) {
+                        continue;
+                    }
+
+                    store.insert(key, value, expires_at);
+                    loaded += 1;
+                }
+            }
+        }
+
+        if version >= CHECKSUM_VERSION {
+            let computed = crc64(0, &self.buffer[..self.position]);
+            let expected = u64::from_le_bytes(self.read_array()?);
+
+            // A zero checksum means the writer had checksums disabled.
+            if expected != 0 && expected != computed {
+                return Err(RdbError::ChecksumMismatch { expected, computed });
+            }
+        }
+
+        Ok(loaded)
+    }
+
+    fn read_header(&mut self) -> Result<u32, RdbError> {
+        if self.read_exact(MAGIC.len())? != MAGIC {
+            return Err(RdbError::InvalidHeader);
+        }
+
+        let version = std::str::from_utf8(self.read_exact(4)?)
+            .ok()
+            .and_then(|version| version.parse::<u32>().ok())
+            .ok_or(RdbError::InvalidHeader)?;
+
+        if version == 0 || version > MAX_VERSION {
+            return Err(RdbError::UnsupportedVersion(version));
+        }
+
+        Ok(version)
+    }
+
+    fn read_value(&mut self, value_type: u8) -> Result<Value, RdbError> {
+        match value_type {
+            TYPE_STRING => Ok(Value::String(self.read_string()?)),
+            TYPE_LIST => {
+                let length = self.read_usize()?;
+                let mut list = List::new();
+
+                for _ in 0..length {
+                    list.push_back(self.read_string()?);
+                }
+
+                Ok(Value::List(list))
+            }
+            TYPE_LIST_QUICKLIST_2 => self.read_quicklist(),
+            TYPE_HASH => {
+                let length = self.read_usize()?;
+                let mut hash = Hash::new();
+
+                for _ in 0..length {
+                    hash.insert(self.read_string()?, self.read_string()?);
+                }
+
+                Ok(Value::Hash(hash))
+            }
+            TYPE_HASH_LISTPACK => {
+                let entries = listpack_entries(&self.read_string()?)?;
+
+                if entries.len() % 2 != 0 {
+                    return Err(RdbError::InvalidListpack);
+                }
+
+                Ok(Value::Hash(
+                    entries
+                        .chunks(2)
+                        .map(|pair| (pair[0].to_owned(), pair[1].to_owned()))
+                        .collect(),
+                ))
+            }
+            TYPE_SET => {
+                let length = self.read_usize()?;
+                let mut set = Set::new();
+
+                for _ in 0..length {
+                    set.insert(self.read_string()?);
+                }
+
+                Ok(Value::Set(set))
+            }
+            TYPE_SET_INTSET => Ok(Value::Set(
+                intset_members(&self.read_string()?)?.into_iter().collect(),
+            )),
+            TYPE_SET_LISTPACK => Ok(Value::Set(
+                listpack_entries(&self.read_string()?)?
+                    .into_iter()
+                    .collect(),
+            )),
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let length = self.read_usize()?;
+                let mut zset = SortedSet::new();
+
+                for _ in 0..length {
+                    let member = self.read_string()?;
+                    let score = if value_type == TYPE_ZSET_2 {
+                        f64::from_le_bytes(self.read_array()?)
+                    } else {
+                        self.read_text_score()?
+                    };
+
+                    if score.is_nan() {
+                        return Err(RdbError::InvalidScore);
+                    }
+
+                    zset.insert(member, score);
+                }
+
+                Ok(Value::SortedSet(zset))
+            }
+            TYPE_ZSET_LISTPACK => {
+                let entries = listpack_entries(&self.read_string()?)?;
+                let mut zset = SortedSet::new();
+
+                if entries.len() % 2 != 0 {
+                    return Err(RdbError::InvalidListpack);
+                }
+
+                for pair in entries.chunks(2) {
+                    let score = std::str::from_utf8(&pair[1])
+                        .ok()
+                        .and_then(|score| score.parse::<f64>().ok())
+                        .filter(|score| !score.is_nan())
+                        .ok_or(RdbError::InvalidScore)?;
+
+                    zset.insert(pair[0].to_owned(), score);
+                }
+
+                Ok(Value::SortedSet(zset))
+            }
+            _ => Err(RdbError::UnsupportedType(value_type)),
+        }
+    }
+
+    /// A score as the original sorted set encoding writes it: a length byte
+    /// and that many ASCII characters, with three lengths reserved for NaN
+    /// and the infinities.
+    fn read_text_score(&mut self) -> Result<f64, RdbError> {
+        match self.read_u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            length => std::str::from_utf8(self.read_exact(length as usize)?)
+                .ok()
+                .and_then(|score| score.parse().ok())
+                .ok_or(RdbError::InvalidScore),
+        }
+    }
+
+    /// A list as Redis 7 writes it: a sequence of nodes, each a listpack of
+    /// elements or, for large elements, a single plain one.
+    fn read_quicklist(&mut self) -> Result<Value, RdbError> {
+        let nodes = self.read_length()?;
+        let mut list = List::new();
+
+        for _ in 0..nodes {
+            let container = self.read_length()?;
+            let node = self.read_string()?;
+
+            match container {
+                QUICKLIST_NODE_PLAIN => list.push_back(node),
+                QUICKLIST_NODE_PACKED => list.extend(listpack_entries(&node)?),
+                _ => return Err(RdbError::InvalidListpack),
+            }
+        }
+
+        Ok(Value::List(list))
+    }
+
+    fn read_string(&mut self) -> Result<Bytes, RdbError> {
+        match self.read_length_or_encoding()? {
+            Length::Plain(length) => {
+                let length = usize::try_from(length).map_err(|_| RdbError::InvalidLength)?;
+
+                Ok(Bytes::copy_from_slice(self.read_exact(length)?))
+            }
+            Length::Encoded(ENCODING_INT8) => Ok(Bytes::from((self.read_u8()? as i8).to_string())),
+            Length::Encoded(ENCODING_INT16) => Ok(Bytes::from(
+                i16::from_le_bytes(self.read_array()?).to_string(),
+            )),
+            Length::Encoded(ENCODING_INT32) => Ok(Bytes::from(
+                i32::from_le_bytes(self.read_array()?).to_string(),
+            )),
+            Length::Encoded(ENCODING_LZF) => {
+                let compressed_length = self.read_usize()?;
+                let length = self.read_usize()?;
+                let compressed = self.read_exact(compressed_length)?;
+
+                Ok(Bytes::from(lzf_decompress(compressed, length)?))
+            }
+            Length::Encoded(encoding) => Err(RdbError::UnsupportedEncoding(encoding)),
+        }
+    }
+
+    fn read_length(&mut self) -> Result<u64, RdbError> {
+        match self.read_length_or_encoding()? {
+            Length::Plain(length) => Ok(length),
+            Length::Encoded(_) => Err(RdbError::InvalidLength),
+        }
+    }
+
+    fn read_usize(&mut self) -> Result<usize, RdbError> {
+        usize::try_from(self.read_length()?).map_err(|_| RdbError::InvalidLength)
+    }
+
+    /// The two high bits of the first byte select a 6, 14, 32 or 64 bit
+    /// length, or mark the following bits as a special string encoding.
+    fn read_length_or_encoding(&mut self) -> Result<Length, RdbError> {
+        let first = self.read_u8()?;
+
+        match first >> 6 {
+            0b00 => Ok(Length::Plain((first & 0x3f) as u64)),
+            0b01 => Ok(Length::Plain(
+                (((first & 0x3f) as u64) << 8) | self.read_u8()? as u64,
+            )),
+            0b10 => match first {
+                0x80 => Ok(Length::Plain(u32::from_be_bytes(self.read_array()?) as u64)),
+                0x81 => Ok(Length::Plain(u64::from_be_bytes(self.read_array()?))),
+                _ => Err(RdbError::InvalidLength),
+            },
+            _ => Ok(Length::Encoded(first & 0x3f)),
+        }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, RdbError> {
+        Ok(self.read_exact(1)?[0])
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        Ok(self.read_exact(N)?.try_into().unwrap())
+    }
+
+    fn read_exact(&mut self, length: usize) -> Result<&'a [u8], RdbError> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.buffer.len())
+            .ok_or(RdbError::UnexpectedEof)?;
+        let bytes = &self.buffer[self.position..end];
+
+        self.position = end;
+
+        Ok(bytes)
+    }
+}
+
+/// Reads the elements of a listpack: a header with the total size and
+/// element count, then entries that each carry their encoding, their data
+/// and their own length for walking backwards, which is skipped.
+fn listpack_entries(listpack: &[u8]) -> Result<Vec<Bytes>, RdbError> {
+    let entry_bytes = |start: usize, length: usize| {
+        listpack
+            .get(start..start + length)
+            .ok_or(RdbError::InvalidListpack)
+    };
+    let integer = |start: usize, length: usize| -> Result<Bytes, RdbError> {
+        let bytes = entry_bytes(start, length)?;
+        // Sign extend from the entry's width.
+        let shift = 64 - 8 * length as u32;
+        let mut value = 0u64;
+
+        for (index, byte) in bytes.iter().enumerate() {
+            value |= (*byte as u64) << (8 * index);
+        }
+
+        Ok(Bytes::from(
+            (((value << shift) as i64) >> shift).to_string(),
+        ))
+    };
+    let mut entries = Vec::new();
+    let mut position = LISTPACK_HEADER_SIZE;
+
+    if listpack.len() < LISTPACK_HEADER_SIZE {
+        return Err(RdbError::InvalidListpack);
+    }
+
+    loop {
+        let first = *listpack.get(position).ok_or(RdbError::InvalidListpack)?;
+
+        if first == LISTPACK_END {
+            break;
+        }
+
+        let (entry, length) = match first {
+            0x00..=0x7f => (Bytes::from(first.to_string()), 1),
+            0x80..=0xbf => {
+                let length = (first & 0x3f) as usize;
+
+                (
+                    Bytes::copy_from_slice(entry_bytes(position + 1, length)?),
+                    1 + length,
+                )
+            }
+            0xc0..=0xdf => {
+                let value = (((first & 0x1f) as i64) << 8)
+                    | *entry_bytes(position + 1, 1)?.first().unwrap() as i64;
+                // 13 bit two's complement.
+                let value = if value >= 1 << 12 {
+                    value - (1 << 13)
+                } else {
+                    value
+                };
+
+                (Bytes::from(value.to_string()), 2)
+            }
+            0xe0..=0xef => {
+                let length =
+                    (((first & 0x0f) as usize) << 8) | entry_bytes(position + 1, 1)?[0] as usize;
+
+                (
+                    Bytes::copy_from_slice(entry_bytes(position + 2, length)?),
+                    2 + length,
+                )
+            }
+            0xf0 => {
+                let length =
+                    u32::from_le_bytes(entry_bytes(position + 1, 4)?.try_into().unwrap()) as usize;
+
+                (
+                    Bytes::copy_from_slice(entry_bytes(position + 5, length)?),
+                    5 + length,
+                )
+            }
+            0xf1 => (integer(position + 1, 2)?, 3),
+            0xf2 => (integer(position + 1, 3)?, 4),
+            0xf3 => (integer(position + 1, 4)?, 5),
+            0xf4 => (integer(position + 1, 8)?, 9),
+            _ => return Err(RdbError::InvalidListpack),
+        };
+        let backlen = match length {
+            0..=127 => 1,
+            128..=16_383 => 2,
+            16_384..=2_097_151 => 3,
+            2_097_152..=268_435_455 => 4,
+            _ => 5,
+        };
+
+        entries.push(entry);
+        position += length + backlen;
+    }
+
+    Ok(entries)
+}
+
+/// Reads the members of an intset: the width of its integers, their count,
+/// then the integers themselves in little endian order.
+fn intset_members(intset: &[u8]) -> Result<Vec<Bytes>, RdbError> {
+    let header = |start: usize| {
+        intset
+            .get(start..start + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+            .ok_or(RdbError::InvalidIntset)
+    };
+    let width = header(0)?;
+    let length = header(4)?;
+
+    if !matches!(width, 2 | 4 | 8) || intset.len() != 8 + width * length {
+        return Err(RdbError::InvalidIntset);
+    }
+
+    Ok(intset[8..]
+        .chunks(width)
+        .map(|bytes| {
+            let value = match width {
+                2 => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+                4 => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+                _ => i64::from_le_bytes(bytes.try_into().unwrap()),
+            };
+
+            Bytes::from(value.to_string())
+        })
+        .collect())
+}
+
+/// Expands LZF data: a control byte below 32 introduces a run of literals,
+/// anything else is a back reference into the output produced so far.
+/// `length` comes from the file, so the output is reserved only up to what
+/// `input` can expand to, and decoding stops as soon as it overshoots.
+fn lzf_decompress(input: &[u8], length: usize) -> Result<Vec<u8>, RdbError> {
+    let mut output = Vec::with_capacity(length.min(input.len().saturating_mul(LZF_MAX_EXPANSION)));
+    let mut position = 0;
+
+    while position < input.len() {
+        if output.len() > length {
+            return Err(RdbError::InvalidLzf);
+        }
+
+        let control = input[position] as usize;
+
+        position += 1;
+
+        if control < 32 {
+            let literal = input
+                .get(position..position + control + 1)
+                .ok_or(RdbError::InvalidLzf)?;
+
+            output.extend_from_slice(literal);
+            position += control + 1;
+        } else {
+            let mut run = control >> 5;
+
+            if run == 7 {
+                run += *input.get(position).ok_or(RdbError::InvalidLzf)? as usize;
+                position += 1;
+            }
+
+            let offset = ((control & 0x1f) << 8)
+                + *input.get(position).ok_or(RdbError::InvalidLzf)? as usize
+                + 1;
+
+            position += 1;
+
+            if offset > output.len() {
+                return Err(RdbError::InvalidLzf);
+            }
+
+            // The reference may overlap the bytes it produces, so copy one
+            // byte at a time.
+            let start = output.len() - offset;
+
+            for index in 0..run + 2 {
+                output.push(output[start + index]);
+            }
+        }
+    }
+
+    if output.len() != length {
+        return Err(RdbError::InvalidLzf);
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process, sync::Arc};
+
+    use bytes::Bytes;
+
+    use super::{intset_members, listpack_entries, load, lzf_decompress, save, RdbParser};
+    use crate::redis::{
+        crc64::crc64,
+        error::RdbError,
+        sorted_set::SortedSet,
+        store::{now_millis, Hash, KvStore, List, Set, Ttl, Value},
+    };
+
+    fn with_checksum(mut rdb: Vec<u8>) -> Vec<u8> {
+        let checksum = crc64(0, &rdb);
+
+        rdb.extend_from_slice(&checksum.to_le_bytes());
+
+        rdb
+    }
+
+    fn snapshot() -> Vec<u8> {
+        let deadline = now_millis() + 60_000;
+        let mut rdb = b"REDIS0011".to_vec();
+
+        // Aux fields, including an integer encoded value.
+        rdb.extend_from_slice(b"\xfa\x09redis-ver\x057.2.0");
+        rdb.extend_from_slice(b"\xfa\x0aredis-bits\xc0\x40");
+        rdb.extend_from_slice(b"\xfe\x00\xfb\x04\x01");
+        // Plain string.
+        rdb.extend_from_slice(b"\x00\x05plain\x05value");
+        // Integer encodings.
+        rdb.extend_from_slice(b"\x00\x04int8\xc0\xfe");
+        rdb.extend_from_slice(b"\x00\x05int16\xc1\x39\x30");
+        rdb.extend_from_slice(b"\x00\x05int32\xc2\x87\xd6\x12\x00");
+        // LZF: literal "abc" followed by a 9 byte back reference.
+        rdb.extend_from_slice(b"\x00\x03lzf\xc3\x07\x0c\x02abc\xe0\x00\x02");
+        // Millisecond and second expiries, one in the future and one past.
+        rdb.push(0xfc);
+        rdb.extend_from_slice(&deadline.to_le_bytes());
+        rdb.extend_from_slice(b"\x00\x08volatile\x01v");
+        rdb.extend_from_slice(b"\xfd\x01\x00\x00\x00\x00\x07expired\x01v");
+        // Keys outside database 0 are skipped.
+        rdb.extend_from_slice(b"\xfe\x01\x00\x05other\x01v");
+        rdb.push(0xff);
+
+        with_checksum(rdb)
+    }
+
+    #[test]
+    fn loads_strings_and_expiries() {
+        let store = KvStore::new();
+
+        assert_eq!(RdbParser::new(&snapshot()).load(&store).unwrap(), 6);
+        assert_eq!(store.get(b"plain").unwrap(), Some(Bytes::from("value")));
+        assert_eq!(store.get(b"int8").unwrap(), Some(Bytes::from("-2")));
+        assert_eq!(store.get(b"int16").unwrap(), Some(Bytes::from("12345")));
+        assert_eq!(store.get(b"int32").unwrap(), Some(Bytes::from("1234567")));
+        assert_eq!(
+            store.get(b"lzf").unwrap(),
+            Some(Bytes::from("abcabcabcabc"))
+        );
+        assert!(matches!(store.ttl(b"volatile"), Ttl::ExpiresAt(_)));
+        assert_eq!(store.get(b"expired").unwrap(), None);
+        assert_eq!(store.get(b"other").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_corruption() {
+        let mut rdb = snapshot();
+        let length = rdb.len();
+
+        rdb[length - 12] ^= 0xff;
+
+        assert!(matches!(
+            RdbParser::new(&rdb).load(&KvStore::new()),
+            Err(RdbError::ChecksumMismatch { .. })
+        ));
+        assert!(matches!(
+            RdbParser::new(&rdb[..length - 20]).load(&KvStore::new()),
+            Err(RdbError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            RdbParser::new(b"RUBIS0011\xff").load(&KvStore::new()),
+            Err(RdbError::InvalidHeader)
+        ));
+        assert!(matches!(
+            RdbParser::new(b"REDIS0099\xff").load(&KvStore::new()),
+            Err(RdbError::UnsupportedVersion(99))
+        ));
+    }
+
+    #[test]
+    fn zero_checksum_is_not_verified() {
+        let mut rdb = b"REDIS0011\x00\x01k\x01v\xff".to_vec();
+
+        rdb.extend_from_slice(&[0; 8]);
+
+        assert_eq!(RdbParser::new(&rdb).load(&KvStore::new()).unwrap(), 1);
+    }
+
+    #[test]
+    fn lzf_rejects_bad_references() {
+        assert_eq!(lzf_decompress(b"\x01ab", 2).unwrap(), b"ab");
+        assert!(lzf_decompress(b"\x00a\xe0\x00\x05", 10).is_err());
+        assert!(lzf_decompress(b"\x02ab", 3).is_err());
+        // A length the data cannot reach is refused without reserving it.
+        assert!(lzf_decompress(b"\x01ab", usize::MAX).is_err());
+        assert!(lzf_decompress(b"\x00a\xe0\xff\x00\xe0\xff\x00", 10).is_err());
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let path = env::temp_dir().join(format!("redis-save-{}.rdb", process::id()));
+        let deadline = now_millis() + 60_000;
+        let mut zset = SortedSet::new();
+
+        zset.insert(Bytes::from("low"), f64::NEG_INFINITY);
+        zset.insert(Bytes::from("high"), 0.1);
+
+        let entries = vec![
+            (
+                Bytes::from("short"),
+                Value::String(Bytes::from("value")),
+                None,
+            ),
+            (
+                Bytes::from("k".repeat(100)),
+                Value::String(Bytes::from(vec![0; 20_000])),
+                Some(deadline),
+            ),
+            (
+                Bytes::from_static(b"\x00\xff"),
+                Value::String(Bytes::new()),
+                None,
+            ),
+            (
+                Bytes::from("list"),
+                Value::List(List::from([Bytes::from("a"), Bytes::new()])),
+                None,
+            ),
+            (
+                Bytes::from("hash"),
+                Value::Hash(Hash::from([
+                    (Bytes::from("field"), Bytes::from("value")),
+                    (Bytes::from("empty"), Bytes::new()),
+                ])),
+                Some(deadline),
+            ),
+            (
+                Bytes::from("set"),
+                Value::Set(Set::from([Bytes::from("a"), Bytes::from("1")])),
+                None,
+            ),
+            (Bytes::from("zset"), Value::SortedSet(zset), None),
+        ];
+
+        let mut entries = entries
+            .into_iter()
+            .map(|(key, value, expires_at)| (key, Arc::new(value), expires_at))
+            .collect::<Vec<_>>();
+
+        save(&path, &entries).unwrap();
+
+        let store = KvStore::new();
+        let loaded = load(&path, &store).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        let mut restored = store.snapshot();
+
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        restored.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(loaded, 7);
+        assert_eq!(restored, entries);
+    }
+
+    #[test]
+    fn loads_lists() {
+        let mut rdb = b"REDIS0011\xfe\x00".to_vec();
+
+        // A plain list.
+        rdb.extend_from_slice(b"\x01\x05plain\x02\x01a\xc0\x07");
+        // A quicklist with a packed node holding "a", 5, -3 and 1000, then a
+        // plain node.
+        rdb.extend_from_slice(b"\x12\x06packed\x02\x02\x13");
+        rdb.extend_from_slice(b"\x13\x00\x00\x00\x04\x00");
+        rdb.extend_from_slice(b"\x81a\x02\x05\x01\xdf\xfd\x02\xf1\xe8\x03\x03\xff");
+        rdb.extend_from_slice(b"\x01\x04last");
+        rdb.push(0xff);
+
+        let store = KvStore::new();
+
+        assert_eq!(RdbParser::new(&with_checksum(rdb)).load(&store).unwrap(), 2);
+
+        let elements = |key: &[u8]| {
+            store
+                .read(key, |list: &List| list.iter().cloned().collect::<Vec<_>>())
+                .unwrap()
+                .unwrap()
+        };
+
+        assert_eq!(elements(b"plain"), vec![Bytes::from("a"), Bytes::from("7")]);
+        assert_eq!(
+            elements(b"packed"),
+            ["a", "5", "-3", "1000", "last"].map(Bytes::from).to_vec()
+        );
+    }
+
+    #[test]
+    fn loads_hashes() {
+        let mut rdb = b"REDIS0011\xfe\x00".to_vec();
+
+        // A plain hash.
+        rdb.extend_from_slice(b"\x04\x05plain\x01\x01a\x01b");
+        // A listpack hash holding f => v and n => 7.
+        rdb.extend_from_slice(b"\x10\x06packed\x12\x12\x00\x00\x00\x04\x00");
+        rdb.extend_from_slice(b"\x81f\x02\x81v\x02\x81n\x02\x07\x01\xff");
+        rdb.push(0xff);
+
+        let store = KvStore::new();
+
+        assert_eq!(RdbParser::new(&with_checksum(rdb)).load(&store).unwrap(), 2);
+
+        let hash = |key: &[u8]| {
+            store
+                .read(key, |hash: &Hash| hash.clone())
+                .unwrap()
+                .unwrap()
+        };
+
+        assert_eq!(
+            hash(b"plain"),
+            Hash::from([(Bytes::from("a"), Bytes::from("b"))])
+        );
+        assert_eq!(
+            hash(b"packed"),
+            Hash::from([
+                (Bytes::from("f"), Bytes::from("v")),
+                (Bytes::from("n"), Bytes::from("7")),
+            ])
+        );
+    }
+
+    #[test]
+    fn loads_sets() {
+        let mut rdb = b"REDIS0011\xfe\x00".to_vec();
+
+        // A plain set.
+        rdb.extend_from_slice(b"\x02\x05plain\x02\x01a\x01b");
+        // An intset of 16 bit integers holding -2 and 300.
+        rdb.extend_from_slice(
+            b"\x0b\x06intset\x0c\x02\x00\x00\x00\x02\x00\x00\x00\xfe\xff\x2c\x01",
+        );
+        // A listpack set holding x and 7.
+        rdb.extend_from_slice(b"\x14\x06packed\x0c\x0c\x00\x00\x00\x02\x00");
+        rdb.extend_from_slice(b"\x81x\x02\x07\x01\xff");
+        rdb.push(0xff);
+
+        let store = KvStore::new();
+
+        assert_eq!(RdbParser::new(&with_checksum(rdb)).load(&store).unwrap(), 3);
+
+        let set = |key: &[u8]| store.read(key, |set: &Set| set.clone()).unwrap().unwrap();
+
+        assert_eq!(
+            set(b"plain"),
+            Set::from([Bytes::from("a"), Bytes::from("b")])
+        );
+        assert_eq!(
+            set(b"intset"),
+            Set::from([Bytes::from("-2"), Bytes::from("300")])
+        );
+        assert_eq!(
+            set(b"packed"),
+            Set::from([Bytes::from("x"), Bytes::from("7")])
+        );
+        assert!(matches!(
+            intset_members(b"\x02\x00\x00\x00\x02\x00\x00\x00\x01\x00"),
+            Err(RdbError::InvalidIntset)
+        ));
+    }
+
+    #[test]
+    fn loads_sorted_sets() {
+        let mut rdb = b"REDIS0011\xfe\x00".to_vec();
+
+        // The original encoding with text scores, one of them -inf.
+        rdb.extend_from_slice(b"\x03\x04text\x02\x01a\x031.5\x01b\xff");
+        // Binary scores.
+        rdb.extend_from_slice(b"\x05\x06binary\x01\x01c");
+        rdb.extend_from_slice(&2.5f64.to_le_bytes());
+        // A listpack holding x with score 7.
+        rdb.extend_from_slice(b"\x11\x06packed\x0c\x0c\x00\x00\x00\x02\x00");
+        rdb.extend_from_slice(b"\x81x\x02\x07\x01\xff");
+        rdb.push(0xff);
+
+        let store = KvStore::new();
+
+        assert_eq!(RdbParser::new(&with_checksum(rdb)).load(&store).unwrap(), 3);
+
+        let members = |key: &[u8]| {
+            store
+                .read(key, |zset: &SortedSet| {
+                    zset.iter()
+                        .map(|(member, score)| (member.clone(), score))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap()
+                .unwrap()
+        };
+
+        assert_eq!(
+            members(b"text"),
+            [
+                (Bytes::from("b"), f64::NEG_INFINITY),
+                (Bytes::from("a"), 1.5)
+            ]
+        );
+        assert_eq!(members(b"binary"), [(Bytes::from("c"), 2.5)]);
+        assert_eq!(members(b"packed"), [(Bytes::from("x"), 7.0)]);
+    }
+
+    #[test]
+    fn listpack_rejects_truncation() {
+        assert!(matches!(
+            listpack_entries(b"\x0a\x00\x00\x00\x01\x00\x85ab"),
+            Err(RdbError::InvalidListpack)
+        ));
+        assert!(matches!(
+            listpack_entries(b"\x07\x00"),
+            Err(RdbError::InvalidListpack)
+        ));
+    }
+}
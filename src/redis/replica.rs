@@ -0,0 +1,321 @@
+use std::{io, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task, time,
+};
+
+use crate::redis::{
+    aof::serialize_command,
+    client::Client,
+    commands::RedisCommand,
+    error::ReplicationError,
+    rdb,
+    replication::LinkStatus,
+    resp::{ProtocolLimits, RESPDataTypes, RESPDecoder},
+    server::ServerContext,
+    store::KvStore,
+};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+impl ServerContext {
+    /// Whether clients are refused writes: replicas only take them from
+    /// their master unless `replica-read-only` is off.
+    pub fn is_read_only(&self) -> bool {
+        self.replication.is_replica() && self.config.values().replica_read_only
+    }
+
+    /// Starts following the master at `host:port`, reconnecting whenever the
+    /// link drops, until the instance is promoted or pointed elsewhere.
+    pub fn replicate(self: &Arc<Self>, host: String, port: u16) {
+        let context = Arc::clone(self);
+        let master_host = host.to_owned();
+
+        self.replication.replicate_from(host, port, move || {
+            tokio::spawn(async move {
+                loop {
+                    if let Err(error) = follow_master(&context, &master_host, port).await {
+                        eprintln!("Replication from {master_host}:{port} failed: {error}");
+                    }
+
+                    context.replication.set_link_status(LinkStatus::Connecting);
+                    time::sleep(RECONNECT_DELAY).await;
+                }
+            })
+        });
+    }
+}
+
+/// Connects to the master, asks to continue our history and, if the master
+/// answers with a full resync instead, loads its snapshot. Then applies
+/// the command stream that follows.
+async fn follow_master(
+    context: &Arc<ServerContext>,
+    host: &str,
+    port: u16,
+) -> Result<(), ReplicationError> {
+    let mut stream = TcpStream::connect((host, port)).await?;
+    // The RDB payload is not bound by the bulk length limit clients are.
+    let mut decoder = RESPDecoder::with_limits(ProtocolLimits {
+        max_bulk_length: usize::MAX,
+        ..ProtocolLimits::default()
+    });
+    let listening_port = context.config.values().port.to_string();
+
+    let _ = stream.set_nodelay(true);
+
+    for command in [
+        vec!["PING"],
+        vec!["REPLCONF", "listening-port", &listening_port],
+        vec!["REPLCONF", "capa", "psync2"],
+    ] {
+        send(&mut stream, &command).await?;
+
+        match read_reply(&mut stream, &mut decoder).await? {
+            RESPDataTypes::SimpleString(_) => {}
+            reply => return Err(ReplicationError::UnexpectedReply(format!("{reply:?}"))),
+        }
+    }
+
+    let (replid, psync_offset) = context.replication.psync_request();
+
+    send(&mut stream, &["PSYNC", &replid, &psync_offset.to_string()]).await?;
+
+    let reply = match read_reply(&mut stream, &mut decoder).await? {
+        RESPDataTypes::SimpleString(reply) => parse_psync_reply(&reply)
+            .ok_or_else(|| ReplicationError::UnexpectedReply(reply.to_owned()))?,
+        reply => return Err(ReplicationError::UnexpectedReply(format!("{reply:?}"))),
+    };
+
+    match reply {
+        PsyncReply::FullResync { replid, offset } => {
+            full_resync(context, &mut stream, &mut decoder, replid, offset).await?
+        }
+        PsyncReply::Continue { replid } => {
+            let _write = context.write_lock.write().unwrap();
+
+            context.replication.continue_stream(replid)
+        }
+    }
+
+    let mut client = Client::new();
+    let mut acks = time::interval(ACK_INTERVAL);
+
+    loop {
+        let mut replies = Vec::new();
+
+        while let Some((request, raw)) = decoder.decode_raw()? {
+            apply(context, &mut client, request, raw, &mut replies);
+        }
+
+        if !replies.is_empty() {
+            stream.write_all(&replies).await?;
+        }
+
+        tokio::select! {
+            _ = acks.tick() => {
+                stream.write_all(&ack(context, context.replication.offset())).await?;
+            }
+            read = read_more(&mut stream, &mut decoder) => {
+                read?;
+                context.replication.touch_master();
+            }
+        }
+    }
+}
+
+/// Replaces the keyspace with the RDB payload the master sends after
+/// `+FULLRESYNC`.
+async fn full_resync(
+    context: &Arc<ServerContext>,
+    stream: &mut TcpStream,
+    decoder: &mut RESPDecoder,
+    replid: String,
+    offset: u64,
+) -> Result<(), ReplicationError> {
+    context.replication.set_link_status(LinkStatus::Sync);
+
+    let payload = loop {
+        if let Some(payload) = decoder.decode_rdb_payload()? {
+            break payload;
+        }
+
+        read_more(stream, decoder).await?;
+    };
+
+    // The payload is loaded off the event loops into a store of its own,
+    // which is swapped in under the write lock once it is complete.
+    let loaded = task::spawn_blocking(move || {
+        let store = KvStore::new();
+
+        rdb::load_bytes(&payload, &store).map(|_| store)
+    })
+    .await
+    .map_err(io::Error::from)??;
+
+    {
+        let _write = context.write_lock.write().unwrap();
+
+        context.store.swap(&loaded);
+        context.replication.start_stream(replid, offset);
+    }
+
+    // `loaded` now holds the previous dataset.
+    task::spawn_blocking(move || drop(loaded));
+
+    // The log has to describe the dataset that was just loaded.
+    if context.aof.is_open() {
+        if let Err(error) = context.background_rewrite_aof() {
+            eprintln!("Failed to rewrite the AOF after the resync: {error}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Relays one command from the master, byte for byte, to our own replicas
+/// and applies it. It is counted first so the AOF records the offset that
+/// covers it. `REPLCONF GETACK` is answered with the offset reached before
+/// it.
+fn apply(
+    context: &Arc<ServerContext>,
+    client: &mut Client,
+    request: RESPDataTypes,
+    raw: Bytes,
+    replies: &mut Vec<u8>,
+) {
+    {
+        let _write = context.write_lock.write().unwrap();
+        let command = RedisCommand::try_from(request);
+
+        if let Ok(RedisCommand::REPLCONF { options }) = &command {
+            if options.iter().any(|(option, _)| option == "getack") {
+                replies.extend_from_slice(&ack(context, context.replication.offset()));
+            }
+        }
+
+        context.replication.feed(&raw);
+
+        match command {
+            Ok(RedisCommand::REPLCONF { .. }) | Err(_) => {}
+            Ok(mut command) => {
+                command.execute(context, client);
+            }
+        }
+    }
+
+    context.sync_aof();
+}
+
+/// `REPLCONF ACK <offset>`, with `FACK <offset>` reporting how much of the
+/// stream the AOF has on disk when it is enabled.
+fn ack(context: &ServerContext, offset: u64) -> Bytes {
+    let mut command = vec![
+        Bytes::from_static(b"REPLCONF"),
+        Bytes::from_static(b"ACK"),
+        Bytes::from(offset.to_string()),
+    ];
+
+    if let Some(synced) = context.aof.synced_offset() {
+        command.push(Bytes::from_static(b"FACK"));
+        command.push(Bytes::from(synced.min(offset).to_string()));
+    }
+
+    serialize_command(&command)
+}
+
+#[derive(Debug, PartialEq)]
+enum PsyncReply {
+    FullResync {
+        replid: String,
+        offset: u64,
+    },
+    /// Masters that predate replication ID changes omit the ID.
+    Continue {
+        replid: Option<String>,
+    },
+}
+
+fn parse_psync_reply(reply: &str) -> Option<PsyncReply> {
+    match reply.split(' ').collect::<Vec<_>>()[..] {
+        ["FULLRESYNC", replid, offset] => Some(PsyncReply::FullResync {
+            replid: replid.to_owned(),
+            offset: offset.parse().ok()?,
+        }),
+        ["CONTINUE"] => Some(PsyncReply::Continue { replid: None }),
+        ["CONTINUE", replid] => Some(PsyncReply::Continue {
+            replid: Some(replid.to_owned()),
+        }),
+        _ => None,
+    }
+}
+
+async fn send(stream: &mut TcpStream, command: &[&str]) -> Result<(), ReplicationError> {
+    let command = command
+        .iter()
+        .map(|arg| Bytes::from(arg.to_string()))
+        .collect::<Vec<_>>();
+
+    Ok(stream.write_all(&serialize_command(&command)).await?)
+}
+
+async fn read_reply(
+    stream: &mut TcpStream,
+    decoder: &mut RESPDecoder,
+) -> Result<RESPDataTypes, ReplicationError> {
+    loop {
+        if let Some(reply) = decoder.decode()? {
+            return Ok(reply);
+        }
+
+        read_more(stream, decoder).await?;
+    }
+}
+
+async fn read_more(
+    stream: &mut TcpStream,
+    decoder: &mut RESPDecoder,
+) -> Result<(), ReplicationError> {
+    let buffer = decoder.buffer_mut();
+
+    buffer.reserve(READ_BUFFER_SIZE);
+
+    match stream.read_buf(buffer).await? {
+        0 => Err(ReplicationError::Closed),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_psync_reply, PsyncReply};
+
+    #[test]
+    fn psync_replies() {
+        let replid = "a".repeat(40);
+
+        assert_eq!(
+            parse_psync_reply(&format!("FULLRESYNC {replid} 42")),
+            Some(PsyncReply::FullResync {
+                replid: replid.to_owned(),
+                offset: 42
+            })
+        );
+        assert_eq!(
+            parse_psync_reply(&format!("CONTINUE {replid}")),
+            Some(PsyncReply::Continue {
+                replid: Some(replid.to_owned())
+            })
+        );
+        assert_eq!(
+            parse_psync_reply("CONTINUE"),
+            Some(PsyncReply::Continue { replid: None })
+        );
+        assert_eq!(parse_psync_reply(&format!("FULLRESYNC {replid}")), None);
+    }
+}
@@ -0,0 +1,908 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Write,
+    hash::{BuildHasher, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::Bytes;
+use tokio::{
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        watch, Notify,
+    },
+    task::JoinHandle,
+};
+
+use crate::redis::{
+    aof::serialize_command,
+    resp::RESPDataTypes,
+    store::{now_millis, SnapshotEntry},
+};
+
+const REPLID_LENGTH: usize = 40;
+const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+const MIN_BACKLOG_SIZE: usize = 16 * 1024;
+/// Bytes of the stream that may wait for a replica's connection before the
+/// replica is dropped, the default hard `client-output-buffer-limit` Redis
+/// applies to replicas.
+const REPLICA_OUTPUT_LIMIT: usize = 256 * 1024 * 1024;
+
+/// Random hex string built from std's randomly keyed hasher, as no RNG
+/// crate is available.
+pub fn random_hex(length: usize) -> String {
+    let mut hex = String::with_capacity(length + 16);
+
+    while hex.len() < length {
+        let mut hasher = RandomState::new().build_hasher();
+
+        hasher.write_usize(hex.len());
+        write!(hex, "{:016x}", hasher.finish()).unwrap();
+    }
+
+    hex.truncate(length);
+
+    hex
+}
+
+/// Random number below `bound`, drawn the same way as `random_hex`.
+pub fn random_index(bound: usize) -> usize {
+    RandomState::new().build_hasher().finish() as usize % bound
+}
+
+struct Replica {
+    client_id: u64,
+    address: Option<IpAddr>,
+    listening_port: u16,
+    sender: UnboundedSender<Bytes>,
+    output: Arc<ReplicaOutput>,
+    acks: Arc<ReplicaAcks>,
+}
+
+impl Replica {
+    /// Queues `data` for the replica's connection. Returns false once the
+    /// connection is gone or more than `limit` bytes would be waiting, in
+    /// which case the replica is cut off.
+    fn send(&self, data: &Bytes, limit: usize) -> bool {
+        let queued = self.output.queued.fetch_add(data.len(), Ordering::SeqCst) + data.len();
+
+        if queued > limit {
+            eprintln!(
+                "Dropping replica {}:{}: {queued} bytes of output exceed the limit of {limit}",
+                Replication::address(self),
+                self.listening_port
+            );
+            self.output.overflowed.store(true, Ordering::SeqCst);
+            self.output.closed.notify_waiters();
+
+            return false;
+        }
+
+        self.sender.send(data.clone()).is_ok()
+    }
+}
+
+/// How far a replica's connection is behind the stream.
+#[derive(Default)]
+struct ReplicaOutput {
+    queued: AtomicUsize,
+    overflowed: AtomicBool,
+    closed: Notify,
+}
+
+/// The writes queued for a replica's connection, in stream order.
+pub struct ReplicaStream {
+    receiver: UnboundedReceiver<Bytes>,
+    output: Arc<ReplicaOutput>,
+}
+
+impl ReplicaStream {
+    /// The next write to send, or `None` once the replica was dropped.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        let data = self.receiver.recv().await?;
+
+        self.taken(data)
+    }
+
+    #[cfg(test)]
+    pub fn try_recv(&mut self) -> Result<Bytes, mpsc::error::TryRecvError> {
+        let data = self.receiver.try_recv()?;
+
+        self.taken(data)
+            .ok_or(mpsc::error::TryRecvError::Disconnected)
+    }
+
+    /// Resolves when the replica went over the output limit, so a write
+    /// stuck on a stalled connection is abandoned.
+    pub async fn overflowed(&self) {
+        loop {
+            let closed = self.output.closed.notified();
+
+            if self.output.overflowed.load(Ordering::SeqCst) {
+                return;
+            }
+
+            closed.await;
+        }
+    }
+
+    fn taken(&self, data: Bytes) -> Option<Bytes> {
+        if self.output.overflowed.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        self.output.queued.fetch_sub(data.len(), Ordering::SeqCst);
+
+        Some(data)
+    }
+}
+
+/// Offsets a replica reported with `REPLCONF ACK <offset> [FACK <offset>]`:
+/// how much of the stream it processed and how much of that it fsynced
+/// to its AOF.
+#[derive(Default)]
+pub struct ReplicaAcks {
+    pub offset: AtomicU64,
+    pub aof_offset: AtomicU64,
+}
+
+/// Handed to the connection of a replica that completed PSYNC: the
+/// keyspace to send as the RDB payload, unless the replica continues from
+/// the backlog, and the stream of writes that follow it.
+pub struct ReplicaLink {
+    pub entries: Option<Vec<SnapshotEntry>>,
+    pub writes: ReplicaStream,
+    pub acks: Arc<ReplicaAcks>,
+}
+
+/// Progress of a replica's link to its master, as reported by ROLE.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkStatus {
+    Connecting,
+    Sync,
+    Connected,
+}
+
+struct MasterLink {
+    host: String,
+    port: u16,
+    status: LinkStatus,
+    /// Unix time in milliseconds of the last data received from the master.
+    last_io: i64,
+    task: JoinHandle<()>,
+}
+
+/// Circular buffer holding the most recent part of the replication stream,
+/// so replicas that briefly lost their link can resume from it.
+struct Backlog {
+    buffer: Vec<u8>,
+    /// Where the next byte is written.
+    index: usize,
+    /// How many bytes of the stream the buffer holds.
+    histlen: usize,
+}
+
+impl Backlog {
+    fn new(size: usize) -> Self {
+        Self {
+            buffer: vec![0; size.max(MIN_BACKLOG_SIZE)],
+            index: 0,
+            histlen: 0,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        let size = self.size();
+        let data = &data[data.len().saturating_sub(size)..];
+        let head = data.len().min(size - self.index);
+
+        self.buffer[self.index..self.index + head].copy_from_slice(&data[..head]);
+        self.buffer[..data.len() - head].copy_from_slice(&data[head..]);
+        self.index = (self.index + data.len()) % size;
+        self.histlen = (self.histlen + data.len()).min(size);
+    }
+
+    /// The last `length` bytes of the stream; `length` is at most `histlen`.
+    fn tail(&self, length: usize) -> Vec<u8> {
+        let size = self.size();
+        let start = (self.index + size - length) % size;
+
+        if start + length <= size {
+            self.buffer[start..start + length].to_vec()
+        } else {
+            [&self.buffer[start..], &self.buffer[..start + length - size]].concat()
+        }
+    }
+
+    /// Reallocates the buffer, keeping as much of the history as fits.
+    fn resize(&mut self, size: usize) {
+        let history = self.tail(self.histlen);
+
+        *self = Self::new(size);
+        self.append(&history);
+    }
+}
+
+struct ReplicationState {
+    replid: String,
+    /// The replication ID this instance had before its last promotion or
+    /// change of master, and the first offset that is not part of it.
+    /// Replicas still following the old history can continue from it.
+    replid2: Option<(String, u64)>,
+    /// The master's stream offset, or on a replica the offset processed so
+    /// far of the stream received from its master.
+    offset: u64,
+    replicas: Vec<Replica>,
+    master: Option<MasterLink>,
+    backlog_size: usize,
+    /// Created once the first replica attaches, or when this instance
+    /// syncs with a master of its own.
+    backlog: Option<Backlog>,
+    output_limit: usize,
+}
+
+impl ReplicationState {
+    fn create_backlog(&mut self) {
+        if self.backlog.is_none() {
+            self.backlog = Some(Backlog::new(self.backlog_size));
+        }
+    }
+
+    /// Starts a new history, keeping the current one as the secondary ID.
+    fn shift_replid(&mut self, replid: String) {
+        let previous = std::mem::replace(&mut self.replid, replid);
+
+        self.replid2 = Some((previous, self.offset + 1));
+    }
+
+    fn register(
+        &mut self,
+        client_id: u64,
+        address: Option<IpAddr>,
+        listening_port: u16,
+    ) -> (ReplicaStream, Arc<ReplicaAcks>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let output = Arc::new(ReplicaOutput::default());
+        let acks = Arc::new(ReplicaAcks::default());
+
+        self.replicas
+            .retain(|replica| replica.client_id != client_id);
+        self.replicas.push(Replica {
+            client_id,
+            address,
+            listening_port,
+            sender,
+            output: Arc::clone(&output),
+            acks: Arc::clone(&acks),
+        });
+
+        (ReplicaStream { receiver, output }, acks)
+    }
+}
+
+/// The replication ID, the offset of the write stream, the replicas it is
+/// fed to and, on a replica, the link to its master.
+pub struct Replication {
+    state: Mutex<ReplicationState>,
+    /// Signalled when a replica acknowledges an offset or the AOF is
+    /// fsynced, waking connections blocked in WAIT or WAITAOF.
+    acknowledged: watch::Sender<()>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(ReplicationState {
+                replid: random_hex(REPLID_LENGTH),
+                replid2: None,
+                offset: 0,
+                replicas: Vec::new(),
+                master: None,
+                backlog_size: DEFAULT_BACKLOG_SIZE,
+                backlog: None,
+                output_limit: REPLICA_OUTPUT_LIMIT,
+            }),
+            acknowledged: watch::channel(()).0,
+        }
+    }
+
+    /// Applies `repl-backlog-size`, resizing a backlog that already exists.
+    pub fn set_backlog_size(&self, size: usize) {
+        let mut state = self.state.lock().unwrap();
+
+        state.backlog_size = size;
+
+        if let Some(backlog) = state.backlog.as_mut() {
+            if backlog.size() != size.max(MIN_BACKLOG_SIZE) {
+                backlog.resize(size);
+            }
+        }
+    }
+
+    /// Starts streaming writes to a replica. Returns the replication ID and
+    /// the offset the stream starts at; callers hold the write lock so no
+    /// write lands between the snapshot they take and the stream.
+    pub fn add_replica(
+        &self,
+        client_id: u64,
+        address: Option<IpAddr>,
+        listening_port: u16,
+    ) -> (String, u64, ReplicaStream, Arc<ReplicaAcks>) {
+        let mut state = self.state.lock().unwrap();
+
+        state.create_backlog();
+
+        let (stream, ack_offset) = state.register(client_id, address, listening_port);
+
+        (state.replid.to_owned(), state.offset, stream, ack_offset)
+    }
+
+    /// Resumes a replica's stream from the backlog when it follows our
+    /// history, under the current or the secondary ID, and `psync_offset`,
+    /// the first byte it is missing, is still held there. The missing bytes
+    /// are queued ahead of the writes that follow. Returns the replication
+    /// ID to announce with `+CONTINUE`.
+    pub fn continue_replica(
+        &self,
+        client_id: u64,
+        address: Option<IpAddr>,
+        listening_port: u16,
+        replid: &str,
+        psync_offset: i64,
+    ) -> Option<(String, ReplicaStream, Arc<ReplicaAcks>)> {
+        let mut state = self.state.lock().unwrap();
+        let psync_offset = u64::try_from(psync_offset).ok()?;
+        let follows_history = replid == state.replid
+            || matches!(&state.replid2, Some((replid2, until)) if replid == replid2 && psync_offset <= *until);
+        let missing = (state.offset + 1).checked_sub(psync_offset)?;
+        let backlog = state.backlog.as_ref()?;
+
+        if !follows_history || missing > backlog.histlen as u64 {
+            return None;
+        }
+
+        let missing = backlog.tail(missing as usize);
+        let (stream, ack_offset) = state.register(client_id, address, listening_port);
+
+        if !missing.is_empty() {
+            state
+                .replicas
+                .last()
+                .unwrap()
+                .send(&Bytes::from(missing), state.output_limit);
+        }
+
+        Some((state.replid.to_owned(), stream, ack_offset))
+    }
+
+    pub fn remove_replica(&self, client_id: u64) {
+        self.state
+            .lock()
+            .unwrap()
+            .replicas
+            .retain(|replica| replica.client_id != client_id);
+    }
+
+    pub fn has_backlog(&self) -> bool {
+        self.state.lock().unwrap().backlog.is_some()
+    }
+
+    pub fn replica_count(&self) -> usize {
+        self.state.lock().unwrap().replicas.len()
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.state.lock().unwrap().offset
+    }
+
+    /// How many replicas acknowledged processing, or with `aof` fsyncing,
+    /// the stream up to `offset`.
+    pub fn count_acks(&self, offset: u64, aof: bool) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .replicas
+            .iter()
+            .filter(|replica| {
+                let acked = if aof {
+                    &replica.acks.aof_offset
+                } else {
+                    &replica.acks.offset
+                };
+
+                acked.load(Ordering::SeqCst) >= offset
+            })
+            .count()
+    }
+
+    /// Asks every replica to acknowledge its offset right away rather than
+    /// with its next periodic ACK.
+    pub fn request_acks(&self) {
+        if self.replica_count() > 0 {
+            self.feed(&serialize_command(&[
+                Bytes::from_static(b"REPLCONF"),
+                Bytes::from_static(b"GETACK"),
+                Bytes::from_static(b"*"),
+            ]));
+        }
+    }
+
+    pub fn acknowledge(&self) {
+        self.acknowledged.send_replace(());
+    }
+
+    pub fn subscribe_acks(&self) -> watch::Receiver<()> {
+        self.acknowledged.subscribe()
+    }
+
+    /// The arguments of the PSYNC a replica sends: our history, so the
+    /// master can continue it, or `? -1` when there is none to continue.
+    pub fn psync_request(&self) -> (String, i64) {
+        let state = self.state.lock().unwrap();
+
+        if state.backlog.is_some() {
+            (state.replid.to_owned(), state.offset as i64 + 1)
+        } else {
+            ("?".to_string(), -1)
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.state.lock().unwrap().master.is_some()
+    }
+
+    pub fn master(&self) -> Option<(String, u16)> {
+        self.state
+            .lock()
+            .unwrap()
+            .master
+            .as_ref()
+            .map(|master| (master.host.to_owned(), master.port))
+    }
+
+    /// Turns this instance into a replica of `host:port`, replacing the link
+    /// to a previous master. `spawn` starts the task that follows the master;
+    /// it runs under the lock so a concurrent promotion cannot miss it.
+    /// Replicas of our own are dropped, as they have to resync with the new
+    /// dataset.
+    pub fn replicate_from<F>(&self, host: String, port: u16, spawn: F)
+    where
+        F: FnOnce() -> JoinHandle<()>,
+    {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(master) = state.master.take() {
+            master.task.abort();
+        }
+
+        state.replicas.clear();
+        state.master = Some(MasterLink {
+            host,
+            port,
+            status: LinkStatus::Connecting,
+            last_io: 0,
+            task: spawn(),
+        });
+    }
+
+    /// Stops following the master and starts a new history under a fresh
+    /// replication ID. The old one is kept as the secondary ID so replicas
+    /// of the old master can continue with us. Connected replicas keep
+    /// their streams.
+    pub fn promote(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(master) = state.master.take() {
+            master.task.abort();
+            state.shift_replid(random_hex(REPLID_LENGTH));
+            state.create_backlog();
+        }
+    }
+
+    pub fn set_link_status(&self, status: LinkStatus) {
+        if let Some(master) = self.state.lock().unwrap().master.as_mut() {
+            master.status = status;
+        }
+    }
+
+    /// Records that data arrived from the master.
+    pub fn touch_master(&self) {
+        if let Some(master) = self.state.lock().unwrap().master.as_mut() {
+            master.last_io = now_millis();
+        }
+    }
+
+    /// Adopts the master's history after a full resync: its replication ID
+    /// and the offset its stream continues from. Our own replicas are
+    /// dropped as the dataset they copied was replaced.
+    pub fn start_stream(&self, replid: String, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        state.replid = replid;
+        state.replid2 = None;
+        state.offset = offset;
+        state.replicas.clear();
+        state.backlog = Some(Backlog::new(state.backlog_size));
+        Self::link_up(&mut state);
+    }
+
+    /// Resumes the stream after `+CONTINUE`. A master that announces a
+    /// different ID was promoted since; its ID is adopted and our replicas
+    /// are dropped so they pick it up when they reconnect.
+    pub fn continue_stream(&self, replid: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(replid) = replid.filter(|replid| *replid != state.replid) {
+            state.shift_replid(replid);
+            state.replicas.clear();
+        }
+
+        state.create_backlog();
+        Self::link_up(&mut state);
+    }
+
+    fn link_up(state: &mut ReplicationState) {
+        if let Some(master) = state.master.as_mut() {
+            master.status = LinkStatus::Connected;
+            master.last_io = now_millis();
+        }
+    }
+
+    /// Appends `data` to the replication stream, dropping replicas whose
+    /// connection has gone away or fell too far behind.
+    pub fn feed(&self, data: &Bytes) {
+        let mut state = self.state.lock().unwrap();
+        let limit = state.output_limit;
+
+        state.offset += data.len() as u64;
+
+        if let Some(backlog) = state.backlog.as_mut() {
+            backlog.append(data);
+        }
+
+        state.replicas.retain(|replica| replica.send(data, limit));
+    }
+
+    pub fn role(&self) -> RESPDataTypes {
+        let bulk = |value: String| RESPDataTypes::BulkString(Some(Bytes::from(value)));
+        let state = self.state.lock().unwrap();
+
+        if let Some(master) = state.master.as_ref() {
+            let status = match master.status {
+                LinkStatus::Connecting => "connecting",
+                LinkStatus::Sync => "sync",
+                LinkStatus::Connected => "connected",
+            };
+
+            return RESPDataTypes::Array(Some(vec![
+                bulk("slave".to_string()),
+                bulk(master.host.to_owned()),
+                RESPDataTypes::Integer(master.port as i64),
+                bulk(status.to_string()),
+                RESPDataTypes::Integer(state.offset as i64),
+            ]));
+        }
+
+        RESPDataTypes::Array(Some(vec![
+            bulk("master".to_string()),
+            RESPDataTypes::Integer(state.offset as i64),
+            RESPDataTypes::Array(Some(
+                state
+                    .replicas
+                    .iter()
+                    .map(|replica| {
+                        RESPDataTypes::Array(Some(vec![
+                            bulk(Self::address(replica)),
+                            bulk(replica.listening_port.to_string()),
+                            bulk(replica.acks.offset.load(Ordering::SeqCst).to_string()),
+                        ]))
+                    })
+                    .collect(),
+            )),
+        ]))
+    }
+
+    /// The `# Replication` section of INFO.
+    pub fn info(&self, read_only: bool) -> String {
+        let state = self.state.lock().unwrap();
+        let mut info = String::from("# Replication\r\n");
+
+        if let Some(master) = state.master.as_ref() {
+            let connected = master.status == LinkStatus::Connected;
+
+            writeln!(info, "role:slave\r").unwrap();
+            writeln!(info, "master_host:{}\r", master.host).unwrap();
+            writeln!(info, "master_port:{}\r", master.port).unwrap();
+            writeln!(
+                info,
+                "master_link_status:{}\r",
+                if connected { "up" } else { "down" }
+            )
+            .unwrap();
+            writeln!(
+                info,
+                "master_last_io_seconds_ago:{}\r",
+                if connected {
+                    (now_millis() - master.last_io) / 1000
+                } else {
+                    -1
+                }
+            )
+            .unwrap();
+            writeln!(
+                info,
+                "master_sync_in_progress:{}\r",
+                (master.status == LinkStatus::Sync) as u8
+            )
+            .unwrap();
+            writeln!(info, "slave_read_repl_offset:{}\r", state.offset).unwrap();
+            writeln!(info, "slave_repl_offset:{}\r", state.offset).unwrap();
+            writeln!(info, "slave_priority:100\r").unwrap();
+            writeln!(info, "slave_read_only:{}\r", read_only as u8).unwrap();
+            writeln!(info, "replica_announced:1\r").unwrap();
+        } else {
+            writeln!(info, "role:master\r").unwrap();
+        }
+
+        writeln!(info, "connected_slaves:{}\r", state.replicas.len()).unwrap();
+
+        for (index, replica) in state.replicas.iter().enumerate() {
+            writeln!(
+                info,
+                "slave{index}:ip={},port={},state=online,offset={},lag=0\r",
+                Self::address(replica),
+                replica.listening_port,
+                replica.acks.offset.load(Ordering::SeqCst),
+            )
+            .unwrap();
+        }
+
+        writeln!(info, "master_failover_state:no-failover\r").unwrap();
+        let (replid2, second_offset) = state
+            .replid2
+            .as_ref()
+            .map_or(("0".repeat(REPLID_LENGTH), -1), |(replid2, until)| {
+                (replid2.to_owned(), *until as i64)
+            });
+        let (histlen, first_byte_offset) = state.backlog.as_ref().map_or((0, 0), |backlog| {
+            (backlog.histlen, state.offset + 1 - backlog.histlen as u64)
+        });
+
+        writeln!(info, "master_replid:{}\r", state.replid).unwrap();
+        writeln!(info, "master_replid2:{replid2}\r").unwrap();
+        writeln!(info, "master_repl_offset:{}\r", state.offset).unwrap();
+        writeln!(info, "second_repl_offset:{second_offset}\r").unwrap();
+        writeln!(
+            info,
+            "repl_backlog_active:{}\r",
+            state.backlog.is_some() as u8
+        )
+        .unwrap();
+        writeln!(
+            info,
+            "repl_backlog_size:{}\r",
+            state.backlog_size.max(MIN_BACKLOG_SIZE)
+        )
+        .unwrap();
+        writeln!(info, "repl_backlog_first_byte_offset:{first_byte_offset}\r").unwrap();
+        writeln!(info, "repl_backlog_histlen:{histlen}\r").unwrap();
+
+        info
+    }
+
+    fn address(replica: &Replica) -> String {
+        replica
+            .address
+            .map_or_else(String::new, |address| address.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future, sync::atomic::Ordering};
+
+    use bytes::Bytes;
+
+    use super::{random_hex, Backlog, Replication, MIN_BACKLOG_SIZE};
+    use crate::redis::resp::RESPDataTypes;
+
+    #[test]
+    fn replication_ids() {
+        let replid = random_hex(40);
+
+        assert_eq!(replid.len(), 40);
+        assert!(replid
+            .chars()
+            .all(|character| character.is_ascii_hexdigit()));
+        assert_ne!(replid, random_hex(40));
+    }
+
+    #[test]
+    fn feeds_registered_replicas() {
+        let replication = Replication::new();
+
+        replication.feed(&Bytes::from("*1\r\n$4\r\nPING\r\n"));
+
+        let (replid, offset, mut receiver, acks) = replication.add_replica(7, None, 6380);
+
+        assert_eq!(replid.len(), 40);
+        assert_eq!(offset, 14);
+
+        replication.feed(&Bytes::from("+x\r\n"));
+        acks.offset.store(18, Ordering::SeqCst);
+
+        assert_eq!(receiver.try_recv().unwrap(), Bytes::from("+x\r\n"));
+        assert!(replication.info(true).contains("master_repl_offset:18"));
+        assert!(replication
+            .info(true)
+            .contains("slave0:ip=,port=6380,state=online,offset=18"));
+
+        drop(receiver);
+        replication.feed(&Bytes::from("+y\r\n"));
+
+        assert_eq!(replication.replica_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn drops_replicas_that_fall_too_far_behind() {
+        let replication = Replication::new();
+        let (_, _, mut slow, _) = replication.add_replica(1, None, 6380);
+        let (_, _, mut fast, _) = replication.add_replica(2, None, 6381);
+        let write = Bytes::from("+x\r\n");
+
+        replication.state.lock().unwrap().output_limit = 8;
+        replication.feed(&write);
+        replication.feed(&write);
+
+        assert_eq!(fast.try_recv().unwrap(), write);
+        assert_eq!(fast.try_recv().unwrap(), write);
+
+        replication.feed(&write);
+
+        assert_eq!(replication.replica_count(), 1);
+        assert_eq!(fast.try_recv().unwrap(), write);
+
+        // Writes still queued for the dropped replica are not sent.
+        slow.overflowed().await;
+
+        assert!(slow.recv().await.is_none());
+    }
+
+    #[test]
+    fn backlog_wraps_around() {
+        let mut backlog = Backlog::new(0);
+        let stream = (0..MIN_BACKLOG_SIZE * 3)
+            .map(|index| (index % 251) as u8)
+            .collect::<Vec<_>>();
+
+        backlog.append(&stream[..10]);
+
+        assert_eq!(backlog.tail(4), stream[6..10]);
+
+        for chunk in stream[10..].chunks(1000) {
+            backlog.append(chunk);
+        }
+
+        assert_eq!(backlog.histlen, MIN_BACKLOG_SIZE);
+        assert_eq!(
+            backlog.tail(MIN_BACKLOG_SIZE),
+            stream[stream.len() - MIN_BACKLOG_SIZE..]
+        );
+
+        backlog.resize(MIN_BACKLOG_SIZE * 2);
+        backlog.append(&stream[..5]);
+
+        assert_eq!(backlog.histlen, MIN_BACKLOG_SIZE + 5);
+        assert_eq!(backlog.tail(5), stream[..5]);
+
+        backlog.append(&stream);
+
+        assert_eq!(backlog.tail(backlog.histlen), stream[MIN_BACKLOG_SIZE..]);
+    }
+
+    #[tokio::test]
+    async fn continues_from_the_backlog() {
+        let replication = Replication::new();
+        let ping = Bytes::from("*1\r\n$4\r\nPING\r\n");
+
+        assert!(replication
+            .continue_replica(1, None, 6380, "?", -1)
+            .is_none());
+
+        let (replid, _, _receiver, _) = replication.add_replica(1, None, 6380);
+
+        replication.feed(&ping);
+        replication.feed(&Bytes::from("+x\r\n"));
+
+        let (continued, mut receiver, _) = replication
+            .continue_replica(2, None, 6381, &replid, 15)
+            .unwrap();
+
+        assert_eq!(continued, replid);
+        assert_eq!(receiver.try_recv().unwrap(), Bytes::from("+x\r\n"));
+        assert!(replication
+            .continue_replica(3, None, 6382, &replid, 19)
+            .is_some());
+        assert!(replication
+            .continue_replica(3, None, 6382, &replid, 20)
+            .is_none());
+        assert!(replication
+            .continue_replica(3, None, 6382, &random_hex(40), 15)
+            .is_none());
+        assert!(replication
+            .info(true)
+            .contains("repl_backlog_first_byte_offset:1\r\nrepl_backlog_histlen:18"));
+
+        // After a promotion, replicas of the old history can continue up to
+        // the offset it ended at.
+        replication.replicate_from("127.0.0.1".to_string(), 6379, || {
+            tokio::spawn(future::pending::<()>())
+        });
+        replication.promote();
+        replication.feed(&ping);
+
+        assert_eq!(replication.psync_request().1, 33);
+        assert!(replication
+            .continue_replica(4, None, 6383, &replid, 19)
+            .is_some());
+        assert!(replication
+            .continue_replica(4, None, 6383, &replid, 20)
+            .is_none());
+        assert!(replication
+            .info(true)
+            .contains(&format!("master_replid2:{replid}\r\n")));
+    }
+
+    #[tokio::test]
+    async fn follows_and_leaves_a_master() {
+        let replication = Replication::new();
+        let (_, _, _receiver, _) = replication.add_replica(7, None, 6380);
+        replication.replicate_from("127.0.0.1".to_string(), 6379, || {
+            tokio::spawn(future::pending::<()>())
+        });
+
+        assert!(replication.is_replica());
+        assert_eq!(replication.replica_count(), 0);
+        assert!(replication.info(true).contains("master_link_status:down"));
+
+        replication.start_stream("a".repeat(40), 100);
+        replication.feed(&Bytes::from("+x\r\n"));
+
+        assert_eq!(
+            replication.role(),
+            RESPDataTypes::Array(Some(vec![
+                RESPDataTypes::BulkString(Some(Bytes::from("slave"))),
+                RESPDataTypes::BulkString(Some(Bytes::from("127.0.0.1"))),
+                RESPDataTypes::Integer(6379),
+                RESPDataTypes::BulkString(Some(Bytes::from("connected"))),
+                RESPDataTypes::Integer(104),
+            ]))
+        );
+        assert!(replication.info(false).contains("slave_read_only:0"));
+
+        replication.promote();
+
+        assert!(!replication.is_replica());
+        assert!(!replication
+            .info(true)
+            .contains(&format!("master_replid:{}", "a".repeat(40))));
+        assert!(replication
+            .info(true)
+            .contains(&format!("master_replid2:{}", "a".repeat(40))));
+        assert_eq!(replication.offset(), 104);
+    }
+}
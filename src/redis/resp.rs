@@ -1,211 +1,629 @@
-use std::{
-    collections::{BTreeSet, HashMap},
-    io::{BufRead, BufReader, Read, Write},
-    net::TcpStream,
-};
+use std::fmt::Write;
 
-#[derive(Debug)]
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::redis::error::ProtocolError;
+
+const MAX_HEADER_LENGTH: usize = 64 * 1024;
+const MAX_NESTING_DEPTH: usize = 128;
+
+type ParseResult<T> = Result<Option<T>, ProtocolError>;
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
 pub enum RESPDataTypes {
     SimpleString(String),
     SimpleError(String),
     Integer(i64),
-    BulkString(Option<String>),
-    Array(Vec<RESPDataTypes>),
+    BulkString(Option<Bytes>),
+    Array(Option<Vec<RESPDataTypes>>),
     Null,
     Boolean(bool),
     Double(f64),
     BigNumber(i128),
     BulkError(String),
-    VerbatimString { encoding: String, string: String },
-    Map(HashMap<RESPDataTypes, RESPDataTypes>),
-    Set(BTreeSet<RESPDataTypes>),
+    VerbatimString {
+        encoding: String,
+        string: String,
+    },
+    Map(Vec<(RESPDataTypes, RESPDataTypes)>),
+    Set(Vec<RESPDataTypes>),
     Push(Vec<RESPDataTypes>),
+    Attribute {
+        attributes: Vec<(RESPDataTypes, RESPDataTypes)>,
+        value: Box<RESPDataTypes>,
+    },
 }
 
-impl TryFrom<&TcpStream> for RESPDataTypes {
-    type Error = RESPDataTypes;
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolVersion {
+    RESP2,
+    RESP3,
+}
 
-    fn try_from(value: &TcpStream) -> Result<Self, Self::Error> {
-        Self::deserialize(value)
+/// Bails out of a parse step with `Ok(None)` when the buffer does not hold a
+/// complete frame yet.
+macro_rules! complete {
+    ($parsed:expr) => {
+        match $parsed {
+            Some(parsed) => parsed,
+            None => return Ok(None),
+        }
+    };
+}
+
+/// Upper bounds a peer may announce in a frame header before the parser
+/// gives up on the connection.
+#[derive(Clone, Copy, Debug)]
+pub struct ProtocolLimits {
+    pub max_bulk_length: usize,
+    pub max_multibulk_length: usize,
+}
+
+impl Default for ProtocolLimits {
+    fn default() -> Self {
+        Self {
+            max_bulk_length: 512 * 1024 * 1024,
+            max_multibulk_length: i32::MAX as usize,
+        }
     }
 }
 
 impl TryFrom<&[u8]> for RESPDataTypes {
-    type Error = RESPDataTypes;
+    type Error = ProtocolError;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        Self::deserialize(value)
+        RESPParser::new(value, ProtocolLimits::default())
+            .parse()?
+            .ok_or(ProtocolError::UnexpectedEof)
     }
 }
 
 impl RESPDataTypes {
-    fn deserialize<T>(value: T) -> Result<RESPDataTypes, RESPDataTypes>
-    where
-        T: Read,
-    {
-        let mut resp_buffer_reader = BufReader::new(value);
-        let mut data_type_marker = [u8::default()];
-
-        if resp_buffer_reader.read(&mut data_type_marker).unwrap() == 0 {
-            return Err(RESPDataTypes::Null);
-        }
+    pub fn serialize(&self) -> Bytes {
+        let mut serialized = BytesMut::new();
 
-        let mut resp_parser = RESPParser::new(resp_buffer_reader);
+        self.serialize_into(&mut serialized);
 
-        match &data_type_marker[..] {
-            b"+" => resp_parser.parse_simple_string(),
-            b"$" => resp_parser.parse_bulk_string(),
-            b"*" => resp_parser.parse_array(),
-            _ => Ok(RESPDataTypes::Null),
-        }
+        serialized.freeze()
     }
 
-    pub fn serialize(&self) -> String {
+    fn serialize_into(&self, serialized: &mut BytesMut) {
         use RESPDataTypes::*;
 
         match self {
-            SimpleString(value) => format!("+{value}\r\n"),
-            SimpleError(value) => format!("-{value}\r\n"),
+            SimpleString(value) => write!(serialized, "+{value}\r\n").unwrap(),
+            SimpleError(value) => write!(serialized, "-{value}\r\n").unwrap(),
+            Integer(value) => write!(serialized, ":{value}\r\n").unwrap(),
             BulkString(value) => {
                 if let Some(value) = value {
-                    format!("${}\r\n{value}\r\n", value.len())
+                    Self::serialize_blob('$', value, serialized);
+                } else {
+                    serialized.extend_from_slice(b"$-1\r\n");
+                }
+            }
+            Array(elements) => {
+                if let Some(elements) = elements {
+                    Self::serialize_aggregate('*', elements, serialized);
                 } else {
-                    "-1\r\n".to_string()
+                    serialized.extend_from_slice(b"*-1\r\n");
                 }
             }
-            Null => "_\r\n".to_string(),
-            BulkError(value) => format!("!{}\r\n{value}\r\n", value.len()),
-            _ => "-Error\r\n".to_string(),
+            Null => serialized.extend_from_slice(b"_\r\n"),
+            Boolean(value) => {
+                write!(serialized, "#{}\r\n", if *value { 't' } else { 'f' }).unwrap()
+            }
+            Double(value) => {
+                if value.is_nan() {
+                    serialized.extend_from_slice(b",nan\r\n");
+                } else if value.is_infinite() {
+                    write!(
+                        serialized,
+                        ",{}inf\r\n",
+                        if *value < 0.0 { "-" } else { "" }
+                    )
+                    .unwrap();
+                } else {
+                    write!(serialized, ",{value}\r\n").unwrap();
+                }
+            }
+            BigNumber(value) => write!(serialized, "({value}\r\n").unwrap(),
+            BulkError(value) => Self::serialize_blob('!', value.as_bytes(), serialized),
+            VerbatimString { encoding, string } => {
+                Self::serialize_blob('=', format!("{encoding}:{string}").as_bytes(), serialized)
+            }
+            Map(entries) => Self::serialize_pairs('%', entries, serialized),
+            Set(elements) => Self::serialize_aggregate('~', elements, serialized),
+            Push(elements) => Self::serialize_aggregate('>', elements, serialized),
+            Attribute { attributes, value } => {
+                Self::serialize_pairs('|', attributes, serialized);
+                value.serialize_into(serialized);
+            }
+        }
+    }
+
+    /// Serializes for a connection speaking `protocol`, replacing RESP3-only
+    /// types with their RESP2 equivalents when needed.
+    pub fn serialize_for(self, protocol: ProtocolVersion) -> Bytes {
+        match protocol {
+            ProtocolVersion::RESP2 => self.into_resp2().serialize(),
+            ProtocolVersion::RESP3 => self.serialize(),
+        }
+    }
+
+    pub fn into_resp2(self) -> RESPDataTypes {
+        use RESPDataTypes::*;
+
+        match self {
+            Array(Some(elements)) | Set(elements) | Push(elements) => {
+                Array(Some(elements.into_iter().map(Self::into_resp2).collect()))
+            }
+            Map(entries) => Array(Some(
+                entries
+                    .into_iter()
+                    .flat_map(|(key, value)| [key.into_resp2(), value.into_resp2()])
+                    .collect(),
+            )),
+            Null => BulkString(None),
+            Boolean(value) => Integer(value as i64),
+            Double(value) => BulkString(Some(Bytes::from(match value {
+                value if value.is_nan() => "nan".to_string(),
+                value if value.is_infinite() && value < 0.0 => "-inf".to_string(),
+                value if value.is_infinite() => "inf".to_string(),
+                value => value.to_string(),
+            }))),
+            BigNumber(value) => BulkString(Some(Bytes::from(value.to_string()))),
+            BulkError(value) => SimpleError(value.replace(['\r', '\n'], " ")),
+            VerbatimString { string, .. } => BulkString(Some(Bytes::from(string))),
+            Attribute { value, .. } => value.into_resp2(),
+            value => value,
+        }
+    }
+
+    fn serialize_blob(marker: char, blob: &[u8], serialized: &mut BytesMut) {
+        write!(serialized, "{marker}{}\r\n", blob.len()).unwrap();
+        serialized.extend_from_slice(blob);
+        serialized.extend_from_slice(b"\r\n");
+    }
+
+    fn serialize_pairs(
+        marker: char,
+        entries: &[(RESPDataTypes, RESPDataTypes)],
+        serialized: &mut BytesMut,
+    ) {
+        write!(serialized, "{marker}{}\r\n", entries.len()).unwrap();
+
+        for (key, value) in entries {
+            key.serialize_into(serialized);
+            value.serialize_into(serialized);
+        }
+    }
+
+    fn serialize_aggregate(marker: char, elements: &[RESPDataTypes], serialized: &mut BytesMut) {
+        write!(serialized, "{marker}{}\r\n", elements.len()).unwrap();
+
+        for element in elements {
+            element.serialize_into(serialized);
         }
     }
 }
 
-struct RESPParser<T>
-where
-    T: Read,
-{
-    resp_buffer_reader: BufReader<T>,
+/// Per-connection incremental decoder. Bytes read from the socket are
+/// appended to the buffer and complete frames are taken off its front, so
+/// pipelined requests and frames split across reads are never lost.
+#[derive(Default)]
+pub struct RESPDecoder {
+    buffer: BytesMut,
+    limits: ProtocolLimits,
 }
 
-impl<T> RESPParser<T>
-where
-    T: Read,
-{
-    fn new(resp_buffer_reader: BufReader<T>) -> Self {
-        Self { resp_buffer_reader }
+impl RESPDecoder {
+    pub fn new() -> Self {
+        Self::with_limits(ProtocolLimits::default())
     }
 
-    fn parse_simple_string(&mut self) -> Result<RESPDataTypes, RESPDataTypes> {
-        let mut simple_string = String::new();
+    pub fn with_limits(limits: ProtocolLimits) -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            limits,
+        }
+    }
 
-        self.resp_buffer_reader
-            .read_line(&mut simple_string)
-            .unwrap();
+    pub fn set_limits(&mut self, limits: ProtocolLimits) {
+        self.limits = limits;
+    }
 
-        Ok(RESPDataTypes::SimpleString(
-            simple_string
-                .strip_prefix("+")
-                .unwrap()
-                .strip_suffix("\r\n")
-                .unwrap()
-                .to_owned(),
-        ))
+    /// Gives socket reads direct access to the unparsed bytes.
+    pub fn buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.buffer
     }
 
-    fn parse_bulk_string(&mut self) -> Result<RESPDataTypes, RESPDataTypes> {
-        let mut length = String::new();
+    pub fn decode(&mut self) -> ParseResult<RESPDataTypes> {
+        let mut resp_parser = RESPParser::new(&self.buffer, self.limits);
+        let frame = complete!(resp_parser.parse()?);
+        let consumed = resp_parser.position;
 
-        self.resp_buffer_reader.read_line(&mut length).unwrap();
+        self.buffer.advance(consumed);
 
-        let length = if let Some(striped_string) = length.strip_prefix("$") {
-            striped_string
-        } else {
-            &length
-        };
-        let length = if let Some(striped_string) = length.strip_suffix("\r\n") {
-            striped_string
-        } else {
-            &length
-        };
-        let length = length.parse::<usize>().unwrap();
-        let mut bulk_string = vec![u8::default(); length];
+        Ok(Some(frame))
+    }
 
-        self.resp_buffer_reader
-            .read_exact(&mut bulk_string)
-            .unwrap();
+    /// Decodes the next frame along with the exact bytes it was read from,
+    /// so a replica can relay its master's stream verbatim.
+    pub fn decode_raw(&mut self) -> ParseResult<(RESPDataTypes, Bytes)> {
+        let mut resp_parser = RESPParser::new(&self.buffer, self.limits);
+        let frame = complete!(resp_parser.parse()?);
+        let consumed = resp_parser.position;
 
-        Ok(RESPDataTypes::BulkString(Some(
-            String::from_utf8(bulk_string).unwrap(),
+        Ok(Some((frame, self.buffer.split_to(consumed).freeze())))
+    }
+
+    /// Decodes the RDB payload of a full resync: a bulk string header and
+    /// the file, without a trailing CRLF. Newlines a master sends to keep
+    /// the link alive while it prepares the payload are skipped.
+    pub fn decode_rdb_payload(&mut self) -> ParseResult<Bytes> {
+        let keepalive = self
+            .buffer
+            .iter()
+            .take_while(|byte| **byte == b'\n')
+            .count();
+
+        self.buffer.advance(keepalive);
+
+        let mut resp_parser = RESPParser::new(&self.buffer, self.limits);
+        let length =
+            complete!(resp_parser.read_length(b'$')?).ok_or(ProtocolError::InvalidBulkLength)?;
+        let start = resp_parser.position;
+
+        if self.buffer.len() < start + length {
+            return Ok(None);
+        }
+
+        self.buffer.advance(start);
+
+        Ok(Some(self.buffer.split_to(length).freeze()))
+    }
+}
+
+struct RESPParser<'a> {
+    buffer: &'a [u8],
+    position: usize,
+    depth: usize,
+    limits: ProtocolLimits,
+}
+
+impl<'a> RESPParser<'a> {
+    fn new(buffer: &'a [u8], limits: ProtocolLimits) -> Self {
+        Self {
+            buffer,
+            position: 0,
+            depth: 0,
+            limits,
+        }
+    }
+
+    fn parse(&mut self) -> ParseResult<RESPDataTypes> {
+        let data_type_marker = complete!(self.buffer.get(self.position));
+
+        match data_type_marker {
+            b'+' => self.parse_simple_string(),
+            b'-' => self.parse_simple_error(),
+            b':' => self.parse_integer(),
+            b'$' => self.parse_bulk_string(),
+            b'*' => self.parse_array(),
+            b'_' => self.parse_null(),
+            b'#' => self.parse_boolean(),
+            b',' => self.parse_double(),
+            b'(' => self.parse_big_number(),
+            b'!' => self.parse_bulk_error(),
+            b'=' => self.parse_verbatim_string(),
+            b'%' => self.parse_map(),
+            b'~' => self.parse_set(),
+            b'>' => self.parse_push(),
+            b'|' => self.parse_attribute(),
+            _ => Err(ProtocolError::UnknownMarker(
+                data_type_marker.escape_ascii().to_string(),
+            )),
+        }
+    }
+
+    fn parse_simple_string(&mut self) -> ParseResult<RESPDataTypes> {
+        let simple_string = complete!(self.read_header(b'+')?);
+
+        Ok(Some(RESPDataTypes::SimpleString(simple_string)))
+    }
+
+    fn parse_simple_error(&mut self) -> ParseResult<RESPDataTypes> {
+        let simple_error = complete!(self.read_header(b'-')?);
+
+        Ok(Some(RESPDataTypes::SimpleError(simple_error)))
+    }
+
+    fn parse_integer(&mut self) -> ParseResult<RESPDataTypes> {
+        let integer = complete!(self.read_header(b':')?);
+
+        integer
+            .parse::<i64>()
+            .map(|integer| Some(RESPDataTypes::Integer(integer)))
+            .map_err(|_| ProtocolError::InvalidValue("integer"))
+    }
+
+    fn parse_null(&mut self) -> ParseResult<RESPDataTypes> {
+        complete!(self.read_header(b'_')?);
+
+        Ok(Some(RESPDataTypes::Null))
+    }
+
+    fn parse_boolean(&mut self) -> ParseResult<RESPDataTypes> {
+        match complete!(self.read_header(b'#')?).as_str() {
+            "t" => Ok(Some(RESPDataTypes::Boolean(true))),
+            "f" => Ok(Some(RESPDataTypes::Boolean(false))),
+            _ => Err(ProtocolError::InvalidValue("boolean")),
+        }
+    }
+
+    fn parse_double(&mut self) -> ParseResult<RESPDataTypes> {
+        let double = complete!(self.read_header(b',')?);
+
+        Ok(Some(RESPDataTypes::Double(match double.as_str() {
+            "inf" => f64::INFINITY,
+            "-inf" => f64::NEG_INFINITY,
+            "nan" => f64::NAN,
+            _ => double
+                .parse::<f64>()
+                .map_err(|_| ProtocolError::InvalidValue("double"))?,
+        })))
+    }
+
+    fn parse_big_number(&mut self) -> ParseResult<RESPDataTypes> {
+        let big_number = complete!(self.read_header(b'(')?);
+
+        big_number
+            .parse::<i128>()
+            .map(|big_number| Some(RESPDataTypes::BigNumber(big_number)))
+            .map_err(|_| ProtocolError::InvalidValue("big number"))
+    }
+
+    fn parse_bulk_string(&mut self) -> ParseResult<RESPDataTypes> {
+        let bulk_string = complete!(self.read_blob(b'$')?);
+
+        Ok(Some(RESPDataTypes::BulkString(bulk_string)))
+    }
+
+    fn parse_bulk_error(&mut self) -> ParseResult<RESPDataTypes> {
+        let bulk_error = complete!(self.read_blob(b'!')?).unwrap_or_default();
+
+        Ok(Some(RESPDataTypes::BulkError(
+            String::from_utf8_lossy(&bulk_error).into_owned(),
         )))
     }
 
-    fn parse_array(&mut self) -> Result<RESPDataTypes, RESPDataTypes> {
-        let mut length = String::new();
+    fn parse_verbatim_string(&mut self) -> ParseResult<RESPDataTypes> {
+        let verbatim_string = complete!(self.read_blob(b'=')?).unwrap_or_default();
+        let verbatim_string = String::from_utf8_lossy(&verbatim_string);
+
+        if let Some((encoding, string)) = verbatim_string.split_once(':') {
+            Ok(Some(RESPDataTypes::VerbatimString {
+                encoding: encoding.to_owned(),
+                string: string.to_owned(),
+            }))
+        } else {
+            Err(ProtocolError::InvalidValue("verbatim string"))
+        }
+    }
 
-        self.resp_buffer_reader.read_line(&mut length).unwrap();
+    fn parse_array(&mut self) -> ParseResult<RESPDataTypes> {
+        if let Some(length) = complete!(self.read_length(b'*')?) {
+            let elements = complete!(self.parse_elements(length)?);
 
-        let length = if let Some(striped_string) = length.strip_prefix("*") {
-            striped_string
+            Ok(Some(RESPDataTypes::Array(Some(elements))))
         } else {
-            &length
+            Ok(Some(RESPDataTypes::Array(None)))
+        }
+    }
+
+    fn parse_map(&mut self) -> ParseResult<RESPDataTypes> {
+        let length = complete!(self.read_length(b'%')?).unwrap_or_default();
+        let entries = complete!(self.parse_pairs(length)?);
+
+        Ok(Some(RESPDataTypes::Map(entries)))
+    }
+
+    fn parse_set(&mut self) -> ParseResult<RESPDataTypes> {
+        let length = complete!(self.read_length(b'~')?).unwrap_or_default();
+        let elements = complete!(self.parse_elements(length)?);
+
+        Ok(Some(RESPDataTypes::Set(elements)))
+    }
+
+    fn parse_push(&mut self) -> ParseResult<RESPDataTypes> {
+        let length = complete!(self.read_length(b'>')?).unwrap_or_default();
+        let elements = complete!(self.parse_elements(length)?);
+
+        Ok(Some(RESPDataTypes::Push(elements)))
+    }
+
+    fn parse_attribute(&mut self) -> ParseResult<RESPDataTypes> {
+        self.nested(|parser| {
+            let length = complete!(parser.read_length(b'|')?).unwrap_or_default();
+            let attributes = complete!(parser.parse_pairs(length)?);
+            let value = complete!(parser.parse()?);
+
+            Ok(Some(RESPDataTypes::Attribute {
+                attributes,
+                value: Box::new(value),
+            }))
+        })
+    }
+
+    fn parse_elements(&mut self, length: usize) -> ParseResult<Vec<RESPDataTypes>> {
+        self.nested(|parser| {
+            let mut elements = Vec::with_capacity(length.min(1024));
+
+            for _ in 0..length {
+                elements.push(complete!(parser.parse()?));
+            }
+
+            Ok(Some(elements))
+        })
+    }
+
+    fn parse_pairs(&mut self, length: usize) -> ParseResult<Vec<(RESPDataTypes, RESPDataTypes)>> {
+        self.nested(|parser| {
+            let mut pairs = Vec::with_capacity(length.min(1024));
+
+            for _ in 0..length {
+                let key = complete!(parser.parse()?);
+                let value = complete!(parser.parse()?);
+
+                pairs.push((key, value));
+            }
+
+            Ok(Some(pairs))
+        })
+    }
+
+    /// Runs a parse step one aggregate level deeper. Frames are parsed
+    /// recursively, so the depth is capped to keep a peer from exhausting
+    /// the stack.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> ParseResult<T>) -> ParseResult<T> {
+        if self.depth == MAX_NESTING_DEPTH {
+            return Err(ProtocolError::TooDeeplyNested);
+        }
+
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+
+        parsed
+    }
+
+    /// Reads the line following a type marker, without the marker and the
+    /// trailing CRLF.
+    fn read_header(&mut self, marker: u8) -> ParseResult<String> {
+        let remaining = &self.buffer[self.position..];
+        let line_end =
+            if let Some(line_end) = remaining.windows(2).position(|window| window == b"\r\n") {
+                line_end
+            } else if remaining.len() > MAX_HEADER_LENGTH {
+                return Err(ProtocolError::HeaderTooLong);
+            } else {
+                return Ok(None);
+            };
+
+        if remaining.first() != Some(&marker) {
+            return Err(ProtocolError::UnexpectedMarker {
+                expected: marker.escape_ascii().to_string(),
+                found: remaining[..1].escape_ascii().to_string(),
+            });
+        }
+
+        let header = std::str::from_utf8(&remaining[1..line_end])
+            .map_err(|_| ProtocolError::InvalidValue("header encoding"))?
+            .to_owned();
+
+        self.position += line_end + 2;
+
+        Ok(Some(header))
+    }
+
+    /// Reads a length header, where `-1` stands for a null blob or aggregate.
+    fn read_length(&mut self, marker: u8) -> ParseResult<Option<usize>> {
+        let (limit, error) = match marker {
+            b'$' | b'!' | b'=' => (
+                self.limits.max_bulk_length,
+                ProtocolError::InvalidBulkLength,
+            ),
+            _ => (
+                self.limits.max_multibulk_length,
+                ProtocolError::InvalidMultibulkLength,
+            ),
         };
-        let length = if let Some(striped_string) = length.strip_suffix("\r\n") {
-            striped_string
+        let length = complete!(self.read_header(marker)?);
+
+        if length == "-1" {
+            return Ok(Some(None));
+        }
+
+        match length.parse::<usize>() {
+            Ok(length) if length <= limit => Ok(Some(Some(length))),
+            _ => Err(error),
+        }
+    }
+
+    fn read_blob(&mut self, marker: u8) -> ParseResult<Option<Bytes>> {
+        let start = self.position;
+        let length = if let Some(length) = complete!(self.read_length(marker)?) {
+            length
         } else {
-            &length
+            return Ok(Some(None));
         };
-        let length = length.parse::<usize>().unwrap();
-        let mut elements = Vec::<RESPDataTypes>::new();
+        let blob_end = self.position + length;
 
-        for _ in 0..length {
-            let mut element_buffer = String::new();
+        if self.buffer.len() < blob_end + 2 {
+            self.position = start;
 
-            self.resp_buffer_reader
-                .read_line(&mut element_buffer)
-                .unwrap();
-            self.resp_buffer_reader
-                .read_line(&mut element_buffer)
-                .unwrap();
-            elements.push(RESPDataTypes::try_from(element_buffer.as_bytes())?);
+            return Ok(None);
         }
 
-        Ok(RESPDataTypes::Array(elements))
+        if &self.buffer[blob_end..blob_end + 2] != b"\r\n" {
+            return Err(ProtocolError::MissingCrlf);
+        }
+
+        let blob = Bytes::copy_from_slice(&self.buffer[self.position..blob_end]);
+
+        self.position = blob_end + 2;
+
+        Ok(Some(Some(blob)))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::BufReader;
+    use bytes::Bytes;
 
-    use super::{RESPDataTypes::*, RESPParser};
+    use super::{
+        ProtocolLimits, ProtocolVersion, RESPDataTypes, RESPDataTypes::*, RESPDecoder, RESPParser,
+        MAX_HEADER_LENGTH, MAX_NESTING_DEPTH,
+    };
+    use crate::redis::error::ProtocolError;
 
     #[test]
+    #[allow(clippy::match_like_matches_macro)]
     fn simple_string() {
         assert!(match create_parser("+OK\r\n").parse_simple_string() {
-            Ok(SimpleString(string)) if &string == "OK" => true,
+            Ok(Some(SimpleString(string))) if &string == "OK" => true,
             _ => false,
         });
     }
 
     #[test]
+    #[allow(clippy::match_like_matches_macro)]
     fn bulk_string() {
         assert!(match create_parser("$5\r\nhello\r\n").parse_bulk_string() {
-            Ok(BulkString(Some(string))) if &string == "hello" => true,
+            Ok(Some(BulkString(Some(string)))) if &string == "hello" => true,
             _ => false,
         });
     }
 
     #[test]
+    #[allow(
+        unused_mut,
+        clippy::match_like_matches_macro,
+        clippy::collapsible_match,
+        clippy::needless_bool
+    )]
     fn array() {
         let parsed_array = create_parser("*2\r\n$5\r\nhello\r\n$5\r\nworld\r\n").parse_array();
 
-        assert!(if let Ok(Array(_)) = parsed_array {
+        assert!(if let Ok(Some(Array(_))) = parsed_array {
             true
         } else {
             false
         });
 
-        let mut elements = if let Ok(Array(mut elements)) = parsed_array {
+        let mut elements = if let Ok(Some(Array(Some(mut elements)))) = parsed_array {
             elements
         } else {
             panic!("");
@@ -242,7 +660,350 @@ mod tests {
         });
     }
 
-    fn create_parser(data: &str) -> RESPParser<&[u8]> {
-        RESPParser::new(BufReader::new(data.as_bytes()))
+    #[test]
+    fn serialize_scalars() {
+        assert_eq!(Integer(-42).serialize(), ":-42\r\n");
+        assert_eq!(BulkString(None).serialize(), "$-1\r\n");
+        assert_eq!(Null.serialize(), "_\r\n");
+        assert_eq!(Boolean(true).serialize(), "#t\r\n");
+        assert_eq!(Boolean(false).serialize(), "#f\r\n");
+        assert_eq!(Double(1.5).serialize(), ",1.5\r\n");
+        assert_eq!(Double(f64::NEG_INFINITY).serialize(), ",-inf\r\n");
+        assert_eq!(Double(f64::NAN).serialize(), ",nan\r\n");
+        assert_eq!(
+            BigNumber(-170141183460469231731687303715884105728).serialize(),
+            "(-170141183460469231731687303715884105728\r\n"
+        );
+        assert_eq!(
+            BulkError("SYNTAX invalid syntax".to_string()).serialize(),
+            "!21\r\nSYNTAX invalid syntax\r\n"
+        );
+        assert_eq!(
+            VerbatimString {
+                encoding: "txt".to_string(),
+                string: "Some string".to_string()
+            }
+            .serialize(),
+            "=15\r\ntxt:Some string\r\n"
+        );
+    }
+
+    #[test]
+    fn serialize_aggregates() {
+        assert_eq!(Array(None).serialize(), "*-1\r\n");
+        assert_eq!(Array(Some(Vec::new())).serialize(), "*0\r\n");
+        assert_eq!(
+            Array(Some(vec![
+                Integer(1),
+                Array(Some(vec![BulkString(Some(Bytes::from("a"))), Null])),
+            ]))
+            .serialize(),
+            "*2\r\n:1\r\n*2\r\n$1\r\na\r\n_\r\n"
+        );
+        assert_eq!(
+            Map(vec![(SimpleString("first".to_string()), Integer(1))]).serialize(),
+            "%1\r\n+first\r\n:1\r\n"
+        );
+        assert_eq!(
+            Set(vec![Boolean(true), Double(2.0)]).serialize(),
+            "~2\r\n#t\r\n,2\r\n"
+        );
+        assert_eq!(
+            Push(vec![SimpleString("message".to_string())]).serialize(),
+            ">1\r\n+message\r\n"
+        );
+    }
+
+    #[test]
+    fn downgrade_to_resp2() {
+        assert_eq!(Null.serialize_for(ProtocolVersion::RESP2), "$-1\r\n");
+        assert_eq!(Null.serialize_for(ProtocolVersion::RESP3), "_\r\n");
+        assert_eq!(
+            Boolean(true).serialize_for(ProtocolVersion::RESP2),
+            ":1\r\n"
+        );
+        assert_eq!(
+            Double(1.5).serialize_for(ProtocolVersion::RESP2),
+            "$3\r\n1.5\r\n"
+        );
+        assert_eq!(
+            BulkError("ERR bad".to_string()).serialize_for(ProtocolVersion::RESP2),
+            "-ERR bad\r\n"
+        );
+        assert_eq!(
+            Map(vec![(
+                SimpleString("key".to_string()),
+                Set(vec![Integer(1), Null])
+            )])
+            .serialize_for(ProtocolVersion::RESP2),
+            "*2\r\n+key\r\n*2\r\n:1\r\n$-1\r\n"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = vec![
+            SimpleString("OK".to_string()),
+            SimpleError("ERR unknown command".to_string()),
+            Integer(-1000),
+            BulkString(Some(Bytes::from("hello world"))),
+            BulkString(Some(Bytes::new())),
+            BulkString(None),
+            Array(Some(vec![
+                BulkString(Some(Bytes::from("get"))),
+                BulkString(Some(Bytes::from("key"))),
+            ])),
+            Array(Some(vec![
+                Integer(1),
+                Array(Some(vec![Array(None), Array(Some(Vec::new()))])),
+                Null,
+            ])),
+            Array(None),
+            Null,
+            Boolean(false),
+            Double(-3.25),
+            Double(f64::INFINITY),
+            BigNumber(i128::MAX),
+            BulkError("SYNTAX invalid\r\nsyntax".to_string()),
+            VerbatimString {
+                encoding: "txt".to_string(),
+                string: "a:b".to_string(),
+            },
+            Map(vec![
+                (SimpleString("first".to_string()), Integer(1)),
+                (
+                    BulkString(Some(Bytes::from("second"))),
+                    Set(vec![Boolean(true)]),
+                ),
+            ]),
+            Set(vec![Integer(1), Double(2.5)]),
+            Push(vec![
+                BulkString(Some(Bytes::from("message"))),
+                Map(Vec::new()),
+            ]),
+            Attribute {
+                attributes: vec![(SimpleString("ttl".to_string()), Integer(3600))],
+                value: Box::new(Array(Some(vec![Integer(2039123), Integer(9543892)]))),
+            },
+        ];
+
+        for value in values {
+            assert_eq!(
+                RESPDataTypes::try_from(&value.serialize()[..]).unwrap(),
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn binary_bulk_string() {
+        let mut parser = RESPParser::new(b"$4\r\n\x00\xff\r\n\r\n", ProtocolLimits::default());
+
+        assert_eq!(
+            parser.parse(),
+            Ok(Some(BulkString(Some(Bytes::from_static(b"\x00\xff\r\n")))))
+        );
+        assert_eq!(
+            BulkString(Some(Bytes::from_static(b"\x00\xff"))).serialize(),
+            &b"$2\r\n\x00\xff\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn nested_aggregates() {
+        let parsed = create_parser("*2\r\n*1\r\n:1\r\n%1\r\n+key\r\n~1\r\n#t\r\n").parse();
+
+        assert_eq!(
+            parsed,
+            Ok(Some(Array(Some(vec![
+                Array(Some(vec![Integer(1)])),
+                Map(vec![(
+                    SimpleString("key".to_string()),
+                    Set(vec![Boolean(true)])
+                )]),
+            ]))))
+        );
+    }
+
+    #[test]
+    fn nesting_depth_limit() {
+        let nested = |depth: usize| format!("{}:1\r\n", "*1\r\n".repeat(depth));
+
+        assert!(matches!(
+            create_parser(&nested(MAX_NESTING_DEPTH)).parse(),
+            Ok(Some(Array(_)))
+        ));
+        assert_eq!(
+            create_parser(&nested(MAX_NESTING_DEPTH + 1)).parse(),
+            Err(ProtocolError::TooDeeplyNested)
+        );
+        assert_eq!(
+            create_parser(&format!("{}:1\r\n", "|0\r\n".repeat(200_000))).parse(),
+            Err(ProtocolError::TooDeeplyNested)
+        );
+    }
+
+    #[test]
+    fn consecutive_frames() {
+        let mut parser = create_parser("$-1\r\n*-1\r\n,nan\r\n");
+
+        assert_eq!(parser.parse(), Ok(Some(BulkString(None))));
+        assert_eq!(parser.parse(), Ok(Some(Array(None))));
+        assert!(matches!(parser.parse(), Ok(Some(Double(value))) if value.is_nan()));
+        assert_eq!(parser.parse(), Ok(None));
+    }
+
+    #[test]
+    fn unknown_marker() {
+        assert_eq!(
+            create_parser("?\r\n").parse(),
+            Err(ProtocolError::UnknownMarker("?".to_string()))
+        );
+    }
+
+    #[test]
+    fn malformed_frames() {
+        assert_eq!(
+            create_parser("*x\r\n").parse(),
+            Err(ProtocolError::InvalidMultibulkLength)
+        );
+        assert_eq!(
+            create_parser("$-2\r\n").parse(),
+            Err(ProtocolError::InvalidBulkLength)
+        );
+        assert_eq!(
+            create_parser("$3\r\nabcd\r\n").parse(),
+            Err(ProtocolError::MissingCrlf)
+        );
+        assert_eq!(
+            create_parser(":1.5\r\n").parse(),
+            Err(ProtocolError::InvalidValue("integer"))
+        );
+        assert_eq!(
+            create_parser(&"+".repeat(MAX_HEADER_LENGTH + 1)).parse(),
+            Err(ProtocolError::HeaderTooLong)
+        );
+    }
+
+    #[test]
+    fn decoder_limits() {
+        let mut decoder = RESPDecoder::with_limits(ProtocolLimits {
+            max_bulk_length: 4,
+            max_multibulk_length: 2,
+        });
+
+        decoder
+            .buffer_mut()
+            .extend_from_slice(b"*2\r\n$4\r\nping\r\n$5\r\n");
+
+        assert_eq!(decoder.decode(), Err(ProtocolError::InvalidBulkLength));
+
+        let mut decoder = RESPDecoder::with_limits(ProtocolLimits {
+            max_bulk_length: 4,
+            max_multibulk_length: 2,
+        });
+
+        decoder.buffer_mut().extend_from_slice(b"*3\r\n");
+
+        assert_eq!(decoder.decode(), Err(ProtocolError::InvalidMultibulkLength));
+    }
+
+    #[test]
+    fn decoder_rejects_deep_nesting() {
+        let mut decoder = RESPDecoder::new();
+
+        decoder
+            .buffer_mut()
+            .extend_from_slice(&b"*1\r\n".repeat(200_000));
+        decoder.buffer_mut().extend_from_slice(b":1\r\n");
+
+        assert_eq!(decoder.decode(), Err(ProtocolError::TooDeeplyNested));
+    }
+
+    #[test]
+    fn decoder_keeps_pipelined_frames() {
+        let mut decoder = RESPDecoder::new();
+
+        decoder.buffer_mut().extend_from_slice(
+            b"*1\r\n$4\r\nping\r\n*2\r\n$4\r\necho\r\n$2\r\nhi\r\n*1\r\n$4\r\npi",
+        );
+
+        assert_eq!(
+            decoder.decode(),
+            Ok(Some(Array(Some(vec![BulkString(Some(Bytes::from(
+                "ping"
+            )))]))))
+        );
+        assert_eq!(
+            decoder.decode(),
+            Ok(Some(Array(Some(vec![
+                BulkString(Some(Bytes::from("echo"))),
+                BulkString(Some(Bytes::from("hi"))),
+            ]))))
+        );
+        assert_eq!(decoder.decode(), Ok(None));
+
+        decoder.buffer_mut().extend_from_slice(b"ng\r");
+
+        assert_eq!(decoder.decode(), Ok(None));
+
+        decoder.buffer_mut().extend_from_slice(b"\n");
+
+        assert_eq!(
+            decoder.decode(),
+            Ok(Some(Array(Some(vec![BulkString(Some(Bytes::from(
+                "ping"
+            )))]))))
+        );
+        assert_eq!(decoder.decode(), Ok(None));
+    }
+
+    #[test]
+    fn decoder_reads_rdb_payloads() {
+        let mut decoder = RESPDecoder::new();
+
+        decoder
+            .buffer_mut()
+            .extend_from_slice(b"\n\n$5\r\nREDIS*1\r\n$4\r\nPING\r\n");
+
+        assert_eq!(decoder.decode_rdb_payload(), Ok(Some(Bytes::from("REDIS"))));
+        assert_eq!(
+            decoder.decode_raw(),
+            Ok(Some((
+                RESPDataTypes::Array(Some(vec![RESPDataTypes::BulkString(Some(Bytes::from(
+                    "PING"
+                )))])),
+                Bytes::from("*1\r\n$4\r\nPING\r\n")
+            )))
+        );
+
+        decoder.buffer_mut().extend_from_slice(b"$10\r\nREDIS");
+
+        assert_eq!(decoder.decode_rdb_payload(), Ok(None));
+    }
+
+    #[test]
+    fn decoder_waits_for_split_headers() {
+        let mut decoder = RESPDecoder::new();
+
+        for byte in b"*2\r\n:1\r\n$3\r\nabc\r" {
+            decoder.buffer_mut().extend_from_slice(&[*byte]);
+
+            assert_eq!(decoder.decode(), Ok(None));
+        }
+
+        decoder.buffer_mut().extend_from_slice(b"\n");
+
+        assert_eq!(
+            decoder.decode(),
+            Ok(Some(Array(Some(vec![
+                Integer(1),
+                BulkString(Some(Bytes::from("abc")))
+            ]))))
+        );
+    }
+
+    fn create_parser(data: &str) -> RESPParser<'_> {
+        RESPParser::new(data.as_bytes(), ProtocolLimits::default())
     }
 }
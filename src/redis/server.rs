@@ -1,74 +1,626 @@
 use std::{
-    io::{self, Write},
+    io,
+    sync::{atomic::Ordering, Arc, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    runtime::Builder,
+    signal,
+    sync::oneshot,
+    time,
 };
 
 use crate::{
     executor::ThreadPoolExecutor,
-    redis::{commands::RedisCommand, resp::RESPDataTypes},
+    redis::{
+        aof::{self, AppendOnlyFile},
+        blocking::KeyWaiters,
+        client::Client,
+        commands::RedisCommand,
+        config::{AppendFsync, Config},
+        persistence::SnapshotState,
+        rdb,
+        replication::{ReplicaLink, Replication},
+        resp::{ProtocolLimits, RESPDataTypes, RESPDecoder},
+        stats::ServerStats,
+        store::{KvStore, SHARD_COUNT},
+    },
 };
 
-pub struct Redis {
-    host: &'static str,
-    port: u16,
-    executor: ThreadPoolExecutor,
+const ACTIVE_EXPIRY_INTERVAL: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRY_KEYS_PER_SHARD: usize = 20;
+const ACTIVE_EXPIRY_TIME_BUDGET: Duration = Duration::from_millis(25);
+const SAVE_RULES_INTERVAL: Duration = Duration::from_secs(1);
+const AOF_FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+const REPLICA_PING_INTERVAL: Duration = Duration::from_secs(10);
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
 }
 
-impl Default for Redis {
-    fn default() -> Self {
+/// State shared by every connection.
+pub struct ServerContext {
+    pub store: KvStore,
+    pub config: Config,
+    pub executor: ThreadPoolExecutor,
+    pub snapshots: SnapshotState,
+    pub aof: AppendOnlyFile,
+    /// Taken by writes, see [`ServerContext::lock_write`], and exclusively by
+    /// anything that needs a consistent cut of the keyspace and the stream.
+    pub write_lock: RwLock<()>,
+    pub replication: Replication,
+    pub stats: ServerStats,
+    pub waiters: KeyWaiters,
+}
+
+impl ServerContext {
+    pub fn new(config: Config) -> Self {
+        let replication = Replication::new();
+
+        replication.set_backlog_size(config.values().repl_backlog_size);
+
         Self {
-            host: "127.0.0.1",
-            port: 6379,
+            store: KvStore::new(),
+            config,
             executor: ThreadPoolExecutor::new(),
+            snapshots: SnapshotState::new(),
+            aof: AppendOnlyFile::new(),
+            write_lock: RwLock::new(()),
+            replication,
+            stats: ServerStats::new(),
+            waiters: KeyWaiters::new(),
+        }
+    }
+
+    /// One active expiry cycle. Due keys are evicted a few per shard at a
+    /// time, again while more than a quarter of a batch was due and the time
+    /// budget lasts. Each eviction is recorded as a DEL so the AOF and
+    /// replicas drop the key too; replicas wait for those instead of
+    /// expiring keys themselves. Keys that commands removed lazily and
+    /// that were not recorded yet go first.
+    fn expire_keys(&self) {
+        if self.store.has_lazily_expired() {
+            let _write = self.lock_write();
+
+            self.propagate_expired();
+        }
+
+        if self.replication.is_replica() {
+            return;
+        }
+
+        let started = Instant::now();
+
+        loop {
+            let evicted = {
+                let _write = self.lock_write();
+                let evicted = self.store.evict_expired(ACTIVE_EXPIRY_KEYS_PER_SHARD);
+
+                for key in evicted.iter() {
+                    self.propagate(&[Bytes::from_static(b"DEL"), key.clone()]);
+                }
+
+                evicted.len()
+            };
+
+            self.sync_aof();
+
+            if evicted * 4 <= ACTIVE_EXPIRY_KEYS_PER_SHARD * SHARD_COUNT
+                || started.elapsed() >= ACTIVE_EXPIRY_TIME_BUDGET
+            {
+                break;
+            }
         }
     }
 }
 
-impl Redis {
-    pub fn set_host(&mut self, host: &'static str) {
-        self.host = host;
+pub struct Redis {
+    context: Arc<ServerContext>,
+}
+
+impl Default for Redis {
+    fn default() -> Self {
+        Self::new(Config::default())
     }
+}
 
-    pub fn set_port(&mut self, port: u16) {
-        self.port = port;
+impl Redis {
+    pub fn new(config: Config) -> Self {
+        Self {
+            context: Arc::new(ServerContext::new(config)),
+        }
     }
 
+    /// Serves clients from a small pool of event-loop threads, one per CPU,
+    /// so idle connections cost a task rather than a thread.
     pub fn listen(&mut self) -> io::Result<()> {
-        let listener = TcpListener::bind(format!("{}:{}", self.host, self.port))?;
+        self.load_data()?;
+
+        let worker_threads = thread::available_parallelism().map_or(1, |count| count.get());
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(worker_threads)
+            .thread_name("Redis event loop")
+            .enable_all()
+            .build()?;
+
+        let served = runtime.block_on(async {
+            tokio::select! {
+                served = self.serve() => served,
+                _ = signal::ctrl_c() => Ok(()),
+            }
+        });
+
+        // Let a running background save finish before the final snapshot.
+        self.context.executor.shutdown();
+        self.context.executor.join();
+
+        if let Err(error) = self.context.aof.close() {
+            eprintln!("Error trying to fsync the AOF file: {error}");
+        }
+
+        if !self.context.config.values().save.is_empty() {
+            if let Err(error) = self.context.save() {
+                eprintln!("Error trying to save the DB: {error}");
+            }
+        }
 
-        for stream in listener.incoming().flatten() {
-            self.executor.submit(|| Self::handle(stream));
+        served
+    }
+
+    /// Restores the keyspace from the AOF when it is enabled, as it is the
+    /// more complete record, and from the RDB snapshot otherwise.
+    fn load_data(&self) -> io::Result<()> {
+        if !self.context.config.values().appendonly {
+            return rdb::load(&self.context.snapshot_path(), &self.context.store)
+                .map(|_| ())
+                .map_err(invalid_data);
+        }
+
+        let path = self.context.aof_path();
+
+        if path.exists() {
+            aof::load(&path, &self.context).map_err(invalid_data)?;
+            self.context.aof.open(&path)
+        } else {
+            rdb::load(&self.context.snapshot_path(), &self.context.store).map_err(invalid_data)?;
+            self.context.rewrite_aof_now().map_err(invalid_data)
+        }
+    }
+
+    async fn serve(&self) -> io::Result<()> {
+        let (bind, port) = {
+            let values = self.context.config.values();
+
+            (values.bind.to_owned(), values.port)
+        };
+        let mut listeners = Vec::new();
+
+        for address in bind {
+            listeners.push(TcpListener::bind((address.as_str(), port)).await?);
+        }
+
+        self.start_active_expiry();
+        self.start_save_rules();
+        self.start_aof_fsync();
+        self.start_replica_ping();
+
+        let replicaof = self.context.config.values().replicaof.to_owned();
+
+        if let Some((host, port)) = replicaof {
+            self.context.replicate(host, port);
+        }
+
+        let accept_loops = listeners
+            .into_iter()
+            .map(|listener| tokio::spawn(Self::accept(listener, Arc::clone(&self.context))))
+            .collect::<Vec<_>>();
+
+        for accept_loop in accept_loops {
+            let _ = accept_loop.await;
         }
 
         Ok(())
     }
 
-    fn respond_error(error: RESPDataTypes, stream: &mut TcpStream) {
-        stream.write(error.serialize().as_bytes()).unwrap();
-        stream.flush().unwrap();
+    async fn accept(listener: TcpListener, context: Arc<ServerContext>) {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    // Errors such as running out of file descriptors persist
+                    // until connections close, so wait instead of spinning.
+                    eprintln!("Error accepting a connection: {error}");
+                    time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            let context = Arc::clone(&context);
+
+            tokio::spawn(async move {
+                let stats = &context.stats;
+
+                stats.connections_received.fetch_add(1, Ordering::Relaxed);
+                stats.connected_clients.fetch_add(1, Ordering::Relaxed);
+
+                Self::handle(stream, Arc::clone(&context)).await;
+
+                stats.connected_clients.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    }
+
+    /// Periodically hands an expiry sweep to the background pool so the
+    /// event loops never stall on it. A sweep is skipped while the pool is
+    /// saturated; the next tick catches up.
+    fn start_active_expiry(&self) {
+        let context = Arc::clone(&self.context);
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(ACTIVE_EXPIRY_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                let expiry_context = Arc::clone(&context);
+
+                let _ = context
+                    .executor
+                    .try_submit(move || expiry_context.expire_keys());
+            }
+        });
+    }
+
+    fn start_save_rules(&self) {
+        let context = Arc::clone(&self.context);
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(SAVE_RULES_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                context.run_save_rules();
+            }
+        });
+    }
+
+    /// Syncs the log once a second under `appendfsync everysec`, off the
+    /// event loops.
+    fn start_aof_fsync(&self) {
+        let context = Arc::clone(&self.context);
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(AOF_FSYNC_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                if context.config.values().appendfsync != AppendFsync::EverySec {
+                    continue;
+                }
+
+                let fsync_context = Arc::clone(&context);
+
+                let _ = context
+                    .executor
+                    .try_submit(move || match fsync_context.aof.fsync() {
+                        Ok(()) => fsync_context.replication.acknowledge(),
+                        Err(error) => eprintln!("Error trying to fsync the AOF file: {error}"),
+                    });
+            }
+        });
+    }
+
+    /// Pings replicas periodically so they can tell a quiet master from a
+    /// dead link. A replica relays its master's pings instead.
+    fn start_replica_ping(&self) {
+        let context = Arc::clone(&self.context);
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(REPLICA_PING_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                if context.replication.replica_count() > 0 && !context.replication.is_replica() {
+                    context
+                        .replication
+                        .feed(&aof::serialize_command(&[Bytes::from_static(b"PING")]));
+                }
+            }
+        });
     }
 
-    fn handle(mut stream: TcpStream) {
+    async fn handle(mut stream: TcpStream, context: Arc<ServerContext>) {
+        let mut client = Client::new();
+        let mut decoder = RESPDecoder::new();
+        let mut responses = Vec::new();
+
+        client.address = stream.peer_addr().ok();
+
+        let _ = stream.set_nodelay(true);
+
         loop {
-            match RESPDataTypes::try_from(&stream) {
-                Ok(request) => {
-                    let mut command = match RedisCommand::try_from(request) {
-                        Ok(command) => command,
+            // Picked up on every read so CONFIG SET applies to open connections too.
+            decoder.set_limits(ProtocolLimits {
+                max_bulk_length: context.config.values().proto_max_bulk_len,
+                ..ProtocolLimits::default()
+            });
+
+            loop {
+                match decoder.decode() {
+                    Ok(Some(request)) => match RedisCommand::try_from(request) {
+                        Ok(mut command) => {
+                            command.respond(&mut responses, &context, &mut client);
+
+                            if client.replica_link.is_some() || client.blocked.is_some() {
+                                break;
+                            }
+                        }
                         Err(error) => {
-                            Self::respond_error(error, &mut stream);
-                            return;
+                            context.stats.record_error(&error);
+                            responses.extend_from_slice(&error.serialize_for(client.protocol))
                         }
-                    };
-                    command.respond(&mut stream);
-                }
-                Err(error) => {
-                    if let RESPDataTypes::Null = error {
+                    },
+                    Ok(None) => break,
+                    Err(error) => {
+                        let error = RESPDataTypes::from(error);
+
+                        context.stats.record_error(&error);
+                        responses.extend_from_slice(&error.serialize_for(client.protocol));
+                        let _ = stream.write_all(&responses).await;
+                        let _ = stream.shutdown().await;
+
                         return;
-                    } else if let RESPDataTypes::BulkError(_) = error {
-                        Self::respond_error(error, &mut stream);
                     }
                 }
             }
+
+            if !responses.is_empty() {
+                if stream.write_all(&responses).await.is_err() {
+                    return;
+                }
+
+                context.stats.record_output(responses.len());
+                responses.clear();
+            }
+
+            if let Some(mut blocked) = client.blocked.take() {
+                // Reading on while blocked notices a client that goes away;
+                // what it sends is decoded once the reply is written.
+                context
+                    .stats
+                    .blocked_clients
+                    .fetch_add(1, Ordering::Relaxed);
+
+                let reply = loop {
+                    decoder.buffer_mut().reserve(READ_BUFFER_SIZE);
+
+                    tokio::select! {
+                        reply = &mut blocked => break Some(reply),
+                        read = stream.read_buf(decoder.buffer_mut()) => match read {
+                            Ok(0) | Err(_) => break None,
+                            Ok(read) => context.stats.record_input(read),
+                        },
+                    }
+                };
+
+                context
+                    .stats
+                    .blocked_clients
+                    .fetch_sub(1, Ordering::Relaxed);
+
+                let reply = match reply {
+                    Some(reply) => reply.serialize_for(client.protocol),
+                    None => return,
+                };
+
+                if stream.write_all(&reply).await.is_err() {
+                    return;
+                }
+
+                context.stats.record_output(reply.len());
+
+                continue;
+            }
+
+            if let Some(link) = client.replica_link.take() {
+                let _ = Self::serve_replica(&mut stream, decoder, link, &context).await;
+
+                context.replication.remove_replica(client.id);
+
+                return;
+            }
+
+            let buffer = decoder.buffer_mut();
+
+            buffer.reserve(READ_BUFFER_SIZE);
+
+            match stream.read_buf(buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => context.stats.record_input(read),
+            }
+        }
+    }
+
+    /// Sends the RDB payload of a full resync, then streams every write to
+    /// the replica while recording the offsets it acknowledges. A partial
+    /// resync goes straight to the stream.
+    async fn serve_replica(
+        stream: &mut TcpStream,
+        mut decoder: RESPDecoder,
+        link: ReplicaLink,
+        context: &ServerContext,
+    ) -> io::Result<()> {
+        let ReplicaLink {
+            entries,
+            mut writes,
+            acks,
+        } = link;
+
+        if let Some(entries) = entries {
+            let (payload_sender, payload) = oneshot::channel();
+
+            // A full pool must not stall the event loop; the replica is told
+            // and retries the sync.
+            let submitted = context.executor.try_submit(move || {
+                let _ = payload_sender.send(rdb::to_bytes(&entries));
+            });
+
+            if let Err(error) = submitted {
+                let reply =
+                    RESPDataTypes::SimpleError(format!("ERR Full resync failed to start: {error}"));
+
+                stream.write_all(&reply.serialize()).await?;
+
+                return Err(io::Error::other(error));
+            }
+
+            let payload = payload.await.map_err(io::Error::other)?;
+
+            stream
+                .write_all(format!("${}\r\n", payload.len()).as_bytes())
+                .await?;
+            stream.write_all(&payload).await?;
+        }
+
+        let (mut reader, mut writer) = stream.split();
+
+        loop {
+            decoder.buffer_mut().reserve(READ_BUFFER_SIZE);
+
+            tokio::select! {
+                command = writes.recv() => match command {
+                    // A replica that falls too far behind is dropped, even
+                    // while a write to it is stuck.
+                    Some(command) => tokio::select! {
+                        written = writer.write_all(&command) => written?,
+                        _ = writes.overflowed() => return Ok(()),
+                    },
+                    None => return Ok(()),
+                },
+                read = reader.read_buf(decoder.buffer_mut()) => {
+                    if read? == 0 {
+                        return Ok(());
+                    }
+
+                    while let Some(request) = decoder.decode().map_err(invalid_data)? {
+                        if let Ok(RedisCommand::REPLCONF { options }) = RedisCommand::try_from(request) {
+                            for (option, value) in options {
+                                let offset = std::str::from_utf8(&value)
+                                    .ok()
+                                    .and_then(|offset| offset.parse::<u64>().ok());
+
+                                match (option.as_str(), offset) {
+                                    ("ack", Some(offset)) => acks.offset.store(offset, Ordering::SeqCst),
+                                    ("fack", Some(offset)) => acks.aof_offset.store(offset, Ordering::SeqCst),
+                                    _ => {}
+                                }
+                            }
+
+                            context.replication.acknowledge();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        sync::Arc,
+        thread,
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use tokio::{net::TcpListener, runtime::Builder};
+
+    use super::{Redis, ServerContext};
+    use crate::redis::{
+        aof,
+        config::Config,
+        store::{now_millis, Value},
+    };
+
+    #[test]
+    fn active_expiry_propagates_deletes() {
+        let context = ServerContext::new(Config::default());
+        let (_, _, mut receiver, _) = context.replication.add_replica(0, None, 6380);
+        let expires_at = Some(now_millis() - 1);
+
+        for index in 0..1000 {
+            context.store.insert(
+                Bytes::from(format!("key:{index}")),
+                Value::String(Bytes::new()),
+                expires_at,
+            );
+        }
+
+        context.expire_keys();
+
+        assert_eq!(context.store.stats().keys, 0);
+
+        let deleted = std::iter::from_fn(|| receiver.try_recv().ok()).collect::<Vec<_>>();
+
+        assert_eq!(deleted.len(), 1000);
+        assert!(deleted.contains(&aof::serialize_command(&[
+            Bytes::from("DEL"),
+            Bytes::from("key:7")
+        ])));
+    }
+
+    #[test]
+    fn serves_more_connections_than_event_loop_threads() {
+        let worker_threads = thread::available_parallelism().map_or(1, |count| count.get());
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(worker_threads)
+            .enable_all()
+            .build()
+            .unwrap();
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let address = listener.local_addr().unwrap();
+        let context = Arc::new(ServerContext::new(Config::default()));
+
+        runtime.spawn(Redis::accept(listener, context));
+
+        // Every connection stays open while the others are served.
+        let mut streams = (0..worker_threads * 4 + 1)
+            .map(|_| {
+                let stream = TcpStream::connect(address).unwrap();
+
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+
+                stream
+            })
+            .collect::<Vec<_>>();
+
+        for stream in streams.iter_mut().rev() {
+            stream.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+        }
+
+        for stream in streams.iter_mut() {
+            let mut reply = [0; 7];
+
+            stream.read_exact(&mut reply).unwrap();
+
+            assert_eq!(&reply, b"+PONG\r\n");
         }
     }
 }
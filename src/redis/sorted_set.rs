@@ -0,0 +1,524 @@
+use std::{collections::HashMap, fmt, ops::Range};
+
+use bytes::Bytes;
+
+use crate::redis::replication::random_index;
+
+/// As in Redis: enough levels for 2^64 members with a 1/4 chance of
+/// promotion.
+const MAX_LEVEL: usize = 32;
+const PROMOTION_ODDS: usize = 4;
+const HEAD: usize = 0;
+
+/// One end of a BYSCORE range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScoreBound {
+    pub score: f64,
+    pub exclusive: bool,
+}
+
+/// One end of a BYLEX range, where `-` and `+` are below and above every
+/// member.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(Bytes),
+    Exclusive(Bytes),
+}
+
+#[derive(Clone, Copy)]
+struct Level {
+    forward: Option<usize>,
+    /// How many members the forward link skips over, counting the one it
+    /// lands on, so ranks add up along a search path.
+    span: usize,
+}
+
+#[derive(Clone)]
+struct Node {
+    member: Bytes,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+impl Node {
+    /// Whether the node sorts before `score` and `member`: by score, then
+    /// by member for equal scores.
+    fn precedes(&self, score: f64, member: &[u8]) -> bool {
+        self.score < score || (self.score == score && &self.member[..] < member)
+    }
+}
+
+/// A skiplist ordered by score then member, with spans on every link so a
+/// rank is found in O(log n). Nodes live in one vector and link by index;
+/// the first is a head that holds no member.
+#[derive(Clone)]
+struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    level: usize,
+    length: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node {
+                member: Bytes::new(),
+                score: 0.0,
+                backward: None,
+                levels: vec![
+                    Level {
+                        forward: None,
+                        span: 0,
+                    };
+                    MAX_LEVEL
+                ],
+            }],
+            free: Vec::new(),
+            level: 1,
+            length: 0,
+        }
+    }
+}
+
+impl SkipList {
+    fn random_level() -> usize {
+        let mut level = 1;
+
+        while level < MAX_LEVEL && random_index(PROMOTION_ODDS) == 0 {
+            level += 1;
+        }
+
+        level
+    }
+
+    /// Inserts a member that is not in the list yet.
+    fn insert(&mut self, score: f64, member: Bytes) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut current = HEAD;
+
+        for level in (0..self.level).rev() {
+            rank[level] = if level + 1 == self.level {
+                0
+            } else {
+                rank[level + 1]
+            };
+
+            while let Some(next) = self.nodes[current].levels[level].forward {
+                if !self.nodes[next].precedes(score, &member) {
+                    break;
+                }
+
+                rank[level] += self.nodes[current].levels[level].span;
+                current = next;
+            }
+
+            update[level] = current;
+        }
+
+        let node_level = Self::random_level();
+
+        if node_level > self.level {
+            for level in self.level..node_level {
+                self.nodes[HEAD].levels[level].span = self.length;
+            }
+
+            self.level = node_level;
+        }
+
+        let node = Node {
+            member,
+            score,
+            backward: (update[0] != HEAD).then_some(update[0]),
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0,
+                };
+                node_level
+            ],
+        };
+        let index = if let Some(index) = self.free.pop() {
+            self.nodes[index] = node;
+            index
+        } else {
+            self.nodes.push(node);
+            self.nodes.len() - 1
+        };
+
+        for level in 0..node_level {
+            let previous = &mut self.nodes[update[level]].levels[level];
+            let skipped = rank[0] - rank[level];
+            let link = Level {
+                forward: previous.forward,
+                span: previous.span - skipped,
+            };
+
+            *previous = Level {
+                forward: Some(index),
+                span: skipped + 1,
+            };
+            self.nodes[index].levels[level] = link;
+        }
+
+        for (level, previous) in update.iter().enumerate().take(self.level).skip(node_level) {
+            self.nodes[*previous].levels[level].span += 1;
+        }
+
+        if let Some(next) = self.nodes[index].levels[0].forward {
+            self.nodes[next].backward = Some(index);
+        }
+
+        self.length += 1;
+    }
+
+    fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut current = HEAD;
+
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.nodes[current].levels[level].forward {
+                if !self.nodes[next].precedes(score, member) {
+                    break;
+                }
+
+                current = next;
+            }
+
+            update[level] = current;
+        }
+
+        let index = match self.nodes[current].levels[0].forward {
+            Some(next) if self.nodes[next].score == score && self.nodes[next].member == member => {
+                next
+            }
+            _ => return false,
+        };
+
+        for (level, previous) in update.iter().enumerate().take(self.level) {
+            let removed = self.nodes[index].levels.get(level).copied();
+            let previous = &mut self.nodes[*previous].levels[level];
+
+            match removed {
+                Some(removed) if previous.forward == Some(index) => {
+                    *previous = Level {
+                        forward: removed.forward,
+                        span: previous.span + removed.span - 1,
+                    };
+                }
+                _ => previous.span -= 1,
+            }
+        }
+
+        if let Some(next) = self.nodes[index].levels[0].forward {
+            self.nodes[next].backward = self.nodes[index].backward;
+        }
+
+        while self.level > 1 && self.nodes[HEAD].levels[self.level - 1].forward.is_none() {
+            self.level -= 1;
+        }
+
+        self.nodes[index].member = Bytes::new();
+        self.free.push(index);
+        self.length -= 1;
+
+        true
+    }
+
+    /// How many members sort before the first one `before` rejects.
+    /// `before` must hold for a prefix of the list and nothing after it.
+    fn count_before<F>(&self, before: F) -> usize
+    where
+        F: Fn(f64, &[u8]) -> bool,
+    {
+        let mut rank = 0;
+        let mut current = HEAD;
+
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.nodes[current].levels[level].forward {
+                if !before(self.nodes[next].score, &self.nodes[next].member) {
+                    break;
+                }
+
+                rank += self.nodes[current].levels[level].span;
+                current = next;
+            }
+        }
+
+        rank
+    }
+
+    /// The node at the 0 based `rank`.
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        if rank >= self.length {
+            return None;
+        }
+
+        let mut traversed = 0;
+        let mut current = HEAD;
+
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.nodes[current].levels[level].forward {
+                if traversed + self.nodes[current].levels[level].span > rank + 1 {
+                    break;
+                }
+
+                traversed += self.nodes[current].levels[level].span;
+                current = next;
+            }
+
+            if traversed == rank + 1 {
+                return Some(current);
+            }
+        }
+
+        None
+    }
+}
+
+/// A sorted set: members with scores, kept in a map for score lookups and
+/// in a skiplist for ordered and ranked access.
+#[derive(Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    list: SkipList,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Adds `member` or moves it to `score`. Returns whether it was added.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(previous) if previous == score => false,
+            Some(previous) => {
+                self.list.remove(previous, &member);
+                self.list.insert(score, member);
+
+                false
+            }
+            None => {
+                self.list.insert(score, member);
+
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.list.remove(score, member),
+            None => false,
+        }
+    }
+
+    /// The 0 based rank of `member` by ascending score.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+
+        Some(self.list.count_before(|other_score, other_member| {
+            other_score < score || (other_score == score && other_member < member)
+        }))
+    }
+
+    /// The ranks of the members scored between `min` and `max`.
+    pub fn score_range(&self, min: &ScoreBound, max: &ScoreBound) -> Range<usize> {
+        let start = self
+            .list
+            .count_before(|score, _| score < min.score || (min.exclusive && score == min.score));
+        let end = self
+            .list
+            .count_before(|score, _| score < max.score || (!max.exclusive && score == max.score));
+
+        start..end.max(start)
+    }
+
+    /// The ranks of the members between `min` and `max`, when every member
+    /// has the same score.
+    pub fn lex_range(&self, min: &LexBound, max: &LexBound) -> Range<usize> {
+        let start = self.list.count_before(|_, member| match min {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member < &bound[..],
+            LexBound::Exclusive(bound) => member <= &bound[..],
+        });
+        let end = self.list.count_before(|_, member| match max {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member <= &bound[..],
+            LexBound::Exclusive(bound) => member < &bound[..],
+        });
+
+        start..end.max(start)
+    }
+
+    /// The members at `ranks`, walked from either end of the range.
+    pub fn range(
+        &self,
+        ranks: Range<usize>,
+        reverse: bool,
+    ) -> impl Iterator<Item = (&Bytes, f64)> + '_ {
+        let first = if reverse {
+            ranks.end.checked_sub(1)
+        } else {
+            Some(ranks.start)
+        };
+        let mut current = if ranks.is_empty() {
+            None
+        } else {
+            first.and_then(|rank| self.list.node_at(rank))
+        };
+
+        (0..ranks.len()).map_while(move |_| {
+            let node = &self.list.nodes[current?];
+
+            current = if reverse {
+                node.backward
+            } else {
+                node.levels[0].forward
+            };
+
+            Some((&node.member, node.score))
+        })
+    }
+
+    /// Every member by ascending score.
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, f64)> + '_ {
+        self.range(0..self.len(), false)
+    }
+
+    /// Removes the members at `ranks`, returning them.
+    pub fn remove_range(&mut self, ranks: Range<usize>) -> Vec<Bytes> {
+        let members = self
+            .range(ranks, false)
+            .map(|(member, _)| member.clone())
+            .collect::<Vec<_>>();
+
+        for member in members.iter() {
+            self.remove(member);
+        }
+
+        members
+    }
+}
+
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl fmt::Debug for SortedSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{LexBound, ScoreBound, SortedSet};
+
+    fn members<'a>(iter: impl Iterator<Item = (&'a Bytes, f64)>) -> Vec<String> {
+        iter.map(|(member, _)| String::from_utf8(member.to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ranks_follow_scores_then_members() {
+        let mut zset = SortedSet::new();
+
+        for index in 0..1000 {
+            assert!(zset.insert(Bytes::from(format!("m{index:04}")), (index / 10) as f64));
+        }
+
+        assert!(!zset.insert(Bytes::from("m0500"), 1000.0));
+        assert!(zset.remove(b"m0001"));
+        assert!(!zset.remove(b"m0001"));
+        assert_eq!(zset.len(), 999);
+        assert_eq!(zset.rank(b"m0000"), Some(0));
+        assert_eq!(zset.rank(b"m0002"), Some(1));
+        assert_eq!(zset.rank(b"m0500"), Some(998));
+        assert_eq!(zset.rank(b"m0999"), Some(997));
+        assert_eq!(zset.rank(b"missing"), None);
+        assert_eq!(
+            members(zset.range(996..999, true)),
+            ["m0500", "m0999", "m0998"]
+        );
+
+        let ranks = (0..zset.len())
+            .map(|rank| members(zset.range(rank..rank + 1, false)).remove(0))
+            .collect::<Vec<_>>();
+
+        assert_eq!(ranks, members(zset.iter()));
+
+        for (rank, member) in ranks.iter().enumerate() {
+            assert_eq!(zset.rank(member.as_bytes()), Some(rank));
+        }
+    }
+
+    #[test]
+    fn score_and_lex_ranges() {
+        let mut zset = SortedSet::new();
+
+        for (member, score) in [("a", 1.0), ("b", 2.0), ("c", 2.0), ("d", 3.0)] {
+            zset.insert(Bytes::from(member), score);
+        }
+
+        let bound = |score, exclusive| ScoreBound { score, exclusive };
+
+        assert_eq!(
+            zset.score_range(&bound(2.0, false), &bound(2.0, false)),
+            1..3
+        );
+        assert_eq!(zset.score_range(&bound(1.0, true), &bound(3.0, true)), 1..3);
+        assert_eq!(
+            zset.score_range(
+                &bound(f64::NEG_INFINITY, false),
+                &bound(f64::INFINITY, false)
+            ),
+            0..4
+        );
+        assert!(zset
+            .score_range(&bound(3.0, false), &bound(1.0, false))
+            .is_empty());
+        assert_eq!(
+            zset.remove_range(1..3),
+            [Bytes::from("b"), Bytes::from("c")]
+        );
+        assert_eq!(members(zset.iter()), ["a", "d"]);
+
+        let mut zset = SortedSet::new();
+
+        for member in ["a", "b", "c", "d"] {
+            zset.insert(Bytes::from(member), 0.0);
+        }
+
+        assert_eq!(zset.lex_range(&LexBound::Min, &LexBound::Max), 0..4);
+        assert_eq!(
+            zset.lex_range(
+                &LexBound::Exclusive(Bytes::from("a")),
+                &LexBound::Inclusive(Bytes::from("c"))
+            ),
+            1..3
+        );
+        assert!(zset
+            .lex_range(&LexBound::Max, &LexBound::Inclusive(Bytes::from("c")))
+            .is_empty());
+    }
+}
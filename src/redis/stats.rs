@@ -0,0 +1,518 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    fmt::Write,
+    fs, process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::redis::{
+    commands::REDIS_VERSION, replication::random_hex, resp::RESPDataTypes, server::ServerContext,
+    store::now_millis,
+};
+
+const RUN_ID_LENGTH: usize = 40;
+/// Sections INFO returns without arguments; `commandstats` has to be asked
+/// for.
+const DEFAULT_SECTIONS: &[&str] = &[
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "cpu",
+    "errorstats",
+    "keyspace",
+];
+const ALL_SECTIONS: &[&str] = &[
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "cpu",
+    "commandstats",
+    "errorstats",
+    "keyspace",
+];
+/// Linux reports CPU time in clock ticks, which are 100 per second on
+/// every platform it supports in practice.
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Default)]
+struct CommandStats {
+    calls: u64,
+    usec: u64,
+    rejected_calls: u64,
+    failed_calls: u64,
+}
+
+/// Counters behind INFO, recorded as connections are served and commands
+/// run.
+pub struct ServerStats {
+    started: Instant,
+    run_id: String,
+    pub connections_received: AtomicU64,
+    pub connected_clients: AtomicU64,
+    pub blocked_clients: AtomicU64,
+    net_input_bytes: AtomicU64,
+    net_output_bytes: AtomicU64,
+    pub sync_full: AtomicU64,
+    pub sync_partial_ok: AtomicU64,
+    pub sync_partial_err: AtomicU64,
+    commands_processed: AtomicU64,
+    error_replies: AtomicU64,
+    peak_memory: AtomicU64,
+    commands: Mutex<HashMap<&'static str, CommandStats>>,
+    errors: Mutex<HashMap<String, u64>>,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            run_id: random_hex(RUN_ID_LENGTH),
+            connections_received: AtomicU64::new(0),
+            connected_clients: AtomicU64::new(0),
+            blocked_clients: AtomicU64::new(0),
+            net_input_bytes: AtomicU64::new(0),
+            net_output_bytes: AtomicU64::new(0),
+            sync_full: AtomicU64::new(0),
+            sync_partial_ok: AtomicU64::new(0),
+            sync_partial_err: AtomicU64::new(0),
+            commands_processed: AtomicU64::new(0),
+            error_replies: AtomicU64::new(0),
+            peak_memory: AtomicU64::new(0),
+            commands: Mutex::new(HashMap::new()),
+            errors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record_input(&self, bytes: usize) {
+        self.net_input_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_output(&self, bytes: usize) {
+        self.net_output_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records a command that ran, and its reply when that is an error.
+    pub fn record_call(
+        &self,
+        command: &'static str,
+        duration: Duration,
+        reply: Option<&RESPDataTypes>,
+    ) {
+        let failed = reply.is_some_and(|reply| self.record_error(reply));
+        let mut commands = self.commands.lock().unwrap();
+        let stats = commands.entry(command).or_default();
+
+        stats.calls += 1;
+        stats.usec += duration.as_micros() as u64;
+        stats.failed_calls += failed as u64;
+        self.commands_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a command that was refused before it ran.
+    pub fn record_rejected(&self, command: &'static str, reply: &RESPDataTypes) {
+        self.record_error(reply);
+        self.commands
+            .lock()
+            .unwrap()
+            .entry(command)
+            .or_default()
+            .rejected_calls += 1;
+    }
+
+    /// Counts `reply` under its error code, such as `ERR` or `READONLY`.
+    /// Returns whether it was an error.
+    pub fn record_error(&self, reply: &RESPDataTypes) -> bool {
+        let message = match reply {
+            RESPDataTypes::SimpleError(message) | RESPDataTypes::BulkError(message) => message,
+            _ => return false,
+        };
+        let code = message.split(' ').next().unwrap_or_default();
+
+        *self
+            .errors
+            .lock()
+            .unwrap()
+            .entry(code.to_string())
+            .or_default() += 1;
+        self.error_replies.fetch_add(1, Ordering::Relaxed);
+
+        true
+    }
+
+    fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+impl ServerContext {
+    /// The text of `INFO [section ...]`: the default sections when none are
+    /// named, every section for `all` or `everything`.
+    pub fn info(&self, sections: &[String]) -> String {
+        let requested = |section: &str| {
+            sections.iter().any(|requested| match requested.as_str() {
+                "all" | "everything" => true,
+                "default" => DEFAULT_SECTIONS.contains(&section),
+                requested => requested == section,
+            }) || (sections.is_empty() && DEFAULT_SECTIONS.contains(&section))
+        };
+
+        ALL_SECTIONS
+            .iter()
+            .filter(|section| requested(section))
+            .map(|section| match *section {
+                "server" => self.server_info(),
+                "clients" => self.clients_info(),
+                "memory" => self.memory_info(),
+                "persistence" => self.persistence_info(),
+                "stats" => self.stats_info(),
+                "replication" => self
+                    .replication
+                    .info(self.config.values().replica_read_only),
+                "cpu" => cpu_info(),
+                "commandstats" => self.commandstats_info(),
+                "errorstats" => self.errorstats_info(),
+                _ => self.keyspace_info(),
+            })
+            .collect::<Vec<_>>()
+            .join("\r\n")
+    }
+
+    fn server_info(&self) -> String {
+        let uptime = self.stats.uptime().as_secs();
+        let mut info = String::from("# Server\r\n");
+
+        writeln!(info, "redis_version:{REDIS_VERSION}\r").unwrap();
+        writeln!(info, "redis_mode:standalone\r").unwrap();
+        writeln!(info, "os:{} {}\r", env::consts::OS, env::consts::ARCH).unwrap();
+        writeln!(info, "arch_bits:{}\r", usize::BITS).unwrap();
+        writeln!(info, "process_id:{}\r", process::id()).unwrap();
+        writeln!(info, "run_id:{}\r", self.stats.run_id).unwrap();
+        writeln!(info, "tcp_port:{}\r", self.config.values().port).unwrap();
+        writeln!(info, "server_time_usec:{}\r", now_millis() * 1000).unwrap();
+        writeln!(info, "uptime_in_seconds:{uptime}\r").unwrap();
+        writeln!(info, "uptime_in_days:{}\r", uptime / 86400).unwrap();
+        writeln!(
+            info,
+            "executable:{}\r",
+            env::current_exe()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default()
+        )
+        .unwrap();
+        writeln!(
+            info,
+            "config_file:{}\r",
+            self.config
+                .path()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default()
+        )
+        .unwrap();
+
+        info
+    }
+
+    fn clients_info(&self) -> String {
+        let mut info = String::from("# Clients\r\n");
+
+        writeln!(
+            info,
+            "connected_clients:{}\r",
+            self.stats.connected_clients.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        writeln!(
+            info,
+            "blocked_clients:{}\r",
+            self.stats.blocked_clients.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        info
+    }
+
+    /// No allocator statistics are available, so memory is reported as the
+    /// resident size of the process.
+    fn memory_info(&self) -> String {
+        let used = resident_memory();
+        let peak = self
+            .stats
+            .peak_memory
+            .fetch_max(used, Ordering::Relaxed)
+            .max(used);
+        let mut info = String::from("# Memory\r\n");
+
+        writeln!(info, "used_memory:{used}\r").unwrap();
+        writeln!(info, "used_memory_human:{}\r", human_bytes(used)).unwrap();
+        writeln!(info, "used_memory_rss:{used}\r").unwrap();
+        writeln!(info, "used_memory_peak:{peak}\r").unwrap();
+        writeln!(info, "used_memory_peak_human:{}\r", human_bytes(peak)).unwrap();
+        writeln!(info, "maxmemory:0\r").unwrap();
+        writeln!(info, "maxmemory_policy:noeviction\r").unwrap();
+        writeln!(info, "mem_allocator:libc\r").unwrap();
+
+        info
+    }
+
+    fn stats_info(&self) -> String {
+        let stats = &self.stats;
+        let keyspace = self.store.stats();
+        let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut info = String::from("# Stats\r\n");
+
+        writeln!(
+            info,
+            "total_connections_received:{}\r",
+            counter(&stats.connections_received)
+        )
+        .unwrap();
+        writeln!(
+            info,
+            "total_commands_processed:{}\r",
+            counter(&stats.commands_processed)
+        )
+        .unwrap();
+        writeln!(
+            info,
+            "total_net_input_bytes:{}\r",
+            counter(&stats.net_input_bytes)
+        )
+        .unwrap();
+        writeln!(
+            info,
+            "total_net_output_bytes:{}\r",
+            counter(&stats.net_output_bytes)
+        )
+        .unwrap();
+        writeln!(info, "expired_keys:{}\r", keyspace.expired).unwrap();
+        writeln!(info, "evicted_keys:0\r").unwrap();
+        writeln!(info, "keyspace_hits:{}\r", keyspace.hits).unwrap();
+        writeln!(info, "keyspace_misses:{}\r", keyspace.misses).unwrap();
+        writeln!(info, "sync_full:{}\r", counter(&stats.sync_full)).unwrap();
+        writeln!(
+            info,
+            "sync_partial_ok:{}\r",
+            counter(&stats.sync_partial_ok)
+        )
+        .unwrap();
+        writeln!(
+            info,
+            "sync_partial_err:{}\r",
+            counter(&stats.sync_partial_err)
+        )
+        .unwrap();
+        writeln!(
+            info,
+            "total_error_replies:{}\r",
+            counter(&stats.error_replies)
+        )
+        .unwrap();
+
+        for worker in self.executor.stats() {
+            writeln!(
+                info,
+                "executor_thread{}:queue_depth={},busy={},tasks_run={},panics={},respawns={}\r",
+                worker.thread_number,
+                worker.queue_depth,
+                worker.busy as u8,
+                worker.tasks_run,
+                worker.panics,
+                worker.respawns,
+            )
+            .unwrap();
+        }
+
+        info
+    }
+
+    fn commandstats_info(&self) -> String {
+        let commands = self.stats.commands.lock().unwrap();
+        let mut info = String::from("# Commandstats\r\n");
+
+        for (command, stats) in commands.iter().collect::<BTreeMap<_, _>>() {
+            writeln!(
+                info,
+                "cmdstat_{command}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}\r",
+                stats.calls,
+                stats.usec,
+                stats.usec as f64 / stats.calls.max(1) as f64,
+                stats.rejected_calls,
+                stats.failed_calls,
+            )
+            .unwrap();
+        }
+
+        info
+    }
+
+    fn errorstats_info(&self) -> String {
+        let errors = self.stats.errors.lock().unwrap();
+        let mut info = String::from("# Errorstats\r\n");
+
+        for (code, count) in errors.iter().collect::<BTreeMap<_, _>>() {
+            writeln!(info, "errorstat_{code}:count={count}\r").unwrap();
+        }
+
+        info
+    }
+
+    fn keyspace_info(&self) -> String {
+        let keyspace = self.store.stats();
+        let mut info = String::from("# Keyspace\r\n");
+
+        if keyspace.keys > 0 {
+            writeln!(
+                info,
+                "db0:keys={},expires={},avg_ttl=0\r",
+                keyspace.keys, keyspace.expires
+            )
+            .unwrap();
+        }
+
+        info
+    }
+}
+
+/// CPU time from `/proc/self/stat`, zero where it is not available.
+fn cpu_info() -> String {
+    let times = fs::read_to_string("/proc/self/stat")
+        .ok()
+        .and_then(|stat| {
+            // Fields after the parenthesised command name, which may contain
+            // spaces; user and system time are the 14th and 15th fields.
+            let fields = stat[stat.rfind(')')? + 2..]
+                .split(' ')
+                .map(str::to_owned)
+                .collect::<Vec<_>>();
+
+            Some((
+                fields.get(11)?.parse::<u64>().ok()?,
+                fields.get(12)?.parse::<u64>().ok()?,
+            ))
+        })
+        .unwrap_or_default();
+    let mut info = String::from("# CPU\r\n");
+
+    writeln!(
+        info,
+        "used_cpu_sys:{:.6}\r",
+        times.1 as f64 / CLOCK_TICKS_PER_SECOND
+    )
+    .unwrap();
+    writeln!(
+        info,
+        "used_cpu_user:{:.6}\r",
+        times.0 as f64 / CLOCK_TICKS_PER_SECOND
+    )
+    .unwrap();
+
+    info
+}
+
+fn resident_memory() -> u64 {
+    fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split(' ').nth(1)?.parse::<u64>().ok())
+        .map_or(0, |pages| pages * PAGE_SIZE)
+}
+
+fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{value:.2}{}", units[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::human_bytes;
+    use crate::redis::{config::Config, resp::RESPDataTypes, server::ServerContext};
+
+    #[test]
+    fn sections() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let default = context.info(&[]);
+
+        assert!(default.starts_with("# Server\r\nredis_version:"));
+        assert!(default.contains("\r\n\r\n# Clients\r\n"));
+        assert!(!default.contains("# Commandstats"));
+        assert!(context
+            .info(&["all".to_string()])
+            .contains("# Commandstats"));
+        assert_eq!(
+            context
+                .info(&["cpu".to_string(), "keyspace".to_string()])
+                .lines()
+                .filter(|line| line.starts_with('#'))
+                .collect::<Vec<_>>(),
+            vec!["# CPU", "# Keyspace"]
+        );
+        assert_eq!(context.info(&["nonsense".to_string()]), "");
+    }
+
+    #[test]
+    fn command_and_error_stats() {
+        let context = Arc::new(ServerContext::new(Config::default()));
+        let stats = &context.stats;
+
+        stats.record_call("get", Duration::from_micros(10), None);
+        stats.record_call(
+            "get",
+            Duration::from_micros(20),
+            Some(&RESPDataTypes::BulkError("ERR boom".to_string())),
+        );
+        stats.record_rejected(
+            "set",
+            &RESPDataTypes::SimpleError("READONLY no writes".to_string()),
+        );
+
+        let info = context.info(&["commandstats".to_string(), "errorstats".to_string()]);
+
+        assert!(info.contains(
+            "cmdstat_get:calls=2,usec=30,usec_per_call=15.00,rejected_calls=0,failed_calls=1\r\n"
+        ));
+        assert!(info.contains(
+            "cmdstat_set:calls=0,usec=0,usec_per_call=0.00,rejected_calls=1,failed_calls=0\r\n"
+        ));
+        assert!(info.contains("errorstat_ERR:count=1\r\nerrorstat_READONLY:count=1\r\n"));
+    }
+
+    #[test]
+    fn human_readable_sizes() {
+        assert_eq!(human_bytes(512), "512B");
+        assert_eq!(human_bytes(1536), "1.50K");
+        assert_eq!(human_bytes(3 * 1024 * 1024 * 1024), "3.00G");
+    }
+}
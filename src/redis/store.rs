@@ -0,0 +1,1274 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap, HashSet, VecDeque},
+    hash::{Hash as _, Hasher},
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, RwLockWriteGuard,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+
+use crate::redis::{error::WrongType, sorted_set::SortedSet};
+
+pub const SHARD_COUNT: usize = 16;
+
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+pub type List = VecDeque<Bytes>;
+pub type Hash = HashMap<Bytes, Bytes>;
+pub type Set = HashSet<Bytes>;
+
+/// A key with its value and deadline, as `KvStore::snapshot` copies it.
+pub type SnapshotEntry = (Bytes, Arc<Value>, Option<i64>);
+
+/// A value held in the keyspace.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    List(List),
+    Hash(Hash),
+    Set(Set),
+    SortedSet(SortedSet),
+}
+
+impl Value {
+    /// The name TYPE reports.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+        }
+    }
+}
+
+/// One kind of `Value`, as commands of that type see it. Commands reach a
+/// key through its kind, and a key holding another kind is refused with
+/// `WRONGTYPE`.
+pub trait ValueType: Default {
+    fn from_value(value: &Value) -> Option<&Self>;
+
+    fn from_value_mut(value: &mut Value) -> Option<&mut Self>;
+
+    fn into_value(self) -> Value;
+
+    /// Collections are removed with their key once they become empty.
+    fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+impl ValueType for Bytes {
+    fn from_value(value: &Value) -> Option<&Self> {
+        match value {
+            Value::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    fn from_value_mut(value: &mut Value) -> Option<&mut Self> {
+        match value {
+            Value::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        Value::String(self)
+    }
+}
+
+/// Implements `ValueType` for a collection held by the `Value` variant of
+/// the same name.
+macro_rules! collection_value_type {
+    ($collection:ident) => {
+        impl ValueType for $collection {
+            fn from_value(value: &Value) -> Option<&Self> {
+                match value {
+                    Value::$collection(collection) => Some(collection),
+                    _ => None,
+                }
+            }
+
+            fn from_value_mut(value: &mut Value) -> Option<&mut Self> {
+                match value {
+                    Value::$collection(collection) => Some(collection),
+                    _ => None,
+                }
+            }
+
+            fn into_value(self) -> Value {
+                Value::$collection(self)
+            }
+
+            fn is_empty(&self) -> bool {
+                self.len() == 0
+            }
+        }
+    };
+}
+
+collection_value_type!(List);
+collection_value_type!(Hash);
+collection_value_type!(Set);
+collection_value_type!(SortedSet);
+
+struct Entry {
+    /// Shared with snapshots taken since the last write, which copies it.
+    value: Arc<Value>,
+    expires_at: Option<i64>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: i64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+/// One slice of the keyspace. Keys with a deadline are also indexed by it,
+/// so active expiry finds the keys that are due without walking the rest.
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<Bytes, Entry>,
+    deadlines: BTreeSet<(i64, Bytes)>,
+}
+
+impl Shard {
+    fn get(&self, key: &[u8]) -> Option<&Entry> {
+        self.entries.get(key)
+    }
+
+    /// The entry at `key`, whose deadline is changed with `set_deadline`
+    /// so the index follows.
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        self.entries.get_mut(key)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[cfg(test)]
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn insert(&mut self, key: Bytes, entry: Entry) {
+        if let Some(expires_at) = entry.expires_at {
+            self.deadlines.insert((expires_at, key.clone()));
+        }
+
+        let previous = self.entries.insert(key.clone(), entry);
+
+        if let Some(expires_at) = previous.and_then(|previous| previous.expires_at) {
+            if self.entries[&key].expires_at != Some(expires_at) {
+                self.deadlines.remove(&(expires_at, key));
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let (key, entry) = self.entries.remove_entry(key)?;
+
+        if let Some(expires_at) = entry.expires_at {
+            self.deadlines.remove(&(expires_at, key));
+        }
+
+        Some(entry)
+    }
+
+    fn set_deadline(&mut self, key: &[u8], expires_at: Option<i64>) {
+        let Some((key, entry)) = self.entries.get_key_value(key) else {
+            return;
+        };
+        let (key, previous) = (key.clone(), entry.expires_at);
+
+        if let Some(previous) = previous {
+            self.deadlines.remove(&(previous, key.clone()));
+        }
+
+        if let Some(expires_at) = expires_at {
+            self.deadlines.insert((expires_at, key.clone()));
+        }
+
+        self.entries.get_mut(&key).unwrap().expires_at = expires_at;
+    }
+
+    /// Removes up to `limit` keys whose deadline passed, earliest first.
+    fn evict_due(&mut self, now: i64, limit: usize) -> Vec<Bytes> {
+        let mut evicted = Vec::new();
+
+        while evicted.len() < limit {
+            match self.deadlines.first() {
+                Some((expires_at, _)) if *expires_at <= now => {}
+                _ => break,
+            }
+
+            let (_, key) = self.deadlines.pop_first().unwrap();
+
+            self.entries.remove(&key);
+            evicted.push(key);
+        }
+
+        evicted
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExpireCondition {
+    NX,
+    XX,
+    GT,
+    LT,
+}
+
+impl ExpireCondition {
+    fn allows(&self, current: Option<i64>, requested: i64) -> bool {
+        use ExpireCondition::*;
+
+        match (self, current) {
+            (NX, current) => current.is_none(),
+            (XX, current) => current.is_some(),
+            (GT, Some(current)) => requested > current,
+            (GT, None) => false,
+            (LT, Some(current)) => requested < current,
+            (LT, None) => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetCondition {
+    NX,
+    XX,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+impl ListEnd {
+    pub fn push(&self, list: &mut List, element: Bytes) {
+        match self {
+            ListEnd::Left => list.push_front(element),
+            ListEnd::Right => list.push_back(element),
+        }
+    }
+
+    pub fn pop(&self, list: &mut List) -> Option<Bytes> {
+        match self {
+            ListEnd::Left => list.pop_front(),
+            ListEnd::Right => list.pop_back(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Expiry {
+    Persist,
+    Keep,
+    At(i64),
+}
+
+pub struct SetOutcome {
+    pub applied: bool,
+    pub previous: Option<Bytes>,
+}
+
+pub enum Ttl {
+    Missing,
+    Persistent,
+    ExpiresAt(i64),
+}
+
+/// Keyspace figures reported by INFO.
+pub struct KeyspaceStats {
+    pub keys: usize,
+    pub expires: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub expired: u64,
+}
+
+pub struct KvStore {
+    shards: Vec<RwLock<Shard>>,
+    dirty: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired: AtomicU64,
+    /// Keys commands found past their deadline and removed, until they are
+    /// recorded as DELs.
+    lazily_expired: Mutex<Vec<Bytes>>,
+}
+
+impl Default for KvStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KvStore {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARD_COUNT)
+                .map(|_| RwLock::new(Shard::default()))
+                .collect(),
+            dirty: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            lazily_expired: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>, WrongType> {
+        self.read(key, |value: &Bytes| value.to_owned())
+    }
+
+    /// Runs `read` on the value at `key`, or returns `None` when the key
+    /// does not exist.
+    pub fn read<V, T, F>(&self, key: &[u8], read: F) -> Result<Option<T>, WrongType>
+    where
+        V: ValueType,
+        F: FnOnce(&V) -> T,
+    {
+        let now = now_millis();
+
+        if let Some(entry) = self.shard(key).read().unwrap().get(key) {
+            if !entry.is_expired(now) {
+                return self.read_live(entry, read);
+            }
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+
+            return Ok(None);
+        }
+
+        self.remove_if_expired(key, now);
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        Ok(None)
+    }
+
+    fn read_live<V, T, F>(&self, entry: &Entry, read: F) -> Result<Option<T>, WrongType>
+    where
+        V: ValueType,
+        F: FnOnce(&V) -> T,
+    {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        V::from_value(&entry.value)
+            .map(|value| Some(read(value)))
+            .ok_or(WrongType)
+    }
+
+    /// Write-locks the shards holding `keys`, in shard order so commands
+    /// locking several never deadlock. A command that spans keys runs all of
+    /// its reads and writes under one `LockedKeys`, so other clients see it
+    /// whole.
+    pub fn lock<'k>(&self, keys: impl IntoIterator<Item = &'k Bytes>) -> LockedKeys<'_> {
+        let indexes = keys
+            .into_iter()
+            .map(|key| Self::shard_index(key))
+            .collect::<BTreeSet<_>>();
+
+        LockedKeys {
+            store: self,
+            shards: indexes
+                .into_iter()
+                .map(|index| (index, self.shards[index].write().unwrap()))
+                .collect(),
+        }
+    }
+
+    /// Runs `write` on the value at `key`. A missing key is created empty
+    /// when `create` is set and skipped otherwise, and a collection left
+    /// empty is removed. The TTL of an existing key is kept.
+    pub fn write<V, T, F>(
+        &self,
+        key: &Bytes,
+        create: bool,
+        write: F,
+    ) -> Result<Option<T>, WrongType>
+    where
+        V: ValueType,
+        F: FnOnce(&mut V) -> T,
+    {
+        self.write_in(&mut self.shard(key).write().unwrap(), key, create, write)
+    }
+
+    fn write_in<V, T, F>(
+        &self,
+        shard: &mut Shard,
+        key: &Bytes,
+        create: bool,
+        write: F,
+    ) -> Result<Option<T>, WrongType>
+    where
+        V: ValueType,
+        F: FnOnce(&mut V) -> T,
+    {
+        let now = now_millis();
+
+        if matches!(shard.get(key), Some(entry) if entry.is_expired(now)) {
+            self.remove_expired(shard, key);
+        }
+
+        if !shard.contains_key(key) {
+            if !create {
+                return Ok(None);
+            }
+
+            shard.insert(
+                key.clone(),
+                Entry {
+                    value: Arc::new(V::default().into_value()),
+                    expires_at: None,
+                },
+            );
+        }
+
+        let entry = shard.get_mut(key).unwrap();
+
+        V::from_value(&entry.value).ok_or(WrongType)?;
+
+        // Copies the value first if a snapshot still holds it.
+        let value = V::from_value_mut(Arc::make_mut(&mut entry.value)).unwrap();
+        let written = write(value);
+
+        if value.is_empty() {
+            shard.remove(key);
+        }
+
+        self.dirty.fetch_add(1, Ordering::SeqCst);
+
+        Ok(Some(written))
+    }
+
+    /// Stores `value` as is, as loading a snapshot does.
+    pub fn insert(&self, key: Bytes, value: Value, expires_at: Option<i64>) {
+        self.insert_in(
+            &mut self.shard(&key).write().unwrap(),
+            key,
+            value,
+            expires_at,
+        );
+    }
+
+    fn insert_in(&self, shard: &mut Shard, key: Bytes, value: Value, expires_at: Option<i64>) {
+        shard.insert(
+            key,
+            Entry {
+                value: Arc::new(value),
+                expires_at,
+            },
+        );
+        self.dirty.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Deletes `key`, reporting whether it held a value.
+    pub fn remove(&self, key: &[u8]) -> bool {
+        self.remove_in(&mut self.shard(key).write().unwrap(), key)
+    }
+
+    fn remove_in(&self, shard: &mut Shard, key: &[u8]) -> bool {
+        let now = now_millis();
+
+        match shard.get(key) {
+            Some(entry) if !entry.is_expired(now) => {
+                shard.remove(key);
+                self.dirty.fetch_add(1, Ordering::SeqCst);
+
+                true
+            }
+            Some(_) => {
+                self.remove_expired(shard, key);
+
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// The type name of the value at `key`.
+    pub fn value_type(&self, key: &[u8]) -> Option<&'static str> {
+        let now = now_millis();
+
+        match self.shard(key).read().unwrap().get(key) {
+            Some(entry) if !entry.is_expired(now) => return Some(entry.value.type_name()),
+            Some(_) => {}
+            None => return None,
+        }
+
+        self.remove_if_expired(key, now);
+
+        None
+    }
+
+    pub fn set(
+        &self,
+        key: &Bytes,
+        value: &Bytes,
+        condition: Option<SetCondition>,
+        expiry: Expiry,
+    ) -> SetOutcome {
+        match self.replace(key, value, condition, expiry, false) {
+            Ok(outcome) => outcome,
+            Err(WrongType) => unreachable!("only SET ... GET checks the type"),
+        }
+    }
+
+    /// `set` for SET ... GET: a value that is not a string is neither
+    /// returned nor replaced. The check and the write share one lock, so the
+    /// previous value is the one that was overwritten.
+    pub fn set_get(
+        &self,
+        key: &Bytes,
+        value: &Bytes,
+        condition: Option<SetCondition>,
+        expiry: Expiry,
+    ) -> Result<SetOutcome, WrongType> {
+        self.replace(key, value, condition, expiry, true)
+    }
+
+    fn replace(
+        &self,
+        key: &Bytes,
+        value: &Bytes,
+        condition: Option<SetCondition>,
+        expiry: Expiry,
+        strings_only: bool,
+    ) -> Result<SetOutcome, WrongType> {
+        let now = now_millis();
+        let mut shard = self.shard(key).write().unwrap();
+        let current = shard.get(key).filter(|entry| !entry.is_expired(now));
+        let previous = current
+            .and_then(|entry| Bytes::from_value(&entry.value))
+            .cloned();
+
+        if strings_only && current.is_some() && previous.is_none() {
+            return Err(WrongType);
+        }
+        let applied = match condition {
+            Some(SetCondition::NX) => current.is_none(),
+            Some(SetCondition::XX) => current.is_some(),
+            None => true,
+        };
+
+        if applied {
+            let expires_at = match expiry {
+                Expiry::Persist => None,
+                Expiry::Keep => current.and_then(|entry| entry.expires_at),
+                Expiry::At(expires_at) => Some(expires_at),
+            };
+
+            shard.insert(
+                key.clone(),
+                Entry {
+                    value: Arc::new(Value::String(value.clone())),
+                    expires_at,
+                },
+            );
+            self.dirty.fetch_add(1, Ordering::SeqCst);
+        }
+
+        Ok(SetOutcome { applied, previous })
+    }
+
+    pub fn ttl(&self, key: &[u8]) -> Ttl {
+        let now = now_millis();
+
+        match self.shard(key).read().unwrap().get(key) {
+            Some(entry) if !entry.is_expired(now) => match entry.expires_at {
+                Some(expires_at) => return Ttl::ExpiresAt(expires_at),
+                None => return Ttl::Persistent,
+            },
+            Some(_) => {}
+            None => return Ttl::Missing,
+        }
+
+        self.remove_if_expired(key, now);
+
+        Ttl::Missing
+    }
+
+    /// Sets the absolute deadline of `key` in unix milliseconds, returning
+    /// whether the deadline was applied. A deadline that already passed
+    /// deletes the key straight away.
+    pub fn expire(&self, key: &[u8], expires_at: i64, conditions: &[ExpireCondition]) -> bool {
+        let now = now_millis();
+        let mut shard = self.shard(key).write().unwrap();
+        let current = match shard.get(key) {
+            Some(entry) if !entry.is_expired(now) => entry.expires_at,
+            Some(_) => {
+                self.remove_expired(&mut shard, key);
+
+                return false;
+            }
+            None => return false,
+        };
+
+        if !conditions
+            .iter()
+            .all(|condition| condition.allows(current, expires_at))
+        {
+            return false;
+        }
+
+        if expires_at <= now {
+            shard.remove(key);
+        } else {
+            shard.set_deadline(key, Some(expires_at));
+        }
+
+        self.dirty.fetch_add(1, Ordering::SeqCst);
+
+        true
+    }
+
+    pub fn persist(&self, key: &[u8]) -> bool {
+        let now = now_millis();
+        let mut shard = self.shard(key).write().unwrap();
+
+        match shard.get(key) {
+            Some(entry) if !entry.is_expired(now) => {
+                let persisted = entry.expires_at.is_some();
+
+                if persisted {
+                    shard.set_deadline(key, None);
+                    self.dirty.fetch_add(1, Ordering::SeqCst);
+                }
+
+                persisted
+            }
+            Some(_) => {
+                self.remove_expired(&mut shard, key);
+
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Active expiry: drops up to `limit` keys per shard whose deadline has
+    /// passed, so keys that are never read again do not linger in memory.
+    /// Each shard is locked only for its own batch. Returns the evicted
+    /// keys.
+    pub fn evict_expired(&self, limit: usize) -> Vec<Bytes> {
+        let now = now_millis();
+        let mut evicted = Vec::new();
+
+        for shard in self.shards.iter() {
+            evicted.extend(shard.write().unwrap().evict_due(now, limit));
+        }
+
+        self.expired
+            .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+
+        evicted
+    }
+
+    /// Hands over the keys commands removed because their deadline passed,
+    /// so they can be recorded as DELs like the ones active expiry evicts.
+    pub fn take_lazily_expired(&self) -> Vec<Bytes> {
+        mem::take(&mut *self.lazily_expired.lock().unwrap())
+    }
+
+    pub fn has_lazily_expired(&self) -> bool {
+        !self.lazily_expired.lock().unwrap().is_empty()
+    }
+
+    /// Number of writes since the server started, used by the `save` rules.
+    pub fn dirty(&self) -> u64 {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    /// Copies every live key with its deadline. Values are shared with the
+    /// keyspace instead of cloned, and a write copies a value only if the
+    /// snapshot still holds it, so each shard is locked just long enough to
+    /// walk it. Shards are copied one at a time: callers that need a cut
+    /// consistent across keys hold the write lock exclusively.
+    pub fn snapshot(&self) -> Vec<SnapshotEntry> {
+        let now = now_millis();
+        let mut entries = Vec::new();
+
+        for shard in self.shards.iter() {
+            entries.extend(
+                shard
+                    .read()
+                    .unwrap()
+                    .entries
+                    .iter()
+                    .filter(|(_, entry)| !entry.is_expired(now))
+                    .map(|(key, entry)| (key.clone(), entry.value.clone(), entry.expires_at)),
+            );
+        }
+
+        entries
+    }
+
+    /// Exchanges the keyspaces of two stores shard by shard, so a dataset
+    /// loaded on the side replaces this one without rebuilding it.
+    pub fn swap(&self, other: &KvStore) {
+        for (shard, other) in self.shards.iter().zip(other.shards.iter()) {
+            mem::swap(&mut *shard.write().unwrap(), &mut *other.write().unwrap());
+        }
+
+        self.dirty.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Counts keys and keys with a TTL.
+    pub fn stats(&self) -> KeyspaceStats {
+        let mut keys = 0;
+        let mut expires = 0;
+
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+
+            keys += shard.len();
+            expires += shard.deadlines.len();
+        }
+
+        KeyspaceStats {
+            keys,
+            expires,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+        }
+    }
+
+    fn remove_if_expired(&self, key: &[u8], now: i64) {
+        let mut shard = self.shard(key).write().unwrap();
+
+        if matches!(shard.get(key), Some(entry) if entry.is_expired(now)) {
+            self.remove_expired(&mut shard, key);
+        }
+    }
+
+    /// Removes a key a command found past its deadline. It is queued for
+    /// `take_lazily_expired` while the shard is still locked, so a write
+    /// that recreates the key is recorded after its DEL.
+    fn remove_expired(&self, shard: &mut Shard, key: &[u8]) {
+        shard.remove(key);
+        self.expired.fetch_add(1, Ordering::Relaxed);
+        self.lazily_expired
+            .lock()
+            .unwrap()
+            .push(Bytes::copy_from_slice(key));
+    }
+
+    fn shard(&self, key: &[u8]) -> &RwLock<Shard> {
+        &self.shards[Self::shard_index(key)]
+    }
+
+    fn shard_index(key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+
+        key.hash(&mut hasher);
+
+        hasher.finish() as usize % SHARD_COUNT
+    }
+}
+
+/// The shards a command spanning several keys has locked, with the same
+/// accessors as `KvStore` for the keys they hold.
+pub struct LockedKeys<'a> {
+    store: &'a KvStore,
+    shards: Vec<(usize, RwLockWriteGuard<'a, Shard>)>,
+}
+
+impl LockedKeys<'_> {
+    pub fn read<V, T, F>(&self, key: &[u8], read: F) -> Result<Option<T>, WrongType>
+    where
+        V: ValueType,
+        F: FnOnce(&V) -> T,
+    {
+        let now = now_millis();
+
+        match self.shard(key).get(key) {
+            Some(entry) if !entry.is_expired(now) => self.store.read_live(entry, read),
+            _ => {
+                self.store.misses.fetch_add(1, Ordering::Relaxed);
+
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn write<V, T, F>(
+        &mut self,
+        key: &Bytes,
+        create: bool,
+        write: F,
+    ) -> Result<Option<T>, WrongType>
+    where
+        V: ValueType,
+        F: FnOnce(&mut V) -> T,
+    {
+        let store = self.store;
+
+        store.write_in(self.shard_mut(key), key, create, write)
+    }
+
+    pub fn insert(&mut self, key: Bytes, value: Value, expires_at: Option<i64>) {
+        let store = self.store;
+
+        store.insert_in(self.shard_mut(&key), key, value, expires_at);
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        let store = self.store;
+
+        store.remove_in(self.shard_mut(key), key)
+    }
+
+    fn shard(&self, key: &[u8]) -> &Shard {
+        let index = KvStore::shard_index(key);
+
+        self.shards
+            .iter()
+            .find(|(locked, _)| *locked == index)
+            .map(|(_, shard)| &**shard)
+            .expect("the key was locked")
+    }
+
+    fn shard_mut(&mut self, key: &[u8]) -> &mut Shard {
+        let index = KvStore::shard_index(key);
+
+        self.shards
+            .iter_mut()
+            .find(|(locked, _)| *locked == index)
+            .map(|(_, shard)| &mut **shard)
+            .expect("the key was locked")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use bytes::Bytes;
+
+    use super::{
+        now_millis, ExpireCondition, Expiry, KvStore, List, ListEnd, SetCondition, Ttl, Value,
+    };
+    use crate::redis::error::WrongType;
+
+    #[test]
+    fn shared_between_threads() {
+        let store = Arc::new(KvStore::new());
+        let writer_store = Arc::clone(&store);
+
+        thread::spawn(move || {
+            writer_store.set(
+                &Bytes::from("key"),
+                &Bytes::from("value"),
+                None,
+                Expiry::Persist,
+            )
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(store.get(b"key").unwrap(), Some(Bytes::from("value")));
+    }
+
+    #[test]
+    fn set_returns_previous_value() {
+        let store = KvStore::new();
+
+        assert_eq!(
+            store
+                .set(
+                    &Bytes::from("key"),
+                    &Bytes::from("first"),
+                    None,
+                    Expiry::Persist
+                )
+                .previous,
+            None
+        );
+        assert_eq!(
+            store
+                .set(
+                    &Bytes::from("key"),
+                    &Bytes::from("second"),
+                    None,
+                    Expiry::Persist
+                )
+                .previous,
+            Some(Bytes::from("first"))
+        );
+    }
+
+    #[test]
+    fn set_get_leaves_other_types_alone() {
+        let store = KvStore::new();
+        let key = Bytes::from("key");
+
+        store
+            .write(&key, true, |list: &mut List| {
+                list.push_back(Bytes::from("a"))
+            })
+            .unwrap();
+
+        assert!(matches!(
+            store.set_get(&key, &Bytes::from("value"), None, Expiry::Persist),
+            Err(WrongType)
+        ));
+        assert_eq!(store.value_type(&key), Some("list"));
+        assert_eq!(
+            store
+                .set_get(&Bytes::from("other"), &key, None, Expiry::Persist)
+                .unwrap()
+                .previous,
+            None
+        );
+    }
+
+    #[test]
+    fn conditional_set() {
+        let store = KvStore::new();
+
+        assert!(
+            !store
+                .set(
+                    &Bytes::from("key"),
+                    &Bytes::from("value"),
+                    Some(SetCondition::XX),
+                    Expiry::Persist
+                )
+                .applied
+        );
+        assert!(
+            store
+                .set(
+                    &Bytes::from("key"),
+                    &Bytes::from("value"),
+                    Some(SetCondition::NX),
+                    Expiry::Persist
+                )
+                .applied
+        );
+
+        let outcome = store.set(
+            &Bytes::from("key"),
+            &Bytes::from("other"),
+            Some(SetCondition::NX),
+            Expiry::Persist,
+        );
+
+        assert!(!outcome.applied);
+        assert_eq!(outcome.previous, Some(Bytes::from("value")));
+        assert_eq!(store.get(b"key").unwrap(), Some(Bytes::from("value")));
+    }
+
+    #[test]
+    fn set_keeps_or_clears_ttl() {
+        let store = KvStore::new();
+        let deadline = now_millis() + 10_000;
+
+        store.set(
+            &Bytes::from("key"),
+            &Bytes::from("value"),
+            None,
+            Expiry::At(deadline),
+        );
+        store.set(
+            &Bytes::from("key"),
+            &Bytes::from("value"),
+            None,
+            Expiry::Keep,
+        );
+
+        assert!(matches!(store.ttl(b"key"), Ttl::ExpiresAt(at) if at == deadline));
+
+        store.set(
+            &Bytes::from("key"),
+            &Bytes::from("value"),
+            None,
+            Expiry::Persist,
+        );
+
+        assert!(matches!(store.ttl(b"key"), Ttl::Persistent));
+    }
+
+    #[test]
+    fn deadline_index_follows_writes() {
+        let store = KvStore::new();
+        let past = now_millis() - 1;
+        let key = Bytes::from("key");
+
+        store.insert(key.clone(), Value::String(Bytes::from("a")), Some(past + 1));
+        store.insert(key.clone(), Value::String(Bytes::from("b")), Some(past));
+        store.set(&Bytes::from("other"), &key, None, Expiry::At(past));
+        store.set(&Bytes::from("other"), &key, None, Expiry::Persist);
+
+        assert_eq!(store.stats().expires, 1);
+        assert_eq!(store.evict_expired(10), vec![key.clone()]);
+
+        store.insert(key.clone(), Value::String(Bytes::from("c")), Some(past));
+        store.persist(b"missing");
+        store.shard(&key).write().unwrap().set_deadline(&key, None);
+
+        assert!(store.evict_expired(10).is_empty());
+        assert!(store.remove(&key));
+        assert_eq!(store.stats().expires, 0);
+    }
+
+    #[test]
+    fn expired_keys_are_removed_lazily() {
+        let store = KvStore::new();
+
+        store.set(
+            &Bytes::from("key"),
+            &Bytes::from("value"),
+            None,
+            Expiry::Persist,
+        );
+        store
+            .shard(b"key")
+            .write()
+            .unwrap()
+            .get_mut(&b"key"[..])
+            .unwrap()
+            .expires_at = Some(now_millis() - 1);
+
+        assert_eq!(store.get(b"key").unwrap(), None);
+        assert!(store.shard(b"key").read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn expired_keys_are_removed_actively() {
+        let store = KvStore::new();
+
+        store.set(
+            &Bytes::from("stale"),
+            &Bytes::from("value"),
+            None,
+            Expiry::Persist,
+        );
+        store.set(
+            &Bytes::from("fresh"),
+            &Bytes::from("value"),
+            None,
+            Expiry::Persist,
+        );
+        store.expire(b"fresh", now_millis() + 10_000, &[]);
+        store
+            .shard(b"stale")
+            .write()
+            .unwrap()
+            .set_deadline(b"stale", Some(now_millis() - 1));
+
+        assert_eq!(store.evict_expired(1), vec![Bytes::from("stale")]);
+        assert!(store.evict_expired(1).is_empty());
+        assert_eq!(store.get(b"fresh").unwrap(), Some(Bytes::from("value")));
+        assert_eq!(store.stats().expires, 1);
+    }
+
+    #[test]
+    fn expire_conditions() {
+        let store = KvStore::new();
+        let deadline = now_millis() + 10_000;
+
+        store.set(
+            &Bytes::from("key"),
+            &Bytes::from("value"),
+            None,
+            Expiry::Persist,
+        );
+
+        assert!(!store.expire(b"key", deadline, &[ExpireCondition::XX]));
+        assert!(!store.expire(b"key", deadline, &[ExpireCondition::GT]));
+        assert!(store.expire(b"key", deadline, &[ExpireCondition::NX]));
+        assert!(!store.expire(b"key", deadline, &[ExpireCondition::NX]));
+        assert!(!store.expire(b"key", deadline - 1, &[ExpireCondition::GT]));
+        assert!(store.expire(b"key", deadline - 1, &[ExpireCondition::LT]));
+        assert!(matches!(store.ttl(b"key"), Ttl::ExpiresAt(at) if at == deadline - 1));
+
+        assert!(store.persist(b"key"));
+        assert!(matches!(store.ttl(b"key"), Ttl::Persistent));
+        assert!(matches!(store.ttl(b"missing"), Ttl::Missing));
+    }
+
+    #[test]
+    fn expire_in_the_past_deletes() {
+        let store = KvStore::new();
+
+        store.set(
+            &Bytes::from("key"),
+            &Bytes::from("value"),
+            None,
+            Expiry::Persist,
+        );
+
+        assert!(store.expire(b"key", now_millis() - 1, &[]));
+        assert_eq!(store.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn counts_writes_and_snapshots_live_keys() {
+        let store = KvStore::new();
+        let deadline = now_millis() + 10_000;
+
+        store.set(
+            &Bytes::from("key"),
+            &Bytes::from("value"),
+            None,
+            Expiry::At(deadline),
+        );
+        store.set(
+            &Bytes::from("key"),
+            &Bytes::from("other"),
+            Some(SetCondition::NX),
+            Expiry::Persist,
+        );
+        store.persist(b"missing");
+
+        assert_eq!(store.dirty(), 1);
+        assert_eq!(
+            store.snapshot(),
+            vec![(
+                Bytes::from("key"),
+                Arc::new(Value::String(Bytes::from("value"))),
+                Some(deadline)
+            )]
+        );
+
+        assert_eq!(store.get(b"key").unwrap(), Some(Bytes::from("value")));
+        assert_eq!(store.get(b"missing").unwrap(), None);
+
+        let stats = store.stats();
+
+        assert_eq!((stats.keys, stats.expires), (1, 1));
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+
+        let replaced = KvStore::new();
+
+        store.swap(&replaced);
+
+        assert_eq!(store.dirty(), 2);
+        assert!(store.snapshot().is_empty());
+        assert_eq!(replaced.snapshot().len(), 1);
+    }
+
+    #[test]
+    fn snapshots_share_values_until_written() {
+        let store = KvStore::new();
+        let key = Bytes::from("list");
+
+        store.insert(
+            key.clone(),
+            Value::List(List::from([Bytes::from("a")])),
+            None,
+        );
+
+        let snapshot = store.snapshot();
+
+        store
+            .write(&key, false, |list: &mut List| {
+                list.push_back(Bytes::from("b"))
+            })
+            .unwrap();
+
+        assert_eq!(*snapshot[0].1, Value::List(List::from([Bytes::from("a")])));
+        assert_eq!(store.read(&key, |list: &List| list.len()), Ok(Some(2)));
+        assert_eq!(Arc::strong_count(&snapshot[0].1), 1);
+    }
+
+    #[test]
+    fn locked_keys_write_across_shards() {
+        let store = Arc::new(KvStore::new());
+        let keys = (0..8)
+            .map(|index| Bytes::from(format!("key:{index}")))
+            .collect::<Vec<_>>();
+        let mut locked = store.lock(keys.iter());
+
+        for key in keys.iter() {
+            locked
+                .write(key, true, |list: &mut List| list.push_back(key.clone()))
+                .unwrap();
+        }
+
+        // Other clients wait until every key is written.
+        let reader_store = Arc::clone(&store);
+        let reader = thread::spawn(move || reader_store.stats().keys);
+
+        thread::sleep(Duration::from_millis(20));
+
+        assert!(!reader.is_finished());
+        assert_eq!(locked.read(&keys[3], |list: &List| list.len()), Ok(Some(1)));
+
+        drop(locked);
+
+        assert_eq!(reader.join().unwrap(), 8);
+    }
+
+    #[test]
+    fn typed_access() {
+        let store = KvStore::new();
+        let key = Bytes::from("list");
+
+        assert_eq!(
+            store.write(&key, false, |list: &mut List| list.len()),
+            Ok(None)
+        );
+        assert_eq!(
+            store.write(&key, true, |list: &mut List| {
+                ListEnd::Left.push(list, Bytes::from("a"));
+                ListEnd::Right.push(list, Bytes::from("b"));
+                list.len()
+            }),
+            Ok(Some(2))
+        );
+        assert_eq!(store.value_type(b"list"), Some("list"));
+        assert_eq!(store.get(b"list"), Err(WrongType));
+        assert_eq!(
+            store.read(b"list", |list: &List| list
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()),
+            Ok(Some(vec![Bytes::from("a"), Bytes::from("b")]))
+        );
+
+        store.expire(b"list", now_millis() + 10_000, &[]);
+        assert_eq!(
+            store.write(&key, false, |list: &mut List| ListEnd::Left.pop(list)),
+            Ok(Some(Some(Bytes::from("a"))))
+        );
+
+        assert!(matches!(store.ttl(b"list"), Ttl::ExpiresAt(_)));
+
+        assert_eq!(
+            store.write(&key, false, |list: &mut List| ListEnd::Right.pop(list)),
+            Ok(Some(Some(Bytes::from("b"))))
+        );
+
+        assert_eq!(store.value_type(b"list"), None);
+
+        store.set(&key, &Bytes::from("value"), None, Expiry::Persist);
+
+        assert_eq!(
+            store.write(&key, true, |list: &mut List| list.len()),
+            Err(WrongType)
+        );
+    }
+}